@@ -1,13 +1,25 @@
-use crate::lang::highassembly::{ArgValue, GenericBlock, GenericLine, KeyValue, SectionName};
+use crate::diagnostics::Diagnostic;
+use crate::lang::directive::Directive;
+use crate::lang::expr::{Expr, Operator, Value, to_i32};
+use crate::lang::highassembly::{
+    ArgValue, GenericBlock, GenericLine, KeyValue, SectionFlags, SectionName, SymbolAttributes,
+    SymbolBinding, SymbolDirective, SymbolKind, SymbolVisibility,
+};
 use crate::lang::lowassembly::{
-    EncodableKey, EncodableLine, PositionedEncodableBlock, PositionedEncodedBlock,
+    DataEndianness, EncodableKey, EncodableLine, PositionedEncodableBlock, PositionedEncodedBlock,
 };
+use crate::lang::pseudo::{lower_12_bits, upper_20_bits};
 use crate::utils::words_to_bytes_be;
 use std::collections::HashMap;
 
 pub trait Assembler {
     type Input;
-    fn assemble(&self, instructions: Self::Input) -> AssemblerTools;
+    /// Lines holding errors are left out, so that the rest can still be checked
+    fn assemble(
+        &self,
+        instructions: Self::Input,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> AssemblerTools;
 }
 
 /**/
@@ -17,13 +29,24 @@ pub struct Symbol {
     pub(crate) section: SectionName,
     pub(crate) relative_address: usize,
     pub(crate) length: usize,
-    pub(crate) scope: String,
+    pub(crate) attributes: SymbolAttributes,
 }
 
 #[derive(Debug)]
 pub struct Section {
     pub(crate) address: usize,
     pub(crate) name: SectionName,
+    pub(crate) alignment: usize,
+    pub(crate) flags: SectionFlags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelocationKind {
+    /// Address taken by an 'auipc' and the instruction following it (R_RISCV_PCREL_HI20 and
+    /// R_RISCV_PCREL_LO12_I)
+    PcrelPair,
+    /// Address held by data (R_RISCV_32)
+    Abs32,
 }
 
 #[derive(Debug)]
@@ -31,6 +54,9 @@ pub struct RelocationEntry {
     pub(crate) id: usize,
     pub(crate) address: usize,
     pub(crate) addend: i32,
+    pub(crate) kind: RelocationKind,
+    /// Section the address to be relocated is in
+    pub(crate) section: SectionName,
 }
 
 #[derive(Debug)]
@@ -44,25 +70,16 @@ pub struct AssemblerTools {
 }
 
 impl AssemblerTools {
-    fn section_words(&self, name: SectionName) -> Vec<u32> {
-        let text_sections_data: Vec<Vec<u32>> = self
+    pub fn section_words(&self, name: SectionName) -> Vec<u32> {
+        let mut bytes: Vec<u8> = self
             .blocks
             .iter()
-            .filter_map(|block| {
-                if block.name == name {
-                    let data: Vec<_> = block
-                        .instructions
-                        .iter()
-                        .map(|i| i.data.clone())
-                        .flatten()
-                        .collect();
-                    Some(data)
-                } else {
-                    None
-                }
-            })
+            .filter(|block| block.name == name)
+            .flat_map(|block| block.instructions.iter())
+            .flat_map(|i| i.data.iter().copied())
             .collect();
-        text_sections_data.into_iter().flatten().collect()
+        bytes.resize(bytes.len().next_multiple_of(4), 0);
+        DataEndianness::build_words_from_bytes(&bytes, DataEndianness::Le)
     }
 
     pub fn text_section_words(&self) -> Vec<u32> {
@@ -107,9 +124,27 @@ pub struct PositionedGenericLine {
 pub struct PositionedGenericBlock {
     address: usize,
     name: SectionName,
+    flags: SectionFlags,
+    /// Boundary the section must start at
+    alignment: usize,
     lines: Vec<PositionedGenericLine>,
 }
 
+impl PositionedGenericBlock {
+    /// Code is kept aligned to instructions, while data is packed
+    fn line_alignment(&self) -> usize {
+        if self.flags.exec { 4 } else { 1 }
+    }
+
+    fn size(&self) -> usize {
+        let alignment = self.line_alignment();
+        self.lines
+            .iter()
+            .map(|line| line.line.size_bytes_with_alignment(alignment))
+            .sum()
+    }
+}
+
 // 2.1 Extracting the metadata section
 //   The metadata section is intended to:
 //    1. store directives that modify the visibility of labels
@@ -134,7 +169,9 @@ fn cast_generic_to_positioned_blocks(blocks: Vec<GenericBlock>) -> Vec<Positione
         .into_iter()
         .map(|block| PositionedGenericBlock {
             address: 0,
+            alignment: 1,
             name: block.name,
+            flags: block.flags,
             lines: block
                 .lines
                 .into_iter()
@@ -155,45 +192,50 @@ fn gen_section_address(
     initial_addr: usize,
     block_offset: usize,
 ) -> Vec<PositionedGenericBlock> {
-    //OBS: if block_offset % 4 != 0, then this could lead to section alignment problems
     let mut new_blocks = Vec::new();
     let mut next_block_address = initial_addr;
     for block in blocks {
-        let block_size: usize = block
-            .lines
-            .iter()
-            .map(|line| line.line.size_bytes_at_word_boundary())
-            .sum();
-        new_blocks.push(PositionedGenericBlock {
-            address: next_block_address,
-            ..block
-        });
-        next_block_address += block_size + block_offset;
+        let address = next_block_address.next_multiple_of(block.alignment);
+        next_block_address = address + block.size() + block_offset;
+        new_blocks.push(PositionedGenericBlock { address, ..block });
     }
     new_blocks
 }
 
 // 2.4 Generating the relative address of each instruction
 
+//   Alignment directives get translated into the padding they need here, and the section takes
+//   the largest of their boundaries (sections are at least word aligned)
+
 fn gen_line_address(blocks: Vec<PositionedGenericBlock>) -> Vec<PositionedGenericBlock> {
     let mut new_blocks = Vec::new();
     for block in blocks {
+        let code = block.flags.exec;
+        let line_alignment = block.line_alignment();
+        let mut alignment = 4;
         let mut relative_address = 0;
         let lines_with_address = block
             .lines
             .into_iter()
-            .map(|line| {
+            .map(|mut line| {
+                if let KeyValue::AssemblyDirective(d) = &line.line.keyword
+                    && let Ok(Some(boundary)) = d.boundary(&line.line.args)
+                {
+                    alignment = alignment.max(boundary);
+                    let padding = d.padding(&line.line.args, relative_address, code);
+                    line.line.args = padding.into_iter().map(ArgValue::Byte).collect();
+                }
                 let new_line = PositionedGenericLine {
                     relative_address,
                     ..line
                 };
-                // relative_address += new_line.line.size_bytes_unaligned();
-                relative_address += new_line.line.size_bytes_at_word_boundary();
+                relative_address += new_line.line.size_bytes_with_alignment(line_alignment);
                 new_line
             })
             .collect();
         new_blocks.push(PositionedGenericBlock {
             lines: lines_with_address,
+            alignment,
             ..block
         });
     }
@@ -246,6 +288,8 @@ fn gen_section_table(sections: &Vec<PositionedGenericBlock>) -> HashMap<String,
         let value = Section {
             name: section.name.clone(),
             address: section.address,
+            alignment: section.alignment,
+            flags: section.flags,
         };
         map.insert(section.name.default_name(), value);
     }
@@ -272,8 +316,8 @@ fn gen_symbol_table(sections: &Vec<PositionedGenericBlock>) -> HashMap<String, S
                         let value = Symbol {
                             section: section.name.clone(),
                             relative_address: line.relative_address,
-                            scope: String::from("File"),
                             length: symbol_size,
+                            attributes: SymbolAttributes::default(),
                         };
                         v.insert(s.clone(), value);
                     }
@@ -285,6 +329,185 @@ fn gen_symbol_table(sections: &Vec<PositionedGenericBlock>) -> HashMap<String, S
     v
 }
 
+/// Symbols assigned with '.equ', '.set' or '=' which the parser couldn't turn into constants:
+/// they're absolute when their value is one, or else take the section of the symbols they're
+/// computed from
+fn gen_assigned_symbols(
+    sections: &Vec<PositionedGenericBlock>,
+    symbols: &mut HashMap<String, Symbol>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for section in sections {
+        for line in &section.lines {
+            let KeyValue::Assignment(name) = &line.line.keyword else {
+                continue;
+            };
+            let Some(expr) = line.line.args.first().and_then(arg_to_expr) else {
+                continue;
+            };
+            let value = match evaluate_expr(&expr, symbols, section, line) {
+                Ok(value) => value,
+                Err(message) => {
+                    diagnostics.push(Diagnostic::error(line.line.file_pos, message));
+                    continue;
+                }
+            };
+            let (section, address) = match value.relative_to() {
+                _ if value.is_constant() => match to_i32(value.constant) {
+                    Ok(n) => (SectionName::Absolute, n as u32 as usize),
+                    Err(message) => {
+                        diagnostics.push(Diagnostic::error(line.line.file_pos, message));
+                        continue;
+                    }
+                },
+                Some((section, offset)) if offset >= 0 => {
+                    (SectionName::from_default_name(section), offset as usize)
+                }
+                _ => {
+                    let message = format!(
+                        "'{}' must be a constant or an address in a single section",
+                        name
+                    );
+                    diagnostics.push(Diagnostic::error(line.line.file_pos, message));
+                    continue;
+                }
+            };
+            let symbol = Symbol {
+                section,
+                relative_address: address,
+                length: 0,
+                attributes: SymbolAttributes::default(),
+            };
+            symbols.insert(name.clone(), symbol);
+        }
+    }
+}
+
+/// Attributes given to symbols by '.globl', '.local', '.weak', '.hidden', '.protected' and
+/// '.type' (from the metadata), and by '.size' (where it's written, as '.' is often used)
+///
+/// Global and weak symbols which no line defines are left to other objects, as undefined ones
+fn gen_symbol_attributes(
+    metadata: Option<&GenericBlock>,
+    sections: &[PositionedGenericBlock],
+    symbols: &mut HashMap<String, Symbol>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let lines = metadata.map_or(&[][..], |block| &block.lines[..]);
+    // bindings go first, as they declare the undefined symbols the others can refer to
+    let (bindings, others): (Vec<&GenericLine>, Vec<&GenericLine>) =
+        lines.iter().partition(|line| {
+            matches!(
+                line.keyword,
+                KeyValue::LinkerDirective(
+                    SymbolDirective::Global | SymbolDirective::Weak | SymbolDirective::Local
+                )
+            )
+        });
+    for line in bindings.into_iter().chain(others) {
+        let KeyValue::LinkerDirective(directive) = &line.keyword else {
+            continue;
+        };
+        if let Err(message) = set_symbol_attributes(directive, &line.args, symbols) {
+            diagnostics.push(Diagnostic::error(line.file_pos, message));
+        }
+    }
+    for section in sections {
+        for line in &section.lines {
+            let KeyValue::LinkerDirective(SymbolDirective::Size) = &line.line.keyword else {
+                continue;
+            };
+            if let Err(message) = set_symbol_size(&line.line.args, symbols, section, line) {
+                diagnostics.push(Diagnostic::error(line.line.file_pos, message));
+            }
+        }
+    }
+}
+
+fn set_symbol_attributes(
+    directive: &SymbolDirective,
+    args: &[ArgValue],
+    symbols: &mut HashMap<String, Symbol>,
+) -> Result<(), String> {
+    if let SymbolDirective::Type { symbol, kind } = directive {
+        if symbol.is_empty() || kind.is_empty() || !args.is_empty() {
+            return Err(String::from("expected a symbol name and its type"));
+        }
+        let kind = SymbolKind::parse(kind)?;
+        let symbol = symbols
+            .get_mut(symbol)
+            .ok_or_else(|| format!("unknown symbol '{}'", symbol))?;
+        symbol.attributes.kind = kind;
+        return Ok(());
+    }
+    if args.is_empty() || args.iter().any(|arg| !matches!(arg, ArgValue::Use(_, 0))) {
+        return Err(String::from("expected symbol names"));
+    }
+    for arg in args {
+        let ArgValue::Use(name, _) = arg else {
+            continue;
+        };
+        let binding = match directive {
+            SymbolDirective::Global => SymbolBinding::Global,
+            SymbolDirective::Weak => SymbolBinding::Weak,
+            _ => SymbolBinding::Local,
+        };
+        let symbol = match directive {
+            SymbolDirective::Global | SymbolDirective::Weak => {
+                symbols.entry(name.clone()).or_insert(Symbol {
+                    section: SectionName::Undefined,
+                    relative_address: 0,
+                    length: 0,
+                    attributes: SymbolAttributes::default(),
+                })
+            }
+            _ => symbols
+                .get_mut(name)
+                .ok_or_else(|| format!("unknown symbol '{}'", name))?,
+        };
+        match directive {
+            SymbolDirective::Local if symbol.section == SectionName::Undefined => {
+                return Err(format!("'{}' is never defined, so it can't be local", name));
+            }
+            SymbolDirective::Global | SymbolDirective::Weak | SymbolDirective::Local => {
+                symbol.attributes.binding = binding;
+            }
+            SymbolDirective::Hidden => symbol.attributes.visibility = SymbolVisibility::Hidden,
+            SymbolDirective::Protected => {
+                symbol.attributes.visibility = SymbolVisibility::Protected;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// '.size name, expr', whose size must be a constant
+fn set_symbol_size(
+    args: &[ArgValue],
+    symbols: &mut HashMap<String, Symbol>,
+    section: &PositionedGenericBlock,
+    line: &PositionedGenericLine,
+) -> Result<(), String> {
+    let [ArgValue::Use(name, 0), size] = args else {
+        return Err(String::from("expected a symbol name and its size"));
+    };
+    let Some(expr) = arg_to_expr(size) else {
+        return Err(String::from("expected a symbol name and its size"));
+    };
+    let value = evaluate_expr(&expr, symbols, section, line)?;
+    if !value.is_constant() {
+        return Err(format!("the size of '{}' must be a constant", name));
+    }
+    let size = usize::try_from(value.constant)
+        .map_err(|_| format!("the size of '{}' can't be negative", name))?;
+    let symbol = symbols
+        .get_mut(name)
+        .ok_or_else(|| format!("unknown symbol '{}'", name))?;
+    symbol.length = size;
+    Ok(())
+}
+
 // 2.8 Generating the string table
 
 fn gen_string_table(_sections: &Vec<PositionedGenericBlock>) -> Vec<String> {
@@ -308,29 +531,35 @@ fn gen_relocation_table(
     let mut relocation_table = HashMap::new();
     let mut rel_count = 0;
     for section in blocks {
-        if section.name != SectionName::Text {
+        if !section.flags.exec {
             continue;
         }
         for line in &section.lines {
             for arg in &line.line.args {
                 match arg {
                     ArgValue::UseHi(s, addend) => {
-                        if let Ok((_, symb_sect)) = get_symb_addrs(&s, symbols, sections) {
-                            let refers_external_symbol = symb_sect.name != section.name;
-                            if refers_external_symbol {
-                                let relname = s.clone();
-                                let relocation = RelocationEntry {
-                                    id: rel_count,
-                                    address: line.relative_address,
-                                    addend: *addend,
-                                };
-                                rel_count += 1;
-                                relocation_table
-                                    .entry(relname)
-                                    .or_insert(Vec::new())
-                                    .push(relocation);
-                            }
+                        let refers_external_symbol = match get_symb_addrs(s, symbols, sections) {
+                            Ok((_, symb_sect)) => symb_sect.name != section.name,
+                            // defined by another object
+                            Err(()) => symbols
+                                .get(s)
+                                .is_some_and(|symb| symb.section == SectionName::Undefined),
                         };
+                        if refers_external_symbol {
+                            let relname = s.clone();
+                            let relocation = RelocationEntry {
+                                id: rel_count,
+                                address: line.relative_address,
+                                addend: *addend,
+                                kind: RelocationKind::PcrelPair,
+                                section: section.name.clone(),
+                            };
+                            rel_count += 1;
+                            relocation_table
+                                .entry(relname)
+                                .or_insert(Vec::new())
+                                .push(relocation);
+                        }
                     }
                     _ => {}
                 }
@@ -372,72 +601,322 @@ fn compute_offset(
     symb_faddr - line_faddr
 }
 
+/// Value of an expression, in which symbols (and '.', the address of the line) are relative to
+/// their sections
+fn evaluate_expr(
+    expr: &Expr,
+    symbols: &HashMap<String, Symbol>,
+    section: &PositionedGenericBlock,
+    line: &PositionedGenericLine,
+) -> Result<Value, String> {
+    if let Some(name) = expr
+        .names()
+        .into_iter()
+        .find(|name| *name != "." && !symbols.contains_key(*name))
+    {
+        return Err(format!("unknown symbol '{}'", name));
+    }
+    expr.evaluate(&|name| match name {
+        "." => Some(Value::relative(
+            &section.name.default_name(),
+            line.root_relative_address as i64,
+        )),
+        _ => symbols.get(name).map(|symb| match symb.section {
+            SectionName::Absolute => Value::constant(symb.relative_address as i64),
+            // only known by name, which relocations refer to
+            SectionName::Undefined => Value::relative(name, 0),
+            _ => Value::relative(&symb.section.default_name(), symb.relative_address as i64),
+        }),
+    })
+}
+
+/// Symbol use (or expression) as an expression
+fn arg_to_expr(arg: &ArgValue) -> Option<Expr> {
+    match arg {
+        ArgValue::Number(n) => Some(Expr::Number(*n)),
+        ArgValue::Use(s, addend) => Some(Expr::Binary(
+            Operator::Plus,
+            Box::new(Expr::Symbol(s.to_string())),
+            Box::new(Expr::Number(*addend as i64)),
+        )),
+        ArgValue::Expr(expr) => Some(expr.clone()),
+        _ => None,
+    }
+}
+
+/// Offset from a line to a symbol (plus 'addend'), which can also be an absolute one
+fn symbol_offset(
+    s: &str,
+    addend: i32,
+    symbols: &HashMap<String, Symbol>,
+    sections: &HashMap<String, Section>,
+    section: &PositionedGenericBlock,
+    line: &PositionedGenericLine,
+) -> Option<i32> {
+    if let Some(symb) = symbols.get(s)
+        && symb.section == SectionName::Absolute
+    {
+        let line_faddr = (section.address + line.root_relative_address) as i32;
+        return Some((symb.relative_address as i32).wrapping_add(addend) - line_faddr);
+    }
+    let (symb, symb_sect) = get_symb_addrs(s, symbols, sections).ok()?;
+    Some(compute_offset(
+        section.address,
+        line.root_relative_address,
+        symb,
+        symb_sect,
+        addend,
+    ))
+}
+
+/// Operand of an instruction: either a constant, or an address (which becomes pc-relative, as
+/// the symbols used by instructions)
+fn resolve_operand(
+    expr: &Expr,
+    symbols: &HashMap<String, Symbol>,
+    sections: &HashMap<String, Section>,
+    section: &PositionedGenericBlock,
+    line: &PositionedGenericLine,
+) -> Result<i32, String> {
+    let value = evaluate_expr(expr, symbols, section, line)?;
+    if value.is_constant() {
+        return to_i32(value.constant);
+    }
+    let Some((name, offset)) = value.relative_to() else {
+        return Err(String::from(
+            "expression must be a constant or an address in a single section",
+        ));
+    };
+    let Some(target_section) = sections.get(name) else {
+        return Err(undefined_use(name));
+    };
+    let target = target_section.address as i64 + offset;
+    let line_faddr = (section.address + line.root_relative_address) as i64;
+    to_i32(target - line_faddr)
+}
+
+/// Value loaded by 'li', which can refer to symbols as long as it's a constant (i.e: 'end - start')
+fn resolve_constant(
+    expr: &Expr,
+    symbols: &HashMap<String, Symbol>,
+    section: &PositionedGenericBlock,
+    line: &PositionedGenericLine,
+) -> Result<i32, String> {
+    let value = evaluate_expr(expr, symbols, section, line)?;
+    if !value.is_constant() {
+        return Err(String::from(
+            "'li' needs a constant, such as the difference of two symbols in the same section \
+             (addresses are loaded with 'la')",
+        ));
+    }
+    to_i32(value.constant)
+}
+
+/// Data directive whose arguments needed the address of symbols (i.e: '.word end - start')
+///
+/// Addresses (i.e: '.word msg + 4') get relocations, to the symbol used or else to the section
+/// they're in
+fn resolve_data(
+    directive: &dyn Directive,
+    args: &[ArgValue],
+    symbols: &HashMap<String, Symbol>,
+    sections: &HashMap<String, Section>,
+    section: &PositionedGenericBlock,
+    line: &PositionedGenericLine,
+    relocations: &mut HashMap<String, Vec<RelocationEntry>>,
+) -> Result<Vec<ArgValue>, String> {
+    let size = directive.datatype().size_bytes();
+    let mut numbers = Vec::new();
+    let mut addresses = Vec::new();
+    for (idx, arg) in args.iter().enumerate() {
+        let expr = match arg {
+            ArgValue::Use(..) | ArgValue::Expr(_) => arg_to_expr(arg).unwrap(),
+            other => {
+                numbers.push(other.clone());
+                continue;
+            }
+        };
+        let value = evaluate_expr(&expr, symbols, section, line)?;
+        if value.is_constant() {
+            numbers.push(ArgValue::Number(value.constant));
+            continue;
+        }
+        let Some((name, offset)) = value.relative_to().filter(|_| size == 4) else {
+            return Err(String::from(
+                "data can only hold constants, differences of symbols and (32 bit) addresses",
+            ));
+        };
+        let target = match arg {
+            ArgValue::Use(symbol, addend) => (symbol.clone(), *addend),
+            _ => (name.to_string(), to_i32(offset)?),
+        };
+        addresses.push((line.relative_address + idx * size, target));
+        // the addresses of undefined symbols are left to the relocations
+        let address = sections.get(name).map_or(0, |section| section.address);
+        numbers.push(ArgValue::Number(address as i64 + offset));
+    }
+    let bytes = directive.translate(&numbers)?;
+
+    for (address, (name, addend)) in addresses {
+        let id = relocations.values().map(Vec::len).sum();
+        let relocation = RelocationEntry {
+            id,
+            address,
+            addend,
+            kind: RelocationKind::Abs32,
+            section: section.name.clone(),
+        };
+        relocations.entry(name).or_default().push(relocation);
+    }
+    Ok(bytes.into_iter().map(ArgValue::Byte).collect())
+}
+
+fn undefined_use(name: &str) -> String {
+    format!(
+        "'{}' is defined by another object, so only 'la' and data can refer to it",
+        name
+    )
+}
+
+fn resolve_args(
+    line: &PositionedGenericLine,
+    section: &PositionedGenericBlock,
+    symbols: &HashMap<String, Symbol>,
+    sections: &HashMap<String, Section>,
+    relocations: &mut HashMap<String, Vec<RelocationEntry>>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Vec<ArgValue> {
+    // already evaluated when generating the symbol table
+    if let KeyValue::Assignment(_) | KeyValue::LinkerDirective(_) = &line.line.keyword {
+        return vec![];
+    }
+    if let KeyValue::AssemblyDirective(d) = &line.line.keyword
+        && line
+            .line
+            .args
+            .iter()
+            .any(|arg| !matches!(arg, ArgValue::Byte(_)))
+    {
+        let args = &line.line.args;
+        return match resolve_data(
+            d.as_ref(),
+            args,
+            symbols,
+            sections,
+            section,
+            line,
+            relocations,
+        ) {
+            Ok(args) => args,
+            Err(message) => {
+                diagnostics.push(Diagnostic::error(line.line.file_pos, message));
+                vec![]
+            }
+        };
+    }
+    let mut new_args = Vec::new();
+    for arg in &line.line.args {
+        let (s, addend) = match arg {
+            ArgValue::Use(s, addend) | ArgValue::UseHi(s, addend) | ArgValue::UseLo(s, addend) => {
+                (s, *addend)
+            }
+            ArgValue::Expr(expr) => {
+                match resolve_operand(expr, symbols, sections, section, line) {
+                    Ok(n) => new_args.push(ArgValue::Number(n.into())),
+                    Err(message) => {
+                        diagnostics.push(Diagnostic::error(line.line.file_pos, message))
+                    }
+                }
+                continue;
+            }
+            ArgValue::ExprHi(expr) | ArgValue::ExprLo(expr) => {
+                match resolve_constant(expr, symbols, section, line) {
+                    Ok(n) if matches!(arg, ArgValue::ExprHi(_)) => {
+                        new_args.push(ArgValue::Number(upper_20_bits(n).into()))
+                    }
+                    Ok(n) => new_args.push(ArgValue::Number(lower_12_bits(n).into())),
+                    // reported once, for the upper half
+                    Err(_) if matches!(arg, ArgValue::ExprLo(_)) => {}
+                    Err(message) => {
+                        diagnostics.push(Diagnostic::error(line.line.file_pos, message))
+                    }
+                }
+                continue;
+            }
+            _ => {
+                new_args.push(arg.clone());
+                continue;
+            }
+        };
+        if symbols
+            .get(s)
+            .is_some_and(|symb| symb.section == SectionName::Undefined)
+        {
+            // the linker fills in the address, from the relocations
+            match arg {
+                ArgValue::UseHi(..) | ArgValue::UseLo(..) => new_args.push(ArgValue::Number(0)),
+                _ => diagnostics.push(Diagnostic::error(line.line.file_pos, undefined_use(s))),
+            }
+            continue;
+        }
+        let Some(offset) = symbol_offset(s, addend, symbols, sections, section, line) else {
+            let message = format!("unknown symbol '{}'", s);
+            diagnostics.push(Diagnostic::error(line.line.file_pos, message));
+            continue;
+        };
+        let value = match arg {
+            ArgValue::UseHi(..) => upper_20_bits(offset),
+            ArgValue::UseLo(..) => lower_12_bits(offset),
+            // constants are used as they are, while addresses are relative to the line
+            _ if symbols[s].section == SectionName::Absolute => {
+                (symbols[s].relative_address as i32).wrapping_add(addend)
+            }
+            _ => offset,
+        };
+        new_args.push(ArgValue::Number(value.into()));
+    }
+    new_args
+}
+
 fn resolve_symbols(
     blocks: Vec<PositionedGenericBlock>,
     symbols: &HashMap<String, Symbol>,
     sections: &HashMap<String, Section>,
+    relocations: &mut HashMap<String, Vec<RelocationEntry>>,
+    diagnostics: &mut Vec<Diagnostic>,
 ) -> Vec<PositionedGenericBlock> {
     let mut resolved_sections = Vec::new();
     for section in blocks {
-        let mut new_lines = Vec::new();
-        for line in section.lines {
-            let mut new_args = Vec::new();
-            for arg in line.line.args {
-                match arg {
-                    ArgValue::Use(s, addend) => {
-                        let res = get_symb_addrs(&s, symbols, sections);
-                        if let Ok((symb, symb_sect)) = res {
-                            let offset = compute_offset(
-                                section.address,
-                                line.root_relative_address,
-                                symb,
-                                symb_sect,
-                                addend,
-                            );
-                            new_args.push(ArgValue::Number(offset));
-                        }
-                    }
-                    ArgValue::UseHi(s, addend) => {
-                        let res = get_symb_addrs(&s, symbols, sections);
-                        if let Ok((symb, symb_sect)) = res {
-                            let offset = compute_offset(
-                                section.address,
-                                line.root_relative_address,
-                                symb,
-                                symb_sect,
-                                addend,
-                            );
-                            let hi = (offset >> 12) & 0b11111_11111_11111_11111;
-                            new_args.push(ArgValue::Number(hi));
-                        }
-                    }
-                    ArgValue::UseLo(s, addend) => {
-                        let res = get_symb_addrs(&s, symbols, sections);
-                        if let Ok((symb, symb_sect)) = res {
-                            let offset = compute_offset(
-                                section.address,
-                                line.root_relative_address,
-                                symb,
-                                symb_sect,
-                                addend,
-                            );
-                            let lo = offset & 0b1111_1111_1111;
-                            new_args.push(ArgValue::Number(lo));
-                        }
-                    }
-                    _ => {
-                        new_args.push(arg.clone());
-                    }
+        // statements missing symbols are left out (along with all the lines a pseudo instruction
+        // expands to), as they would only add bogus argument errors
+        let mut failed = Vec::new();
+        let args: Vec<Vec<ArgValue>> = section
+            .lines
+            .iter()
+            .map(|line| {
+                // (reported once per statement)
+                if failed.contains(&line.line.id) {
+                    return vec![];
                 }
-            }
-            new_lines.push(PositionedGenericLine {
-                line: GenericLine {
-                    args: new_args,
-                    ..line.line
-                },
+                let found = diagnostics.len();
+                let args =
+                    resolve_args(line, &section, symbols, sections, relocations, diagnostics);
+                if diagnostics.len() != found {
+                    failed.push(line.line.id);
+                }
+                args
+            })
+            .collect();
+        let new_lines = section
+            .lines
+            .into_iter()
+            .zip(args)
+            .filter(|(line, _)| !failed.contains(&line.line.id))
+            .map(|(line, args)| PositionedGenericLine {
+                line: GenericLine { args, ..line.line },
                 ..line
-            });
-        }
+            })
+            .collect();
         resolved_sections.push(PositionedGenericBlock {
             lines: new_lines,
             ..section
@@ -446,9 +925,29 @@ fn resolve_symbols(
     resolved_sections
 }
 
+/// Sections which take no space in objects (as '.bss') can only reserve zeroed memory
+fn check_nobits_sections(blocks: &[PositionedGenericBlock], diagnostics: &mut Vec<Diagnostic>) {
+    for block in blocks.iter().filter(|block| block.flags.nobits) {
+        for line in &block.lines {
+            let zeros = match &line.line.keyword {
+                KeyValue::Op(_) => false,
+                KeyValue::AssemblyDirective(_) => {
+                    line.line.args.iter().all(|arg| arg.to_number() == Some(0))
+                }
+                _ => true,
+            };
+            if !zeros {
+                let message = format!("'{}' can only hold zeros", block.name.default_name());
+                diagnostics.push(Diagnostic::error(line.line.file_pos, message));
+            }
+        }
+    }
+}
+
 // 2.11 Converting all arguments to numbers
 
-fn generic_to_encodable_lines(lines: Vec<PositionedGenericLine>) -> Vec<EncodableLine> {
+/// Data is turned into bytes (one per argument), padded to words in code ('code')
+fn generic_to_encodable_lines(lines: Vec<PositionedGenericLine>, code: bool) -> Vec<EncodableLine> {
     let mut new_lines = Vec::new();
     for line in lines {
         let kw = line.line.keyword;
@@ -463,27 +962,10 @@ fn generic_to_encodable_lines(lines: Vec<PositionedGenericLine>) -> Vec<Encodabl
                 });
             }
             KeyValue::AssemblyDirective(d) => {
-                let handle_endian = match d.datatype().size_bytes() {
-                    1 => i32::from_le_bytes,
-                    _ => i32::from_le_bytes,
-                };
-                let args: Vec<u8> = args
-                    .iter()
-                    .filter_map(|arg| arg.to_number())
-                    .map(|n| n as u8)
-                    .collect();
-                let args: Vec<i32> = args
-                    .chunks(4)
-                    .map(|chunk| {
-                        let b = [
-                            chunk.get(0).map_or(0u8, |v| *v),
-                            chunk.get(1).map_or(0u8, |v| *v),
-                            chunk.get(2).map_or(0u8, |v| *v),
-                            chunk.get(3).map_or(0u8, |v| *v),
-                        ];
-                        handle_endian(b)
-                    })
-                    .collect();
+                let mut args: Vec<i32> = args.iter().filter_map(|arg| arg.to_number()).collect();
+                if code {
+                    args.resize(args.len().next_multiple_of(4), 0);
+                }
                 new_lines.push(EncodableLine {
                     file_pos: line.line.file_pos,
                     key: EncodableKey::Directive(d),
@@ -499,7 +981,7 @@ fn generic_to_encodable_lines(lines: Vec<PositionedGenericLine>) -> Vec<Encodabl
 fn args_to_numbers(blocks: Vec<PositionedGenericBlock>) -> Vec<PositionedEncodableBlock> {
     let mut sections = Vec::new();
     for block in blocks {
-        let instructions = generic_to_encodable_lines(block.lines);
+        let instructions = generic_to_encodable_lines(block.lines, block.flags.exec);
         sections.push(PositionedEncodableBlock {
             addr: block.address,
             name: block.name,
@@ -512,7 +994,10 @@ fn args_to_numbers(blocks: Vec<PositionedGenericBlock>) -> Vec<PositionedEncodab
 // 2.12 Encoding blocks
 //   Each line gets associated with some alignment
 
-fn encode_blocks(blocks: Vec<PositionedEncodableBlock>) -> Vec<PositionedEncodedBlock> {
+fn encode_blocks(
+    blocks: Vec<PositionedEncodableBlock>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Vec<PositionedEncodedBlock> {
     let mut new_blocks = Vec::new();
     for block in blocks {
         // println!("Processing {:?}", &i.key);
@@ -522,36 +1007,42 @@ fn encode_blocks(blocks: Vec<PositionedEncodableBlock>) -> Vec<PositionedEncoded
             instructions: block
                 .instructions
                 .into_iter()
-                .map(|line| line.encode())
+                .filter_map(|line| line.encode().map_err(|d| diagnostics.push(d)).ok())
                 .collect(),
         });
     }
     new_blocks
 }
 
-pub fn assemble(mut blocks: Vec<GenericBlock>) -> AssemblerTools {
+pub fn assemble(
+    mut blocks: Vec<GenericBlock>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> AssemblerTools {
     let metadata = extract_metadata(&mut blocks);
 
     let blocks = cast_generic_to_positioned_blocks(blocks);
 
+    let blocks = gen_line_address(blocks);
     let blocks = gen_section_address(blocks, 0, 4);
     // println!("{:?}", blocks);
     // dbg!(&blocks);
 
-    let blocks = gen_line_address(blocks);
     let blocks = gen_root_line_address(blocks);
     // println!("{:?}", blocks);
     // dbg!(&blocks);
 
     let sections = gen_section_table(&blocks);
-    let symbols = gen_symbol_table(&blocks);
+    let mut symbols = gen_symbol_table(&blocks);
+    gen_assigned_symbols(&blocks, &mut symbols, diagnostics);
+    gen_symbol_attributes(metadata.as_ref(), &blocks, &mut symbols, diagnostics);
     let strings = gen_string_table(&blocks);
-    let relocations = gen_relocation_table(&blocks, &symbols, &sections);
+    let mut relocations = gen_relocation_table(&blocks, &symbols, &sections);
     // dbg!(&sections);
     // dbg!(&symbols);
     // dbg!(&strings);
 
-    let blocks = resolve_symbols(blocks, &symbols, &sections);
+    let blocks = resolve_symbols(blocks, &symbols, &sections, &mut relocations, diagnostics);
+    check_nobits_sections(&blocks, diagnostics);
     // println!("{:?}", blocks);
     // dbg!(&blocks);
 
@@ -559,7 +1050,7 @@ pub fn assemble(mut blocks: Vec<GenericBlock>) -> AssemblerTools {
     // println!("{:?}", blocks);
     // dbg!(&blocks);
 
-    let blocks = encode_blocks(blocks);
+    let blocks = encode_blocks(blocks, diagnostics);
     // println!("{:?}", blocks);
     // dbg!(&blocks);
 
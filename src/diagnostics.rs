@@ -0,0 +1,137 @@
+//! Errors found while assembling a source, reported rustc-style with the offending line:
+//! ```text
+//! error: unknown symbol 'rb'
+//!  --> examples/wrong-addi.s:2:1
+//!   |
+//! 2 | addi  rb, 1, 16
+//!   | ^^^^^^^^^^^^^^^
+//! ```
+
+use crate::sources::SourceMap;
+use crate::streamreader::Position;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub span: Position,
+    /// Number of chars underlined from 'span' (None underlines the rest of the statement)
+    pub len: Option<usize>,
+    pub message: String,
+}
+
+pub type Result<T> = std::result::Result<T, Vec<Diagnostic>>;
+
+impl Diagnostic {
+    pub fn error(span: Position, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            span,
+            len: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(span: Position, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(span, message)
+        }
+    }
+
+    pub fn with_len(self, len: usize) -> Self {
+        Diagnostic {
+            len: Some(len),
+            ..self
+        }
+    }
+
+    /// Renders the diagnostic along with the line of 'source' it points to
+    pub fn render(&self, filename: &str, source: &str) -> String {
+        let row = self.span.row() + 1;
+        let col = self.span.col() + 1;
+        let mut out = format!("{}: {}\n", self.severity, self.message);
+        let gutter = " ".repeat(row.to_string().len());
+        out += &format!("{}--> {}:{}:{}\n", gutter, filename, row, col);
+
+        let Some(line) = source.lines().nth(self.span.row()) else {
+            return out;
+        };
+        let line = line.replace('\t', " ");
+        // trailing comments aren't part of the statement
+        let statement = line.split("//").next().unwrap_or("").trim_end();
+        let rest = statement.chars().count().saturating_sub(self.span.col());
+        let len = self.len.unwrap_or(rest).max(1);
+        out += &format!("{} |\n", gutter);
+        out += &format!("{} | {}\n", row, line.trim_end());
+        out += &format!(
+            "{} | {}{}\n",
+            gutter,
+            " ".repeat(self.span.col()),
+            "^".repeat(len)
+        );
+        out
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} at line {} column {}",
+            self.severity,
+            self.message,
+            self.span.row() + 1,
+            self.span.col() + 1
+        )
+    }
+}
+
+/// Renders all diagnostics, in the order they appear in 'source', followed by a summary
+pub fn render(diagnostics: &[Diagnostic], filename: &str, source: &str) -> String {
+    let mut sources = SourceMap::default();
+    sources.add(filename, source);
+    render_sources(diagnostics, &sources)
+}
+
+/// Same as 'render', for diagnostics found in any of the files of 'sources' (the summary names
+/// the first one)
+pub fn render_sources(diagnostics: &[Diagnostic], sources: &SourceMap) -> String {
+    let mut sorted: Vec<&Diagnostic> = diagnostics.iter().collect();
+    sorted.sort_by_key(|d| (d.span.line(), d.span.col()));
+    let mut out: String = sorted
+        .iter()
+        .filter_map(|d| {
+            let file = sources.get(d.span.file())?;
+            Some(d.render(&file.name, &file.code) + "\n")
+        })
+        .collect();
+    let filename = sources.get(0).map_or("", |file| file.name.as_str());
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        out += &format!(
+            "error: could not assemble '{}' due to {} previous error{}\n",
+            filename,
+            errors,
+            if errors == 1 { "" } else { "s" }
+        );
+    }
+    out
+}
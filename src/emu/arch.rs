@@ -0,0 +1,148 @@
+use core::num::NonZeroUsize;
+
+use gdbstub::arch::{Arch, RegId};
+use gdbstub_arch::riscv::reg::RiscvCoreRegs;
+
+use crate::emu::cpu::{
+    CSR_FCSR, CSR_FFLAGS, CSR_FRM, CSR_MARCHID, CSR_MCAUSE, CSR_MEPC, CSR_MHARTID, CSR_MIE,
+    CSR_MIMPID, CSR_MIP, CSR_MISA, CSR_MSCRATCH, CSR_MSTATUS, CSR_MTVAL, CSR_MTVEC, CSR_MVENDORID,
+};
+use crate::emu::machine::FloatExtension;
+
+/// RISC-V 32 bits architecture, as seen by GDB
+///
+/// Unlike `gdbstub_arch::riscv::Riscv32`, the target description isn't fixed: it gets generated
+/// at runtime by `target_description_xml`, since the registers available depend on the machine
+/// configuration (such as the floating point extension in use)
+pub enum Riscv32 {}
+
+impl Arch for Riscv32 {
+    type Usize = u32;
+    type Registers = RiscvCoreRegs<u32>;
+    type BreakpointKind = usize;
+    type RegId = RegisterId;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RegisterId {
+    Gpr(u8),
+    Pc,
+    Fpr(u8),
+    Csr(u16),
+}
+
+// GDB register numbers, as hard-coded by GDB for riscv targets
+const PC_REGNUM: usize = 32;
+const FPR_REGNUM: usize = 33;
+const CSR_REGNUM: usize = 65;
+const LAST_CSR_REGNUM: usize = CSR_REGNUM + 4095;
+
+impl RegId for RegisterId {
+    fn from_raw_id(id: usize) -> Option<(Self, Option<NonZeroUsize>)> {
+        let word = NonZeroUsize::new(4);
+        match id {
+            0..=31 => Some((RegisterId::Gpr(id as u8), word)),
+            PC_REGNUM => Some((RegisterId::Pc, word)),
+            // the width of f0-f31 depends on the extension in use (F or D)
+            FPR_REGNUM..=64 => Some((RegisterId::Fpr((id - FPR_REGNUM) as u8), None)),
+            CSR_REGNUM..=LAST_CSR_REGNUM => Some((RegisterId::Csr((id - CSR_REGNUM) as u16), word)),
+            _ => None,
+        }
+    }
+
+    fn to_raw_id(&self) -> Option<usize> {
+        match self {
+            RegisterId::Gpr(reg) => Some(*reg as usize),
+            RegisterId::Pc => Some(PC_REGNUM),
+            RegisterId::Fpr(reg) => Some(FPR_REGNUM + *reg as usize),
+            RegisterId::Csr(csr) => Some(CSR_REGNUM + *csr as usize),
+        }
+    }
+}
+
+pub const GPR_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+const FPR_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+const FPU_CSRS: [(&str, usize); 3] = [("fflags", CSR_FFLAGS), ("frm", CSR_FRM), ("fcsr", CSR_FCSR)];
+
+/// Machine level CSRs exposed to GDB
+pub const MACHINE_CSRS: [(&str, usize); 13] = [
+    ("mstatus", CSR_MSTATUS),
+    ("misa", CSR_MISA),
+    ("mie", CSR_MIE),
+    ("mtvec", CSR_MTVEC),
+    ("mscratch", CSR_MSCRATCH),
+    ("mepc", CSR_MEPC),
+    ("mcause", CSR_MCAUSE),
+    ("mtval", CSR_MTVAL),
+    ("mip", CSR_MIP),
+    ("mvendorid", CSR_MVENDORID),
+    ("marchid", CSR_MARCHID),
+    ("mimpid", CSR_MIMPID),
+    ("mhartid", CSR_MHARTID),
+];
+
+fn push_reg(xml: &mut String, name: &str, bitsize: usize, kind: &str, regnum: usize, group: &str) {
+    xml.push_str(&format!(
+        "    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\" group=\"{}\"/>\n",
+        name, bitsize, kind, regnum, group
+    ));
+}
+
+/// Builds the 'target.xml' document describing every register GDB is allowed to read/write
+///
+/// All registers have their 'regnum' set explicitly, so that the numbers sent by GDB in 'p'/'P'
+/// packets can be mapped back by `RegisterId::from_raw_id`
+pub fn target_description_xml(float: FloatExtension) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         \x20 <architecture>riscv:rv32</architecture>\n",
+    );
+
+    xml.push_str("  <feature name=\"org.gnu.gdb.riscv.cpu\">\n");
+    for (idx, name) in GPR_NAMES.iter().enumerate() {
+        let kind = match *name {
+            "ra" => "code_ptr",
+            "sp" | "gp" | "tp" | "fp" => "data_ptr",
+            _ => "int",
+        };
+        push_reg(&mut xml, name, 32, kind, idx, "general");
+    }
+    push_reg(&mut xml, "pc", 32, "code_ptr", PC_REGNUM, "general");
+    xml.push_str("  </feature>\n");
+
+    if float != FloatExtension::None {
+        let (bitsize, kind) = match float {
+            FloatExtension::D => (64, "ieee_double"),
+            _ => (32, "ieee_single"),
+        };
+        xml.push_str("  <feature name=\"org.gnu.gdb.riscv.fpu\">\n");
+        for (idx, name) in FPR_NAMES.iter().enumerate() {
+            push_reg(&mut xml, name, bitsize, kind, FPR_REGNUM + idx, "float");
+        }
+        for (name, csr) in FPU_CSRS {
+            push_reg(&mut xml, name, 32, "int", CSR_REGNUM + csr, "float");
+        }
+        xml.push_str("  </feature>\n");
+    }
+
+    xml.push_str("  <feature name=\"org.gnu.gdb.riscv.csr\">\n");
+    for (name, csr) in MACHINE_CSRS {
+        push_reg(&mut xml, name, 32, "int", CSR_REGNUM + csr, "csr");
+    }
+    xml.push_str("  </feature>\n");
+
+    xml.push_str("</target>\n");
+    xml
+}
@@ -7,21 +7,62 @@ pub trait CPU {
 
     fn read_all(&self) -> Vec<u32>;
     fn write_all(&mut self, gps: Vec<u32>, pc: usize) -> () ;
+
+    fn read_csr(&self, csr: usize) -> u32 ;
+    fn write_csr(&mut self, csr: usize, v: u32) ;
+
+    // Floating point registers are stored with 64 bits, so that both F and D can be supported
+    fn read_fpr(&self, reg: usize) -> u64 ;
+    fn write_fpr(&mut self, reg: usize, v: u64) ;
 }
 
 
+// Control and Status Registers (CSR) addresses
+
+pub const CSR_FFLAGS: usize = 0x001;
+pub const CSR_FRM: usize = 0x002;
+pub const CSR_FCSR: usize = 0x003;
+pub const CSR_MSTATUS: usize = 0x300;
+pub const CSR_MISA: usize = 0x301;
+pub const CSR_MIE: usize = 0x304;
+pub const CSR_MTVEC: usize = 0x305;
+pub const CSR_MSCRATCH: usize = 0x340;
+pub const CSR_MEPC: usize = 0x341;
+pub const CSR_MCAUSE: usize = 0x342;
+pub const CSR_MTVAL: usize = 0x343;
+pub const CSR_MIP: usize = 0x344;
+pub const CSR_MVENDORID: usize = 0xF11;
+pub const CSR_MARCHID: usize = 0xF12;
+pub const CSR_MIMPID: usize = 0xF13;
+pub const CSR_MHARTID: usize = 0xF14;
+
+const CSR_COUNT: usize = 4096;
+
+/// 'misa' value for a RV32IM machine (MXL = 1, extensions 'I' and 'M')
+pub const MISA_RV32IM: u32 = (1 << 30) | (1 << 8) | (1 << 12);
+pub const MISA_F: u32 = 1 << 5;
+pub const MISA_D: u32 = 1 << 3;
+
+
 /* Possible implementation */
 
+#[derive(Clone)]
 pub struct SimpleCPU {
     registers: Vec<u32>,
     pc: usize,
+    csrs: Vec<u32>,
+    fprs: Vec<u64>,
 }
 
 impl SimpleCPU {
     pub fn new() -> Self {
+        let mut csrs: Vec<u32> = (0..CSR_COUNT).map(|_| 0).collect();
+        csrs[CSR_MISA] = MISA_RV32IM;
         SimpleCPU {
             registers: (0..32).map(|_| 0).collect(),
             pc: 0,
+            csrs,
+            fprs: (0..32).map(|_| 0).collect(),
         }
     }
 }
@@ -62,4 +103,37 @@ impl CPU for SimpleCPU {
         }
         self.pc = pc;
     }
+
+    fn read_csr(&self, csr: usize) -> u32 {
+        match csr {
+            CSR_FFLAGS => self.csrs[CSR_FCSR] & 0b11111,
+            CSR_FRM => (self.csrs[CSR_FCSR] >> 5) & 0b111,
+            _ => *self.csrs.get(csr).expect("Unknown CSR"),
+        }
+    }
+
+    // 'fflags' and 'frm' are views of 'fcsr', therefore they are kept in sync with it
+    fn write_csr(&mut self, csr: usize, v: u32) {
+        let fcsr = self.csrs[CSR_FCSR];
+        match csr {
+            CSR_FFLAGS => self.csrs[CSR_FCSR] = (fcsr & !0b11111) | (v & 0b11111),
+            CSR_FRM => self.csrs[CSR_FCSR] = (fcsr & !0b1110_0000) | ((v & 0b111) << 5),
+            CSR_FCSR => self.csrs[CSR_FCSR] = v & 0b1111_1111,
+            _ => {
+                if let Some(r) = self.csrs.get_mut(csr) {
+                    *r = v;
+                }
+            }
+        }
+    }
+
+    fn read_fpr(&self, reg: usize) -> u64 {
+        *self.fprs.get(reg).expect("Unknown floating point register")
+    }
+
+    fn write_fpr(&mut self, reg: usize, v: u64) {
+        if let Some(r) = self.fprs.get_mut(reg) {
+            *r = v;
+        }
+    }
 }
@@ -1,63 +1,174 @@
-use std::io::{self, Read};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
 use std::marker::PhantomData;
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::path::PathBuf;
 
+use gdbstub::common::Pid;
 use gdbstub::common::Signal;
+use gdbstub::common::Tid;
 use gdbstub::stub::state_machine::GdbStubStateMachine;
 use gdbstub::target::ext::base::BaseOps;
-use gdbstub::target::ext::base::singlethread::{
-    SingleThreadBase, SingleThreadResume, SingleThreadSingleStep,
+use gdbstub::target::ext::base::multithread::{
+    MultiThreadBase, MultiThreadResume, MultiThreadSchedulerLocking, MultiThreadSingleStep,
+};
+use gdbstub::target::ext::base::multithread::{
+    MultiThreadResumeOps, MultiThreadSchedulerLockingOps, MultiThreadSingleStepOps,
+};
+use gdbstub::target::ext::base::reverse_exec::{
+    ReplayLogPosition, ReverseCont, ReverseContOps, ReverseStep, ReverseStepOps,
+};
+use gdbstub::target::ext::base::single_register_access::{
+    SingleRegisterAccess, SingleRegisterAccessOps,
+};
+use gdbstub::target::ext::breakpoints::{Breakpoints, HwWatchpoint, SwBreakpoint, WatchKind};
+use gdbstub::target::ext::breakpoints::{BreakpointsOps, HwWatchpointOps, SwBreakpointOps};
+use gdbstub::target::ext::catch_syscalls::{
+    CatchSyscallPosition, CatchSyscalls, CatchSyscallsOps, SyscallNumbers,
+};
+use gdbstub::target::ext::exec_file::{ExecFile, ExecFileOps};
+use gdbstub::target::ext::extended_mode::{
+    Args, AttachKind, CurrentActivePid, CurrentActivePidOps, ExtendedMode, ExtendedModeOps,
+    ShouldTerminate,
+};
+use gdbstub::target::ext::host_io::{
+    FsKind, HostIo, HostIoClose, HostIoCloseOps, HostIoErrno, HostIoError, HostIoFstat,
+    HostIoFstatOps, HostIoOpen, HostIoOpenFlags, HostIoOpenMode, HostIoOpenOps, HostIoOps,
+    HostIoPread, HostIoPreadOps, HostIoPwrite, HostIoPwriteOps, HostIoReadlink, HostIoReadlinkOps,
+    HostIoResult, HostIoSetfs, HostIoSetfsOps, HostIoStat, HostIoUnlink, HostIoUnlinkOps,
 };
-use gdbstub::target::ext::base::singlethread::{SingleThreadResumeOps, SingleThreadSingleStepOps};
-use gdbstub::target::ext::breakpoints::{Breakpoints, SwBreakpoint};
-use gdbstub::target::ext::breakpoints::{BreakpointsOps, SwBreakpointOps};
 use gdbstub::target::ext::memory_map::MemoryMap;
-use gdbstub::target::{Target, TargetResult};
+use gdbstub::target::ext::monitor_cmd::{ConsoleOutput, MonitorCmd, MonitorCmdOps, outputln};
+use gdbstub::target::ext::target_description_xml_override::{
+    TargetDescriptionXmlOverride, TargetDescriptionXmlOverrideOps,
+};
+use gdbstub::target::ext::thread_extra_info::{ThreadExtraInfo, ThreadExtraInfoOps};
+use gdbstub::target::{Target, TargetError, TargetResult};
 
 use gdbstub::conn::{Connection, ConnectionExt};
-use gdbstub::stub::SingleThreadStopReason;
+use gdbstub::stub::MultiThreadStopReason;
 use gdbstub::stub::{DisconnectReason, GdbStub, run_blocking};
 
-use crate::emu::machine::Machine;
+use crate::emu::arch::{self, RegisterId};
+use crate::emu::hostio::{self, MemoryPacket, Sandbox};
+use crate::emu::machine::{
+    self, FloatExtension, Machine, MachineError, MachineState, SyscallCatch, SyscallStop, WatchHit,
+    Watchpoint,
+};
+use crate::emu::trace::CommitLog;
+use crate::emu::transport::{GdbConnection, GdbListener, Transport};
 use crate::lang::lowassembly::DataEndianness;
+use crate::obj::elfreader::ElfReader;
+use crate::utils::{STACK_SIZE, load_elf, push_guest_args};
 
-/// TCP based Stub
+/// Stub reachable through TCP, a Unix domain socket or stdio (see `Transport`)
 pub struct SimpleGdbStub<'a, T: Machine> {
     // Connection
-    // addr: SocketAddr,
+    //   When 'persistent' is set, new GDB connections are accepted from 'listener' once the
+    //   current one ends, each of them getting a fresh machine
+    listener: GdbListener,
+    persistent: bool,
 
     // Target
     target: SimpleTarget<T>,
 
     // GdbStug
-    stub: GdbStub<'a, SimpleTarget<T>, TcpStream>,
+    stub: GdbStub<'a, SimpleTarget<T>, GdbConnection>,
     // Loop
     // _
 }
 
 impl<'a, T: Machine> SimpleGdbStub<'a, T> {
-    pub fn new(memsize: usize, port: u16) -> io::Result<Self> {
+    pub fn new(memsize: usize, transport: Transport, float: FloatExtension) -> io::Result<Self> {
         let mut mem = Vec::new();
         mem.reserve(memsize);
         for _ in 0..memsize {
             mem.push(0);
         }
-        let target = SimpleTarget::from_words(mem);
-        let (stream, _addr) = wait_for_gdb_connection(port)?;
-        let stub = GdbStub::new(stream);
-        Ok(SimpleGdbStub { target, stub })
+        let mut target = SimpleTarget::from_words(mem, float);
+        target.set_console_to_stderr(transport == Transport::Stdio);
+        let mut listener = listen_for_gdb(&transport, None)?;
+        let stub = GdbStub::new(listener.accept()?);
+        Ok(SimpleGdbStub {
+            listener,
+            persistent: false,
+            target,
+            stub,
+        })
+    }
+
+    /// Same as `new`, but the machine starts with the executable already loaded, so GDB doesn't
+    /// have to `load` it
+    pub fn from_elf(
+        filename: &str,
+        transport: Transport,
+        float: FloatExtension,
+    ) -> io::Result<Self> {
+        let mut target = SimpleTarget::from_elf(filename, float, &[])?;
+        target.set_console_to_stderr(transport == Transport::Stdio);
+        let mut listener = listen_for_gdb(&transport, Some(filename))?;
+        let stub = GdbStub::new(listener.accept()?);
+        Ok(SimpleGdbStub {
+            listener,
+            persistent: false,
+            target,
+            stub,
+        })
+    }
+
+    /// Keeps accepting new GDB connections after the current one ends
+    pub fn set_persistent(&mut self, persistent: bool) {
+        self.persistent = persistent;
+    }
+
+    /// Number of harts of the machine (each one shows up as a GDB thread), and the number of
+    /// instructions each one executes before the next one runs
+    pub fn set_harts(&mut self, count: usize, quantum: usize) {
+        self.target.machine.set_hart_count(count);
+        self.target.machine.set_quantum(quantum);
+    }
+
+    /// Logs every instruction retired by the machine (see `trace::CommitLog`)
+    pub fn set_commit_log(&mut self, log: CommitLog) {
+        self.target.machine.set_commit_log(Some(log));
+    }
+
+    /// Directory whose files can be accessed by GDB (through `remote get`, `remote put`, ...)
+    ///
+    /// Host I/O is only supported once there's a sandbox
+    pub fn set_sandbox(&mut self, root: PathBuf) {
+        self.target.sandbox = Some(Sandbox::new(root));
     }
 
     pub fn custom_gdb_event_loop_thread(mut self) {
-        match self.stub.run_state_machine(&mut self.target) {
-            Ok(sm_ok) => {
-                let mut handle_res = custom_handle_machine_state(sm_ok, &mut self.target);
-                while let Ok(sm_ok) = handle_res {
-                    handle_res = custom_handle_machine_state(sm_ok, &mut self.target);
+        loop {
+            match self.stub.run_state_machine(&mut self.target) {
+                Ok(sm_ok) => {
+                    let mut handle_res = custom_handle_machine_state(sm_ok, &mut self.target);
+                    while let Ok(sm_ok) = handle_res {
+                        handle_res = custom_handle_machine_state(sm_ok, &mut self.target);
+                    }
+                }
+                Err(sm_err) => {
+                    eprintln!("Failed when running state machine: {:?}", sm_err);
                 }
             }
-            Err(sm_err) => {
-                println!("Failed when running state machine: {:?}", sm_err);
+
+            if !self.persistent {
+                return;
+            }
+            eprintln!("GDB disconnected, waiting for a new connection");
+            if let Err(e) = self.target.reload() {
+                eprintln!("Failed when restarting the target: {}", e);
+                return;
+            }
+            match self.listener.accept() {
+                Ok(conn) => self.stub = GdbStub::new(conn),
+                Err(e) => {
+                    eprintln!("Failed when accepting a GDB connection: {}", e);
+                    return;
+                }
             }
         }
     }
@@ -69,29 +180,29 @@ impl<'a, T: Machine> SimpleGdbStub<'a, T> {
         {
             Ok(disconnect_reason) => match disconnect_reason {
                 DisconnectReason::Disconnect => {
-                    println!("Client disconnected")
+                    eprintln!("Client disconnected")
                 }
                 DisconnectReason::TargetExited(code) => {
-                    println!("Target exited with code {}", code)
+                    eprintln!("Target exited with code {}", code)
                 }
                 DisconnectReason::TargetTerminated(sig) => {
-                    println!("Target terminated with signal {}", sig)
+                    eprintln!("Target terminated with signal {}", sig)
                 }
-                DisconnectReason::Kill => println!("GDB sent a kill command"),
+                DisconnectReason::Kill => eprintln!("GDB sent a kill command"),
             },
             Err(e) => {
                 if e.is_target_error() {
-                    // println!(
+                    // eprintln!(
                     //     "target encountered a fatal error: {}",
                     //     e.into_target_error().unwrap()
                     // )
-                    println!("gdbstub encountered a fatal error (target error)");
+                    eprintln!("gdbstub encountered a fatal error (target error)");
                 } else if e.is_connection_error() {
                     let (e, kind) = e.into_connection_error().unwrap();
-                    println!("connection error: {:?} - {}", kind, e,);
+                    eprintln!("connection error: {:?} - {}", kind, e,);
                 } else {
-                    // println!("gdbstub encountered a fatal error: {}", e)
-                    println!("gdbstub encountered a fatal error");
+                    // eprintln!("gdbstub encountered a fatal error: {}", e)
+                    eprintln!("gdbstub encountered a fatal error");
                 }
             }
         }
@@ -100,56 +211,378 @@ impl<'a, T: Machine> SimpleGdbStub<'a, T> {
 
 // Connection
 
-/// Blocks until a GDB client connects via TCP.
+/// Listens for GDB clients, which can then be accepted by the caller.
 /// i.e: Running `target remote localhost:<port>` from the GDB prompt.
-/// `TcpStream` implements `gdbstub::Connection`
-fn wait_for_gdb_connection(port: u16) -> io::Result<(TcpStream, SocketAddr)> {
-    let sockaddr = format!("localhost:{}", port);
-    let sock = TcpListener::bind(sockaddr)?;
-    println!("Waiting for GDB to connect to target at localhost:{}", port);
-    println!("Enter gdb and type:");
-    println!("  gdb> target remote :{}", port);
-    println!("  gdb> load");
-    println!("  gdb> x/1xw 0x10074");
-    sock.accept()
+///
+/// Messages go to stderr, since stdout might be the connection itself
+fn listen_for_gdb(transport: &Transport, exec_file: Option<&str>) -> io::Result<GdbListener> {
+    let listener = GdbListener::bind(transport)?;
+    let target = match transport {
+        // GDB starts the emulator by itself, so there's no one to tell how to connect
+        Transport::Stdio => return Ok(listener),
+        Transport::Tcp(port) => format!(":{}", port),
+        Transport::Unix(path) => format!("unix::{}", path.display()),
+    };
+    eprintln!("Waiting for GDB to connect to target at {}", transport);
+    eprintln!("Enter gdb and type:");
+    eprintln!("  gdb> target remote {}", target);
+    eprintln!("or, to be able to 'run' the program multiple times:");
+    eprintln!("  gdb> target extended-remote {}", target);
+    match exec_file {
+        // the executable is served to GDB, which then reads its symbols by itself
+        Some(_) => eprintln!("  gdb> b _start"),
+        None => {
+            eprintln!("  gdb> load");
+            eprintln!("  gdb> x/1xw 0x10074");
+        }
+    }
+    Ok(listener)
 }
 
 // Target
 
+#[derive(PartialEq)]
 enum TargetState {
     Idle,
     Running,
     // Continuing,
     Stepping,
     // Trapped,
+    ReverseRunning,
+    ReverseStepping,
+}
+
+/// Chunk of the machine memory, as described to GDB by the memory map
+struct MemoryRegion {
+    name: String,
+    start: usize,
+    length: usize,
 }
 
 struct SimpleTarget<T: Machine> {
     machine: T,
     breakpoints: Vec<(u32, usize)>,
     state: TargetState,
+    snapshots: HashMap<String, T::Snapshot>,
+    regions: Vec<MemoryRegion>,
+    exec_file: Option<String>,
+    // Extended mode
+    //   'args' are given to the program each time it's (re)started by GDB
+    extended: bool,
+    args: Vec<String>,
+    // Host I/O
+    sandbox: Option<Sandbox>,
+    // Console
+    //   When GDB talks through stdio, what the program prints is captured and copied to stderr
+    //   instead, so it doesn't get mixed up with the packets
+    console_to_stderr: bool,
+    // Resume actions
+    //   'step_hart' is the hart being (reverse) stepped, while the others keep running as they
+    //   get scheduled, unless 'locked' is set (only 'resumed_hart' runs then)
+    step_hart: Option<usize>,
+    resumed_hart: Option<usize>,
+    locked: bool,
+}
+
+/// GDB threads are numbered from 1, while harts are numbered from 0
+fn hart_tid(hart: usize) -> Tid {
+    Tid::new(hart + 1).unwrap()
+}
+
+fn tid_hart(tid: Tid) -> usize {
+    tid.get() - 1
 }
 
 impl<T: Machine> SimpleTarget<T> {
-    pub fn from_words(mem: Vec<u32>) -> Self {
+    pub fn from_words(mem: Vec<u32>, float: FloatExtension) -> Self {
         let machine = <T>::from_words(&mem, DataEndianness::Le);
+        let regions = vec![MemoryRegion {
+            name: String::from("ram"),
+            start: 0,
+            length: machine.bytes_count(),
+        }];
+        SimpleTarget::with_machine(machine, float, regions, None)
+    }
+
+    /// The memory map is made of the sections loaded from the ELF file, followed by the stack
+    pub fn from_elf(filename: &str, float: FloatExtension, args: &[String]) -> io::Result<Self> {
+        let data = std::fs::read(filename)?;
+        let reader = ElfReader::new(&data, DataEndianness::Le)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let mut machine: T = load_elf(&reader);
+        let argv: Vec<String> = std::iter::once(filename.to_string())
+            .chain(args.iter().cloned())
+            .collect();
+        push_guest_args(&mut machine, &argv);
+
+        let mut regions: Vec<MemoryRegion> = reader
+            .loadable_sections()
+            .iter()
+            .map(|section| MemoryRegion {
+                name: section.name.clone(),
+                start: section.address as usize,
+                length: section.size,
+            })
+            .collect();
+        regions.push(MemoryRegion {
+            name: String::from("stack"),
+            start: machine.memory_start() + machine.bytes_count() - STACK_SIZE,
+            length: STACK_SIZE,
+        });
+
+        let exec_file = std::fs::canonicalize(filename)?;
+        let exec_file = exec_file.to_string_lossy().to_string();
+        let mut target = SimpleTarget::with_machine(machine, float, regions, Some(exec_file));
+        target.args = args.to_vec();
+        Ok(target)
+    }
+
+    /// Replaces the machine by a fresh one, loaded from the executable (if there's one)
+    ///
+    /// Everything tied to the previous run (breakpoints, snapshots, ...) is dropped, while the
+    /// program arguments and extended mode are kept
+    fn reload(&mut self) -> io::Result<()> {
+        let float = self.machine.float_extension();
+        let mut target: SimpleTarget<T> = match &self.exec_file {
+            Some(filename) => SimpleTarget::from_elf(filename, float, &self.args)?,
+            None => SimpleTarget::from_words(vec![0; self.machine.words_count()], float),
+        };
+        target.machine.set_hart_count(self.machine.hart_count());
+        target.machine.set_quantum(self.machine.quantum());
+        target
+            .machine
+            .set_commit_log(self.machine.take_commit_log());
+        target.extended = self.extended;
+        target.set_console_to_stderr(self.console_to_stderr);
+        std::mem::swap(&mut target.sandbox, &mut self.sandbox);
+        *self = target;
+        Ok(())
+    }
+
+    fn with_machine(
+        mut machine: T,
+        float: FloatExtension,
+        regions: Vec<MemoryRegion>,
+        exec_file: Option<String>,
+    ) -> Self {
+        machine.set_float_extension(float);
+        // every instruction executed under the debugger is logged, so that GDB can step backwards
+        machine.set_recording(true);
+        // file related syscalls are performed by GDB on the developer's machine
+        machine.set_forwarded_syscalls(hostio::forwarded_syscalls());
         let breakpoints = Vec::new();
         let state = TargetState::Idle;
         SimpleTarget {
             machine,
             breakpoints,
             state,
+            snapshots: HashMap::new(),
+            regions,
+            exec_file,
+            extended: false,
+            args: Vec::new(),
+            sandbox: None,
+            console_to_stderr: false,
+            step_hart: None,
+            resumed_hart: None,
+            locked: false,
+        }
+    }
+
+    /// Executes the next instruction, of the resumed hart only when the scheduler is locked
+    fn execute(&mut self) -> Result<MachineState, MachineError> {
+        if self.locked
+            && let Some(hart) = self.resumed_hart
+            && self.machine.scheduled_hart() != hart
+        {
+            self.machine.set_scheduled_hart(hart);
+        }
+        let state = self.machine.decode();
+        if self.console_to_stderr {
+            let output = self.machine.take_console_output();
+            if !output.is_empty() {
+                let mut stderr = io::stderr();
+                let _ = stderr.write_all(&output).and_then(|_| stderr.flush());
+            }
         }
+        state
+    }
+
+    fn set_console_to_stderr(&mut self, enabled: bool) {
+        self.console_to_stderr = enabled;
+        self.machine.set_console_capture(enabled);
+    }
+
+    /// Translates the reason (if any) why the machine should stop to what GDB expects
+    fn stop_reason(&mut self) -> Option<MultiThreadStopReason<u32>> {
+        let tid = hart_tid(self.machine.current_hart());
+        let breakpoints = &self.breakpoints;
+        let reason = stop_reason(&mut self.machine, |pc| {
+            breakpoints.iter().any(|b| b.0 as usize == pc)
+        })?;
+        let reason = match reason {
+            StopReason::Syscall(stop) => {
+                let (number, position) = match stop {
+                    SyscallStop::Return(number) => (number, CatchSyscallPosition::Return),
+                    SyscallStop::Entry(number) | SyscallStop::Forward(number) => {
+                        (number, CatchSyscallPosition::Entry)
+                    }
+                };
+                MultiThreadStopReason::CatchSyscall {
+                    tid: Some(tid),
+                    number,
+                    position,
+                }
+            }
+            StopReason::Watch(hit) => MultiThreadStopReason::Watch {
+                tid,
+                kind: to_gdb_watch_kind(hit.watchpoint.kind),
+                addr: hit.addr as u32,
+            },
+            StopReason::Breakpoint { hart, .. } => MultiThreadStopReason::SwBreak(hart_tid(hart)),
+        };
+        Some(reason)
+    }
+
+    /// Stop reason for a finished (reverse) step of 'step_hart'
+    fn done_step(&self) -> MultiThreadStopReason<u32> {
+        let hart = self.step_hart.unwrap_or(self.machine.current_hart());
+        MultiThreadStopReason::SignalWithThread {
+            tid: hart_tid(hart),
+            signal: Signal::SIGTRAP,
+        }
+    }
+}
+
+impl<T: Machine> SimpleTarget<T> {
+    /// Performs a syscall of the guest through the File-I/O protocol, then lets the machine go on
+    /// with its result
+    fn forward_syscall<C: ConnectionExt>(
+        &mut self,
+        conn: &mut C,
+        number: u32,
+    ) -> Result<(), C::Error> {
+        let registers = self.machine.read_registers();
+        let args = [registers[10], registers[11], registers[12], registers[13]];
+        let path_len = self.guest_string_len(args[1]) + 1;
+        let Some(request) = hostio::request_packet(number, args, path_len) else {
+            self.machine.complete_syscall(hostio::ENOSYS.wrapping_neg());
+            return Ok(());
+        };
+        hostio::send_packet(conn, request.as_bytes())?;
+        let tid = hart_tid(self.machine.current_hart());
+
+        // GDB accesses the syscall buffers before sending the result
+        loop {
+            let packet = hostio::read_packet(conn)?;
+            if packet.first() == Some(&b'F') {
+                let result = hostio::parse_reply(&packet).unwrap_or(hostio::EIO.wrapping_neg());
+                self.machine.complete_syscall(result);
+                return Ok(());
+            }
+            let reply = match hostio::parse_memory_packet(&packet) {
+                Some(MemoryPacket::Read { addr, len }) => {
+                    let mut buf = vec![0; len];
+                    let count = self.read_addrs(addr, &mut buf, tid).unwrap_or(0);
+                    hostio::hex_encode(&buf[..count])
+                }
+                Some(MemoryPacket::Write { addr, data }) => {
+                    match self.write_addrs(addr, &data, tid) {
+                        Ok(()) => String::from("OK"),
+                        Err(_) => String::from("E01"),
+                    }
+                }
+                None => String::new(),
+            };
+            hostio::send_packet(conn, reply.as_bytes())?;
+        }
+    }
+
+    /// Length of the NUL terminated string found at 'addr' in the guest memory
+    fn guest_string_len(&mut self, addr: u32) -> usize {
+        const MAX_LEN: usize = 4096;
+        let mut buf = vec![0; MAX_LEN];
+        let tid = hart_tid(self.machine.current_hart());
+        let count = self.read_addrs(addr, &mut buf, tid).unwrap_or(0);
+        buf[..count]
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(count)
+    }
+
+    /// Host I/O is only supported (see 'support_host_io') when GDB is given a sandbox
+    fn sandbox(&mut self) -> HostIoResult<&mut Sandbox, Self> {
+        self.sandbox
+            .as_mut()
+            .ok_or(HostIoError::Errno(HostIoErrno::EACCES))
+    }
+}
+
+// Stop reasons
+
+/// Why the machine stopped, regardless of the frontend (GDB stub or REPL) driving it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StopReason {
+    Syscall(SyscallStop),
+    Watch(WatchHit),
+    Breakpoint { hart: usize, pc: usize },
+}
+
+/// Checks whether the last instruction executed (or undone) by the machine should stop it
+///
+/// Watchpoints take precedence over breakpoints, since GDB needs the data address to tell
+/// which watchpoint got triggered. Syscalls and watchpoints are reported for the hart which
+/// executed the instruction, while breakpoints are checked against the next hart to run (which
+/// gets selected)
+pub fn stop_reason<T: Machine>(
+    machine: &mut T,
+    is_breakpoint: impl Fn(usize) -> bool,
+) -> Option<StopReason> {
+    match machine.syscall_stop() {
+        // performed outside of the machine (see `SimpleTarget::forward_syscall`) before it goes on
+        Some(SyscallStop::Forward(_)) => return None,
+        Some(stop) => return Some(StopReason::Syscall(stop)),
+        None => {}
+    }
+    if let Some(hit) = machine.watch_hit() {
+        return Some(StopReason::Watch(hit));
+    }
+    let hart = machine.scheduled_hart();
+    machine.select_hart(hart);
+    let pc = machine.read_pc() as usize;
+    is_breakpoint(pc).then_some(StopReason::Breakpoint { hart, pc })
+}
+
+fn to_gdb_watch_kind(kind: machine::WatchKind) -> WatchKind {
+    match kind {
+        machine::WatchKind::Read => WatchKind::Read,
+        machine::WatchKind::Write => WatchKind::Write,
+        machine::WatchKind::Access => WatchKind::ReadWrite,
+    }
+}
+
+fn from_gdb_watch_kind(kind: WatchKind) -> machine::WatchKind {
+    match kind {
+        WatchKind::Read => machine::WatchKind::Read,
+        WatchKind::Write => machine::WatchKind::Write,
+        WatchKind::ReadWrite => machine::WatchKind::Access,
     }
 }
 
 impl<T: Machine> Target for SimpleTarget<T> {
     type Error = ();
-    type Arch = gdbstub_arch::riscv::Riscv32;
+    type Arch = arch::Riscv32;
+
+    // File-I/O requests are exchanged outside of the stub (see `forward_syscall`), which relies
+    // on packets being acknowledged
+    #[inline(always)]
+    fn use_no_ack_mode(&self) -> bool {
+        false
+    }
 
     #[inline(always)]
     fn base_ops(&mut self) -> BaseOps<'_, Self::Arch, Self::Error> {
-        BaseOps::SingleThread(self)
+        BaseOps::MultiThread(self)
     }
 
     // opt-in to support for setting/removing breakpoints
@@ -164,13 +597,55 @@ impl<T: Machine> Target for SimpleTarget<T> {
     ) -> Option<gdbstub::target::ext::memory_map::MemoryMapOps<'_, Self>> {
         Some(self)
     }
+
+    #[inline(always)]
+    fn support_catch_syscalls(&mut self) -> Option<CatchSyscallsOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_exec_file(&mut self) -> Option<ExecFileOps<'_, Self>> {
+        if self.exec_file.is_some() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    fn support_extended_mode(&mut self) -> Option<ExtendedModeOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_host_io(&mut self) -> Option<HostIoOps<'_, Self>> {
+        if self.sandbox.is_some() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    fn support_monitor_cmd(&mut self) -> Option<MonitorCmdOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_target_description_xml_override(
+        &mut self,
+    ) -> Option<TargetDescriptionXmlOverrideOps<'_, Self>> {
+        Some(self)
+    }
 }
 
-impl<T: Machine> SingleThreadBase for SimpleTarget<T> {
+impl<T: Machine> MultiThreadBase for SimpleTarget<T> {
     fn read_registers(
         &mut self,
         regs: &mut gdbstub_arch::riscv::reg::RiscvCoreRegs<u32>,
+        tid: Tid,
     ) -> TargetResult<(), Self> {
+        self.machine.select_hart(tid_hart(tid));
         let myregs = self.machine.read_registers();
         let gps = &myregs[..32];
         let pc = &myregs[32];
@@ -184,7 +659,9 @@ impl<T: Machine> SingleThreadBase for SimpleTarget<T> {
     fn write_registers(
         &mut self,
         regs: &gdbstub_arch::riscv::reg::RiscvCoreRegs<u32>,
+        tid: Tid,
     ) -> TargetResult<(), Self> {
+        self.machine.select_hart(tid_hart(tid));
         let gprs = regs.x.to_vec();
         let pc: usize = regs.pc.try_into().unwrap();
         self.machine.write_registers(gprs, pc);
@@ -193,12 +670,19 @@ impl<T: Machine> SingleThreadBase for SimpleTarget<T> {
 
     // TODO: passing 4 as the alignment will later on cause problems. The easiest way to deal with
     // this is to switch the memory endian to match that of gdb (LittleEndian).
-    fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<usize, Self> {
+    fn read_addrs(
+        &mut self,
+        start_addr: u32,
+        data: &mut [u8],
+        _tid: Tid,
+    ) -> TargetResult<usize, Self> {
+        // memory is shared by all harts
         let start_addr: usize = start_addr.try_into().unwrap();
         let data_size = data.len();
-        let mem_size = self.machine.bytes_count();
-        if start_addr < mem_size {
-            let free_mem_size = mem_size - start_addr;
+        let mem_start = self.machine.memory_start();
+        let mem_end = mem_start + self.machine.bytes_count();
+        if (mem_start..mem_end).contains(&start_addr) {
+            let free_mem_size = mem_end - start_addr;
             let bytes_size = if data_size < free_mem_size {
                 data_size
             } else {
@@ -214,22 +698,131 @@ impl<T: Machine> SingleThreadBase for SimpleTarget<T> {
         }
     }
 
-    fn write_addrs(&mut self, start_addr: u32, data: &[u8]) -> TargetResult<(), Self> {
+    fn write_addrs(&mut self, start_addr: u32, data: &[u8], _tid: Tid) -> TargetResult<(), Self> {
         let start: usize = start_addr.try_into().unwrap();
         self.machine.write_memory_bytes(start, data);
         Ok(())
     }
 
+    fn list_active_threads(
+        &mut self,
+        thread_is_active: &mut dyn FnMut(Tid),
+    ) -> Result<(), Self::Error> {
+        for hart in 0..self.machine.hart_count() {
+            thread_is_active(hart_tid(hart));
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<'_, Tid, Self>> {
+        Some(self)
+    }
+
     // most targets will want to support at resumption as well...
     #[inline(always)]
-    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<'_, Self>> {
+    fn support_resume(&mut self) -> Option<MultiThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_thread_extra_info(&mut self) -> Option<ThreadExtraInfoOps<'_, Self>> {
         Some(self)
     }
 }
 
-impl<T: Machine> SingleThreadResume for SimpleTarget<T> {
-    fn resume(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
-        self.state = TargetState::Running;
+impl<T: Machine> ThreadExtraInfo for SimpleTarget<T> {
+    fn thread_extra_info(&self, tid: Tid, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let info = format!("hart {}", tid_hart(tid));
+        Ok(copy_chunk(info.as_bytes(), 0, buf.len(), buf))
+    }
+}
+
+impl<T: Machine> SingleRegisterAccess<Tid> for SimpleTarget<T> {
+    fn read_register(
+        &mut self,
+        tid: Tid,
+        reg_id: RegisterId,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        self.machine.select_hart(tid_hart(tid));
+        let bytes = match reg_id {
+            RegisterId::Gpr(reg) => self.machine.read_registers()[reg as usize]
+                .to_le_bytes()
+                .to_vec(),
+            RegisterId::Pc => self.machine.read_pc().to_le_bytes().to_vec(),
+            RegisterId::Csr(csr) => self.machine.read_csr(csr as usize).to_le_bytes().to_vec(),
+            RegisterId::Fpr(reg) => {
+                let size = self.machine.float_extension().register_size_bytes();
+                if size == 0 {
+                    return Err(TargetError::NonFatal);
+                }
+                let value = self.machine.read_fpr(reg as usize).to_le_bytes();
+                value[..size].to_vec()
+            }
+        };
+        let Some(dst) = buf.get_mut(..bytes.len()) else {
+            return Err(TargetError::NonFatal);
+        };
+        dst.copy_from_slice(&bytes);
+        Ok(bytes.len())
+    }
+
+    fn write_register(
+        &mut self,
+        tid: Tid,
+        reg_id: RegisterId,
+        val: &[u8],
+    ) -> TargetResult<(), Self> {
+        self.machine.select_hart(tid_hart(tid));
+        let mut bytes = [0u8; 8];
+        let Some(dst) = bytes.get_mut(..val.len()) else {
+            return Err(TargetError::NonFatal);
+        };
+        dst.copy_from_slice(val);
+        let value = u64::from_le_bytes(bytes);
+        match reg_id {
+            RegisterId::Gpr(reg) => {
+                let mut gprs = self.machine.read_registers();
+                let pc = gprs.pop().unwrap_or(0) as usize;
+                gprs[reg as usize] = value as u32;
+                self.machine.write_registers(gprs, pc);
+            }
+            RegisterId::Pc => self.machine.set_pc(value as usize),
+            RegisterId::Csr(csr) => self.machine.write_csr(csr as usize, value as u32),
+            RegisterId::Fpr(reg) => {
+                if self.machine.float_extension() == FloatExtension::None {
+                    return Err(TargetError::NonFatal);
+                }
+                self.machine.write_fpr(reg as usize, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Machine> MultiThreadResume for SimpleTarget<T> {
+    fn resume(&mut self) -> Result<(), Self::Error> {
+        self.state = match self.step_hart {
+            Some(_) => TargetState::Stepping,
+            None => TargetState::Running,
+        };
+        Ok(())
+    }
+
+    fn clear_resume_actions(&mut self) -> Result<(), Self::Error> {
+        self.step_hart = None;
+        self.resumed_hart = None;
+        self.locked = false;
+        Ok(())
+    }
+
+    fn set_resume_action_continue(
+        &mut self,
+        tid: Tid,
+        _signal: Option<Signal>,
+    ) -> Result<(), Self::Error> {
+        self.resumed_hart = Some(tid_hart(tid));
         Ok(())
     }
 
@@ -237,16 +830,59 @@ impl<T: Machine> SingleThreadResume for SimpleTarget<T> {
     // single-step resume as well
 
     #[inline(always)]
-    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+    fn support_single_step(&mut self) -> Option<MultiThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_scheduler_locking(&mut self) -> Option<MultiThreadSchedulerLockingOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_reverse_step(&mut self) -> Option<ReverseStepOps<'_, Tid, Self>> {
         Some(self)
     }
 
-    //  support_reverse_cont, support_reverse_step
+    #[inline(always)]
+    fn support_reverse_cont(&mut self) -> Option<ReverseContOps<'_, Tid, Self>> {
+        Some(self)
+    }
 }
 
-impl<T: Machine> SingleThreadSingleStep for SimpleTarget<T> {
-    fn step(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
-        self.state = TargetState::Stepping;
+impl<T: Machine> MultiThreadSingleStep for SimpleTarget<T> {
+    fn set_resume_action_step(
+        &mut self,
+        tid: Tid,
+        _signal: Option<Signal>,
+    ) -> Result<(), Self::Error> {
+        self.step_hart = Some(tid_hart(tid));
+        self.resumed_hart = Some(tid_hart(tid));
+        Ok(())
+    }
+}
+
+// GDB resumes a single thread (i.e: when stepping over a breakpoint), which is the only hart
+// scheduled until the machine stops again
+impl<T: Machine> MultiThreadSchedulerLocking for SimpleTarget<T> {
+    fn set_resume_action_scheduler_lock(&mut self) -> Result<(), Self::Error> {
+        self.locked = true;
+        Ok(())
+    }
+}
+
+impl<T: Machine> ReverseStep<Tid> for SimpleTarget<T> {
+    fn reverse_step(&mut self, tid: Tid) -> Result<(), Self::Error> {
+        self.step_hart = Some(tid_hart(tid));
+        self.state = TargetState::ReverseStepping;
+        Ok(())
+    }
+}
+
+impl<T: Machine> ReverseCont<Tid> for SimpleTarget<T> {
+    fn reverse_cont(&mut self) -> Result<(), Self::Error> {
+        self.step_hart = None;
+        self.state = TargetState::ReverseRunning;
         Ok(())
     }
 }
@@ -258,6 +894,11 @@ impl<T: Machine> Breakpoints for SimpleTarget<T> {
         Some(self)
     }
 
+    #[inline(always)]
+    fn support_hw_watchpoint(&mut self) -> Option<HwWatchpointOps<'_, Self>> {
+        Some(self)
+    }
+
     // support_hw_breakpoint
 }
 
@@ -303,6 +944,35 @@ impl<T: Machine> SwBreakpoint for SimpleTarget<T> {
     }
 }
 
+impl<T: Machine> HwWatchpoint for SimpleTarget<T> {
+    fn add_hw_watchpoint(
+        &mut self,
+        addr: u32,
+        len: u32,
+        kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        self.machine.add_watchpoint(Watchpoint {
+            addr: addr as usize,
+            len: len as usize,
+            kind: from_gdb_watch_kind(kind),
+        });
+        Ok(true)
+    }
+
+    fn remove_hw_watchpoint(
+        &mut self,
+        addr: u32,
+        len: u32,
+        kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        Ok(self.machine.remove_watchpoint(Watchpoint {
+            addr: addr as usize,
+            len: len as usize,
+            kind: from_gdb_watch_kind(kind),
+        }))
+    }
+}
+
 impl<T: Machine> MemoryMap for SimpleTarget<T> {
     fn memory_map_xml(
         &self,
@@ -310,33 +980,351 @@ impl<T: Machine> MemoryMap for SimpleTarget<T> {
         length: usize,
         buf: &mut [u8],
     ) -> TargetResult<usize, Self> {
-        // XML must be returned in chunks based on offset/length
-        // since GDB may request partial reads.
-
-        let memory_size_bytes = self.machine.bytes_count();
-        let xml = format!(
-            "
-<memory-map>
-  <memory type=\"ram\" start=\"0x00000000\" length=\"0x{:08x}\" permissions=\"rwx\"/>
-</memory-map>
-",
-            memory_size_bytes
-        );
-
-        let xml_bytes = xml.as_bytes();
-
-        if (offset as usize) >= xml_bytes.len() {
-            return Ok(0); // nothing left to read
+        let mut xml = String::from("\n<memory-map>\n");
+        for region in &self.regions {
+            xml.push_str(&format!(
+                "  <memory type=\"ram\" start=\"0x{:08x}\" length=\"0x{:08x}\" permissions=\"rwx\"/>\n",
+                region.start, region.length
+            ));
+        }
+        xml.push_str("</memory-map>\n");
+
+        Ok(copy_chunk(xml.as_bytes(), offset, length, buf))
+    }
+}
+
+const MONITOR_HELP: &str = "\
+monitor trace on|off              log every instruction executed (to stderr)
+monitor icount                    show the number of instructions executed
+monitor memmap                    show the memory map of the machine
+monitor reset                     reset the CPU (memory is kept)
+monitor snapshot save <name>      save the machine state
+monitor snapshot restore <name>   restore a saved machine state
+monitor syscalls on|off           print every syscall made by the program";
+
+impl<T: Machine> MonitorCmd for SimpleTarget<T> {
+    fn handle_monitor_cmd(&mut self, cmd: &[u8], mut out: ConsoleOutput<'_>) -> Result<(), ()> {
+        let cmd = String::from_utf8_lossy(cmd);
+        let args: Vec<&str> = cmd.split_whitespace().collect();
+        match args.as_slice() {
+            ["trace", "on"] => self.machine.set_tracing(true),
+            ["trace", "off"] => self.machine.set_tracing(false),
+            ["syscalls", "on"] => self.machine.set_syscall_logging(true),
+            ["syscalls", "off"] => self.machine.set_syscall_logging(false),
+            ["icount"] => outputln!(out, "{}", self.machine.instruction_count()),
+            ["memmap"] => {
+                for region in &self.regions {
+                    outputln!(
+                        out,
+                        "0x{:08x}-0x{:08x} rwx {} ({} bytes)",
+                        region.start,
+                        (region.start + region.length).saturating_sub(1),
+                        region.name,
+                        region.length
+                    );
+                }
+            }
+            ["reset"] => {
+                self.machine.reset();
+                // GDB caches registers, which are now stale
+                outputln!(out, "machine reset (run 'flushregs' to refresh registers)");
+            }
+            ["snapshot", "save", name] => {
+                self.snapshots
+                    .insert(name.to_string(), self.machine.snapshot());
+                outputln!(out, "snapshot '{}' saved", name);
+            }
+            ["snapshot", "restore", name] => match self.snapshots.get(*name) {
+                Some(snapshot) => {
+                    self.machine.restore(snapshot);
+                    outputln!(
+                        out,
+                        "snapshot '{}' restored (run 'flushregs' to refresh registers)",
+                        name
+                    );
+                }
+                None => outputln!(out, "no snapshot named '{}'", name),
+            },
+            _ => outputln!(out, "{}", MONITOR_HELP),
+        }
+        Ok(())
+    }
+}
+
+impl<T: Machine> CatchSyscalls for SimpleTarget<T> {
+    fn enable_catch_syscalls(
+        &mut self,
+        filter: Option<SyscallNumbers<'_, u32>>,
+    ) -> TargetResult<(), Self> {
+        let catch = match filter {
+            Some(numbers) => SyscallCatch::Only(numbers.collect()),
+            None => SyscallCatch::All,
+        };
+        self.machine.set_syscall_catch(catch);
+        Ok(())
+    }
+
+    fn disable_catch_syscalls(&mut self) -> TargetResult<(), Self> {
+        self.machine.set_syscall_catch(SyscallCatch::None);
+        Ok(())
+    }
+}
+
+impl<T: Machine> HostIo for SimpleTarget<T> {
+    #[inline(always)]
+    fn support_open(&mut self) -> Option<HostIoOpenOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_close(&mut self) -> Option<HostIoCloseOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_pread(&mut self) -> Option<HostIoPreadOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_pwrite(&mut self) -> Option<HostIoPwriteOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_fstat(&mut self) -> Option<HostIoFstatOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_unlink(&mut self) -> Option<HostIoUnlinkOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_readlink(&mut self) -> Option<HostIoReadlinkOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_setfs(&mut self) -> Option<HostIoSetfsOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl<T: Machine> HostIoOpen for SimpleTarget<T> {
+    fn open(
+        &mut self,
+        filename: &[u8],
+        flags: HostIoOpenFlags,
+        mode: HostIoOpenMode,
+    ) -> HostIoResult<u32, Self> {
+        let path = self
+            .sandbox()?
+            .resolve(filename)
+            .ok_or(HostIoError::Errno(HostIoErrno::EACCES))?;
+        let access = flags.bits() & 0b11;
+        let file = OpenOptions::new()
+            .read(access != HostIoOpenFlags::O_WRONLY.bits())
+            .write(access != HostIoOpenFlags::O_RDONLY.bits())
+            .append(flags.contains(HostIoOpenFlags::O_APPEND))
+            .create(flags.contains(HostIoOpenFlags::O_CREAT))
+            .truncate(flags.contains(HostIoOpenFlags::O_TRUNC))
+            .create_new(flags.contains(HostIoOpenFlags::O_EXCL))
+            .mode(mode.bits())
+            .open(path)?;
+        Ok(self.sandbox()?.insert(file))
+    }
+}
+
+impl<T: Machine> HostIoClose for SimpleTarget<T> {
+    fn close(&mut self, fd: u32) -> HostIoResult<(), Self> {
+        match self.sandbox()?.remove(fd) {
+            Some(_) => Ok(()),
+            None => Err(HostIoError::Errno(HostIoErrno::EBADF)),
+        }
+    }
+}
+
+impl<T: Machine> HostIoPread for SimpleTarget<T> {
+    fn pread(
+        &mut self,
+        fd: u32,
+        count: usize,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> HostIoResult<usize, Self> {
+        let file = self
+            .sandbox()?
+            .file(fd)
+            .ok_or(HostIoError::Errno(HostIoErrno::EBADF))?;
+        let count = count.min(buf.len());
+        Ok(file.read_at(&mut buf[..count], offset)?)
+    }
+}
+
+impl<T: Machine> HostIoPwrite for SimpleTarget<T> {
+    fn pwrite(&mut self, fd: u32, offset: u32, data: &[u8]) -> HostIoResult<u32, Self> {
+        let file = self
+            .sandbox()?
+            .file(fd)
+            .ok_or(HostIoError::Errno(HostIoErrno::EBADF))?;
+        Ok(file.write_at(data, offset.into())? as u32)
+    }
+}
+
+impl<T: Machine> HostIoFstat for SimpleTarget<T> {
+    fn fstat(&mut self, fd: u32) -> HostIoResult<HostIoStat, Self> {
+        let file = self
+            .sandbox()?
+            .file(fd)
+            .ok_or(HostIoError::Errno(HostIoErrno::EBADF))?;
+        let metadata = file.metadata()?;
+        Ok(HostIoStat {
+            st_dev: metadata.dev() as u32,
+            st_ino: metadata.ino() as u32,
+            st_mode: HostIoOpenMode::from_bits_truncate(metadata.mode()),
+            st_nlink: metadata.nlink() as u32,
+            st_uid: metadata.uid(),
+            st_gid: metadata.gid(),
+            st_rdev: metadata.rdev() as u32,
+            st_size: metadata.size(),
+            st_blksize: metadata.blksize(),
+            st_blocks: metadata.blocks(),
+            st_atime: metadata.atime() as u32,
+            st_mtime: metadata.mtime() as u32,
+            st_ctime: metadata.ctime() as u32,
+        })
+    }
+}
+
+impl<T: Machine> HostIoUnlink for SimpleTarget<T> {
+    fn unlink(&mut self, filename: &[u8]) -> HostIoResult<(), Self> {
+        let path = self
+            .sandbox()?
+            .resolve(filename)
+            .ok_or(HostIoError::Errno(HostIoErrno::EACCES))?;
+        Ok(std::fs::remove_file(path)?)
+    }
+}
+
+impl<T: Machine> HostIoReadlink for SimpleTarget<T> {
+    fn readlink(&mut self, filename: &[u8], buf: &mut [u8]) -> HostIoResult<usize, Self> {
+        let path = self
+            .sandbox()?
+            .resolve(filename)
+            .ok_or(HostIoError::Errno(HostIoErrno::EACCES))?;
+        let target = std::fs::read_link(path)?;
+        let target = target.to_string_lossy();
+        let count = target.len().min(buf.len());
+        buf[..count].copy_from_slice(&target.as_bytes()[..count]);
+        Ok(count)
+    }
+}
+
+impl<T: Machine> HostIoSetfs for SimpleTarget<T> {
+    fn setfs(&mut self, _fs: FsKind) -> HostIoResult<(), Self> {
+        // every process shares the same sandbox
+        Ok(())
+    }
+}
+
+/// The program run by the machine is seen by GDB as a single process
+const PROCESS_ID: Pid = match Pid::new(1) {
+    Some(pid) => pid,
+    None => unreachable!(),
+};
+
+impl<T: Machine> ExtendedMode for SimpleTarget<T> {
+    fn run(&mut self, filename: Option<&[u8]>, args: Args<'_, '_>) -> TargetResult<Pid, Self> {
+        if let Some(filename) = filename.filter(|f| !f.is_empty()) {
+            self.exec_file = Some(String::from_utf8_lossy(filename).to_string());
         }
+        self.args = args
+            .map(|arg| String::from_utf8_lossy(arg).to_string())
+            .collect();
+        self.reload().map_err(|_| TargetError::NonFatal)?;
+        Ok(PROCESS_ID)
+    }
 
-        let remaining = &xml_bytes[offset as usize..];
-        let n = remaining.len().min(length);
-        buf[..n].copy_from_slice(&remaining[..n]);
+    fn attach(&mut self, _pid: Pid) -> TargetResult<(), Self> {
+        // there are no other processes to attach to
+        Err(TargetError::NonFatal)
+    }
 
-        Ok(n)
+    fn query_if_attached(&mut self, _pid: Pid) -> TargetResult<AttachKind, Self> {
+        Ok(AttachKind::Run)
+    }
+
+    fn kill(&mut self, _pid: Option<Pid>) -> TargetResult<ShouldTerminate, Self> {
+        self.reload().map_err(|_| TargetError::NonFatal)?;
+        Ok(ShouldTerminate::No)
+    }
+
+    fn restart(&mut self) -> Result<(), ()> {
+        self.reload().map_err(|_| ())
+    }
+
+    fn on_start(&mut self) -> Result<(), ()> {
+        self.extended = true;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn support_current_active_pid(&mut self) -> Option<CurrentActivePidOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl<T: Machine> CurrentActivePid for SimpleTarget<T> {
+    fn current_active_pid(&mut self) -> Result<Pid, ()> {
+        Ok(PROCESS_ID)
+    }
+}
+
+impl<T: Machine> ExecFile for SimpleTarget<T> {
+    fn get_exec_file(
+        &self,
+        _pid: Option<Pid>,
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        let filename = self.exec_file.as_deref().unwrap_or_default();
+        Ok(copy_chunk(filename.as_bytes(), offset, length, buf))
     }
 }
 
+impl<T: Machine> TargetDescriptionXmlOverride for SimpleTarget<T> {
+    fn target_description_xml(
+        &self,
+        annex: &[u8],
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        if annex != b"target.xml" {
+            return Err(TargetError::NonFatal);
+        }
+        let xml = arch::target_description_xml(self.machine.float_extension());
+        Ok(copy_chunk(xml.as_bytes(), offset, length, buf))
+    }
+}
+
+/// Documents (such as XML files) must be returned in chunks based on offset/length, since GDB
+/// may request partial reads
+fn copy_chunk(xml_bytes: &[u8], offset: u64, length: usize, buf: &mut [u8]) -> usize {
+    if (offset as usize) >= xml_bytes.len() {
+        return 0; // nothing left to read
+    }
+
+    let remaining = &xml_bytes[offset as usize..];
+    let n = remaining.len().min(length).min(buf.len());
+    buf[..n].copy_from_slice(&remaining[..n]);
+
+    n
+}
+
 // Loop
 
 struct SimpleGdbBlockingEventLoop<T: Machine> {
@@ -347,10 +1335,9 @@ struct SimpleGdbBlockingEventLoop<T: Machine> {
 // the `GdbStub::run_blocking` event loop requires you to implement.
 impl<T: Machine> run_blocking::BlockingEventLoop for SimpleGdbBlockingEventLoop<T> {
     type Target = SimpleTarget<T>;
-    type Connection = TcpStream;
+    type Connection = GdbConnection;
 
-    // or MultiThreadStopReason on multi threaded targets
-    type StopReason = SingleThreadStopReason<u32>;
+    type StopReason = MultiThreadStopReason<u32>;
 
     // Invoked immediately after the target's `resume` method has been
     // called. The implementation should block until either the target
@@ -359,7 +1346,7 @@ impl<T: Machine> run_blocking::BlockingEventLoop for SimpleGdbBlockingEventLoop<
         target: &mut SimpleTarget<T>,
         conn: &mut Self::Connection,
     ) -> Result<
-        run_blocking::Event<SingleThreadStopReason<u32>>,
+        run_blocking::Event<MultiThreadStopReason<u32>>,
         run_blocking::WaitForStopReasonError<
             <Self::Target as Target>::Error,
             <Self::Connection as Connection>::Error,
@@ -390,88 +1377,70 @@ impl<T: Machine> run_blocking::BlockingEventLoop for SimpleGdbBlockingEventLoop<
 
         loop {
             // Try a non-blocking read for incoming data (so GDB can interrupt).
-            // Connection here is TcpStream; set nonblocking briefly.
-            let _ = conn.set_nonblocking(true);
-            let mut buf = [0u8; 1];
-            match Read::read(conn, &mut buf) {
-                Ok(1) => {
-                    let _ = conn.set_nonblocking(false);
-                    return Ok(run_blocking::Event::IncomingData(buf[0]));
-                }
-                Ok(0) => {
-                    let _ = conn.set_nonblocking(false);
-                    return Err(run_blocking::WaitForStopReasonError::Connection(
-                        std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed")
-                            .into(),
-                    ));
-                }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    let _ = conn.set_nonblocking(false);
-                    // no data pending; fall through and run the target for one insn
-                }
-                Err(e) => {
-                    let _ = conn.set_nonblocking(false);
-                    return Err(run_blocking::WaitForStopReasonError::Connection(e.into()));
-                }
-                Ok(_) => panic!(),
+            match conn.try_read() {
+                Ok(Some(byte)) => return Ok(run_blocking::Event::IncomingData(byte)),
+                // no data pending; fall through and run the target for one insn
+                Ok(None) => {}
+                Err(e) => return Err(run_blocking::WaitForStopReasonError::Connection(e)),
             }
 
             match target.state {
-                TargetState::Stepping => {
-                    let _pc_before = target.machine.read_pc();
-                    let Ok(state) = target.machine.decode() else {
+                TargetState::Stepping | TargetState::Running => {
+                    // Execute a single instruction per loop to remain responsive.
+                    let Ok(state) = target.execute() else {
                         return Err(run_blocking::WaitForStopReasonError::Target(()));
                     };
+                    if let Some(SyscallStop::Forward(number)) = target.machine.syscall_stop() {
+                        target
+                            .forward_syscall(conn, number)
+                            .map_err(run_blocking::WaitForStopReasonError::Connection)?;
+                    }
 
                     match state {
-                        crate::emu::machine::MachineState::Exit(s) => {
+                        MachineState::Exit(s) => {
                             return Ok(run_blocking::Event::TargetStopped(
-                                SingleThreadStopReason::Exited(s as u8),
+                                MultiThreadStopReason::Exited(s as u8),
                             ));
                         }
 
-                        crate::emu::machine::MachineState::Ok => {
-                            let pc_after = target.machine.read_pc();
-
-                            // if we hit a breakpoint, report SwBreak; else DoneStep
-                            if target.breakpoints.iter().any(|b| b.0 == pc_after) {
+                        MachineState::Ok => {
+                            // the step is done once the stepped hart executes an instruction
+                            let stepped = target.state == TargetState::Stepping
+                                && target.step_hart == Some(target.machine.current_hart());
+                            // if we hit a watchpoint/breakpoint, report it
+                            if let Some(reason) = target.stop_reason() {
                                 target.state = TargetState::Idle;
-                                return Ok(run_blocking::Event::TargetStopped(
-                                    SingleThreadStopReason::SwBreak(()),
-                                ));
-                            } else {
+                                return Ok(run_blocking::Event::TargetStopped(reason));
+                            }
+                            if stepped {
                                 target.state = TargetState::Idle;
-                                return Ok(run_blocking::Event::TargetStopped(
-                                    SingleThreadStopReason::DoneStep,
-                                ));
+                                return Ok(run_blocking::Event::TargetStopped(target.done_step()));
                             }
+                            // continue the loop (we'll check incoming data every iteration)
                         }
                     }
                 }
 
-                TargetState::Running => {
-                    // Execute a single instruction per loop to remain responsive.
-                    let Ok(state) = target.machine.decode() else {
-                        return Err(run_blocking::WaitForStopReasonError::Target(()));
+                TargetState::ReverseStepping | TargetState::ReverseRunning => {
+                    // Undo a single instruction per loop, for the same reason as 'Running'
+                    let Some(record) = target.machine.step_back() else {
+                        target.state = TargetState::Idle;
+                        return Ok(run_blocking::Event::TargetStopped(
+                            MultiThreadStopReason::ReplayLog {
+                                tid: None,
+                                pos: ReplayLogPosition::Begin,
+                            },
+                        ));
                     };
-
-                    match state {
-                        crate::emu::machine::MachineState::Exit(s) => {
-                            return Ok(run_blocking::Event::TargetStopped(
-                                SingleThreadStopReason::Exited(s as u8),
-                            ));
-                        }
-
-                        crate::emu::machine::MachineState::Ok => {
-                            let pc = target.machine.read_pc();
-                            if target.breakpoints.iter().any(|b| b.0 == pc) {
-                                target.state = TargetState::Idle;
-                                return Ok(run_blocking::Event::TargetStopped(
-                                    SingleThreadStopReason::SwBreak(()),
-                                ));
-                            }
-                            // continue the loop (we'll check incoming data every iteration)
-                        }
+                    let stepped = target.state == TargetState::ReverseStepping
+                        && target.step_hart == Some(record.hart);
+                    if let Some(reason) = target.stop_reason() {
+                        target.state = TargetState::Idle;
+                        return Ok(run_blocking::Event::TargetStopped(reason));
+                    }
+                    if stepped {
+                        target.state = TargetState::Idle;
+                        return Ok(run_blocking::Event::TargetStopped(target.done_step()));
                     }
                 }
 
@@ -485,21 +1454,24 @@ impl<T: Machine> run_blocking::BlockingEventLoop for SimpleGdbBlockingEventLoop<
 
     // Invoked when the GDB client sends a Ctrl-C interrupt.
     fn on_interrupt(
-        _target: &mut SimpleTarget<T>,
-    ) -> Result<Option<SingleThreadStopReason<u32>>, <SimpleTarget<T> as Target>::Error> {
+        target: &mut SimpleTarget<T>,
+    ) -> Result<Option<MultiThreadStopReason<u32>>, <SimpleTarget<T> as Target>::Error> {
         // notify the target that a ctrl-c interrupt has occurred.
         // target.stop_in_response_to_ctrl_c_interrupt()?;
 
         // a pretty typical stop reason in response to a Ctrl-C interrupt is to
         // report a "Signal::SIGINT".
-        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT).into()))
+        Ok(Some(MultiThreadStopReason::SignalWithThread {
+            tid: hart_tid(target.machine.scheduled_hart()),
+            signal: Signal::SIGINT,
+        }))
     }
 }
 
 fn custom_handle_machine_state<'a, T: Machine>(
-    stub_sm: GdbStubStateMachine<'a, SimpleTarget<T>, TcpStream>,
+    stub_sm: GdbStubStateMachine<'a, SimpleTarget<T>, GdbConnection>,
     target: &mut SimpleTarget<T>,
-) -> Result<GdbStubStateMachine<'a, SimpleTarget<T>, TcpStream>, ()> {
+) -> Result<GdbStubStateMachine<'a, SimpleTarget<T>, GdbConnection>, ()> {
     match stub_sm {
         gdbstub::stub::state_machine::GdbStubStateMachine::Idle(
             mut gdb_stub_state_machine_inner,
@@ -568,7 +1540,18 @@ fn custom_handle_machine_state<'a, T: Machine>(
             _gdb_stub_state_machine_inner,
         ) => Err(()),
         gdbstub::stub::state_machine::GdbStubStateMachine::Disconnected(
-            _gdb_stub_state_machine_inner,
-        ) => Err(()),
+            gdb_stub_state_machine_inner,
+        ) => {
+            // in extended mode, GDB stays connected after the program exits, so that it can be
+            // run again
+            match gdb_stub_state_machine_inner.get_reason() {
+                DisconnectReason::TargetExited(_) | DisconnectReason::TargetTerminated(_)
+                    if target.extended =>
+                {
+                    Ok(gdb_stub_state_machine_inner.return_to_idle())
+                }
+                _ => Err(()),
+            }
+        }
     }
 }
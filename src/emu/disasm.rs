@@ -0,0 +1,177 @@
+//! Translation of instruction words back to assembly, for the instructions handled by the machine
+
+use crate::emu::arch::{GPR_NAMES, MACHINE_CSRS};
+use crate::lang::ext::{Immediate, InstructionFormat};
+
+fn reg(id: u32) -> &'static str {
+    GPR_NAMES[(id & 0b11111) as usize]
+}
+
+fn csr(addr: u32) -> String {
+    MACHINE_CSRS
+        .iter()
+        .find(|(_, id)| *id == addr as usize)
+        .map_or(format!("0x{:x}", addr), |(name, _)| name.to_string())
+}
+
+/// Disassembles the instruction found at 'pc' (branch and jump targets are given as addresses)
+///
+/// Words which don't encode a known instruction are shown as '.word' directives
+pub fn disassemble(word: u32, pc: usize) -> String {
+    let unknown = format!(".word 0x{:08x}", word);
+    let Some(ifmt) = InstructionFormat::decode(word) else {
+        return unknown;
+    };
+    match ifmt {
+        InstructionFormat::R {
+            funct7,
+            rs2,
+            rs1,
+            funct3,
+            rd,
+            opcode: _,
+        } => {
+            let name = match (funct7, funct3) {
+                (0b0000000, 0b000) => "add",
+                (0b0100000, 0b000) => "sub",
+                (0b0000000, 0b001) => "sll",
+                (0b0000000, 0b010) => "slt",
+                (0b0000000, 0b011) => "sltu",
+                (0b0000000, 0b100) => "xor",
+                (0b0000000, 0b101) => "srl",
+                (0b0100000, 0b101) => "sra",
+                (0b0000000, 0b110) => "or",
+                (0b0000000, 0b111) => "and",
+                (0b0000001, 0b000) => "mul",
+                (0b0000001, 0b001) => "mulh",
+                (0b0000001, 0b010) => "mulhsu",
+                (0b0000001, 0b011) => "mulhu",
+                (0b0000001, 0b100) => "div",
+                (0b0000001, 0b101) => "divu",
+                (0b0000001, 0b110) => "rem",
+                (0b0000001, 0b111) => "remu",
+                _ => return unknown,
+            };
+            format!("{} {}, {}, {}", name, reg(rd), reg(rs1), reg(rs2))
+        }
+        InstructionFormat::I {
+            imm,
+            rs1,
+            funct3,
+            rd,
+            opcode,
+        } => {
+            let imm = imm.decode();
+            match (funct3, opcode) {
+                (0b000, 0b1110011) if imm == 0 => String::from("ecall"),
+                (0b000, 0b1110011) if imm == 1 => String::from("ebreak"),
+                (0b000, 0b1110011) if imm & 0xfff == 0x302 => String::from("mret"),
+                (0b010 | 0b011, 0b1110011) if rs1 == 0 => {
+                    format!("csrr {}, {}", reg(rd), csr(imm & 0xfff))
+                }
+                (0b001..=0b011, 0b1110011) => {
+                    let name = ["csrrw", "csrrs", "csrrc"][funct3 as usize - 1];
+                    format!("{} {}, {}, {}", name, reg(rd), csr(imm & 0xfff), reg(rs1))
+                }
+                (0b101..=0b111, 0b1110011) => {
+                    let name = ["csrrwi", "csrrsi", "csrrci"][funct3 as usize - 5];
+                    format!("{} {}, {}, {}", name, reg(rd), csr(imm & 0xfff), rs1)
+                }
+                (0b000, 0b1100111) => format!("jalr {}, {}({})", reg(rd), imm as i32, reg(rs1)),
+                (_, 0b0000011) => {
+                    let name = match funct3 {
+                        0b000 => "lb",
+                        0b001 => "lh",
+                        0b010 => "lw",
+                        0b100 => "lbu",
+                        0b101 => "lhu",
+                        _ => return unknown,
+                    };
+                    format!("{} {}, {}({})", name, reg(rd), imm as i32, reg(rs1))
+                }
+                (_, 0b0010011) => {
+                    let shamt = imm & 0b11111;
+                    match funct3 {
+                        0b000 => format!("addi {}, {}, {}", reg(rd), reg(rs1), imm as i32),
+                        0b010 => format!("slti {}, {}, {}", reg(rd), reg(rs1), imm as i32),
+                        0b011 => format!("sltiu {}, {}, {}", reg(rd), reg(rs1), imm as i32),
+                        0b100 => format!("xori {}, {}, {}", reg(rd), reg(rs1), imm as i32),
+                        0b110 => format!("ori {}, {}, {}", reg(rd), reg(rs1), imm as i32),
+                        0b111 => format!("andi {}, {}, {}", reg(rd), reg(rs1), imm as i32),
+                        0b001 => format!("slli {}, {}, {}", reg(rd), reg(rs1), shamt),
+                        0b101 if imm & 0x400 != 0 => {
+                            format!("srai {}, {}, {}", reg(rd), reg(rs1), shamt)
+                        }
+                        0b101 => format!("srli {}, {}, {}", reg(rd), reg(rs1), shamt),
+                        _ => unknown,
+                    }
+                }
+                _ => unknown,
+            }
+        }
+        InstructionFormat::S {
+            imm,
+            rs2,
+            rs1,
+            funct3,
+            opcode: _,
+        } => {
+            let name = match funct3 {
+                0b000 => "sb",
+                0b001 => "sh",
+                0b010 => "sw",
+                _ => return unknown,
+            };
+            format!(
+                "{} {}, {}({})",
+                name,
+                reg(rs2),
+                imm.decode() as i32,
+                reg(rs1)
+            )
+        }
+        InstructionFormat::B {
+            imm,
+            rs2,
+            rs1,
+            funct3,
+            opcode: _,
+        } => {
+            let name = match funct3 {
+                0b000 => "beq",
+                0b001 => "bne",
+                0b100 => "blt",
+                0b101 => "bge",
+                0b110 => "bltu",
+                0b111 => "bgeu",
+                _ => return unknown,
+            };
+            let target = (pc as u32).wrapping_add(imm.decode());
+            format!("{} {}, {}, 0x{:x}", name, reg(rs1), reg(rs2), target)
+        }
+        InstructionFormat::U { imm, rd, opcode } => {
+            let name = match opcode {
+                0b0110111 => "lui",
+                _ => "auipc",
+            };
+            format!("{} {}, 0x{:x}", name, reg(rd), imm.decode() >> 12)
+        }
+        InstructionFormat::J { imm, rd, opcode: _ } => {
+            let target = (pc as u32).wrapping_add(imm.decode());
+            format!("jal {}, 0x{:x}", reg(rd), target)
+        }
+    }
+}
+
+/// Whether the instruction calls a function (i.e: 'jal'/'jalr' saving the return address in ra)
+pub fn is_call(word: u32) -> bool {
+    match InstructionFormat::decode(word) {
+        Some(InstructionFormat::J { rd, .. }) => rd == 1,
+        Some(InstructionFormat::I {
+            rd,
+            opcode: 0b1100111,
+            ..
+        }) => rd == 1,
+        _ => false,
+    }
+}
@@ -0,0 +1,249 @@
+//! Access to host files while the machine is being debugged
+//!
+//! * Host I/O (vFile packets): GDB opens/reads/writes files on the emulator side, which are
+//!   confined to a sandbox directory
+//! * File-I/O: the file related syscalls of the guest are forwarded to GDB, which performs them
+//!   on the developer's machine (so the output of the program shows up in the GDB console)
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+
+use gdbstub::conn::{Connection, ConnectionExt};
+use syscalls::riscv32::Sysno;
+
+// Host I/O
+
+/// Directory of the host where the files accessed by GDB live
+pub struct Sandbox {
+    root: PathBuf,
+    files: HashMap<u32, File>,
+    next_fd: u32,
+}
+
+impl Sandbox {
+    pub fn new(root: PathBuf) -> Self {
+        Sandbox {
+            root,
+            files: HashMap::new(),
+            next_fd: 0,
+        }
+    }
+
+    /// Maps a filename given by GDB to a path inside of the sandbox
+    ///
+    /// Absolute filenames are taken as relative to the sandbox root, and filenames which would
+    /// escape it (through '..' or symbolic links) are rejected
+    pub fn resolve(&self, filename: &[u8]) -> Option<PathBuf> {
+        let filename = std::str::from_utf8(filename).ok()?;
+        let mut path = self.root.clone();
+        for component in Path::new(filename).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+        let root = self.root.canonicalize().ok()?;
+        let parent = path.parent()?.canonicalize().ok()?;
+        let path = match path.file_name() {
+            Some(name) => parent.join(name),
+            None => parent,
+        };
+        // a file which doesn't exist yet can only be created in its (already checked) parent,
+        // but an existing one (or a dangling link) might point anywhere
+        let path = match std::fs::symlink_metadata(&path) {
+            Ok(_) => path.canonicalize().ok()?,
+            Err(_) => path,
+        };
+        path.starts_with(&root).then_some(path)
+    }
+
+    pub fn insert(&mut self, file: File) -> u32 {
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(fd, file);
+        fd
+    }
+
+    pub fn file(&mut self, fd: u32) -> Option<&mut File> {
+        self.files.get_mut(&fd)
+    }
+
+    pub fn remove(&mut self, fd: u32) -> Option<File> {
+        self.files.remove(&fd)
+    }
+}
+
+// File-I/O
+
+/// Syscalls of the guest which get forwarded to GDB
+pub fn forwarded_syscalls() -> Vec<u32> {
+    [Sysno::openat, Sysno::close, Sysno::read, Sysno::write]
+        .iter()
+        .map(|sys| sys.id() as u32)
+        .collect()
+}
+
+// open flags, as defined by linux (riscv) and by the File-I/O protocol
+const LINUX_O_CREAT: u32 = 0o100;
+const LINUX_O_EXCL: u32 = 0o200;
+const LINUX_O_TRUNC: u32 = 0o1000;
+const LINUX_O_APPEND: u32 = 0o2000;
+const GDB_O_APPEND: u32 = 0x8;
+const GDB_O_CREAT: u32 = 0x200;
+const GDB_O_TRUNC: u32 = 0x400;
+const GDB_O_EXCL: u32 = 0x800;
+
+/// Translates the flags given to 'openat' to those understood by GDB
+pub fn open_flags_to_gdb(flags: u32) -> u32 {
+    let access = flags & 0b11; // O_RDONLY, O_WRONLY and O_RDWR have the same values
+    [
+        (LINUX_O_CREAT, GDB_O_CREAT),
+        (LINUX_O_EXCL, GDB_O_EXCL),
+        (LINUX_O_TRUNC, GDB_O_TRUNC),
+        (LINUX_O_APPEND, GDB_O_APPEND),
+    ]
+    .iter()
+    .filter(|(linux, _)| flags & linux != 0)
+    .fold(access, |acc, (_, gdb)| acc | gdb)
+}
+
+/// Builds the 'F' request sent to GDB for a syscall, given its arguments (a0-a3)
+///
+/// 'path_len' is the length of the path given to 'openat', including the terminating NUL
+pub fn request_packet(number: u32, args: [u32; 4], path_len: usize) -> Option<String> {
+    let sys = Sysno::new(number as usize)?;
+    let packet = match sys {
+        // the directory fd (a0) is ignored, paths are relative to the GDB working directory
+        Sysno::openat => format!(
+            "Fopen,{:x}/{:x},{:x},{:x}",
+            args[1],
+            path_len,
+            open_flags_to_gdb(args[2]),
+            args[3]
+        ),
+        Sysno::close => format!("Fclose,{:x}", args[0]),
+        Sysno::read => format!("Fread,{:x},{:x},{:x}", args[0], args[1], args[2]),
+        Sysno::write => format!("Fwrite,{:x},{:x},{:x}", args[0], args[1], args[2]),
+        _ => return None,
+    };
+    Some(packet)
+}
+
+/// Parses the 'Fretcode[,errno[,Ctrl-C flag]]' reply of GDB into the value returned to the
+/// guest (a negative errno on failures, as linux does)
+pub fn parse_reply(payload: &[u8]) -> Option<u32> {
+    let payload = std::str::from_utf8(payload.strip_prefix(b"F")?).ok()?;
+    let mut fields = payload.split(',');
+    let retcode = fields.next()?;
+    let retcode = match retcode.strip_prefix('-') {
+        Some(value) => -i64::from_str_radix(value, 16).ok()?,
+        None => i64::from_str_radix(retcode, 16).ok()?,
+    };
+    if retcode >= 0 {
+        return Some(retcode as u32);
+    }
+    let errno = fields
+        .next()
+        .and_then(|errno| u32::from_str_radix(errno, 16).ok())
+        .map_or(EIO, errno_from_gdb);
+    Some((-(errno as i32)) as u32)
+}
+
+pub const EIO: u32 = 5;
+pub const ENOSYS: u32 = 38;
+const ENAMETOOLONG: u32 = 36;
+
+/// File-I/O errno values match those of linux, except for ENAMETOOLONG and EUNKNOWN
+fn errno_from_gdb(errno: u32) -> u32 {
+    match errno {
+        1..=30 => errno,
+        91 => ENAMETOOLONG,
+        _ => EIO,
+    }
+}
+
+// Packets
+//   While a File-I/O request is being served, GDB talks directly to the target (to read or
+//   write the buffers of the syscall) outside of the stub state machine, so packets have to be
+//   handled by hand
+
+fn checksum(payload: &[u8]) -> u8 {
+    payload
+        .iter()
+        .fold(0u8, |acc, byte| acc.wrapping_add(*byte))
+}
+
+pub fn send_packet<C: Connection>(conn: &mut C, payload: &[u8]) -> Result<(), C::Error> {
+    conn.write(b'$')?;
+    conn.write_all(payload)?;
+    conn.write_all(format!("#{:02x}", checksum(payload)).as_bytes())?;
+    conn.flush()
+}
+
+/// Reads the next packet sent by GDB (acknowledging it), skipping acks and interrupts
+pub fn read_packet<C: ConnectionExt>(conn: &mut C) -> Result<Vec<u8>, C::Error> {
+    while conn.read()? != b'$' {}
+    let mut payload = Vec::new();
+    loop {
+        match conn.read()? {
+            b'#' => break,
+            byte => payload.push(byte),
+        }
+    }
+    conn.read()?;
+    conn.read()?;
+    conn.write(b'+')?;
+    conn.flush()?;
+    Ok(payload)
+}
+
+/// Memory access requested by GDB while serving a File-I/O request
+pub enum MemoryPacket {
+    Read { addr: u32, len: usize },
+    Write { addr: u32, data: Vec<u8> },
+}
+
+/// Parses 'm addr,len', 'M addr,len:hex' and 'X addr,len:binary' packets
+pub fn parse_memory_packet(payload: &[u8]) -> Option<MemoryPacket> {
+    let (&kind, rest) = payload.split_first()?;
+    let sep = rest.iter().position(|byte| *byte == b':');
+    let (header, data) = match sep {
+        Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+        None => (rest, &[][..]),
+    };
+    let header = std::str::from_utf8(header).ok()?;
+    let (addr, len) = header.split_once(',')?;
+    let addr = u32::from_str_radix(addr, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    match kind {
+        b'm' => Some(MemoryPacket::Read { addr, len }),
+        b'M' => {
+            let data = data
+                .chunks(2)
+                .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            Some(MemoryPacket::Write { addr, data })
+        }
+        b'X' => {
+            let mut unescaped = Vec::new();
+            let mut bytes = data.iter();
+            while let Some(byte) = bytes.next() {
+                match byte {
+                    b'}' => unescaped.push(bytes.next()? ^ 0x20),
+                    _ => unescaped.push(*byte),
+                }
+            }
+            Some(MemoryPacket::Write {
+                addr,
+                data: unescaped,
+            })
+        }
+        _ => None,
+    }
+}
+
+pub fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
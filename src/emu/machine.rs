@@ -1,3 +1,4 @@
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum MachineState {
     Exit(i32),
     Ok,
@@ -9,7 +10,171 @@ pub enum MachineError {
     UnhandledInstruction(String),
 }
 
+/// Floating point extension enabled in the machine, which determines the width of f0-f31
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FloatExtension {
+    None,
+    F,
+    D,
+}
+
+impl FloatExtension {
+    pub fn register_size_bytes(&self) -> usize {
+        match self {
+            FloatExtension::None => 0,
+            FloatExtension::F => 4,
+            FloatExtension::D => 8,
+        }
+    }
+}
+
+/// A register write performed by a single instruction
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RegisterWrite {
+    pub reg: usize,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// A memory write performed by a single instruction ('size' is given in bytes)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MemoryWrite {
+    pub addr: usize,
+    pub size: usize,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// A memory read performed by a single instruction ('size' is given in bytes)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MemoryRead {
+    pub addr: usize,
+    pub size: usize,
+}
+
+/// Architectural side effects of a single retired instruction
+///
+/// Storing both the old and the new values allows a record to be undone (reverse execution) and
+/// to be applied again (replay) without having to execute the instruction once more
+///
+/// 'hart' is the hart which executed the instruction, and 'slice' the number of instructions it
+/// had already executed in its quantum, so that the scheduling can be replayed as well
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepRecord {
+    pub hart: usize,
+    pub slice: usize,
+    pub pc: usize,
+    pub next_pc: usize,
+    pub word: u32,
+    pub registers: Vec<RegisterWrite>,
+    /// CSR writes ('reg' being the number of the CSR)
+    pub csrs: Vec<RegisterWrite>,
+    pub memory: Vec<MemoryWrite>,
+    pub reads: Vec<MemoryRead>,
+    pub state: MachineState,
+}
+
+impl StepRecord {
+    fn new(pc: usize, word: u32) -> Self {
+        StepRecord {
+            hart: 0,
+            slice: 0,
+            pc,
+            next_pc: pc,
+            word,
+            registers: Vec::new(),
+            csrs: Vec::new(),
+            memory: Vec::new(),
+            reads: Vec::new(),
+            state: MachineState::Ok,
+        }
+    }
+
+    /// Finds the first access of this record which overlaps the watched range
+    fn touches(&self, watchpoint: &Watchpoint) -> Option<WatchHit> {
+        let overlaps = |addr: usize, size: usize| {
+            addr < watchpoint.addr + watchpoint.len && watchpoint.addr < addr + size
+        };
+        if matches!(watchpoint.kind, WatchKind::Write | WatchKind::Access)
+            && let Some(w) = self.memory.iter().find(|w| overlaps(w.addr, w.size))
+        {
+            return Some(WatchHit {
+                addr: w.addr,
+                kind: WatchKind::Write,
+                watchpoint: *watchpoint,
+            });
+        }
+        if matches!(watchpoint.kind, WatchKind::Read | WatchKind::Access)
+            && let Some(r) = self.reads.iter().find(|r| overlaps(r.addr, r.size))
+        {
+            return Some(WatchHit {
+                addr: r.addr,
+                kind: WatchKind::Read,
+                watchpoint: *watchpoint,
+            });
+        }
+        None
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+/// A memory range which stops the machine whenever an instruction accesses it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub addr: usize,
+    pub len: usize,
+    pub kind: WatchKind,
+}
+
+/// The watchpoint which got triggered by the last instruction, along with the accessed address
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WatchHit {
+    pub addr: usize,
+    pub kind: WatchKind,
+    pub watchpoint: Watchpoint,
+}
+
+/// Syscalls which make the machine stop around 'ecall'
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyscallCatch {
+    None,
+    All,
+    Only(Vec<u32>),
+}
+
+impl SyscallCatch {
+    fn matches(&self, number: u32) -> bool {
+        match self {
+            SyscallCatch::None => false,
+            SyscallCatch::All => true,
+            SyscallCatch::Only(numbers) => numbers.contains(&number),
+        }
+    }
+}
+
+/// Caught syscall (its number, taken from a7) the machine stopped at
+///
+/// On 'Entry' the 'ecall' hasn't been executed yet (pc still points to it), which happens on
+/// the next step, that then stops on 'Return'
+///
+/// 'Forward' is used for syscalls performed outside of the machine, which stays stopped at the
+/// 'ecall' until `complete_syscall` is called with the syscall result
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SyscallStop {
+    Entry(u32),
+    Return(u32),
+    Forward(u32),
+}
+
 pub trait Machine {
+    type Snapshot;
+
     // Init
     fn from_bytes_size(byte_count: usize, machine_endian: DataEndianness) -> Self;
     fn from_words_size(word_count: usize, machine_endian: DataEndianness) -> Self;
@@ -28,10 +193,20 @@ pub trait Machine {
     fn read_registers(&self) -> Vec<u32>;
     fn write_registers(&mut self, gprs: Vec<u32>, pc: usize) -> ();
     fn read_pc(&self) -> u32;
+    fn read_csr(&self, csr: usize) -> u32;
+    fn write_csr(&mut self, csr: usize, value: u32);
+    fn read_fpr(&self, reg: usize) -> u64;
+    fn write_fpr(&mut self, reg: usize, value: u64);
+    fn float_extension(&self) -> FloatExtension;
+    fn set_float_extension(&mut self, ext: FloatExtension);
 
     // Memory
+    //   Memory holds 'bytes_count' bytes from 'memory_start' on (0 unless programs are loaded
+    //   somewhere else, i.e: at 0x80000000)
     fn bytes_count(&self) -> usize;
     fn words_count(&self) -> usize;
+    fn memory_start(&self) -> usize;
+    fn set_memory_start(&mut self, addr: usize);
 
     fn bytes(&self) -> Vec<u8>;
     fn words(&self) -> Vec<u32>;
@@ -60,36 +235,444 @@ pub trait Machine {
         alignment: usize,
     ) -> bool;
     fn predict_next_pc(&self) -> usize;
+
+    // History
+    fn set_recording(&mut self, enabled: bool);
+    fn step_back(&mut self) -> Option<StepRecord>;
+    fn history_len(&self) -> usize;
+
+    // Watchpoints
+    fn add_watchpoint(&mut self, watchpoint: Watchpoint);
+    fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool;
+    fn watch_hit(&self) -> Option<WatchHit>;
+
+    // Syscalls
+    fn set_syscall_catch(&mut self, catch: SyscallCatch);
+    fn syscall_stop(&self) -> Option<SyscallStop>;
+    fn set_forwarded_syscalls(&mut self, numbers: Vec<u32>);
+    fn complete_syscall(&mut self, result: u32);
+
+    // Host-target interface (HTIF)
+    //   Programs (e.g. riscv-tests) talk to the host by writing commands to 'tohost' (exit, or
+    //   print/read a character), whose replies are written to 'fromhost'
+    fn set_htif(&mut self, tohost: usize, fromhost: Option<usize>);
+    fn tohost(&self) -> Option<usize>;
+
+    // Console
+    //   What programs write to the console (through the 'write' syscall or HTIF) goes to stdout,
+    //   unless it's being captured, to be taken with 'take_console_output'
+    fn set_console_capture(&mut self, enabled: bool);
+    fn take_console_output(&mut self) -> Vec<u8>;
+
+    // Harts
+    //   Harts share the memory, and are scheduled round-robin: each one executes 'quantum'
+    //   instructions before the next one gets to run. Accessors of the CPU (registers, pc, CSRs,
+    //   ...) refer to the selected hart
+    fn set_hart_count(&mut self, count: usize);
+    fn hart_count(&self) -> usize;
+    fn set_quantum(&mut self, quantum: usize);
+    fn quantum(&self) -> usize;
+    fn select_hart(&mut self, hart: usize);
+    fn current_hart(&self) -> usize;
+    fn scheduled_hart(&self) -> usize;
+    fn set_scheduled_hart(&mut self, hart: usize);
+
+    // Introspection
+    //   With tracing enabled, the commit log of every retired instruction goes to stderr
+    fn set_tracing(&mut self, enabled: bool);
+    fn set_commit_log(&mut self, log: Option<CommitLog>);
+    fn take_commit_log(&mut self) -> Option<CommitLog>;
+    fn set_syscall_logging(&mut self, enabled: bool);
+    fn instruction_count(&self) -> u64;
+    fn reset(&mut self);
+    fn snapshot(&self) -> Self::Snapshot;
+    fn restore(&mut self, snapshot: &Self::Snapshot);
 }
 
 /* Possible implementation */
 
+/// Size of the stack of every hart but the first one, which are placed one after the other below
+/// the stack of the first hart
+pub const HART_STACK_SIZE: usize = 4 * 1024;
+
+use crate::emu::cpu::{CSR_MEPC, CSR_MHARTID, CSR_MISA, CSR_MSTATUS, MISA_D, MISA_F, MISA_RV32IM};
+use crate::emu::trace::{CommitLog, TraceFilter};
 use crate::emu::{cpu::CPU, cpu::SimpleCPU};
 use crate::emu::{memory::Memory, memory::SimpleMemory};
 use crate::lang::ext::{Immediate, InstructionFormat};
 use crate::lang::highassembly::Register;
 use crate::lang::lowassembly::DataEndianness;
-use crate::utils::swap_chunk_endianness;
+use std::io::{Read, Write};
 use syscalls::riscv32::Sysno;
 
+/// Copy of the whole state of a `SimpleMachine` (harts, scheduler, memory and instruction count)
+#[derive(Clone)]
+pub struct SimpleSnapshot {
+    harts: Vec<SimpleCPU>,
+    running: usize,
+    slice: usize,
+    mem: SimpleMemory,
+    icount: u64,
+}
+
 pub struct SimpleMachine {
     cpu: SimpleCPU,
     mem: SimpleMemory,
     endian: DataEndianness,
+    float: FloatExtension,
+
+    // Harts
+    //   'cpu' is the selected hart ('hart'), while the others are kept in 'harts' (whose entry
+    //   for the selected hart is stale). 'running' is the hart executing the next instruction,
+    //   which has already executed 'slice' instructions of its quantum
+    harts: Vec<SimpleCPU>,
+    hart: usize,
+    running: usize,
+    slice: usize,
+    quantum: usize,
+
+    // Record/replay
+    //   'step' collects the side effects of the instruction being executed. Once the instruction
+    //   retires, it gets pushed to 'history' (if recording is enabled). Stepping back moves records
+    //   from 'history' to 'future', which are then replayed by 'decode' before any new instruction
+    //   gets executed.
+    recording: bool,
+    step: StepRecord,
+    history: Vec<StepRecord>,
+    future: Vec<StepRecord>,
+
+    // Watchpoints
+    //   The accesses of every instruction (executed, replayed or undone) are checked against
+    //   'watchpoints', and the first match is kept in 'watch_hit' until the next instruction
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<WatchHit>,
+
+    // Syscalls
+    //   'syscall_stop' is set by the last instruction, if it was an 'ecall' being caught. After
+    //   stopping on entry, 'syscall_entry_pc' lets the 'ecall' at that pc run on the next step
+    syscall_catch: SyscallCatch,
+    syscall_stop: Option<SyscallStop>,
+    syscall_entry_pc: Option<usize>,
+    forwarded_syscalls: Vec<u32>,
+
+    // HTIF
+    //   A command written to 'tohost' is carried out before the next instruction runs, once its
+    //   upper half is written (32-bit programs write the lower half first)
+    tohost: Option<usize>,
+    fromhost: Option<usize>,
+    tohost_written: bool,
+
+    // Console
+    //   Output of the program, while it's being captured
+    console: Option<Vec<u8>>,
+
+    // Introspection
+    //   'icount' is the number of instructions retired so far (stepping back decrements it)
+    commit_log: Option<CommitLog>,
+    syscall_logging: bool,
+    icount: u64,
+}
+
+impl SimpleMachine {
+    fn with_memory(mem: SimpleMemory, endian: DataEndianness) -> Self {
+        SimpleMachine {
+            cpu: SimpleCPU::new(),
+            mem,
+            endian,
+            float: FloatExtension::None,
+            harts: vec![SimpleCPU::new()],
+            hart: 0,
+            running: 0,
+            slice: 0,
+            quantum: 1,
+            recording: false,
+            step: StepRecord::new(0, 0),
+            history: Vec::new(),
+            future: Vec::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            syscall_catch: SyscallCatch::None,
+            syscall_stop: None,
+            syscall_entry_pc: None,
+            forwarded_syscalls: Vec::new(),
+            tohost: None,
+            fromhost: None,
+            tohost_written: false,
+            console: None,
+            commit_log: None,
+            syscall_logging: false,
+            icount: 0,
+        }
+    }
+
+    /// Makes 'cpu' refer to the given hart, putting back the state of the previous one
+    fn switch_hart(&mut self, hart: usize) {
+        if hart == self.hart || hart >= self.harts.len() {
+            return;
+        }
+        std::mem::swap(&mut self.cpu, &mut self.harts[self.hart]);
+        std::mem::swap(&mut self.cpu, &mut self.harts[hart]);
+        self.hart = hart;
+    }
+
+    /// State of every hart, including the selected one
+    fn all_harts(&self) -> Vec<SimpleCPU> {
+        let mut harts = self.harts.clone();
+        harts[self.hart] = self.cpu.clone();
+        harts
+    }
+
+    fn set_harts(&mut self, harts: Vec<SimpleCPU>, running: usize, slice: usize) {
+        self.cpu = harts[running].clone();
+        self.harts = harts;
+        self.hart = running;
+        self.running = running;
+        self.slice = slice;
+    }
+
+    /// Finishes the instruction being executed, whose side effects were collected in 'step'
+    fn commit(&mut self, next_pc: usize, state: MachineState) {
+        self.set_pc(next_pc);
+        let mut record = std::mem::replace(&mut self.step, StepRecord::new(0, 0));
+        record.hart = self.running;
+        record.slice = self.slice;
+        record.next_pc = next_pc;
+        record.state = state;
+        self.check_watchpoints(&record);
+        self.tohost_written |= self.writes_tohost(&record);
+        self.retire(&record);
+        if self.recording {
+            self.history.push(record);
+        }
+    }
+
+    /// Accounts for a retired instruction, handing the machine over to the next hart once the
+    /// quantum of the current one is over
+    fn retire(&mut self, record: &StepRecord) {
+        if let Some(log) = &mut self.commit_log
+            && let Err(e) = log.log(record, self.icount)
+        {
+            eprintln!("WARNING: commit log disabled: {}", e);
+            self.commit_log = None;
+        }
+        self.icount += 1;
+        self.running = record.hart;
+        self.slice = record.slice + 1;
+        if self.slice >= self.quantum {
+            self.running = (record.hart + 1) % self.harts.len();
+            self.slice = 0;
+        }
+    }
+
+    /// Whether 'record' writes the upper half of 'tohost', which hands a command over to the host
+    fn writes_tohost(&self, record: &StepRecord) -> bool {
+        self.tohost.is_some_and(|tohost| {
+            record.memory.iter().any(|w| w.addr < tohost + 8 && tohost + 4 < w.addr + w.size)
+        })
+    }
+
+    /// Carries out the command found in 'tohost' (if any), clearing it afterwards
+    ///
+    /// The command is made of a device (bits 63-56), a command for that device (bits 55-48) and a
+    /// payload, as in Spike. Device 0 exits with the code 'payload >> 1' (when bit 0 is set), and
+    /// device 1 is the console, which prints (command 1) or reads (command 0) a character
+    ///
+    /// The writes of the host are recorded along with the store which handed the command over, so
+    /// stepping back over it undoes them, and replaying it doesn't run the command again
+    fn htif_command(&mut self) -> Option<MachineState> {
+        let tohost = self.tohost?;
+        let (low, high) = (self.mem.read_word(tohost), self.mem.read_word(tohost + 4));
+        if low == 0 && high == 0 {
+            return None;
+        }
+        self.host_store_word(tohost, 0);
+        self.host_store_word(tohost + 4, 0);
+        let (device, command) = (high >> 24, (high >> 16) & 0xff);
+        let payload = ((high as u64 & 0xffff) << 32) | low as u64;
+        let reply = match (device, command) {
+            (0, _) if payload & 1 == 1 => {
+                let state = MachineState::Exit((payload >> 1) as i32);
+                if self.recording
+                    && let Some(record) = self.history.last_mut()
+                {
+                    record.state = state;
+                }
+                return Some(state);
+            }
+            (1, 1) => {
+                self.console_write(&[payload as u8]);
+                0x100 | (payload & 0xff) as u32
+            }
+            (1, 0) => {
+                let mut byte = [0u8; 1];
+                match std::io::stdin().read(&mut byte) {
+                    Ok(1) => 0x100 | byte[0] as u32,
+                    _ => 0,
+                }
+            }
+            _ => {
+                eprintln!(
+                    "WARNING: unsupported HTIF command: device {}, command {}",
+                    device, command
+                );
+                return None;
+            }
+        };
+        if let Some(fromhost) = self.fromhost {
+            self.host_store_word(fromhost, reply);
+            self.host_store_word(fromhost + 4, high & 0xffff_0000);
+        }
+        None
+    }
+
+    /// Stores a word on behalf of the host, as part of the last retired instruction
+    fn host_store_word(&mut self, addr: usize, v: u32) {
+        let old = self.mem.read_word(addr);
+        self.mem.write_word(addr, v);
+        if self.recording
+            && let Some(record) = self.history.last_mut()
+        {
+            record.memory.push(MemoryWrite {
+                addr,
+                size: 4,
+                old,
+                new: v,
+            });
+        }
+    }
+
+    fn console_write(&mut self, bytes: &[u8]) {
+        match &mut self.console {
+            Some(output) => output.extend_from_slice(bytes),
+            None => {
+                let mut stdout = std::io::stdout();
+                let _ = stdout.write_all(bytes).and_then(|_| stdout.flush());
+            }
+        }
+    }
+
+    /// Bytes from 'addr' on, in the order of their addresses (memory in big endian keeps the bytes
+    /// of every word the other way around)
+    /// Index in memory of the byte the guest sees at 'addr': big endian memory keeps the bytes of
+    /// every word reversed
+    fn byte_index(&self, addr: usize) -> usize {
+        match self.mem.endianness() {
+            DataEndianness::Be => addr ^ 3,
+            _ => addr,
+        }
+    }
+
+    fn guest_bytes(&self, addr: usize, count: usize) -> Vec<u8> {
+        let start = self.mem.base_address();
+        let range = start..start + self.mem.bytes_count();
+        (addr..addr.saturating_add(count))
+            .map(|addr| self.byte_index(addr))
+            .filter(|idx| range.contains(idx))
+            .map(|idx| self.mem.read_byte(idx))
+            .collect()
+    }
+
+    fn check_watchpoints(&mut self, record: &StepRecord) {
+        self.watch_hit = self.watchpoints.iter().find_map(|w| record.touches(w));
+    }
+
+    fn load_word(&mut self, addr: usize) -> u32 {
+        self.step.reads.push(MemoryRead { addr, size: 4 });
+        self.mem.read_word(addr)
+    }
+
+    fn load_byte(&mut self, addr: usize) -> u8 {
+        self.step.reads.push(MemoryRead { addr, size: 1 });
+        self.mem.read_byte(self.byte_index(addr))
+    }
+
+    fn store_register(&mut self, reg: usize, v: u32) {
+        if reg == 0 {
+            return;
+        }
+        let old = self.cpu.read(reg);
+        self.cpu.write(reg, v);
+        self.step.registers.push(RegisterWrite { reg, old, new: v });
+    }
+
+    fn store_csr(&mut self, csr: usize, v: u32) {
+        let old = self.cpu.read_csr(csr);
+        self.cpu.write_csr(csr, v);
+        self.step.csrs.push(RegisterWrite { reg: csr, old, new: v });
+    }
+
+    fn store_word(&mut self, addr: usize, v: u32) {
+        let old = self.mem.read_word(addr);
+        self.mem.write_word(addr, v);
+        self.step.memory.push(MemoryWrite {
+            addr,
+            size: 4,
+            old,
+            new: v,
+        });
+    }
+
+    fn store_byte(&mut self, addr: usize, v: u8) {
+        let idx = self.byte_index(addr);
+        let start = self.mem.base_address();
+        let old = if (start..start + self.mem.bytes_count()).contains(&idx) {
+            self.mem.read_byte(idx)
+        } else {
+            0
+        };
+        self.mem.write_byte(idx, v);
+        self.step.memory.push(MemoryWrite {
+            addr,
+            size: 1,
+            old: old.into(),
+            new: v.into(),
+        });
+    }
+
+    fn apply_memory_write(&mut self, addr: usize, size: usize, v: u32) {
+        match size {
+            1 => self.mem.write_byte(self.byte_index(addr), v as u8),
+            _ => self.mem.write_word(addr, v),
+        }
+    }
+
+    fn replay(&mut self, record: StepRecord) -> MachineState {
+        self.switch_hart(record.hart);
+        self.check_watchpoints(&record);
+        self.tohost_written |= self.writes_tohost(&record);
+        self.retire(&record);
+        for w in &record.registers {
+            self.cpu.write(w.reg, w.new);
+        }
+        for w in &record.csrs {
+            self.cpu.write_csr(w.reg, w.new);
+        }
+        for w in &record.memory {
+            self.apply_memory_write(w.addr, w.size, w.new);
+        }
+        self.cpu.write_pc(record.next_pc);
+        let state = record.state;
+        self.history.push(record);
+        state
+    }
+
+    /// Records written by the debugger (or anything else outside of 'decode') invalidate the
+    /// replay log, since the recorded side effects might no longer match the machine state
+    fn discard_future(&mut self) {
+        self.future.clear();
+    }
 }
 
 impl Machine for SimpleMachine {
+    type Snapshot = SimpleSnapshot;
+
     fn from_bytes_size(byte_count: usize, machine_endian: DataEndianness) -> Self {
         let mut mem = SimpleMemory::new(DataEndianness::Be);
         mem.reserve_bytes(byte_count);
         for i in 0..byte_count {
             mem.write_byte(i, 0);
         }
-        SimpleMachine {
-            cpu: SimpleCPU::new(),
-            mem,
-            endian: machine_endian,
-        }
+        SimpleMachine::with_memory(mem, machine_endian)
     }
 
     fn from_words_size(word_count: usize, machine_endian: DataEndianness) -> Self {
@@ -98,33 +681,21 @@ impl Machine for SimpleMachine {
         for i in 0..word_count {
             mem.write_word(i, 0);
         }
-        SimpleMachine {
-            cpu: SimpleCPU::new(),
-            mem,
-            endian: machine_endian,
-        }
+        SimpleMachine::with_memory(mem, machine_endian)
     }
 
     fn from_bytes(data: &Vec<u8>, machine_endian: DataEndianness) -> Self {
         let mut mem = SimpleMemory::new(DataEndianness::Be);
         mem.reserve_bytes(data.len());
         mem.write_bytes(0, data, machine_endian);
-        SimpleMachine {
-            cpu: SimpleCPU::new(),
-            mem,
-            endian: machine_endian,
-        }
+        SimpleMachine::with_memory(mem, machine_endian)
     }
 
     fn from_words(data: &Vec<u32>, machine_endian: DataEndianness) -> Self {
         let mut mem = SimpleMemory::new(DataEndianness::Be);
         mem.reserve_words(data.len());
         mem.write_words(0, data);
-        SimpleMachine {
-            cpu: SimpleCPU::new(),
-            mem,
-            endian: machine_endian,
-        }
+        SimpleMachine::with_memory(mem, machine_endian)
     }
 
     fn load(&mut self, start_addr: usize, instrs: &Vec<u32>) -> () {
@@ -146,12 +717,32 @@ impl Machine for SimpleMachine {
     }
 
     fn decode(&mut self) -> Result<MachineState, MachineError> {
+        self.syscall_stop = None;
+        if let Some(record) = self.future.pop() {
+            return Ok(self.replay(record));
+        }
+        if std::mem::take(&mut self.tohost_written)
+            && let Some(state) = self.htif_command()
+        {
+            return Ok(state);
+        }
+        self.switch_hart(self.running);
         let word = self.fetch();
         if let Some(ifmt) = InstructionFormat::decode(word) {
             let new_pc = predict_next_pc(self, &ifmt);
+            self.step = StepRecord::new(self.cpu.read_pc(), word);
             let state = handle(self, ifmt);
-            if state.is_ok() {
-                self.set_pc(new_pc);
+            if let Some(SyscallStop::Entry(_)) = self.syscall_stop {
+                // the 'ecall' hasn't been executed yet
+                self.step = StepRecord::new(0, 0);
+                return state;
+            }
+            if let Some(SyscallStop::Forward(_)) = self.syscall_stop {
+                // the 'ecall' gets finished by 'complete_syscall'
+                return state;
+            }
+            if let Ok(s) = state {
+                self.commit(new_pc, s);
             }
             state
         } else {
@@ -169,6 +760,7 @@ impl Machine for SimpleMachine {
     }
 
     fn write_registers(&mut self, gprs: Vec<u32>, pc: usize) -> () {
+        self.discard_future();
         self.cpu.write_all(gprs, pc);
     }
 
@@ -176,6 +768,41 @@ impl Machine for SimpleMachine {
         self.cpu.read_pc() as u32
     }
 
+    fn read_csr(&self, csr: usize) -> u32 {
+        self.cpu.read_csr(csr)
+    }
+
+    fn write_csr(&mut self, csr: usize, value: u32) {
+        self.discard_future();
+        self.cpu.write_csr(csr, value);
+    }
+
+    fn read_fpr(&self, reg: usize) -> u64 {
+        self.cpu.read_fpr(reg)
+    }
+
+    fn write_fpr(&mut self, reg: usize, value: u64) {
+        self.discard_future();
+        self.cpu.write_fpr(reg, value);
+    }
+
+    fn float_extension(&self) -> FloatExtension {
+        self.float
+    }
+
+    fn set_float_extension(&mut self, ext: FloatExtension) {
+        let misa = match ext {
+            FloatExtension::None => MISA_RV32IM,
+            FloatExtension::F => MISA_RV32IM | MISA_F,
+            FloatExtension::D => MISA_RV32IM | MISA_F | MISA_D,
+        };
+        for cpu in self.harts.iter_mut() {
+            cpu.write_csr(CSR_MISA, misa);
+        }
+        self.cpu.write_csr(CSR_MISA, misa);
+        self.float = ext;
+    }
+
     fn bytes_count(&self) -> usize {
         self.mem.bytes_count()
     }
@@ -184,6 +811,14 @@ impl Machine for SimpleMachine {
         self.mem.words_count()
     }
 
+    fn memory_start(&self) -> usize {
+        self.mem.base_address()
+    }
+
+    fn set_memory_start(&mut self, addr: usize) {
+        self.mem.set_base_address(addr);
+    }
+
     fn bytes(&self) -> Vec<u8> {
         self.mem.bytes()
     }
@@ -193,11 +828,12 @@ impl Machine for SimpleMachine {
     }
 
     fn read_memory_byte(&self, addr: usize) -> u8 {
-        self.mem.read_byte(addr)
+        self.mem.read_byte(self.byte_index(addr))
     }
 
     fn write_memory_byte(&mut self, addr: usize, value: u8) -> () {
-        self.mem.write_byte(addr, value)
+        self.discard_future();
+        self.mem.write_byte(self.byte_index(addr), value)
     }
 
     fn read_memory_bytes(&self, addr: usize, count: usize, alignment: usize) -> Vec<u8> {
@@ -205,6 +841,7 @@ impl Machine for SimpleMachine {
     }
 
     fn write_memory_bytes(&mut self, addr: usize, values: &[u8]) -> () {
+        self.discard_future();
         self.mem.write_bytes(addr, values, self.endian)
     }
 
@@ -213,6 +850,7 @@ impl Machine for SimpleMachine {
     }
 
     fn write_memory_word(&mut self, addr: usize, value: u32) -> () {
+        self.discard_future();
         self.mem.write_word(addr, value);
     }
 
@@ -221,6 +859,7 @@ impl Machine for SimpleMachine {
     }
 
     fn write_memory_words(&mut self, addr: usize, values: &[u32]) -> () {
+        self.discard_future();
         self.mem.write_words(addr, values);
     }
 
@@ -268,8 +907,225 @@ impl Machine for SimpleMachine {
             self.cpu.read_pc()
         }
     }
+
+    fn set_recording(&mut self, enabled: bool) {
+        self.recording = enabled;
+        if !enabled {
+            self.history.clear();
+            self.future.clear();
+        }
+    }
+
+    fn step_back(&mut self) -> Option<StepRecord> {
+        let record = self.history.pop()?;
+        self.switch_hart(record.hart);
+        self.running = record.hart;
+        self.slice = record.slice;
+        self.check_watchpoints(&record);
+        self.icount -= 1;
+        for w in record.memory.iter().rev() {
+            self.apply_memory_write(w.addr, w.size, w.old);
+        }
+        for w in record.csrs.iter().rev() {
+            self.cpu.write_csr(w.reg, w.old);
+        }
+        for w in record.registers.iter().rev() {
+            self.cpu.write(w.reg, w.old);
+        }
+        self.cpu.write_pc(record.pc);
+        self.future.push(record.clone());
+        Some(record)
+    }
+
+    fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let Some(idx) = self.watchpoints.iter().position(|w| *w == watchpoint) else {
+            return false;
+        };
+        self.watchpoints.remove(idx);
+        true
+    }
+
+    fn watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit
+    }
+
+    fn set_syscall_catch(&mut self, catch: SyscallCatch) {
+        self.syscall_catch = catch;
+    }
+
+    fn syscall_stop(&self) -> Option<SyscallStop> {
+        self.syscall_stop
+    }
+
+    fn set_forwarded_syscalls(&mut self, numbers: Vec<u32>) {
+        self.forwarded_syscalls = numbers;
+    }
+
+    fn set_htif(&mut self, tohost: usize, fromhost: Option<usize>) {
+        self.tohost = Some(tohost);
+        self.fromhost = fromhost;
+    }
+
+    fn tohost(&self) -> Option<usize> {
+        self.tohost
+    }
+
+    fn set_console_capture(&mut self, enabled: bool) {
+        self.console = enabled.then(|| self.console.take().unwrap_or_default());
+    }
+
+    fn take_console_output(&mut self) -> Vec<u8> {
+        self.console.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn complete_syscall(&mut self, result: u32) {
+        let Some(SyscallStop::Forward(number)) = self.syscall_stop else {
+            return;
+        };
+        self.switch_hart(self.running);
+        self.store_register(Register::A0.id().into(), result);
+        let next_pc = self.cpu.read_pc() + 4;
+        self.commit(next_pc, MachineState::Ok);
+        self.syscall_stop = self
+            .syscall_catch
+            .matches(number)
+            .then_some(SyscallStop::Return(number));
+    }
+
+    /// Harts are added as copies of the first one (only 'mhartid' differs), so they all start
+    /// from the same pc, with the same stack pointer
+    fn set_hart_count(&mut self, count: usize) {
+        let mut harts = self.all_harts();
+        harts.truncate(count.max(1));
+        while harts.len() < count {
+            // every hart gets a stack of its own, below the one of the previous hart, and finds
+            // its id in 'a0' (as in Spike)
+            let id = harts.len();
+            let mut cpu = harts[0].clone();
+            let sp = harts[0].read(Register::SP.id().into()) as usize;
+            cpu.write(Register::SP.id().into(), sp.wrapping_sub(id * HART_STACK_SIZE) as u32);
+            cpu.write(Register::A0.id().into(), id as u32);
+            cpu.write_csr(CSR_MHARTID, id as u32);
+            harts.push(cpu);
+        }
+        self.set_harts(harts, 0, 0);
+        self.history.clear();
+        self.future.clear();
+    }
+
+    fn hart_count(&self) -> usize {
+        self.harts.len()
+    }
+
+    fn set_quantum(&mut self, quantum: usize) {
+        self.quantum = quantum.max(1);
+        self.slice = 0;
+    }
+
+    fn quantum(&self) -> usize {
+        self.quantum
+    }
+
+    fn select_hart(&mut self, hart: usize) {
+        self.switch_hart(hart);
+    }
+
+    fn current_hart(&self) -> usize {
+        self.hart
+    }
+
+    fn scheduled_hart(&self) -> usize {
+        self.running
+    }
+
+    /// Makes the given hart execute the next instruction, starting a new quantum
+    fn set_scheduled_hart(&mut self, hart: usize) {
+        if hart < self.harts.len() {
+            self.running = hart;
+            self.slice = 0;
+        }
+    }
+
+    fn set_tracing(&mut self, enabled: bool) {
+        self.commit_log =
+            enabled.then(|| CommitLog::new(Box::new(std::io::stderr()), TraceFilter::default()));
+    }
+
+    fn set_commit_log(&mut self, log: Option<CommitLog>) {
+        self.commit_log = log;
+    }
+
+    fn take_commit_log(&mut self) -> Option<CommitLog> {
+        self.commit_log.take()
+    }
+
+    fn set_syscall_logging(&mut self, enabled: bool) {
+        self.syscall_logging = enabled;
+    }
+
+    fn instruction_count(&self) -> u64 {
+        self.icount
+    }
+
+    /// Brings the harts back to their initial state (registers, CSRs and pc are cleared), while
+    /// the memory is kept untouched
+    fn reset(&mut self) {
+        let harts = (0..self.harts.len())
+            .map(|id| {
+                let mut cpu = SimpleCPU::new();
+                cpu.write_csr(CSR_MHARTID, id as u32);
+                cpu
+            })
+            .collect();
+        self.set_harts(harts, 0, 0);
+        self.set_float_extension(self.float);
+        self.history.clear();
+        self.future.clear();
+        self.watch_hit = None;
+        self.syscall_stop = None;
+        self.syscall_entry_pc = None;
+        self.tohost_written = false;
+        self.icount = 0;
+    }
+
+    fn snapshot(&self) -> SimpleSnapshot {
+        SimpleSnapshot {
+            harts: self.all_harts(),
+            running: self.running,
+            slice: self.slice,
+            mem: self.mem.clone(),
+            icount: self.icount,
+        }
+    }
+
+    /// The record/replay log is dropped, since it describes a different execution
+    fn restore(&mut self, snapshot: &SimpleSnapshot) {
+        self.set_harts(snapshot.harts.clone(), snapshot.running, snapshot.slice);
+        self.mem = snapshot.mem.clone();
+        self.icount = snapshot.icount;
+        self.history.clear();
+        self.future.clear();
+        self.watch_hit = None;
+        self.syscall_stop = None;
+        self.syscall_entry_pc = None;
+    }
 }
 
+/// Immediate of 'mret' (which is encoded as an 'ecall')
+const MRET: u32 = 0x302;
+
+/// Interrupts enabled (MIE), and whether they were before the last trap (MPIE)
+const MSTATUS_MIE: u32 = 1 << 3;
+const MSTATUS_MPIE: u32 = 1 << 7;
+
 fn handle(m: &mut SimpleMachine, ifmt: InstructionFormat) -> Result<MachineState, MachineError> {
     match ifmt {
         InstructionFormat::R {
@@ -324,7 +1180,7 @@ fn handle(m: &mut SimpleMachine, ifmt: InstructionFormat) -> Result<MachineState
                     return Err(MachineError::UnhandledInstruction(errmsg));
                 }
             };
-            m.cpu.write(rd as usize, res);
+            m.store_register(rd as usize, res);
         }
         InstructionFormat::I {
             imm,
@@ -343,23 +1199,54 @@ fn handle(m: &mut SimpleMachine, ifmt: InstructionFormat) -> Result<MachineState
                 (0b000, 0b1100111) => Some(m.cpu.read_pc() as u32 + 4),              // JALR
                 (0b010, 0b0000011) => {
                     let addr = rs1_val.saturating_add_signed(imm as i32) as usize;
-                    Some(m.read_memory_word(addr))
+                    Some(m.load_word(addr))
                 } // LW
                 (0b000, 0b0000011) => {
                     let addr = rs1_val.saturating_add_signed(imm as i32) as usize;
-                    Some(m.read_memory_byte(addr) as u32)
+                    Some(m.load_byte(addr) as u32)
                 } // LB
+                (0b000, 0b1110011) if imm & 0xfff == MRET => {
+                    // back to 'mepc' (already predicted as the next pc), enabling interrupts
+                    // again as they were before the trap (MIE = MPIE, and MPIE = 1)
+                    let mstatus = m.cpu.read_csr(CSR_MSTATUS);
+                    let mpie = (mstatus & MSTATUS_MPIE) != 0;
+                    let mie = if mpie { MSTATUS_MIE } else { 0 };
+                    m.store_csr(CSR_MSTATUS, (mstatus & !MSTATUS_MIE) | mie | MSTATUS_MPIE);
+                    None
+                } // MRET
                 (0b000, 0b1110011) => {
                     let a7 = m.cpu.read(Register::A7.id().into()) as usize;
+                    if m.syscall_catch.matches(a7 as u32) {
+                        let pc = m.cpu.read_pc();
+                        if m.syscall_entry_pc.take() != Some(pc) {
+                            m.syscall_entry_pc = Some(pc);
+                            m.syscall_stop = Some(SyscallStop::Entry(a7 as u32));
+                            return Ok(MachineState::Ok);
+                        }
+                        m.syscall_stop = Some(SyscallStop::Return(a7 as u32));
+                    }
+                    if m.forwarded_syscalls.contains(&(a7 as u32)) {
+                        m.syscall_stop = Some(SyscallStop::Forward(a7 as u32));
+                        return Ok(MachineState::Ok);
+                    }
                     if let Some(sys) = Sysno::new(a7) {
+                        if m.syscall_logging {
+                            let args: Vec<u32> = [Register::A0, Register::A1, Register::A2]
+                                .iter()
+                                .map(|reg| m.cpu.read(reg.id().into()))
+                                .collect();
+                            eprintln!(
+                                "syscall {}(0x{:x}, 0x{:x}, 0x{:x})",
+                                sys, args[0], args[1], args[2]
+                            );
+                        }
                         match sys {
                             Sysno::write => {
                                 let addr = m.cpu.read(Register::A1.id().into()) as usize;
                                 let msglen = m.cpu.read(Register::A2.id().into()) as usize;
-                                let msgbytes = m.read_memory_bytes(addr, msglen, 1);
-                                let msgbytes = swap_chunk_endianness(&msgbytes, 4);
-                                let msg = String::from_utf8(msgbytes).unwrap();
-                                println!("{}", msg);
+                                m.step.reads.push(MemoryRead { addr, size: msglen });
+                                let msgbytes = m.guest_bytes(addr, msglen);
+                                m.console_write(&msgbytes);
                             }
                             Sysno::exit => {
                                 let a0 = m.cpu.read(Register::A0.id().into()) as usize;
@@ -370,6 +1257,28 @@ fn handle(m: &mut SimpleMachine, ifmt: InstructionFormat) -> Result<MachineState
                     }
                     None
                 } // ECALL
+                (0b001 | 0b010 | 0b011 | 0b101 | 0b110 | 0b111, 0b1110011) => {
+                    // CSRRW, CSRRS, CSRRC and their immediate forms (CSRRWI, CSRRSI, CSRRCI),
+                    // which take the 5 bits of 'rs1' as the value
+                    let csr = (imm & 0xfff) as usize;
+                    let old = m.cpu.read_csr(csr);
+                    let value = if funct3 & 0b100 != 0 { rs1 } else { rs1_val };
+                    // setting or clearing no bits (i.e: 'csrr') leaves the CSR untouched
+                    let new = match funct3 & 0b011 {
+                        0b01 => Some(value),
+                        _ if rs1 == 0 => None,
+                        0b10 => Some(old | value),
+                        _ => Some(old & !value),
+                    };
+                    if let Some(new) = new {
+                        if csr >> 10 == 0b11 {
+                            let errmsg = format!("Write to read-only CSR: csr = 0x{:x}", csr);
+                            return Err(MachineError::UnhandledInstruction(errmsg));
+                        }
+                        m.store_csr(csr, new);
+                    }
+                    Some(old)
+                } // CSRRW, CSRRS, CSRRC
                 (0b100, 0b0000011) => None,                                          // LBU
                 _ => {
                     let errmsg = format!("Unhandled I: (f3, op) = ({}, {})", funct3, opcode);
@@ -377,7 +1286,7 @@ fn handle(m: &mut SimpleMachine, ifmt: InstructionFormat) -> Result<MachineState
                 }
             };
             if let Some(res) = opt {
-                m.cpu.write(rd as usize, res as u32);
+                m.store_register(rd as usize, res);
             }
         }
         InstructionFormat::S {
@@ -393,8 +1302,8 @@ fn handle(m: &mut SimpleMachine, ifmt: InstructionFormat) -> Result<MachineState
             let addr = (rs1 + imm) as usize;
             let val = rs2;
             match (funct3, opcode) {
-                (0b010, 0b0100011) => m.mem.write_word(addr, val), //SW
-                (0b000, 0b0100011) => m.mem.write_byte(addr, (val & 0b1111_1111) as u8), //SB
+                (0b010, 0b0100011) => m.store_word(addr, val), //SW
+                (0b000, 0b0100011) => m.store_byte(addr, (val & 0b1111_1111) as u8), //SB
                 _ => {
                     let errmsg = format!("Unhandled S: (f3, op) = ({}, {})", funct3, opcode);
                     return Err(MachineError::UnhandledInstruction(errmsg));
@@ -404,12 +1313,12 @@ fn handle(m: &mut SimpleMachine, ifmt: InstructionFormat) -> Result<MachineState
         InstructionFormat::U { imm, rd, opcode } => {
             let upper20bits = imm.decode();
             match opcode {
-                0b0110111 => m.cpu.write(rd as usize, upper20bits), //LUI
+                0b0110111 => m.store_register(rd as usize, upper20bits), //LUI
                 0b0010111 => {
                     //AUIPC
                     let pc = m.cpu.read_pc();
                     let addr = pc.saturating_add_signed(upper20bits as isize);
-                    m.cpu.write(rd as usize, addr as u32);
+                    m.store_register(rd as usize, addr as u32);
                 }
                 _ => {
                     let errmsg = format!("Unhandled U: op = {}", opcode);
@@ -422,7 +1331,7 @@ fn handle(m: &mut SimpleMachine, ifmt: InstructionFormat) -> Result<MachineState
                 0b1101111 => {
                     let pc = m.cpu.read_pc();
                     let ret_addr = pc + 4;
-                    m.cpu.write(rd as usize, ret_addr as u32);
+                    m.store_register(rd as usize, ret_addr as u32);
                 } //JAL
                 _ => {
                     let errmsg = format!("Unhandled J: op = {}", opcode);
@@ -439,6 +1348,14 @@ fn handle(m: &mut SimpleMachine, ifmt: InstructionFormat) -> Result<MachineState
 fn predict_next_pc(m: &SimpleMachine, ifmt: &InstructionFormat) -> usize {
     let pc = m.read_pc();
     match ifmt {
+        // MRET
+        InstructionFormat::I {
+            imm,
+            rs1: _,
+            funct3: 0b000,
+            rd: _,
+            opcode: 0b1110011,
+        } if imm.decode() & 0xfff == MRET => m.cpu.read_csr(CSR_MEPC) as usize,
         // JALR
         InstructionFormat::I {
             imm,
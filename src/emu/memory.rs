@@ -6,6 +6,10 @@ pub trait Memory {
     fn bytes_count(&self) -> usize;
     fn words_count(&self) -> usize;
 
+    /// Address of the first byte, which the memory holds the addresses following from
+    fn base_address(&self) -> usize;
+    fn set_base_address(&mut self, base: usize);
+
     fn reserve_bytes(&mut self, sz: usize);
     fn reserve_words(&mut self, sz: usize);
     fn clear(&mut self);
@@ -46,9 +50,11 @@ use crate::utils::swap_chunk_endianness;
 use object::ReadRef;
 use std::fs;
 
+#[derive(Clone)]
 pub struct SimpleMemory {
     data: Vec<u8>,
     endianness: DataEndianness,
+    base: usize,
 }
 
 impl SimpleMemory {
@@ -56,8 +62,15 @@ impl SimpleMemory {
         SimpleMemory {
             data: Vec::new(),
             endianness,
+            base: 0,
         }
     }
+
+    /// Index of an address in 'data' (addresses below the base wrap around, so they end up out
+    /// of bounds as the ones past the end)
+    fn index(&self, addr: usize) -> usize {
+        addr.wrapping_sub(self.base)
+    }
 }
 
 // TODO: create test to all these methods
@@ -77,6 +90,14 @@ impl Memory for SimpleMemory {
         self.data.len() >> 2
     }
 
+    fn base_address(&self) -> usize {
+        self.base
+    }
+
+    fn set_base_address(&mut self, base: usize) {
+        self.base = base;
+    }
+
     fn reserve_bytes(&mut self, sz: usize) {
         self.data.resize(sz, 0);
     }
@@ -97,7 +118,7 @@ impl Memory for SimpleMemory {
         self.data
             .chunks_exact(4)
             .enumerate()
-            .map(|(idx, _)| self.read_word(idx * 4))
+            .map(|(idx, _)| self.read_word(self.base + idx * 4))
             .collect()
     }
 
@@ -108,25 +129,27 @@ impl Memory for SimpleMemory {
     fn read_file(&mut self, filename: &str) -> io::Result<()> {
         let data = fs::read(filename)?;
         let assumed_endianness = DataEndianness::Le;
-        self.write_bytes(0, &data, assumed_endianness);
+        self.write_bytes(self.base, &data, assumed_endianness);
         Ok(())
     }
 
     fn read_byte(&self, idx: usize) -> u8 {
-        *self.data.get(idx).unwrap()
+        *self.data.get(self.index(idx)).unwrap()
     }
 
     fn write_byte(&mut self, idx: usize, v: u8) -> () {
         // println!("Writing value {} at {} address", v, idx);
+        let idx = self.index(idx);
         if idx < self.data.len() {
             self.data[idx] = v;
         } else {
-            println!("Address out of boundaries");
+            eprintln!("Address out of boundaries");
         }
     }
 
     // TODO: maybe return an option to indicate that read_word failed
     fn read_word(&self, idx: usize) -> u32 {
+        let idx = self.index(idx);
         if idx < self.data.len() {
             let idx: u64 = idx
                 .try_into()
@@ -149,6 +172,7 @@ impl Memory for SimpleMemory {
     fn write_word(&mut self, idx: usize, val: u32) -> () {
         // println!("{:x} written in mem at {:?}", val, idx);
         let values = DataEndianness::break_word_into_bytes(val, self.endianness);
+        let idx = self.index(idx);
         let bytes_buffer = self.data.get_mut(idx..idx + 4).unwrap();
         bytes_buffer[0] = values[0];
         bytes_buffer[1] = values[1];
@@ -164,6 +188,7 @@ impl Memory for SimpleMemory {
         alignment: usize,
     ) -> Vec<u8> {
         // println!("Reading {} bytes starting at address {}", count, start_addr);
+        let start_addr = self.index(start_addr);
         let data_len = self.data.len();
         if start_addr < data_len {
             let max_count = data_len - start_addr;
@@ -198,6 +223,7 @@ impl Memory for SimpleMemory {
     }
 
     fn read_words(&self, start_addr: usize, count: usize, res_endian: DataEndianness) -> Vec<u32> {
+        let start_addr = self.index(start_addr);
         let end_addr = start_addr + (count * 4);
         let bytes = &self.data[start_addr..end_addr];
         bytes
@@ -0,0 +1,343 @@
+//! Interactive debugger for the terminal, which drives the machine directly (for when GDB isn't
+//! available)
+//!
+//! It shares the stop reasons with the GDB stub (see `debugger::stop_reason`), while locations
+//! are resolved through the symbol table of the program and shown along with the source lines
+//! found in its debugging information
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::emu::arch::GPR_NAMES;
+use crate::emu::debugger::{StopReason, stop_reason};
+use crate::emu::disasm::{disassemble, is_call};
+use crate::emu::machine::{Machine, MachineState, WatchKind, Watchpoint};
+use crate::obj::dwarfreader::SourceLine;
+
+const HELP: &str = "\
+step [n]            (s)  execute the next instruction (n times)
+next                (n)  like 'step', but runs called functions until they return
+continue            (c)  run until a breakpoint or watchpoint is hit, or the program exits
+break <loc>         (b)  stop before the instruction at <loc> runs
+delete <loc>        (d)  remove the breakpoint at <loc>
+watch <loc> [len]        stop when the memory at <loc> gets written
+info breakpoints         list the breakpoints
+regs                (r)  show the registers
+x <loc> [n]              show n memory words starting at <loc>
+disas [n]                disassemble n instructions before and after pc
+list                (l)  show the source lines around pc
+quit                (q)  leave the debugger
+<loc> is either a label ('loop'), a label and an offset ('main+8') or an address ('0x10074')";
+
+const PROMPT: &str = "(rustv) ";
+
+enum Resume {
+    Step(usize),
+    Next,
+    Continue,
+}
+
+pub struct Repl<T: Machine> {
+    machine: T,
+    // Debugging information
+    //   'symbols' and 'lines' are sorted by address, while 'sources' holds the contents of the
+    //   files the source lines refer to
+    symbols: Vec<(String, usize)>,
+    lines: Vec<SourceLine>,
+    sources: HashMap<String, Vec<String>>,
+    breakpoints: Vec<usize>,
+    exit_code: Option<i32>,
+    // When set, the source, disassembly and registers are shown every time the machine stops
+    context: bool,
+}
+
+impl<T: Machine> Repl<T> {
+    pub fn new(machine: T, mut symbols: Vec<(String, usize)>, mut lines: Vec<SourceLine>) -> Self {
+        symbols.sort_by_key(|(_, addr)| *addr);
+        lines.sort_by_key(|line| line.address);
+        Repl {
+            machine,
+            symbols,
+            lines,
+            sources: HashMap::new(),
+            breakpoints: Vec::new(),
+            exit_code: None,
+            context: false,
+        }
+    }
+
+    pub fn add_source(&mut self, file: &str, code: &str) {
+        let lines = code.lines().map(String::from).collect();
+        self.sources.insert(file.to_string(), lines);
+    }
+
+    pub fn set_context(&mut self, enabled: bool) {
+        self.context = enabled;
+    }
+
+    pub fn machine(&self) -> &T {
+        &self.machine
+    }
+
+    /// Reads commands from 'input' until it ends or 'quit' is given
+    ///
+    /// An empty line repeats the previous command, as in GDB
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> io::Result<()> {
+        self.show_stop(None, out)?;
+        write!(out, "{}", PROMPT)?;
+        out.flush()?;
+        let mut last = String::new();
+        for line in input.lines() {
+            let mut line = line?;
+            if line.trim().is_empty() {
+                line = last.clone();
+            }
+            if !self.execute(&line, out)? {
+                return Ok(());
+            }
+            last = line;
+            write!(out, "{}", PROMPT)?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Runs a single command, returning whether the debugger should keep going
+    pub fn execute<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<bool> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let count = |arg: Option<&&str>, default: usize| {
+            arg.and_then(|n| n.parse().ok()).unwrap_or(default)
+        };
+        match args.as_slice() {
+            [] => {}
+            ["s" | "step" | "si" | "stepi", rest @ ..] => {
+                self.resume(Resume::Step(count(rest.first(), 1)), out)?
+            }
+            ["n" | "next" | "ni" | "nexti"] => self.resume(Resume::Next, out)?,
+            ["c" | "continue"] => self.resume(Resume::Continue, out)?,
+            ["b" | "break", loc] => match self.location(loc) {
+                Some(addr) => {
+                    if !self.breakpoints.contains(&addr) {
+                        self.breakpoints.push(addr);
+                    }
+                    writeln!(out, "breakpoint at {}", self.describe(addr))?;
+                }
+                None => writeln!(out, "unknown location '{}'", loc)?,
+            },
+            ["d" | "delete", loc] => match self.location(loc) {
+                Some(addr) if self.breakpoints.contains(&addr) => {
+                    self.breakpoints.retain(|b| *b != addr);
+                    writeln!(out, "deleted breakpoint at {}", self.describe(addr))?;
+                }
+                _ => writeln!(out, "no breakpoint at '{}'", loc)?,
+            },
+            ["watch", loc, rest @ ..] => match self.location(loc) {
+                Some(addr) => {
+                    let len = count(rest.first(), 4);
+                    self.machine.add_watchpoint(Watchpoint {
+                        addr,
+                        len,
+                        kind: WatchKind::Write,
+                    });
+                    writeln!(out, "watching {} bytes at {}", len, self.describe(addr))?;
+                }
+                None => writeln!(out, "unknown location '{}'", loc)?,
+            },
+            ["info", "b" | "breakpoints"] => {
+                if self.breakpoints.is_empty() {
+                    writeln!(out, "no breakpoints")?;
+                }
+                for addr in &self.breakpoints {
+                    writeln!(out, "  {}", self.describe(*addr))?;
+                }
+            }
+            ["r" | "regs"] | ["info", "r" | "registers"] => self.show_registers(out)?,
+            ["x", loc, rest @ ..] => match self.location(loc) {
+                Some(addr) => self.show_memory(addr, count(rest.first(), 4), out)?,
+                None => writeln!(out, "unknown location '{}'", loc)?,
+            },
+            ["disas", rest @ ..] => self.show_disassembly(count(rest.first(), 4), out)?,
+            ["l" | "list"] => self.show_source(2, out)?,
+            ["q" | "quit"] => return Ok(false),
+            _ => writeln!(out, "{}", HELP)?,
+        }
+        Ok(true)
+    }
+
+    fn resume<W: Write>(&mut self, resume: Resume, out: &mut W) -> io::Result<()> {
+        if let Some(code) = self.exit_code {
+            return writeln!(out, "the program already exited with code {}", code);
+        }
+        let pc = self.machine.read_pc() as usize;
+        let (mut steps, until) = match resume {
+            Resume::Step(n) => (n, None),
+            // calls are run until they return to the next instruction
+            Resume::Next if is_call(self.machine.fetch()) => (usize::MAX, Some(pc + 4)),
+            Resume::Next => (1, None),
+            Resume::Continue => (usize::MAX, None),
+        };
+        loop {
+            match self.machine.decode() {
+                Ok(MachineState::Exit(code)) => {
+                    self.exit_code = Some(code);
+                    return writeln!(out, "the program exited with code {}", code);
+                }
+                Ok(MachineState::Ok) => {}
+                Err(e) => {
+                    writeln!(out, "the machine stopped: {:?}", e)?;
+                    return self.show_stop(None, out);
+                }
+            }
+            let breakpoints = &self.breakpoints;
+            let reason = stop_reason(&mut self.machine, |pc| breakpoints.contains(&pc));
+            if reason.is_some() {
+                return self.show_stop(reason, out);
+            }
+            steps = steps.saturating_sub(1);
+            let pc = self.machine.read_pc() as usize;
+            if steps == 0 || until == Some(pc) {
+                return self.show_stop(None, out);
+            }
+        }
+    }
+
+    // Locations
+
+    /// Resolves a label (optionally followed by an offset) or an address
+    fn location(&self, loc: &str) -> Option<usize> {
+        let (base, offset) = match loc.split_once('+') {
+            Some((base, offset)) => (base, parse_number(offset)?),
+            None => (loc, 0),
+        };
+        let base = match self.symbols.iter().find(|(name, _)| name == base) {
+            Some((_, addr)) => *addr,
+            None => parse_number(base)?,
+        };
+        Some(base + offset)
+    }
+
+    /// Shows an address relative to the closest label before it (i.e: '0x00000008 <loop+4>')
+    fn describe(&self, addr: usize) -> String {
+        let symbol = self
+            .symbols
+            .iter()
+            .rev()
+            .find(|(name, start)| *start <= addr && !name.is_empty());
+        match symbol {
+            Some((name, start)) if *start == addr => format!("0x{:08x} <{}>", addr, name),
+            Some((name, start)) => format!("0x{:08x} <{}+{}>", addr, name, addr - start),
+            None => format!("0x{:08x}", addr),
+        }
+    }
+
+    fn source_line(&self, addr: usize) -> Option<&SourceLine> {
+        self.lines.iter().rev().find(|line| line.address <= addr)
+    }
+
+    // Display
+
+    fn show_stop<W: Write>(&mut self, reason: Option<StopReason>, out: &mut W) -> io::Result<()> {
+        match reason {
+            Some(StopReason::Breakpoint { pc, .. }) => {
+                writeln!(out, "breakpoint hit at {}", self.describe(pc))?
+            }
+            Some(StopReason::Watch(hit)) => writeln!(
+                out,
+                "watchpoint hit: memory at {} was written",
+                self.describe(hit.addr)
+            )?,
+            Some(StopReason::Syscall(stop)) => writeln!(out, "syscall: {:?}", stop)?,
+            None => {}
+        }
+        if self.context {
+            self.show_source(2, out)?;
+            self.show_disassembly(2, out)?;
+            return self.show_registers(out);
+        }
+        let pc = self.machine.read_pc() as usize;
+        if self.machine.hart_count() > 1 {
+            write!(out, "[hart {}] ", self.machine.current_hart())?;
+        }
+        let word = self.machine.fetch();
+        writeln!(out, "{}: {}", self.describe(pc), disassemble(word, pc))?;
+        if let Some(line) = self.source_line(pc) {
+            let text = self
+                .sources
+                .get(&line.file)
+                .and_then(|lines| lines.get(line.line - 1))
+                .map_or("", |text| text.trim());
+            writeln!(out, "{}:{}    {}", line.file, line.line, text)?;
+        }
+        Ok(())
+    }
+
+    fn show_registers<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let registers = self.machine.read_registers();
+        for (row, chunk) in registers[..32].chunks(4).enumerate() {
+            let cells: Vec<String> = chunk
+                .iter()
+                .enumerate()
+                .map(|(idx, value)| format!("{:<4} 0x{:08x}", GPR_NAMES[row * 4 + idx], value))
+                .collect();
+            writeln!(out, "{}", cells.join("   "))?;
+        }
+        writeln!(out, "{:<4} 0x{:08x}", "pc", registers[32])
+    }
+
+    fn show_memory<W: Write>(&self, addr: usize, count: usize, out: &mut W) -> io::Result<()> {
+        let memory_end = self.machine.memory_start() + self.machine.bytes_count();
+        let end = (addr + count * 4).min(memory_end);
+        for word_addr in (addr..end).step_by(4).filter(|a| a + 4 <= end) {
+            let word = self.machine.read_memory_word(word_addr);
+            writeln!(out, "{}: 0x{:08x}", self.describe(word_addr), word)?;
+        }
+        Ok(())
+    }
+
+    fn show_disassembly<W: Write>(&self, around: usize, out: &mut W) -> io::Result<()> {
+        let pc = self.machine.read_pc() as usize;
+        let start = pc.saturating_sub(around * 4);
+        let memory_end = self.machine.memory_start() + self.machine.bytes_count();
+        let end = (pc + (around + 1) * 4).min(memory_end);
+        for addr in (start..end).step_by(4) {
+            let marker = match (addr == pc, self.breakpoints.contains(&addr)) {
+                (true, _) => "=>",
+                (false, true) => " *",
+                (false, false) => "  ",
+            };
+            let word = self.machine.read_memory_word(addr);
+            writeln!(
+                out,
+                "{} {}: {}",
+                marker,
+                self.describe(addr),
+                disassemble(word, addr)
+            )?;
+        }
+        Ok(())
+    }
+
+    fn show_source<W: Write>(&self, around: usize, out: &mut W) -> io::Result<()> {
+        let pc = self.machine.read_pc() as usize;
+        let Some(line) = self.source_line(pc) else {
+            return writeln!(out, "no source information");
+        };
+        let Some(text) = self.sources.get(&line.file) else {
+            return writeln!(out, "{}:{} (source not available)", line.file, line.line);
+        };
+        let first = line.line.saturating_sub(around).max(1);
+        let last = (line.line + around).min(text.len());
+        for number in first..=last {
+            let marker = if number == line.line { "=>" } else { "  " };
+            writeln!(out, "{} {:>4}  {}", marker, number, text[number - 1])?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_number(n: &str) -> Option<usize> {
+    match n.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => n.parse().ok(),
+    }
+}
@@ -0,0 +1,213 @@
+//! Commit log of the retired instructions, in the format used by Spike (`-l --log-commits`), so
+//! that traces can be compared against the ones of reference simulators
+//!
+//! Every instruction takes two lines: its disassembly, and the registers and memory it wrote:
+//! ```text
+//! core   0: 0x00010074 (0x00500513) addi    a0, zero, 5
+//! core   0: 3 0x00010074 (0x00500513) x10 0x00000005
+//! ```
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::emu::arch::MACHINE_CSRS;
+use crate::emu::disasm::disassemble;
+use crate::emu::machine::StepRecord;
+
+/// Privilege mode reported for every instruction (the machine only runs in M-mode)
+const PRIVILEGE_MACHINE: u8 = 3;
+
+/// Instructions which get logged: the ones whose pc and index (number of instructions retired
+/// before them) fall in the given ranges
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceFilter {
+    pub pc: Option<Range<usize>>,
+    pub icount: Option<Range<u64>>,
+}
+
+impl TraceFilter {
+    fn matches(&self, pc: usize, index: u64) -> bool {
+        self.pc.as_ref().is_none_or(|range| range.contains(&pc))
+            && self
+                .icount
+                .as_ref()
+                .is_none_or(|range| range.contains(&index))
+    }
+}
+
+pub struct CommitLog {
+    out: Box<dyn Write>,
+    filter: TraceFilter,
+}
+
+impl CommitLog {
+    pub fn new(out: Box<dyn Write>, filter: TraceFilter) -> Self {
+        CommitLog { out, filter }
+    }
+
+    pub fn to_file(path: &str, filter: TraceFilter) -> io::Result<Self> {
+        let file = std::fs::File::create(path)?;
+        Ok(CommitLog::new(Box::new(io::BufWriter::new(file)), filter))
+    }
+
+    /// Logs the instruction retired at position 'index'
+    pub fn log(&mut self, record: &StepRecord, index: u64) -> io::Result<()> {
+        if !self.filter.matches(record.pc, index) {
+            return Ok(());
+        }
+        writeln!(self.out, "{}", disassembly_line(record))?;
+        writeln!(self.out, "{}", commit_line(record))
+    }
+}
+
+impl Drop for CommitLog {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// In-memory destination of a commit log, which can be read while the log is still in use
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).to_string()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// i.e: 'core   0: 0x00010074 (0x00500513) addi    a0, zero, 5'
+pub fn disassembly_line(record: &StepRecord) -> String {
+    // the mnemonic is padded as in Spike
+    let text = disassemble(record.word, record.pc);
+    let text = match text.split_once(' ') {
+        Some((mnemonic, operands)) => format!("{:<7} {}", mnemonic, operands),
+        None => text,
+    };
+    format!(
+        "core {:>3}: 0x{:08x} (0x{:08x}) {}",
+        record.hart, record.pc, record.word, text
+    )
+}
+
+/// i.e: 'core   0: 3 0x00010078 (0x00a12023) mem 0x0000fff0 0x00000005'
+pub fn commit_line(record: &StepRecord) -> String {
+    let mut line = format!(
+        "core {:>3}: {} 0x{:08x} (0x{:08x})",
+        record.hart, PRIVILEGE_MACHINE, record.pc, record.word
+    );
+    for write in &record.registers {
+        line += &format!(" x{:<2} 0x{:08x}", write.reg, write.new);
+    }
+    // i.e: 'c768_mstatus 0x00000080'
+    for write in &record.csrs {
+        let name = MACHINE_CSRS.iter().find(|(_, csr)| *csr == write.reg);
+        let name = name.map_or(String::new(), |(name, _)| format!("_{}", name));
+        line += &format!(" c{}{} 0x{:08x}", write.reg, name, write.new);
+    }
+    // syscalls record the whole buffers they read, which Spike doesn't log
+    for read in record.reads.iter().filter(|read| read.size <= 4) {
+        line += &format!(" mem 0x{:08x}", read.addr);
+    }
+    for write in &record.memory {
+        line += &format!(
+            " mem 0x{:08x} 0x{:0width$x}",
+            write.addr,
+            write.new,
+            width = write.size * 2
+        );
+    }
+    line
+}
+
+/// Parses ranges given as 'start:end' (either end can be left out, i.e: '0x10074:')
+pub fn parse_range(range: &str) -> Option<Range<u64>> {
+    let parse = |n: &str, default: u64| match n {
+        "" => Some(default),
+        _ => match n.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => n.parse().ok(),
+        },
+    };
+    let (start, end) = range.split_once(':')?;
+    Some(parse(start, 0)?..parse(end, u64::MAX)?)
+}
+
+// Comparison
+
+/// First commit line at which a trace differs from the expected (golden) one
+///
+/// 'line' counts commit lines from 1, and 'context' holds the ones right before it (which both
+/// traces agree on). A missing line means that trace ended earlier
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub line: usize,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+    pub context: Vec<String>,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "traces diverge at instruction {}:", self.line)?;
+        for line in &self.context {
+            writeln!(f, "  {}", line)?;
+        }
+        let end = String::from("<end of trace>");
+        writeln!(f, "- {}", self.expected.as_ref().unwrap_or(&end))?;
+        write!(f, "+ {}", self.actual.as_ref().unwrap_or(&end))
+    }
+}
+
+/// Whether a line of a trace holds the side effects of an instruction (as opposed to its
+/// disassembly), i.e: 'core   0: 3 0x00010074 (0x00500513) x10 0x00000005'
+fn is_commit_line(line: &str) -> bool {
+    let Some((core, rest)) = line.split_once(':') else {
+        return false;
+    };
+    let mut fields = rest.split_whitespace();
+    core.starts_with("core")
+        && fields.next().is_some_and(|p| p.parse::<u8>().is_ok())
+        && fields.next().is_some_and(|pc| pc.starts_with("0x"))
+}
+
+/// Commit lines of a trace (disassembly lines are left out, so traces made with or without
+/// them can be compared)
+pub fn commit_lines(trace: &str) -> Vec<&str> {
+    trace
+        .lines()
+        .map(|line| line.trim_end())
+        .filter(|line| is_commit_line(line))
+        .collect()
+}
+
+/// Compares the commit lines of two traces, giving back the first one which differs along
+/// with (up to) 'context' lines before it
+pub fn first_divergence(expected: &str, actual: &str, context: usize) -> Option<Divergence> {
+    let expected = commit_lines(expected);
+    let actual = commit_lines(actual);
+    let idx =
+        (0..expected.len().max(actual.len())).find(|idx| expected.get(*idx) != actual.get(*idx))?;
+    Some(Divergence {
+        line: idx + 1,
+        expected: expected.get(idx).map(|line| line.to_string()),
+        actual: actual.get(idx).map(|line| line.to_string()),
+        context: expected[idx.saturating_sub(context)..idx]
+            .iter()
+            .map(|line| line.to_string())
+            .collect(),
+    })
+}
@@ -0,0 +1,221 @@
+//! Transports through which GDB can talk to the stub: TCP, Unix domain sockets and the standard
+//! input/output of the emulator
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+use gdbstub::conn::{Connection, ConnectionExt};
+
+/// Where the stub waits for GDB to connect
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    /// Port on localhost (`target remote :<port>`)
+    Tcp(u16),
+    /// Path of a Unix domain socket (`target remote unix::<path>`)
+    Unix(PathBuf),
+    /// Standard input/output, for GDB to start the emulator itself (`target remote | <command>`)
+    Stdio,
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Tcp(port) => write!(f, "localhost:{}", port),
+            Transport::Unix(path) => write!(f, "{}", path.display()),
+            Transport::Stdio => write!(f, "stdio"),
+        }
+    }
+}
+
+// Listener
+
+pub enum GdbListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    /// Whether the (single) connection through stdio was already handed out
+    Stdio(bool),
+}
+
+impl GdbListener {
+    pub fn bind(transport: &Transport) -> io::Result<Self> {
+        match transport {
+            Transport::Tcp(port) => Ok(GdbListener::Tcp(TcpListener::bind(("localhost", *port))?)),
+            Transport::Unix(path) => {
+                // sockets left behind by previous runs would make binding fail
+                if let Ok(metadata) = std::fs::symlink_metadata(path)
+                    && metadata.file_type().is_socket()
+                {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(GdbListener::Unix(UnixListener::bind(path)?))
+            }
+            Transport::Stdio => Ok(GdbListener::Stdio(false)),
+        }
+    }
+
+    /// Waits for GDB to connect
+    ///
+    /// stdio accepts a single connection, since it can't be reopened once GDB closes it
+    pub fn accept(&mut self) -> io::Result<GdbConnection> {
+        match self {
+            GdbListener::Tcp(listener) => Ok(GdbConnection::Tcp(listener.accept()?.0)),
+            GdbListener::Unix(listener) => Ok(GdbConnection::Unix(listener.accept()?.0)),
+            GdbListener::Stdio(true) => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "stdio was closed by the previous connection",
+            )),
+            GdbListener::Stdio(taken) => {
+                *taken = true;
+                Ok(GdbConnection::Stdio(StdioConnection::new()))
+            }
+        }
+    }
+}
+
+// Connection
+
+pub enum GdbConnection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Stdio(StdioConnection),
+}
+
+impl GdbConnection {
+    /// Reads the next byte sent by GDB without blocking (None when there's nothing to be read)
+    pub fn try_read(&mut self) -> io::Result<Option<u8>> {
+        let mut buf = [0u8; 1];
+        let read = match self {
+            GdbConnection::Tcp(stream) => {
+                stream.set_nonblocking(true)?;
+                let read = Read::read(stream, &mut buf);
+                stream.set_nonblocking(false)?;
+                read
+            }
+            GdbConnection::Unix(stream) => {
+                stream.set_nonblocking(true)?;
+                let read = Read::read(stream, &mut buf);
+                stream.set_nonblocking(false)?;
+                read
+            }
+            GdbConnection::Stdio(stdio) => return stdio.try_read(),
+        };
+        match read {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed",
+            )),
+            Ok(_) => Ok(Some(buf[0])),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Connection for GdbConnection {
+    type Error = io::Error;
+
+    fn write(&mut self, byte: u8) -> io::Result<()> {
+        self.write_all(&[byte])
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            GdbConnection::Tcp(stream) => Write::write_all(stream, buf),
+            GdbConnection::Unix(stream) => Write::write_all(stream, buf),
+            GdbConnection::Stdio(_) => io::stdout().lock().write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            GdbConnection::Tcp(stream) => Write::flush(stream),
+            GdbConnection::Unix(stream) => Write::flush(stream),
+            GdbConnection::Stdio(_) => io::stdout().flush(),
+        }
+    }
+
+    fn on_session_start(&mut self) -> io::Result<()> {
+        match self {
+            GdbConnection::Tcp(stream) => stream.set_nodelay(true),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ConnectionExt for GdbConnection {
+    fn read(&mut self) -> io::Result<u8> {
+        match self {
+            GdbConnection::Tcp(stream) => ConnectionExt::read(stream),
+            GdbConnection::Unix(stream) => ConnectionExt::read(stream),
+            GdbConnection::Stdio(stdio) => stdio.read(),
+        }
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        match self {
+            GdbConnection::Tcp(stream) => ConnectionExt::peek(stream),
+            GdbConnection::Unix(stream) => ConnectionExt::peek(stream),
+            GdbConnection::Stdio(stdio) => stdio.peek(),
+        }
+    }
+}
+
+/// Connection through stdin/stdout
+///
+/// stdin can't be read without blocking, so it's read by a separate thread which hands the
+/// bytes over through a channel
+pub struct StdioConnection {
+    input: Receiver<u8>,
+    peeked: Option<u8>,
+}
+
+impl StdioConnection {
+    fn new() -> Self {
+        let (sender, input) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let mut stdin = io::stdin().lock();
+            while let Ok(n @ 1..) = stdin.read(&mut buf) {
+                if buf[..n].iter().any(|byte| sender.send(*byte).is_err()) {
+                    return;
+                }
+            }
+        });
+        StdioConnection {
+            input,
+            peeked: None,
+        }
+    }
+
+    fn closed() -> io::Error {
+        io::Error::new(io::ErrorKind::UnexpectedEof, "stdin was closed")
+    }
+
+    fn read(&mut self) -> io::Result<u8> {
+        match self.peeked.take() {
+            Some(byte) => Ok(byte),
+            None => self.input.recv().map_err(|_| Self::closed()),
+        }
+    }
+
+    fn try_read(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.peek()?;
+        self.peeked = None;
+        Ok(byte)
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        if self.peeked.is_none() {
+            self.peeked = match self.input.try_recv() {
+                Ok(byte) => Some(byte),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return Err(Self::closed()),
+            };
+        }
+        Ok(self.peeked)
+    }
+}
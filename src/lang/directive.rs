@@ -7,13 +7,27 @@ use super::highassembly::Datatype;
 
 /**
 A directive was thought to be a sequence of tokens which can be turned into a sequence of
-raw bytes (4-byte aligned)
+raw bytes
 
-The length of the resulting vector is expected to be a multiple of 4 (to ensure 4bytes alignment)
+Except for alignment directives, whose bytes depend on where they end up: they're translated
+into their padding by the assembler
 */
 pub trait Directive: std::fmt::Debug {
-    fn translate(&self, args: &Vec<ArgValue>) -> Vec<u8> ;
+    /// Fails (with a message) when the arguments don't match the directive
+    fn translate(&self, args: &[ArgValue]) -> Result<Vec<u8>, String> ;
     fn datatype(&self) -> Datatype ;
+
+    /// Boundary (in bytes) alignment directives (i.e: '.align') align their location to, which
+    /// their section must be aligned to as well
+    fn boundary(&self, _args: &[ArgValue]) -> Result<Option<usize>, String> {
+        Ok(None)
+    }
+
+    /// Bytes aligning an alignment directive, placed 'offset' bytes into its section (of code,
+    /// when 'code' is set), to its boundary
+    fn padding(&self, _args: &[ArgValue], _offset: usize, _code: bool) -> Vec<u8> {
+        Vec::new()
+    }
 }
 
 
@@ -24,72 +38,219 @@ pub trait Directive: std::fmt::Debug {
 
 #[derive(Debug)]
 pub enum DirectiveInstruction {
-    Word,
-    Half,
     Byte,
-    Skip,
+    /// '.half', '.short' and '.2byte'
+    Half,
+    /// '.word', '.long' and '.4byte'
+    Word,
+    /// '.dword', '.8byte' and '.quad'
+    Dword,
+    Float,
+    Double,
     Ascii,
+    /// '.asciz' and '.string': strings ended by a 0
+    Asciz,
+    Zero,
+    /// '.skip' and '.space': a size and, optionally, the value of the bytes
+    Skip,
+    /// '.fill repeat, size, value'
+    Fill,
+    /// '.align' and '.p2align': to 2 to the power of the argument
+    Align,
+    /// '.balign': to the number of bytes given
+    Balign,
 }
 
+/// 'nop' ('addi zero, zero, 0'), which pads code
+const NOP: u32 = 0x00000013;
+
+/// Alignments are kept within 64KiB
+const MAX_ALIGNMENT_LOG2: i64 = 16;
+
+/// Space reserved by a single directive ('.zero', '.skip' and '.fill') is kept within 16MiB
+const MAX_RESERVED: i64 = 1 << 24;
+
 // WARNING: when translating a directive into its sequence of bytes, the resulting endianness
 // should be little endian, as to standardize how this data gets handled later on. If this doesn't
 // happen, then things might not work
-// TODO: handle more than 1 byte/word/ascii?
 impl Directive for DirectiveInstruction {
-    fn translate(&self, args: &Vec<ArgValue>) -> Vec<u8>  {
+    fn translate(&self, args: &[ArgValue]) -> Result<Vec<u8>, String>  {
         match self {
-            DirectiveInstruction::Byte => {
-                args.iter()
-                    .map(|arg| {
-                        match arg {
-                            ArgValue::Number(n) => *n as u8,
-                            _ => panic!("Byte directive got something other than a number"),
-                        }
-                    })
-                    .collect()
+            DirectiveInstruction::Byte   => integers(args, 1, ".byte"),
+            DirectiveInstruction::Half   => integers(args, 2, ".half"),
+            DirectiveInstruction::Word   => integers(args, 4, ".word"),
+            DirectiveInstruction::Dword  => integers(args, 8, ".dword"),
+            DirectiveInstruction::Float  => {
+                floats(args, ".float", |x| (x as f32).to_le_bytes().to_vec())
             },
-            DirectiveInstruction::Word => {
-                args.iter()
+            DirectiveInstruction::Double => floats(args, ".double", |x| x.to_le_bytes().to_vec()),
+            DirectiveInstruction::Ascii | DirectiveInstruction::Asciz => {
+                let name = format!("{:?}", self).to_lowercase();
+                let terminator: &[u8] = match self {
+                    DirectiveInstruction::Asciz => &[0],
+                    _ => &[],
+                };
+                if args.is_empty() {
+                    return Err(format!("'.{}' expects strings", name));
+                }
+                let strings: Result<Vec<Vec<u8>>, String> = args.iter()
                     .map(|arg| {
                         match arg {
-                            ArgValue::Number(n) => n.to_le_bytes().to_vec(),
-                            _ => panic!("WORD directive got something other than a number"),
+                            ArgValue::Literal(s) => Ok([s.as_slice(), terminator].concat()),
+                            _ => Err(format!("'.{}' expects strings", name)),
                         }
                     })
-                    .flatten()
-                    .collect()
+                    .collect();
+                Ok(strings?.into_iter().flatten().collect())
             },
-            DirectiveInstruction::Ascii => {
-                match &args[0] {
-                    ArgValue::Literal(s) => s.bytes().collect(),
-                    _ => panic!("ASCII directive got something other than a literal"),
+            DirectiveInstruction::Zero => {
+                match args[..] {
+                    [ArgValue::Number(n)] if n >= 0 => Ok(vec![0; reserved(n, ".zero")?]),
+                    _ => Err(String::from("'.zero' expects a non-negative size")),
                 }
             },
             DirectiveInstruction::Skip => {
-                match &args[0] {
-                    ArgValue::Number(n) => {
-                        let capacity: usize = (*n).try_into().unwrap();
-                        let mut v = Vec::new();
-                        v.reserve(capacity + capacity % 4);
-                        for _ in 0..*n {
-                            v.push(0);
-                        }
-                        v
+                match args[..] {
+                    [ArgValue::Number(n)] if n >= 0 => Ok(vec![0; reserved(n, ".skip")?]),
+                    [ArgValue::Number(n), ArgValue::Number(value)] if n >= 0 => {
+                        Ok(vec![value as u8; reserved(n, ".skip")?])
+                    },
+                    _ => Err(String::from("'.skip' expects a non-negative size")),
+                }
+            },
+            DirectiveInstruction::Fill => {
+                let (repeat, size, value) = match args[..] {
+                    [ArgValue::Number(repeat)] => (repeat, 1, 0),
+                    [ArgValue::Number(repeat), ArgValue::Number(size)] => (repeat, size, 0),
+                    [ArgValue::Number(repeat), ArgValue::Number(size), ArgValue::Number(value)] => {
+                        (repeat, size, value)
                     },
-                    _ => panic!("SKIP directive got something other than a number"),
+                    _ => (-1, 0, 0),
+                };
+                if repeat < 0 || !(0..=8).contains(&size) {
+                    return Err(String::from(
+                        "'.fill' expects a repeat count, a size (up to 8 bytes) and a value"
+                    ));
                 }
+                reserved(repeat.saturating_mul(size), ".fill")?;
+                let bytes = &value.to_le_bytes()[..size as usize];
+                Ok(bytes.repeat(repeat as usize))
+            },
+            // the padding depends on where they are (see 'padding')
+            DirectiveInstruction::Align | DirectiveInstruction::Balign => {
+                self.boundary(args).map(|_| Vec::new())
             },
-            _ => panic!("Unsupported directive")
         }
     }
 
     fn datatype(&self) -> Datatype {
         match self {
-            DirectiveInstruction::Word  => Datatype::Word,
-            DirectiveInstruction::Half  => Datatype::Half,
-            DirectiveInstruction::Byte  => Datatype::Byte,
-            DirectiveInstruction::Skip  => Datatype::Byte,
-            DirectiveInstruction::Ascii => Datatype::Ascii,
+            DirectiveInstruction::Word   => Datatype::Word,
+            DirectiveInstruction::Half   => Datatype::Half,
+            DirectiveInstruction::Byte   => Datatype::Byte,
+            DirectiveInstruction::Dword  => Datatype::Dword,
+            DirectiveInstruction::Float  => Datatype::Word,
+            DirectiveInstruction::Double => Datatype::Dword,
+            DirectiveInstruction::Ascii  => Datatype::Ascii,
+            DirectiveInstruction::Asciz  => Datatype::Ascii,
+            DirectiveInstruction::Zero
+            | DirectiveInstruction::Skip
+            | DirectiveInstruction::Fill
+            | DirectiveInstruction::Align
+            | DirectiveInstruction::Balign => Datatype::Byte,
         }
     }
+
+    fn boundary(&self, args: &[ArgValue]) -> Result<Option<usize>, String> {
+        let boundary = match (self, args.first()) {
+            (DirectiveInstruction::Align, Some(ArgValue::Number(n)))
+                if (0..=MAX_ALIGNMENT_LOG2).contains(n) => 1usize << n,
+            (DirectiveInstruction::Balign, Some(ArgValue::Number(n)))
+                if *n > 0 && *n <= 1 << MAX_ALIGNMENT_LOG2 && (*n as u32).is_power_of_two() => {
+                *n as usize
+            },
+            (DirectiveInstruction::Align, _) => {
+                let max = MAX_ALIGNMENT_LOG2;
+                return Err(format!("'.align' expects the log2 of the alignment (up to {})", max));
+            },
+            (DirectiveInstruction::Balign, _) => {
+                let max = 1 << MAX_ALIGNMENT_LOG2;
+                return Err(format!("'.balign' expects a power of 2 (up to {})", max));
+            },
+            _ => return Ok(None),
+        };
+        // the fill value and the maximum number of bytes to skip
+        if args.len() > 3 || args[1..].iter().any(|arg| !matches!(arg, ArgValue::Number(_))) {
+            let name = format!("{:?}", self).to_lowercase();
+            return Err(format!(
+                "'.{}' expects an alignment, a fill value and a maximum to skip",
+                name
+            ));
+        }
+        Ok(Some(boundary))
+    }
+
+    fn padding(&self, args: &[ArgValue], offset: usize, code: bool) -> Vec<u8> {
+        let Ok(Some(boundary)) = self.boundary(args) else {
+            return Vec::new();
+        };
+        let len = (boundary - offset % boundary) % boundary;
+        // nothing gets skipped when it would take more than the maximum
+        if let Some(ArgValue::Number(max)) = args.get(2) && len > *max as usize {
+            return Vec::new();
+        }
+        match args.get(1) {
+            Some(ArgValue::Number(value)) => vec![*value as u8; len],
+            _ if code => NOP.to_le_bytes().into_iter().cycle().take(len).collect(),
+            _ => vec![0; len],
+        }
+    }
+}
+
+/// Values of 'size' bytes each (truncated)
+fn integers(args: &[ArgValue], size: usize, name: &str) -> Result<Vec<u8>, String> {
+    let values: Result<Vec<Vec<u8>>, String> = args.iter()
+        .map(|arg| {
+            match arg {
+                ArgValue::Number(n) if fits(*n, size) => Ok(n.to_le_bytes()[..size].to_vec()),
+                ArgValue::Number(n) => {
+                    Err(format!("value {} doesn't fit in {} bits ('{}')", n, size * 8, name))
+                },
+                _ => Err(format!("'{}' expects numbers", name)),
+            }
+        })
+        .collect();
+    Ok(values?.into_iter().flatten().collect())
+}
+
+/// Bytes reserved by a directive, unless there are too many
+fn reserved(size: i64, name: &str) -> Result<usize, String> {
+    if size > MAX_RESERVED {
+        return Err(format!("'{}' can't reserve more than {} bytes", name, MAX_RESERVED));
+    }
+    Ok(size as usize)
+}
+
+/// Signed and unsigned values are accepted (i.e: both -1 and 0xff fit in a byte)
+fn fits(n: i64, size: usize) -> bool {
+    let bits = size as u32 * 8;
+    bits >= 64 || (-(1i64 << (bits - 1))..(1i64 << bits)).contains(&n)
+}
+
+/// Floating point values, encoded by 'encode' (integers are accepted too)
+fn floats(
+    args: &[ArgValue],
+    name: &str,
+    encode: impl Fn(f64) -> Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    let values: Result<Vec<Vec<u8>>, String> = args.iter()
+        .map(|arg| {
+            match arg {
+                ArgValue::Float(x) => Ok(encode(*x)),
+                ArgValue::Number(n) => Ok(encode(*n as f64)),
+                _ => Err(format!("'{}' expects numbers", name)),
+            }
+        })
+        .collect();
+    Ok(values?.into_iter().flatten().collect())
 }
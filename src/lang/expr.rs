@@ -0,0 +1,431 @@
+//! Constant expressions of operands and directives, as in GNU as:
+//! `+ - * / % << >> & | ^ ~`, comparisons (`== != < <= > >=`), `&& || !`, parentheses, char
+//! literals, symbols and '.' (the location counter)
+//!
+//! Expressions get evaluated as soon as possible: constant ones by the parser, and the ones which
+//! need the address of symbols (i.e: 'end - start') by the assembler
+
+use std::collections::BTreeMap;
+
+use crate::lang::highassembly::ArgValue;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Operator {
+    Plus,
+    Minus,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+    Not,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
+    LogicalNot,
+    Lpar,
+    Rpar,
+}
+
+impl Operator {
+    /// Precedence as a binary operator (None for the unary only ones), from GNU as
+    fn precedence(&self) -> Option<u8> {
+        match self {
+            Operator::Mul | Operator::Div | Operator::Rem | Operator::Shl | Operator::Shr => {
+                Some(4)
+            }
+            Operator::And | Operator::Or | Operator::Xor => Some(3),
+            Operator::Plus
+            | Operator::Minus
+            | Operator::Eq
+            | Operator::Ne
+            | Operator::Lt
+            | Operator::Le
+            | Operator::Gt
+            | Operator::Ge => Some(2),
+            Operator::LogicalAnd | Operator::LogicalOr => Some(1),
+            Operator::Not | Operator::LogicalNot | Operator::Lpar | Operator::Rpar => None,
+        }
+    }
+
+    fn symbol(&self) -> &str {
+        match self {
+            Operator::Plus => "+",
+            Operator::Minus => "-",
+            Operator::Mul => "*",
+            Operator::Div => "/",
+            Operator::Rem => "%",
+            Operator::Shl => "<<",
+            Operator::Shr => ">>",
+            Operator::And => "&",
+            Operator::Or => "|",
+            Operator::Xor => "^",
+            Operator::Not => "~",
+            Operator::Eq => "==",
+            Operator::Ne => "!=",
+            Operator::Lt => "<",
+            Operator::Le => "<=",
+            Operator::Gt => ">",
+            Operator::Ge => ">=",
+            Operator::LogicalAnd => "&&",
+            Operator::LogicalOr => "||",
+            Operator::LogicalNot => "!",
+            Operator::Lpar => "(",
+            Operator::Rpar => ")",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String),
+    /// Location counter ('.'): the address of the statement it's used in
+    Dot,
+    Unary(Operator, Box<Expr>),
+    Binary(Operator, Box<Expr>, Box<Expr>),
+}
+
+/// Result of an expression: a constant plus a combination of names (symbols or sections) which
+/// couldn't be cancelled out, i.e: 'msg + 4' is `{ constant: 4, terms: {msg: 1} }`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Value {
+    pub constant: i64,
+    pub terms: BTreeMap<String, i64>,
+}
+
+impl Value {
+    pub fn constant(constant: i64) -> Self {
+        Value {
+            constant,
+            ..Default::default()
+        }
+    }
+
+    /// 'name + constant'
+    pub fn relative(name: &str, constant: i64) -> Self {
+        Value {
+            constant,
+            terms: BTreeMap::from([(name.to_string(), 1)]),
+        }
+    }
+
+    pub fn is_constant(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// Name (and offset) the value is relative to, when it's of the form 'name + constant'
+    pub fn relative_to(&self) -> Option<(&str, i64)> {
+        match self.terms.iter().collect::<Vec<_>>()[..] {
+            [(name, 1)] => Some((name, self.constant)),
+            _ => None,
+        }
+    }
+
+    fn scale(mut self, factor: i64) -> Self {
+        self.constant = self.constant.wrapping_mul(factor);
+        self.terms.values_mut().for_each(|coef| *coef *= factor);
+        self
+    }
+
+    fn add(mut self, other: Value) -> Self {
+        self.constant = self.constant.wrapping_add(other.constant);
+        for (name, coef) in other.terms {
+            *self.terms.entry(name).or_insert(0) += coef;
+        }
+        self.terms.retain(|_, coef| *coef != 0);
+        self
+    }
+}
+
+impl Expr {
+    /// Evaluates the expression, taking the value of symbols (and '.') from 'lookup'
+    ///
+    /// Symbols left unknown by 'lookup' are kept as terms of the resulting value
+    pub fn evaluate(&self, lookup: &dyn Fn(&str) -> Option<Value>) -> Result<Value, String> {
+        match self {
+            Expr::Number(n) => Ok(Value::constant(*n)),
+            Expr::Symbol(name) => Ok(lookup(name).unwrap_or(Value::relative(name, 0))),
+            Expr::Dot => Ok(lookup(".").unwrap_or(Value::relative(".", 0))),
+            Expr::Unary(op, expr) => {
+                let value = expr.evaluate(lookup)?;
+                match op {
+                    Operator::Minus => Ok(value.scale(-1)),
+                    Operator::Not if value.is_constant() => Ok(Value::constant(!value.constant)),
+                    Operator::LogicalNot if value.is_constant() => {
+                        Ok(Value::constant((value.constant == 0) as i64))
+                    }
+                    _ => Err(format!("'{}' needs a constant operand", op.symbol())),
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.evaluate(lookup)?;
+                let rhs = rhs.evaluate(lookup)?;
+                match op {
+                    Operator::Plus => return Ok(lhs.add(rhs)),
+                    Operator::Minus => return Ok(lhs.add(rhs.scale(-1))),
+                    _ => {}
+                }
+                if !lhs.is_constant() || !rhs.is_constant() {
+                    return Err(format!("'{}' needs constant operands", op.symbol()));
+                }
+                let (a, b) = (lhs.constant, rhs.constant);
+                let n = match op {
+                    Operator::Mul => a.wrapping_mul(b),
+                    Operator::Div | Operator::Rem if b == 0 => {
+                        return Err(String::from("division by zero"));
+                    }
+                    Operator::Div => a.wrapping_div(b),
+                    Operator::Rem => a.wrapping_rem(b),
+                    Operator::Shl | Operator::Shr if !(0..64).contains(&b) => {
+                        return Err(format!("invalid shift count {}", b));
+                    }
+                    Operator::Shl => a << b,
+                    Operator::Shr => a >> b,
+                    Operator::And => a & b,
+                    Operator::Or => a | b,
+                    Operator::Xor => a ^ b,
+                    // comparisons give -1 when true, as in gas
+                    Operator::Eq => -((a == b) as i64),
+                    Operator::Ne => -((a != b) as i64),
+                    Operator::Lt => -((a < b) as i64),
+                    Operator::Le => -((a <= b) as i64),
+                    Operator::Gt => -((a > b) as i64),
+                    Operator::Ge => -((a >= b) as i64),
+                    Operator::LogicalAnd => (a != 0 && b != 0) as i64,
+                    Operator::LogicalOr => (a != 0 || b != 0) as i64,
+                    _ => unreachable!("not a binary operator"),
+                };
+                Ok(Value::constant(n))
+            }
+        }
+    }
+
+    /// Symbols the expression refers to ('.' included)
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => vec![],
+            Expr::Symbol(name) => vec![name],
+            Expr::Dot => vec!["."],
+            Expr::Unary(_, expr) => expr.names(),
+            Expr::Binary(_, lhs, rhs) => [lhs.names(), rhs.names()].concat(),
+        }
+    }
+}
+
+/// Constants end up in 32 bit fields: signed and unsigned values are accepted (i.e: 0xffffffff)
+pub fn to_i32(n: i64) -> Result<i32, String> {
+    if (i32::MIN as i64..=u32::MAX as i64).contains(&n) {
+        Ok(n as i32)
+    } else {
+        Err(format!("value {} doesn't fit in 32 bits", n))
+    }
+}
+
+// Parsing
+
+/// Pieces the arguments of a statement are made of
+#[derive(Debug, Clone)]
+pub enum Item {
+    Arg(ArgValue),
+    Op(Operator),
+    /// A number written with its sign ('x-1' is lexed as 'x' and '-1')
+    SignedNumber(i64),
+    /// ','
+    Separator,
+}
+
+/// Groups the pieces of a statement into its arguments, i.e: 'a0, 4*2(sp)' gives 'a0', '8' and
+/// 'sp'
+///
+/// Constant expressions become numbers and 'symbol + constant' becomes a symbol use, while the
+/// rest are left for the assembler
+pub fn parse_args(items: &[Item]) -> Result<Vec<ArgValue>, String> {
+    let mut parser = ExprParser { items, idx: 0 };
+    let mut args = Vec::new();
+    // arguments are separated by ',', except for the '(reg)' following an offset
+    let mut separated = true;
+    while let Some(item) = parser.peek() {
+        if let Item::Separator = item {
+            parser.idx += 1;
+            separated = true;
+            continue;
+        }
+        // '(reg)' after an offset
+        if let Some(reg) = parser.parenthesized(|arg| matches!(arg, ArgValue::Register(_))) {
+            args.push(reg);
+            separated = false;
+            continue;
+        }
+        if !separated {
+            return Err(String::from("expected ',' between arguments"));
+        }
+        separated = false;
+        match item {
+            Item::Arg(arg) if !matches!(arg, ArgValue::Number(_) | ArgValue::Use(..)) => {
+                args.push(arg.clone());
+                parser.idx += 1;
+            }
+            _ => {
+                let mut expr = parser.expr(1)?;
+                // 'offset(symbol)' is the symbol plus the offset
+                if let Some(ArgValue::Use(name, 0)) =
+                    parser.parenthesized(|arg| matches!(arg, ArgValue::Use(_, 0)))
+                {
+                    let symbol = Box::new(Expr::Symbol(name));
+                    expr = Expr::Binary(Operator::Plus, Box::new(expr), symbol);
+                }
+                args.push(lower(expr, &|_| None)?);
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Parses the pieces of a single expression, i.e: the condition of '.if'
+pub fn parse_expr(items: &[Item]) -> Result<Expr, String> {
+    let mut parser = ExprParser { items, idx: 0 };
+    let expr = parser.expr(1)?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(Item::Op(op)) => Err(format!("unexpected '{}'", op.symbol())),
+        Some(_) => Err(String::from("expected a single expression")),
+    }
+}
+
+/// Replaces the symbolic constants (i.e: '.equ') an argument refers to with their values
+pub fn substitute(
+    arg: &ArgValue,
+    constants: &dyn Fn(&str) -> Option<i64>,
+) -> Result<ArgValue, String> {
+    let expr = match arg {
+        ArgValue::Use(name, offset) => Expr::Binary(
+            Operator::Plus,
+            Box::new(Expr::Symbol(name.to_string())),
+            Box::new(Expr::Number(*offset as i64)),
+        ),
+        ArgValue::Expr(expr) => expr.clone(),
+        _ => return Ok(arg.clone()),
+    };
+    if expr
+        .names()
+        .into_iter()
+        .all(|name| constants(name).is_none())
+    {
+        return Ok(arg.clone());
+    }
+    lower(expr, constants)
+}
+
+/// Turns an expression into the simplest argument it can be, evaluating it when possible (with
+/// the values of the known 'constants')
+fn lower(expr: Expr, constants: &dyn Fn(&str) -> Option<i64>) -> Result<ArgValue, String> {
+    let value = match expr.evaluate(&|name| constants(name).map(Value::constant)) {
+        Ok(value) => value,
+        // it might be fine once symbols get their addresses
+        Err(_) if !expr.names().is_empty() => return Ok(ArgValue::Expr(expr)),
+        Err(e) => return Err(e),
+    };
+    if value.is_constant() {
+        return Ok(ArgValue::Number(value.constant));
+    }
+    match value.relative_to() {
+        Some((name, offset)) if name != "." => Ok(ArgValue::Use(name.to_string(), to_i32(offset)?)),
+        _ => Ok(ArgValue::Expr(expr)),
+    }
+}
+
+struct ExprParser<'a> {
+    items: &'a [Item],
+    idx: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn peek(&self) -> Option<&'a Item> {
+        self.items.get(self.idx)
+    }
+
+    /// Takes the next three items when they're an argument (matching 'accept') in parentheses
+    fn parenthesized(&mut self, accept: impl Fn(&ArgValue) -> bool) -> Option<ArgValue> {
+        match self.items.get(self.idx..self.idx + 3)? {
+            [
+                Item::Op(Operator::Lpar),
+                Item::Arg(arg),
+                Item::Op(Operator::Rpar),
+            ] if accept(arg) => {
+                self.idx += 3;
+                Some(arg.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn next(&mut self) -> Option<&Item> {
+        self.idx += 1;
+        self.items.get(self.idx - 1)
+    }
+
+    /// Binary operations whose operators bind at least as tight as 'min_precedence'
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.operand()?;
+        loop {
+            let (op, precedence) = match self.peek() {
+                Some(Item::Op(op)) => match op.precedence() {
+                    Some(precedence) => (*op, precedence),
+                    None => break,
+                },
+                // 'x-1' is lexed as 'x' and '-1'
+                Some(Item::SignedNumber(n)) => {
+                    let n = *n;
+                    self.idx += 1;
+                    lhs = Expr::Binary(Operator::Plus, Box::new(lhs), Box::new(Expr::Number(n)));
+                    continue;
+                }
+                _ => break,
+            };
+            if precedence < min_precedence {
+                break;
+            }
+            self.idx += 1;
+            let rhs = self.expr(precedence + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn operand(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Item::Arg(ArgValue::Number(n)) | Item::SignedNumber(n)) => Ok(Expr::Number(*n)),
+            Some(Item::Arg(ArgValue::Use(name, 0))) => match name.as_str() {
+                "." => Ok(Expr::Dot),
+                _ => Ok(Expr::Symbol(name.to_string())),
+            },
+            Some(Item::Op(Operator::Plus)) => self.operand(),
+            Some(Item::Op(op @ (Operator::Minus | Operator::Not | Operator::LogicalNot))) => {
+                let op = *op;
+                Ok(Expr::Unary(op, Box::new(self.operand()?)))
+            }
+            Some(Item::Op(Operator::Lpar)) => {
+                let expr = self.expr(1)?;
+                match self.next() {
+                    Some(Item::Op(Operator::Rpar)) => Ok(expr),
+                    _ => Err(String::from("expected ')'")),
+                }
+            }
+            Some(Item::Op(op)) => Err(format!("unexpected '{}'", op.symbol())),
+            Some(Item::Arg(ArgValue::Register(_))) => {
+                Err(String::from("registers can't be used in expressions"))
+            }
+            Some(_) => Err(String::from("expected an expression")),
+            None => Err(String::from("expression ended unexpectedly")),
+        }
+    }
+}
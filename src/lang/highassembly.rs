@@ -3,7 +3,7 @@
 // * absolute/relative address assignment
 
 use crate::{
-    lang::{directive::Directive, ext::Extension, pseudo::Pseudo},
+    lang::{directive::Directive, expr::Expr, ext::Extension, pseudo::Pseudo},
     streamreader::Position,
 };
 
@@ -129,6 +129,10 @@ pub enum SectionName {
     Data,
     Bss,
     Custom(String),
+    /// Not a section: where the symbols holding constants (i.e: '.equ') live
+    Absolute,
+    /// Not a section: where the symbols other objects define live (declared with '.globl')
+    Undefined,
 }
 
 impl SectionName {
@@ -139,6 +143,8 @@ impl SectionName {
             SectionName::Data => String::from(".data"),
             SectionName::Bss => String::from(".bss"),
             SectionName::Custom(s) => s.to_string(),
+            SectionName::Absolute => String::from("*ABS*"),
+            SectionName::Undefined => String::from("*UND*"),
         }
     }
 
@@ -148,12 +154,206 @@ impl SectionName {
             ".text" => SectionName::Text,
             ".data" => SectionName::Data,
             ".bss" => SectionName::Bss,
-            other => SectionName::Custom(other.to_lowercase()),
+            "*ABS*" => SectionName::Absolute,
+            "*UND*" => SectionName::Undefined,
+            other => SectionName::Custom(other.to_string()),
         }
     }
 }
 
+/// Attributes of a section, as given by '.section name, "flags", @type'
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SectionFlags {
+    /// Occupies memory while the program runs ('a')
+    pub alloc: bool,
+    /// Writable ('w')
+    pub write: bool,
+    /// Holds code ('x')
+    pub exec: bool,
+    /// Only holds zeros, so it takes no space in objects ('@nobits', as '.bss')
+    pub nobits: bool,
+    /// Holds data the runtime gives a meaning to ('@init_array', '@note'...)
+    pub special: Option<SpecialSection>,
+}
+
+/// Types of sections which hold data like '@progbits' ones, but tell what it's for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpecialSection {
+    /// '@init_array', pointers to the functions to call before 'main'
+    InitArray,
+    /// '@fini_array', pointers to the functions to call after 'main'
+    FiniArray,
+    /// '@preinit_array', pointers to the functions to call before the '@init_array' ones
+    PreinitArray,
+    /// '@note', information about the object for other tools
+    Note,
+}
+
+impl SectionFlags {
+    /// Attributes of a section which isn't given any, after its name (as in GNU as)
+    pub fn of(name: &SectionName) -> SectionFlags {
+        let name = name.default_name();
+        let is = |prefixes: &[&str]| {
+            prefixes
+                .iter()
+                .any(|prefix| name == *prefix || name.starts_with(&format!("{}.", prefix)))
+        };
+        let mut flags = SectionFlags::default();
+        if is(&[".text"]) {
+            flags.alloc = true;
+            flags.exec = true;
+        } else if is(&[".data", ".sdata"]) {
+            flags.alloc = true;
+            flags.write = true;
+        } else if is(&[".bss", ".sbss"]) {
+            flags.alloc = true;
+            flags.write = true;
+            flags.nobits = true;
+        } else if is(&[".rodata", ".srodata"]) {
+            flags.alloc = true;
+        }
+        flags
+    }
+
+    /// Attributes given to the section 'name' ('flags' as "awx" and 'kind' as "@nobits")
+    pub fn parse(
+        name: &SectionName,
+        flags: Option<&str>,
+        kind: Option<&str>,
+    ) -> Result<SectionFlags, String> {
+        let mut attributes = SectionFlags::of(name);
+        if let Some(flags) = flags {
+            attributes = SectionFlags {
+                nobits: attributes.nobits,
+                ..SectionFlags::default()
+            };
+            for flag in flags.chars() {
+                match flag {
+                    'a' => attributes.alloc = true,
+                    'w' => attributes.write = true,
+                    'x' => attributes.exec = true,
+                    // merging duplicated constants ('M') and strings ('S') is only an
+                    // optimization, and sections are always kept ('R')
+                    'M' | 'S' | 'R' => {}
+                    'T' => return Err("thread-local sections ('T') aren't supported".into()),
+                    'G' => return Err("section groups ('G') aren't supported".into()),
+                    'o' => return Err("linked-to sections ('o') aren't supported".into()),
+                    other => return Err(format!("unknown section flag '{}'", other)),
+                }
+            }
+        }
+        let kind = kind.map(|kind| kind.trim_start_matches(['@', '%']));
+        match kind {
+            None => {}
+            Some("progbits") => attributes.nobits = false,
+            Some("nobits") => attributes.nobits = true,
+            Some(special) => {
+                attributes.nobits = false;
+                attributes.special = Some(match special {
+                    "init_array" => SpecialSection::InitArray,
+                    "fini_array" => SpecialSection::FiniArray,
+                    "preinit_array" => SpecialSection::PreinitArray,
+                    "note" => SpecialSection::Note,
+                    other => {
+                        return Err(format!(
+                            "unknown section type '@{}' (expected @progbits, @nobits, \
+                             @init_array, @fini_array, @preinit_array or @note)",
+                            other
+                        ));
+                    }
+                });
+            }
+        }
+        Ok(attributes)
+    }
+}
+
+/// Directives giving attributes to the symbols they take as arguments
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolDirective {
+    /// '.globl' (or '.global')
+    Global,
+    Local,
+    Weak,
+    Hidden,
+    Protected,
+    /// '.type name, @kind' (the name and the kind are kept as written)
+    Type {
+        symbol: String,
+        kind: String,
+    },
+    /// '.size name, expr'
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SymbolBinding {
+    #[default]
+    Local,
+    Global,
+    Weak,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SymbolVisibility {
+    #[default]
+    Default,
+    Hidden,
+    Protected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SymbolKind {
+    #[default]
+    NoType,
+    Function,
+    Object,
+}
+
+impl SymbolKind {
+    /// Kind given by '.type' ('@function', '%function', "function" or 'STT_FUNC')
+    pub fn parse(kind: &str) -> Result<SymbolKind, String> {
+        match kind.trim_start_matches(['@', '%']).trim_matches('"') {
+            "function" | "STT_FUNC" => Ok(SymbolKind::Function),
+            "object" | "STT_OBJECT" => Ok(SymbolKind::Object),
+            "notype" | "STT_NOTYPE" => Ok(SymbolKind::NoType),
+            _ => Err(format!(
+                "unknown symbol type '{}' (expected @function, @object or @notype)",
+                kind
+            )),
+        }
+    }
+}
+
+/// How a symbol is seen by other objects and what it names, as in ELF symbols
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SymbolAttributes {
+    pub binding: SymbolBinding,
+    pub visibility: SymbolVisibility,
+    pub kind: SymbolKind,
+}
+
+/// Section a line refers to, along with the flags and the type it was given (checked once lines
+/// get grouped into sections)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SectionSpec {
+    pub name: SectionName,
+    pub flags: Option<String>,
+    pub kind: Option<String>,
+}
+
+/// How a line changes the section the lines following it are put in
+#[derive(Debug, Clone, PartialEq)]
+pub enum SectionChange {
+    /// '.section name, "flags", @type' (and '.text', '.data', '.rodata' and '.bss')
+    Switch(SectionSpec),
+    /// '.pushsection', which the matching '.popsection' goes back from
+    Push(SectionSpec),
+    Pop,
+}
+
 pub enum Datatype {
+    Dword,
     Word,
     Half,
     Byte,
@@ -163,6 +363,7 @@ pub enum Datatype {
 impl Datatype {
     pub fn alignment(&self) -> usize {
         match self {
+            Datatype::Dword => 8usize,
             Datatype::Word => 4usize,
             Datatype::Half => 4usize,
             Datatype::Byte => 1usize,
@@ -172,6 +373,7 @@ impl Datatype {
 
     pub fn size_bytes(&self) -> usize {
         match self {
+            Datatype::Dword => 8usize,
             Datatype::Word => 4usize,
             Datatype::Half => 2usize,
             Datatype::Byte => 1usize,
@@ -185,28 +387,40 @@ pub enum KeyValue {
     Op(Box<dyn Extension>),
     Pseudo(Box<dyn Pseudo>),
     AssemblyDirective(Box<dyn Directive>),
-    LinkerDirective(String),
-    Section(SectionName),
+    LinkerDirective(SymbolDirective),
+    Section(SectionChange),
     Label(String),
+    /// Symbolic constant ('.equ name, value', '.set name, value' or 'name = value')
+    Assignment(String),
 }
 
 #[derive(Clone, Debug)]
 pub enum ArgValue {
     Byte(u8),
-    Number(i32),
+    /// Up to 64 bits, which only data (i.e: '.dword') and constants can take: the parser makes
+    /// sure the rest fit in 32 bits
+    Number(i64),
+    Float(f64),
     Register(Register),
     Offset(usize, i32),
-    Literal(String),
+    /// Bytes of a string (with its escape sequences replaced)
+    Literal(Vec<u8>),
     Use(String, i32),
     UseHi(String, i32),
     UseLo(String, i32),
+    /// Upper 20 bits of an expression which has to turn out a constant once symbols get their
+    /// addresses ('li rd, end - start'), rounded as 'lui' needs them for 'ExprLo' to follow
+    ExprHi(Expr),
+    ExprLo(Expr),
+    /// Expression which can only be evaluated once symbols get their addresses
+    Expr(Expr),
 }
 
 impl ArgValue {
     pub fn to_number(&self) -> Option<i32> {
         match self {
             ArgValue::Byte(b) => Some((*b).try_into().unwrap()),
-            ArgValue::Number(n) => Some(*n),
+            ArgValue::Number(n) => Some(*n as i32),
             ArgValue::Register(register) => Some(register.id().into()),
             ArgValue::Offset(_abs_addr, _rel_addr) => {
                 todo!();
@@ -230,13 +444,18 @@ pub struct GenericLine {
 }
 
 impl GenericLine {
-    fn size_bytes_with_alignment(&self, alignment: usize) -> usize {
+    /// Size of the line, padded to 'alignment'
+    pub fn size_bytes_with_alignment(&self, alignment: usize) -> usize {
         match &self.keyword {
             KeyValue::Op(_) => 4usize,
             KeyValue::AssemblyDirective(d) => {
-                let datatype_size = d.datatype().size_bytes();
-                let len = self.args.len() / datatype_size;
-                let size = datatype_size * len;
+                // args are still expressions when they can't be translated to bytes yet
+                let translated = self.args.iter().all(|arg| matches!(arg, ArgValue::Byte(_)));
+                let size = if translated {
+                    self.args.len()
+                } else {
+                    self.args.len() * d.datatype().size_bytes()
+                };
                 let exceeding = size % alignment;
                 //ensure word alignment for sections
                 let pad = if exceeding > 0 {
@@ -250,10 +469,6 @@ impl GenericLine {
         }
     }
 
-    pub fn size_bytes_at_word_boundary(&self) -> usize {
-        self.size_bytes_with_alignment(4)
-    }
-
     pub fn size_bytes_unaligned(&self) -> usize {
         self.size_bytes_with_alignment(1)
     }
@@ -262,5 +477,6 @@ impl GenericLine {
 #[derive(Debug)]
 pub struct GenericBlock {
     pub(crate) name: SectionName,
+    pub(crate) flags: SectionFlags,
     pub(crate) lines: Vec<GenericLine>,
 }
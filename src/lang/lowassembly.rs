@@ -4,11 +4,12 @@
 // * low level details (such as binary encoding/representation)
 
 use crate::{
+    diagnostics::Diagnostic,
     lang::{directive::Directive, ext::Extension, highassembly::SectionName},
     streamreader::Position,
 };
 
-use super::ext::instruction_to_binary;
+use super::ext::{InstructionToBinaryError, instruction_to_binary};
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum DataEndianness {
@@ -84,7 +85,8 @@ impl DataEndianness {
 #[derive(Debug)]
 pub struct EncodedData {
     pub file_pos: Position,
-    pub data: Vec<u32>,
+    /// Bytes (little endian) the line is encoded into
+    pub data: Vec<u8>,
     pub alignment: usize,
 }
 
@@ -102,38 +104,39 @@ pub struct EncodableLine {
 }
 
 impl EncodableLine {
-    pub fn encode(self) -> EncodedData {
+    pub fn encode(self) -> Result<EncodedData, Diagnostic> {
         match self.key {
             EncodableKey::Op(op) => {
                 let bin = instruction_to_binary(&op, &self.args);
                 let data = match bin {
-                    Ok(bin_inst) => vec![bin_inst],
-                    Err(e) => {
-                        panic!(
-                            "Error at line {} column {}: {}",
-                            self.file_pos.row(),
-                            self.file_pos.col(),
-                            e
+                    Ok(bin_inst) => bin_inst.to_le_bytes().to_vec(),
+                    Err(InstructionToBinaryError::SyntaxError((syntax, args))) => {
+                        let names: Vec<String> =
+                            syntax.iter().map(|name| format!("{:?}", name)).collect();
+                        let message = format!(
+                            "'{:?}' expects {} arguments ({}), got {}",
+                            op,
+                            syntax.len(),
+                            names.join(", "),
+                            args.len()
                         );
+                        return Err(Diagnostic::error(self.file_pos, message.to_lowercase()));
                     }
                 };
-                EncodedData {
+                Ok(EncodedData {
                     file_pos: self.file_pos,
                     data,
                     alignment: 4,
-                }
+                })
             }
             EncodableKey::Directive(d) => {
                 let alignment = d.datatype().alignment();
-                let data: Vec<u32> = {
-                    let args: Vec<u32> = self.args.into_iter().map(|arg| arg as u32).collect();
-                    args
-                };
-                EncodedData {
+                let data: Vec<u8> = self.args.into_iter().map(|arg| arg as u8).collect();
+                Ok(EncodedData {
                     file_pos: self.file_pos,
                     data,
                     alignment,
-                }
+                })
             }
         }
     }
@@ -3,7 +3,8 @@ use crate::lang::ext::RV32I;
 use crate::lang::highassembly::{ArgValue, OpcodeLine, Register};
 
 pub trait Pseudo: std::fmt::Debug {
-    fn translate(&self, args: Vec<ArgValue>) -> Vec<OpcodeLine>;
+    /// Fails (with a message) when the arguments don't match the pseudo instruction
+    fn translate(&self, args: Vec<ArgValue>) -> Result<Vec<OpcodeLine>, String>;
 }
 
 // Pseudo Instructions implementation
@@ -18,32 +19,39 @@ pub enum PseudoInstruction {
 }
 
 impl Pseudo for PseudoInstruction {
-    fn translate(&self, args: Vec<ArgValue>) -> Vec<OpcodeLine> {
+    fn translate(&self, args: Vec<ArgValue>) -> Result<Vec<OpcodeLine>, String> {
         match self {
             PseudoInstruction::LI => {
-                let arg1 = args[0].clone();
-                let arg2 = args[1].clone();
-                match (arg1, arg2) {
-                    (ArgValue::Register(rd), ArgValue::Number(n)) => {
-                        let lo = ArgValue::Number(lower_12_bits(n));
+                match &args[..] {
+                    &[ArgValue::Register(rd), ArgValue::Number(n)] => {
+                        // unsigned values (i.e: 0xffffffff) are the same as the signed ones
+                        let n = n as i32;
+                        let lo = ArgValue::Number(lower_12_bits(n).into());
                         if fits_in_12bit_immediate(n) {
                             //'li' gets simplified to a 'addi' op
                             let addi_line = build_addi_line(rd, Register::ZERO, lo);
-                            return vec![addi_line];
+                            return Ok(vec![addi_line]);
                         } else {
                             //Otherwise we have to:
                             //1. load the upper 20 bits of the immediate using 'lui'
                             //2. add the upper 20 bits with 'addi'
-                            let hi = ArgValue::Number(upper_20_bits(n));
+                            let hi = ArgValue::Number(upper_20_bits(n).into());
                             let lui_line = build_lui_line(rd, hi);
                             let addi_line = build_addi_line(rd, rd, lo);
-                            return vec![lui_line, addi_line];
+                            return Ok(vec![lui_line, addi_line]);
                         }
                     }
+                    [ArgValue::Register(rd), ArgValue::Expr(expr)] => {
+                        // the value (i.e: 'end - start') is only known once symbols get their
+                        // addresses, so both halves are always loaded
+                        let lui_line = build_lui_line(*rd, ArgValue::ExprHi(expr.clone()));
+                        let addi_line = build_addi_line(*rd, *rd, ArgValue::ExprLo(expr.clone()));
+                        return Ok(vec![lui_line, addi_line]);
+                    }
                     _ => {}
                 }
             }
-            PseudoInstruction::RET => {
+            PseudoInstruction::RET if args.is_empty() => {
                 let jalr_line = OpcodeLine {
                     keyword: Box::new(RV32I::JALR),
                     args: vec![
@@ -52,56 +60,79 @@ impl Pseudo for PseudoInstruction {
                         ArgValue::Number(0),
                     ],
                 };
-                return vec![jalr_line];
+                return Ok(vec![jalr_line]);
             }
             PseudoInstruction::MV => {
-                let arg1: ArgValue = args[0].clone();
-                let arg2: ArgValue = args[1].clone();
-                match (arg1, arg2) {
-                    (ArgValue::Register(rd), ArgValue::Register(rsrc)) => {
-                        let addi_line = build_addi_line(rd, rsrc, ArgValue::Number(0));
-                        return vec![addi_line];
-                    }
-                    _ => {}
+                if let [ArgValue::Register(rd), ArgValue::Register(rsrc)] = args[..] {
+                    let addi_line = build_addi_line(rd, rsrc, ArgValue::Number(0));
+                    return Ok(vec![addi_line]);
                 }
             }
             PseudoInstruction::LA => {
-                let arg1 = args[0].clone();
-                let arg2 = args[1].clone();
-                let arg3 = args.get(2);
-
-                match (arg1, arg2, arg3) {
-                    (ArgValue::Register(rd), ArgValue::Number(n), Some(ArgValue::Register(rs))) => {
-                        let lo = ArgValue::Number(lower_12_bits(n));
+                match &args[..] {
+                    &[
+                        ArgValue::Register(rd),
+                        ArgValue::Number(n),
+                        ArgValue::Register(rs),
+                    ] => {
+                        let n = n as i32;
+                        let lo = ArgValue::Number(lower_12_bits(n).into());
                         if fits_in_12bit_immediate(n) {
-                            let addi_line = build_addi_line(rd, *rs, lo);
-                            return vec![addi_line];
+                            let addi_line = build_addi_line(rd, rs, lo);
+                            return Ok(vec![addi_line]);
                         } else {
-                            let hi = ArgValue::Number(upper_20_bits(n));
+                            let hi = ArgValue::Number(upper_20_bits(n).into());
                             let auipc_line = build_auipc_line(rd, hi);
                             let addi_line = build_addi_line(rd, rd, lo);
-                            return vec![auipc_line, addi_line];
+                            return Ok(vec![auipc_line, addi_line]);
                         }
                     }
-                    (ArgValue::Register(rd), ArgValue::Use(s, off), None) => {
+                    [ArgValue::Register(rd), ArgValue::Use(s, off)] => {
                         //We can't know if HI is 0 or not, therefore we can't optimize
-                        let hi = ArgValue::UseHi(s.to_string(), off);
-                        let lo = ArgValue::UseLo(s.to_string(), off);
-                        let auipc_line = build_auipc_line(rd, hi);
-                        let addi_line = build_addi_line(rd, rd, lo);
-                        return vec![auipc_line, addi_line];
+                        let hi = ArgValue::UseHi(s.to_string(), *off);
+                        let lo = ArgValue::UseLo(s.to_string(), *off);
+                        let auipc_line = build_auipc_line(*rd, hi);
+                        let addi_line = build_addi_line(*rd, *rd, lo);
+                        return Ok(vec![auipc_line, addi_line]);
                     }
                     _ => {}
                 }
             }
-            PseudoInstruction::NOP => {
+            PseudoInstruction::NOP if args.is_empty() => {
                 let addi_line =
                     build_addi_line(Register::ZERO, Register::ZERO, ArgValue::Number(0));
-                return vec![addi_line];
+                return Ok(vec![addi_line]);
             }
+            PseudoInstruction::RET | PseudoInstruction::NOP => {}
         }
 
-        Vec::new()
+        Err(format!(
+            "invalid arguments for '{}': {}",
+            self.name(),
+            self.syntax()
+        ))
+    }
+}
+
+impl PseudoInstruction {
+    fn name(&self) -> &str {
+        match self {
+            PseudoInstruction::LI => "li",
+            PseudoInstruction::RET => "ret",
+            PseudoInstruction::MV => "mv",
+            PseudoInstruction::LA => "la",
+            PseudoInstruction::NOP => "nop",
+        }
+    }
+
+    /// Accepted arguments, as shown in diagnostics
+    fn syntax(&self) -> &str {
+        match self {
+            PseudoInstruction::LI => "expected 'rd, imm'",
+            PseudoInstruction::MV => "expected 'rd, rs'",
+            PseudoInstruction::LA => "expected 'rd, symbol' or 'rd, offset, rs'",
+            PseudoInstruction::RET | PseudoInstruction::NOP => "expected no arguments",
+        }
     }
 }
 
@@ -109,11 +140,13 @@ fn fits_in_12bit_immediate(n: i32) -> bool {
     (n >= -2048) && (n <= 2047)
 }
 
-fn upper_20_bits(n: i32) -> i32 {
-    (n >> 12) & 0b11111_11111_11111_11111
+/// Rounded up when the lower 12 bits are negative (as 'addi' sign-extends them), so that adding
+/// both halves gives 'n' back
+pub(crate) fn upper_20_bits(n: i32) -> i32 {
+    (n.wrapping_add(0x800) >> 12) & 0b11111_11111_11111_11111
 }
 
-fn lower_12_bits(n: i32) -> i32 {
+pub(crate) fn lower_12_bits(n: i32) -> i32 {
     n & 0b1111_1111_1111
 }
 
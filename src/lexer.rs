@@ -1,9 +1,10 @@
 pub trait Lexer {
-    fn get_tokens(&mut self, buffer: &str) -> Vec<(String, Position)>;
+    fn get_tokens(&mut self, buffer: &str) -> diagnostics::Result<Vec<(String, Position)>>;
 }
 
 /* The following code was written to ease the implementation of the 'Lexer' trait. */
 
+use crate::diagnostics;
 use crate::streamreader::{CharStreamReader, Position, StreamReader};
 
 #[derive(Debug)]
@@ -51,21 +52,17 @@ pub trait CommonClassifier {
             s.push(start);
         }
 
+        // escape sequences are left for the tokenizer (a '\"' doesn't end the string, though)
         while let Some(ch) = it.advance_and_read() {
             s.push(ch);
             if ch == '"' {
                 it.advance();
                 break;
             }
-            if ch == '\\' {
-                match it.advance_and_read() {
-                    Some('"') => s.push('"'),
-                    Some('n') => {
-                        s.pop();
-                        s.push('\n');
-                    }
-                    _ => {}
-                }
+            if ch == '\\'
+                && let Some(escaped) = it.advance_and_read()
+            {
+                s.push(escaped);
             }
         }
 
@@ -85,22 +82,7 @@ pub trait CommonClassifier {
         ch.is_digit(10)
     }
     fn handle_number(&self, it: &mut CharStreamReader) -> Option<String> {
-        let Some(first_digit) = it.current_token() else {
-            return None;
-        };
-
-        let Some(second_digit) = it.next_token() else {
-            return handle_decimal(it);
-        };
-
-        if first_digit == '0' {
-            match second_digit {
-                'x' | 'X' => handle_hexadecimal(it),
-                _ => handle_decimal(it),
-            }
-        } else {
-            handle_decimal(it)
-        }
+        read_number(it)
     }
 
     fn is_token(&self, ch: char) -> Option<CommonClass> {
@@ -148,6 +130,24 @@ pub trait CommonClassifier {
     }
 }
 
+/// Reads a decimal or hexadecimal number (the default 'CommonClassifier::handle_number')
+pub fn read_number(it: &mut CharStreamReader) -> Option<String> {
+    let first_digit = it.current_token()?;
+
+    let Some(second_digit) = it.next_token() else {
+        return handle_decimal(it);
+    };
+
+    if first_digit == '0' {
+        match second_digit {
+            'x' | 'X' => handle_hexadecimal(it),
+            _ => handle_decimal(it),
+        }
+    } else {
+        handle_decimal(it)
+    }
+}
+
 fn handle_number(it: &mut CharStreamReader, is_valid: impl Fn(char) -> bool) -> Option<String> {
     let mut n = String::new();
     while let Some(ch) = it.current_token() {
@@ -194,21 +194,26 @@ impl std::fmt::Display for LexerError {
 
 ///Default implementation of 'Lexer' for any entity which implements 'CommonClassifier'
 impl<T: CommonClassifier> Lexer for T {
-    fn get_tokens(&mut self, buffer: &str) -> Vec<(String, Position)> {
+    fn get_tokens(&mut self, buffer: &str) -> diagnostics::Result<Vec<(String, Position)>> {
         let mut it = CharStreamReader::new(buffer.chars(), '\n');
 
         let mut tokens = Vec::new();
 
-        while it.current_token().is_some() {
+        while let Some(ch) = it.current_token() {
             let pos = it.current_position().unwrap();
 
             match self.handle_token(&mut it) {
                 Ok(Some(token)) => tokens.push((token, pos)),
                 Ok(None) => {}
-                Err(err) => panic!("{}", err),
+                Err(LexerError::AutomataException(pos)) => {
+                    // left for the tokenizer to report, as it might be in a region which isn't
+                    // assembled (i.e: '.if 0')
+                    tokens.push((ch.to_string(), pos));
+                    it.advance();
+                }
             }
         }
 
-        tokens
+        Ok(tokens)
     }
 }
@@ -1,24 +1,35 @@
 pub mod assembler;
+pub mod diagnostics;
 pub mod lexer;
 pub mod parser;
+pub mod preprocessor;
+pub mod sources;
 pub mod streamreader;
 pub mod syntax;
 pub mod tokenizer;
 pub mod utils;
 pub mod emu {
+    pub mod arch;
     pub mod cpu;
     pub mod debugger;
+    pub mod disasm;
+    pub mod hostio;
     pub mod machine;
     pub mod memory;
+    pub mod repl;
+    pub mod trace;
+    pub mod transport;
 }
 pub mod lang {
     pub mod directive;
+    pub mod expr;
     pub mod ext;
     pub mod highassembly;
     pub mod lowassembly;
     pub mod pseudo;
 }
 pub mod obj {
+    pub mod dwarfreader;
     pub mod dwarfwriter;
     pub mod elfreader;
     pub mod elfwriter;
@@ -113,6 +124,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -126,6 +138,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -143,6 +156,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -159,6 +173,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -175,6 +190,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -188,6 +204,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -201,6 +218,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -222,6 +240,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -257,6 +276,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -270,6 +290,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -291,6 +312,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -317,6 +339,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -345,6 +368,7 @@ mod tests {
             let mut tokenizer = syntax::gas::Lexer;
             let res: Vec<String> = tokenizer
                 .get_tokens(code)
+                .unwrap()
                 .into_iter()
                 .map(|token| token.0)
                 .collect();
@@ -622,6 +646,1043 @@ mod tests {
             assert!(m.assert_reg(10u32, 1000));
         }
 
+        #[test]
+        fn machine_step_back() {
+            let code = "
+                li a2, 90
+                li a3, 150
+                add a1, a2, a3
+            ";
+            let words = encode_to_words(code);
+            let mut m = SimpleMachine::from_words(&words, DataEndianness::Be);
+            m.set_recording(true);
+            for _ in 0..3 {
+                m.decode().unwrap();
+            }
+            assert_eq!(m.history_len(), 3);
+
+            let record = m.step_back().unwrap();
+            assert_eq!(record.pc, 8);
+            assert!(m.assert_pc(8));
+            assert!(m.assert_reg(Register::A1.id().into(), 0));
+            assert!(m.assert_reg(Register::A3.id().into(), 150));
+
+            m.step_back().unwrap();
+            m.step_back().unwrap();
+            assert!(m.step_back().is_none());
+            assert!(m.assert_pc(0));
+            assert!(m.assert_reg(Register::A2.id().into(), 0));
+
+            // going forward again replays the log
+            for _ in 0..3 {
+                m.decode().unwrap();
+            }
+            assert!(m.assert_pc(12));
+            assert!(m.assert_reg(Register::A1.id().into(), 240));
+        }
+
+        #[test]
+        fn machine_step_back_memory() {
+            let code = "
+                li t1, 7
+                sw t1, 32(x0)
+                sb t1, 36(x0)
+            ";
+            let mut words = encode_to_words(code);
+            words.resize(16, 0);
+            let mut m = SimpleMachine::from_words(&words, DataEndianness::Be);
+            m.write_memory_word(32, 3);
+            m.set_recording(true);
+            for _ in 0..3 {
+                m.decode().unwrap();
+            }
+            assert_eq!(m.read_memory_word(32), 7);
+            assert_eq!(m.read_memory_byte(36), 7);
+
+            m.step_back().unwrap();
+            assert_eq!(m.read_memory_byte(36), 0);
+            m.step_back().unwrap();
+            assert_eq!(m.read_memory_word(32), 3);
+        }
+
+        #[test]
+        fn machine_watchpoints() {
+            use crate::emu::machine::{WatchKind, Watchpoint};
+            let code = "
+                li t1, 7
+                sw t1, 32(x0)
+                lw t2, 32(x0)
+                lb t3, 33(x0)
+            ";
+            let mut words = encode_to_words(code);
+            words.resize(16, 0);
+            let mut m = SimpleMachine::from_words(&words, DataEndianness::Be);
+            let write = Watchpoint {
+                addr: 32,
+                len: 4,
+                kind: WatchKind::Write,
+            };
+            let access = Watchpoint {
+                addr: 33,
+                len: 1,
+                kind: WatchKind::Access,
+            };
+            m.add_watchpoint(write);
+            m.add_watchpoint(access);
+
+            m.decode().unwrap();
+            assert!(m.watch_hit().is_none());
+
+            m.decode().unwrap();
+            let hit = m.watch_hit().unwrap();
+            assert_eq!(hit.addr, 32);
+            assert_eq!(hit.kind, WatchKind::Write);
+            assert_eq!(hit.watchpoint, write);
+
+            assert!(m.remove_watchpoint(write));
+            m.decode().unwrap();
+            let hit = m.watch_hit().unwrap();
+            assert_eq!(hit.kind, WatchKind::Read);
+            assert_eq!(hit.watchpoint, access);
+
+            m.decode().unwrap();
+            assert_eq!(m.watch_hit().unwrap().addr, 33);
+        }
+
+        #[test]
+        fn machine_csrs_and_fprs() {
+            use crate::emu::arch::target_description_xml;
+            use crate::emu::cpu::{CSR_FCSR, CSR_FFLAGS, CSR_FRM, CSR_MISA, MISA_D, MISA_F};
+            use crate::emu::machine::FloatExtension;
+            let mut m = SimpleMachine::from_words(&vec![0], DataEndianness::Le);
+            assert_eq!(m.read_csr(CSR_MISA) & (MISA_F | MISA_D), 0);
+
+            m.set_float_extension(FloatExtension::D);
+            assert_eq!(m.read_csr(CSR_MISA) & (MISA_F | MISA_D), MISA_F | MISA_D);
+
+            m.write_csr(CSR_FCSR, 0b101_10011);
+            assert_eq!(m.read_csr(CSR_FFLAGS), 0b10011);
+            assert_eq!(m.read_csr(CSR_FRM), 0b101);
+            m.write_csr(CSR_FRM, 0b001);
+            assert_eq!(m.read_csr(CSR_FCSR), 0b001_10011);
+
+            m.write_fpr(3, 0x4000_0000_0000_0000);
+            assert_eq!(m.read_fpr(3), 0x4000_0000_0000_0000);
+
+            let xml = target_description_xml(FloatExtension::None);
+            assert!(xml.contains("name=\"mcause\""));
+            assert!(!xml.contains("org.gnu.gdb.riscv.fpu"));
+            let xml = target_description_xml(FloatExtension::D);
+            assert!(xml.contains("<reg name=\"ft0\" bitsize=\"64\" type=\"ieee_double\" regnum=\"33\""));
+        }
+
+        #[test]
+        fn machine_catch_syscalls() {
+            use crate::emu::machine::{SyscallCatch, SyscallStop};
+            let code = "
+                li a7, 64
+                li a2, 0
+                ecall
+                li a7, 93
+                ecall
+            ";
+            let words = encode_to_words(code);
+            let mut m = SimpleMachine::from_words(&words, DataEndianness::Be);
+            m.set_syscall_catch(SyscallCatch::Only(vec![93]));
+
+            for _ in 0..3 {
+                m.decode().unwrap();
+                assert!(m.syscall_stop().is_none());
+            }
+            m.decode().unwrap();
+            assert!(m.syscall_stop().is_none());
+
+            // stops before executing the 'ecall'
+            assert_eq!(m.decode().unwrap(), emu::machine::MachineState::Ok);
+            assert_eq!(m.syscall_stop(), Some(SyscallStop::Entry(93)));
+            assert!(m.assert_pc(16));
+            assert_eq!(m.instruction_count(), 4);
+
+            assert_eq!(m.decode().unwrap(), emu::machine::MachineState::Exit(0));
+            assert_eq!(m.syscall_stop(), Some(SyscallStop::Return(93)));
+        }
+
+        #[test]
+        fn machine_write_syscall() {
+            use crate::utils::assemble_code;
+            let code = r#"
+                    .data
+                    .byte 0
+                msg: .ascii "Burrito!\n"
+                    .text
+                _start:
+                    li a0, 1
+                    la a1, msg
+                    li a2, 9
+                    li a7, 64
+                    ecall
+                    li a7, 93
+                    ecall
+            "#;
+            let tools = assemble_code(code).unwrap();
+            let mut m = new_machine_from_tools(&tools);
+            m.set_console_capture(true);
+            while let Ok(emu::machine::MachineState::Ok) = m.decode() {}
+            // the string starts at an odd address and ends in the middle of a word
+            assert_eq!(m.take_console_output(), b"Burrito!\n");
+            assert!(m.take_console_output().is_empty());
+        }
+
+        #[test]
+        fn machine_byte_loads_and_stores() {
+            use crate::utils::assemble_code;
+            let code = "
+                    .data
+                bytes: .byte 1, 2, 3, 4
+                copy: .word 0
+                    .text
+                _start:
+                    la t0, bytes
+                    lb a0, 0(t0)
+                    lb a1, 1(t0)
+                    lb a2, 2(t0)
+                    lb a3, 3(t0)
+                    sb a3, 4(t0)
+                    sb a2, 5(t0)
+                    sb a1, 6(t0)
+                    sb a0, 7(t0)
+                    lw a4, 4(t0)
+                    li a7, 93
+                    ecall
+            ";
+            let tools = assemble_code(code).unwrap();
+            let mut m = new_machine_from_tools(&tools);
+            while let Ok(emu::machine::MachineState::Ok) = m.decode() {}
+            assert!(m.assert_reg(Register::A0.id().into(), 1));
+            assert!(m.assert_reg(Register::A1.id().into(), 2));
+            assert!(m.assert_reg(Register::A2.id().into(), 3));
+            assert!(m.assert_reg(Register::A3.id().into(), 4));
+            // the bytes were stored reversed, in little endian order
+            assert!(m.assert_reg(Register::A4.id().into(), 0x01020304));
+        }
+
+        #[test]
+        fn machine_harts() {
+            use crate::emu::machine::HART_STACK_SIZE;
+            // csrr a0, mhartid; addi a1, a0, 1; j .
+            let words = vec![0xf1402573, 0x00150593, 0x0000006f];
+            let mut m = SimpleMachine::from_words(&words, DataEndianness::Be);
+            let mut registers = m.read_registers();
+            registers.pop();
+            registers[2] = 0x10000;
+            m.write_registers(registers, 0);
+            m.set_recording(true);
+            m.set_hart_count(2);
+            m.set_quantum(2);
+
+            // every hart has its own stack, and its id in 'a0'
+            m.select_hart(1);
+            assert!(m.assert_reg(2, (0x10000 - HART_STACK_SIZE) as u32));
+            assert!(m.assert_reg(10, 1));
+            m.select_hart(0);
+            assert!(m.assert_reg(2, 0x10000));
+
+            // each hart executes 2 instructions before the other one runs
+            let order: Vec<usize> = (0..6)
+                .map(|_| {
+                    m.decode().unwrap();
+                    m.current_hart()
+                })
+                .collect();
+            assert_eq!(order, vec![0, 0, 1, 1, 0, 0]);
+            assert_eq!(m.scheduled_hart(), 1);
+
+            m.select_hart(0);
+            assert!(m.assert_reg(11, 1));
+            m.select_hart(1);
+            assert!(m.assert_reg(10, 1));
+            assert!(m.assert_reg(11, 2));
+
+            // stepping back goes through the harts in the reverse order
+            assert_eq!(m.step_back().unwrap().hart, 0);
+            assert_eq!(m.step_back().unwrap().hart, 0);
+            assert_eq!(m.step_back().unwrap().hart, 1);
+            assert!(m.assert_reg(11, 0));
+            assert_eq!(m.scheduled_hart(), 1);
+            m.decode().unwrap();
+            assert_eq!(m.current_hart(), 1);
+            assert!(m.assert_reg(11, 2));
+        }
+
+        #[test]
+        fn machine_forward_syscalls() {
+            use crate::emu::hostio::forwarded_syscalls;
+            use crate::emu::machine::SyscallStop;
+            let code = "
+                li a0, 1
+                li a7, 64
+                ecall
+                li a7, 93
+                ecall
+            ";
+            let words = encode_to_words(code);
+            let mut m = SimpleMachine::from_words(&words, DataEndianness::Be);
+            m.set_recording(true);
+            m.set_forwarded_syscalls(forwarded_syscalls());
+
+            m.decode().unwrap();
+            m.decode().unwrap();
+            m.decode().unwrap();
+            assert_eq!(m.syscall_stop(), Some(SyscallStop::Forward(64)));
+            assert!(m.assert_pc(8));
+
+            m.complete_syscall(5);
+            assert!(m.syscall_stop().is_none());
+            assert!(m.assert_reg(10, 5));
+            assert!(m.assert_pc(12));
+            assert_eq!(m.instruction_count(), 3);
+
+            // the result of the syscall is undone when stepping back
+            m.step_back();
+            assert!(m.assert_reg(10, 1));
+            assert!(m.assert_pc(8));
+        }
+
+        #[test]
+        fn repl_breakpoints() {
+            use crate::emu::repl::Repl;
+            use crate::obj::dwarfreader::SourceLine;
+            let code = "li a0, 0\nli a1, 2\nloop:\naddi a0, a0, 1\nbne a0, a1, loop";
+            // li a0, 0; li a1, 2; addi a0, a0, 1; bne a0, a1, -4
+            let words = vec![0x00000513, 0x00200593, 0x00150513, 0xfeb51ee3];
+            let m = SimpleMachine::from_words(&words, DataEndianness::Be);
+            let lines = [(0, 1), (4, 2), (8, 4), (12, 5)]
+                .iter()
+                .map(|(address, line)| SourceLine {
+                    address: *address,
+                    file: String::from("main.s"),
+                    line: *line,
+                })
+                .collect();
+            let mut repl = Repl::new(m, vec![(String::from("loop"), 8)], lines);
+            repl.add_source("main.s", code);
+
+            let mut out = Vec::new();
+            for command in ["b loop", "c", "c", "x loop+4 1"] {
+                assert!(repl.execute(command, &mut out).unwrap());
+            }
+            assert!(!repl.execute("q", &mut out).unwrap());
+            let out = String::from_utf8(out).unwrap();
+
+            assert!(out.contains("breakpoint at 0x00000008 <loop>"));
+            assert_eq!(out.matches("breakpoint hit at 0x00000008 <loop>").count(), 2);
+            assert!(out.contains("0x00000008 <loop>: addi a0, a0, 1"));
+            assert!(out.contains("main.s:4    addi a0, a0, 1"));
+            assert!(out.contains("0x0000000c <loop+4>: 0xfeb51ee3"));
+            assert!(repl.machine().assert_reg(10, 1));
+        }
+
+        /// GDB connected to a stub which was started (in a thread) with an executable preloaded
+        struct GdbSession {
+            stream: std::os::unix::net::UnixStream,
+            stub: std::thread::JoinHandle<()>,
+            files: Vec<std::path::PathBuf>,
+        }
+
+        impl GdbSession {
+            fn start(executable: &[u8], name: &str) -> Self {
+                use crate::emu::debugger::SimpleGdbStub;
+                use crate::emu::machine::FloatExtension;
+                use crate::emu::transport::Transport;
+                let dir = std::env::temp_dir();
+                let id = std::process::id();
+                let elf = dir.join(format!("rustv-{}-{}.elf", name, id));
+                let socket = dir.join(format!("rustv-{}-{}.sock", name, id));
+                std::fs::write(&elf, executable).unwrap();
+                let stub = std::thread::spawn({
+                    let (elf, socket) = (elf.clone(), socket.clone());
+                    move || {
+                        let transport = Transport::Unix(socket);
+                        let filename = elf.to_str().unwrap();
+                        let float = FloatExtension::None;
+                        SimpleGdbStub::<SimpleMachine>::from_elf(filename, transport, float)
+                            .unwrap()
+                            .custom_gdb_event_loop_thread();
+                    }
+                });
+                // the socket can be connected to once the stub listens
+                let stream = loop {
+                    match std::os::unix::net::UnixStream::connect(&socket) {
+                        Ok(stream) => break stream,
+                        Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+                    }
+                };
+                GdbSession {
+                    stream,
+                    stub,
+                    files: vec![elf, socket],
+                }
+            }
+
+            /// Sends a packet, giving back the reply of the stub
+            fn request(&mut self, packet: &str) -> String {
+                use std::io::{Read, Write};
+                let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+                write!(self.stream, "${}#{:02x}", packet, checksum).unwrap();
+                let mut byte = [0u8; 1];
+                self.stream.read_exact(&mut byte).unwrap();
+                assert_eq!(byte[0], b'+');
+                let mut reply: Vec<u8> = Vec::new();
+                loop {
+                    self.stream.read_exact(&mut byte).unwrap();
+                    match byte[0] {
+                        b'$' => reply.clear(),
+                        b'#' => break,
+                        // run-length encoding: 'X*n' is X, repeated n - 29 times more
+                        b'*' => {
+                            let last = *reply.last().unwrap();
+                            self.stream.read_exact(&mut byte).unwrap();
+                            reply.extend(std::iter::repeat_n(last, byte[0] as usize - 29));
+                        }
+                        byte => reply.push(byte),
+                    }
+                }
+                let mut checksum = [0u8; 2];
+                self.stream.read_exact(&mut checksum).unwrap();
+                // (the stub might be gone already, after a 'D')
+                let _ = self.stream.write_all(b"+");
+                String::from_utf8(reply).unwrap()
+            }
+
+            /// Value of a register ('g' sends them in order, as little endian hex, 'pc' last)
+            fn register(&mut self, reg: usize) -> u32 {
+                let registers = self.request("g");
+                let hex = &registers[reg * 8..reg * 8 + 8];
+                u32::from_str_radix(hex, 16).unwrap().swap_bytes()
+            }
+
+            fn detach(mut self) {
+                assert_eq!(self.request("D"), "OK");
+                self.stub.join().unwrap();
+                for file in self.files {
+                    let _ = std::fs::remove_file(file);
+                }
+            }
+        }
+
+        #[test]
+        fn debugger_elf_preload() {
+            use crate::utils::assemble_code;
+            let code = "
+                _start: addi a0, zero, 5
+                        addi a1, zero, 7
+                loop:   beq zero, zero, loop
+            ";
+            let tools = assemble_code(code).unwrap();
+            let mut gdb = GdbSession::start(&executable_at(&tools, 0x8000_0000), "preload");
+
+            // the program is there, without GDB having to 'load' it
+            assert!(gdb.request("?").starts_with('T'));
+            assert_eq!(gdb.register(32), 0x8000_0000);
+            let words = encode_to_words(code);
+            let expected: String =
+                words.iter().map(|word| format!("{:08x}", word.swap_bytes())).collect();
+            assert_eq!(gdb.request("m80000000,c"), expected);
+            let map = gdb.request("qXfer:memory-map:read::0,fff");
+            assert!(map.contains("start=\"0x80000000\" length=\"0x0000000c\""));
+            gdb.detach();
+        }
+
+        #[test]
+        fn debugger_extended_restart() {
+            use crate::utils::assemble_code;
+            let code = "
+                _start: addi a0, zero, 5
+                        addi a1, zero, 7
+                loop:   beq zero, zero, loop
+            ";
+            let tools = assemble_code(code).unwrap();
+            let mut gdb = GdbSession::start(&executable_at(&tools, 0x8000_0000), "extended");
+            let program = gdb.request("m80000000,c");
+            assert_eq!(gdb.request("!"), "OK");
+
+            gdb.request("s");
+            assert_eq!(gdb.register(10), 5);
+            assert_eq!(gdb.request("M80000004,4:00000000"), "OK");
+
+            // running the program again starts over from a fresh machine
+            assert!(gdb.request("vRun;").starts_with('T'));
+            assert_eq!(gdb.register(32), 0x8000_0000);
+            assert_eq!(gdb.register(10), 0);
+            assert_eq!(gdb.request("m80000000,c"), program);
+
+            // and so does killing it
+            gdb.request("s");
+            assert_eq!(gdb.request("vKill;1"), "OK");
+            assert_eq!(gdb.register(32), 0x8000_0000);
+            assert_eq!(gdb.register(10), 0);
+            assert!(gdb.request("vRun;").starts_with('T'));
+            gdb.request("s");
+            gdb.request("s");
+            assert_eq!(gdb.register(11), 7);
+            gdb.detach();
+        }
+
+        #[test]
+        fn transport_unix_socket() {
+            use crate::emu::transport::{GdbListener, Transport};
+            use gdbstub::conn::{Connection, ConnectionExt};
+            use std::io::{Read, Write};
+            let path = std::env::temp_dir().join(format!("rustv-{}.sock", std::process::id()));
+            let mut listener = GdbListener::bind(&Transport::Unix(path.clone())).unwrap();
+
+            let client = std::thread::spawn({
+                let path = path.clone();
+                move || {
+                    let mut stream = std::os::unix::net::UnixStream::connect(path).unwrap();
+                    Write::write_all(&mut stream, b"$?#3f").unwrap();
+                    let mut reply = [0u8; 1];
+                    stream.read_exact(&mut reply).unwrap();
+                    reply[0]
+                }
+            });
+            let mut conn = listener.accept().unwrap();
+
+            // nothing is read from the connection until GDB sends something
+            let mut first = conn.try_read().unwrap();
+            while first.is_none() {
+                first = conn.try_read().unwrap();
+            }
+            assert_eq!(first, Some(b'$'));
+            assert_eq!(ConnectionExt::read(&mut conn).unwrap(), b'?');
+            Connection::write(&mut conn, b'+').unwrap();
+            assert_eq!(client.join().unwrap(), b'+');
+
+            // binding again replaces the socket left behind
+            drop(listener);
+            assert!(GdbListener::bind(&Transport::Unix(path.clone())).is_ok());
+            std::fs::remove_file(path).unwrap();
+        }
+
+        #[test]
+        fn machine_commit_log() {
+            use crate::emu::trace::{CommitLog, TraceFilter, parse_range};
+            // addi a0, zero, 5; sw a0, 16(zero); lw a1, 16(zero); sb a1, 16(zero); .word 0
+            let words = vec![0x00500513, 0x00a02823, 0x01002583, 0x00b00823, 0];
+            let mut m = SimpleMachine::from_words(&words, DataEndianness::Be);
+            let path = std::env::temp_dir().join(format!("rustv-{}.trace", std::process::id()));
+            let filter = TraceFilter {
+                pc: None,
+                icount: parse_range("1:"),
+            };
+            let log = CommitLog::to_file(path.to_str().unwrap(), filter).unwrap();
+            m.set_commit_log(Some(log));
+            for _ in 0..4 {
+                m.decode().unwrap();
+            }
+            // dropping the log flushes it
+            m.set_commit_log(None);
+
+            let trace = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(path).unwrap();
+            let lines: Vec<&str> = trace.lines().collect();
+            assert_eq!(
+                lines,
+                vec![
+                    "core   0: 0x00000004 (0x00a02823) sw      a0, 16(zero)",
+                    "core   0: 3 0x00000004 (0x00a02823) mem 0x00000010 0x00000005",
+                    "core   0: 0x00000008 (0x01002583) lw      a1, 16(zero)",
+                    "core   0: 3 0x00000008 (0x01002583) x11 0x00000005 mem 0x00000010",
+                    "core   0: 0x0000000c (0x00b00823) sb      a1, 16(zero)",
+                    "core   0: 3 0x0000000c (0x00b00823) mem 0x00000010 0x05",
+                ]
+            );
+        }
+
+        #[test]
+        fn trace_golden_examples() {
+            use crate::emu::trace::first_divergence;
+            use crate::utils::trace_program;
+            let root = env!("CARGO_MANIFEST_DIR");
+            for name in ["hello-world", "selection-sort", "vector-multiplier"] {
+                let program = format!("{}/examples/{}.s", root, name);
+                let golden = format!("{}/examples/golden/{}.log", root, name);
+                let expected = std::fs::read_to_string(golden).unwrap();
+                let trace = trace_program(&program, 1_000_000);
+                if let Some(divergence) = first_divergence(&expected, &trace, 5) {
+                    panic!("{}: {}", name, divergence);
+                }
+
+                // a single different write is reported along with the lines before it
+                let tampered = trace.replacen(" x", " x0", 1);
+                let divergence = first_divergence(&expected, &tampered, 1).unwrap();
+                assert!(divergence.line > 0 && divergence.context.len() <= 1);
+                assert_ne!(divergence.expected, divergence.actual);
+            }
+        }
+
+        #[test]
+        fn machine_htif() {
+            use crate::emu::machine::MachineState;
+            let code = [
+                "addi a0, zero, 65", // 'A'
+                "lui a1, 4112",      // device 1 (console), command 1 (putchar)
+                "sw a0, 32(zero)",
+                "sw a1, 36(zero)",
+                "addi a0, zero, 7", // exit, test 3 failed
+                "sw a0, 32(zero)",
+                "sw zero, 36(zero)",
+                "jal zero, 0",
+            ];
+            let mut words: Vec<u32> = code.iter().map(|inst| encode_to_word(inst)).collect();
+            words.extend([0, 0, 0, 0]); // tohost, fromhost
+            let mut m = SimpleMachine::from_words(&words, DataEndianness::Be);
+            m.set_htif(32, Some(40));
+            m.set_recording(true);
+            m.set_console_capture(true);
+
+            for _ in 0..4 {
+                assert_eq!(m.decode().unwrap(), MachineState::Ok);
+            }
+            // the command is carried out once its upper half is written
+            assert_eq!(m.read_memory_word(36), 0x01010000);
+            assert_eq!(m.decode().unwrap(), MachineState::Ok);
+            assert_eq!(m.read_memory_words(32, 4), vec![0, 0, 0x141, 0x01010000]);
+            assert!(m.assert_reg(10, 7));
+            assert_eq!(m.take_console_output(), b"A");
+
+            // the writes of the host are undone along with the store which triggered them, and
+            // replaying the store doesn't print the character again
+            m.step_back();
+            m.step_back();
+            assert_eq!(m.read_memory_words(32, 4), vec![65, 0, 0, 0]);
+            for _ in 0..2 {
+                assert_eq!(m.decode().unwrap(), MachineState::Ok);
+            }
+            assert_eq!(m.read_memory_words(32, 4), vec![0, 0, 0x141, 0x01010000]);
+            assert!(m.take_console_output().is_empty());
+
+            for _ in 0..2 {
+                assert_eq!(m.decode().unwrap(), MachineState::Ok);
+            }
+            assert_eq!(m.decode().unwrap(), MachineState::Exit(3));
+            assert!(m.assert_pc(28));
+        }
+
+        /// ELF executable holding the allocated sections of 'tools' (and its symbols), moved from 0
+        /// to 'start' as a linker would place them, so only code which refers to its addresses
+        /// relative to the pc (i.e: 'la') keeps working
+        fn executable_at(tools: &AssemblerTools, start: usize) -> Vec<u8> {
+            use object::elf;
+            use object::write::elf::{FileHeader, SectionHeader, Sym, Writer};
+
+            let mut sections: Vec<_> =
+                tools.sections.values().filter(|section| section.flags.alloc).collect();
+            sections.sort_by_key(|section| section.address);
+            let names: Vec<String> =
+                sections.iter().map(|section| section.name.default_name()).collect();
+            let contents: Vec<Vec<u8>> = sections
+                .iter()
+                .map(|section| utils::words_to_bytes_le(&tools.section_words(section.name.clone())))
+                .collect();
+            let symbols: Vec<(&String, usize, usize)> = tools
+                .symbols
+                .iter()
+                .filter_map(|(name, symbol)| {
+                    let idx = sections.iter().position(|section| section.name == symbol.section)?;
+                    Some((name, idx, start + sections[idx].address + symbol.relative_address))
+                })
+                .collect();
+
+            let mut data = Vec::new();
+            let mut writer = Writer::new(object::Endianness::Little, false, &mut data);
+            writer.reserve_file_header();
+            let headers: Vec<_> = names
+                .iter()
+                .zip(&contents)
+                .map(|(name, bytes)| {
+                    let name = writer.add_section_name(name.as_bytes());
+                    (name, writer.reserve_section_index(), writer.reserve(bytes.len(), 4))
+                })
+                .collect();
+            writer.reserve_null_symbol_index();
+            let symbol_names: Vec<_> = symbols
+                .iter()
+                .map(|(name, idx, _)| {
+                    writer.reserve_symbol_index(Some(headers[*idx].1));
+                    writer.add_string(name.as_bytes())
+                })
+                .collect();
+            writer.reserve_symtab_section_index();
+            writer.reserve_symtab();
+            writer.reserve_strtab_section_index();
+            writer.reserve_strtab();
+            writer.reserve_shstrtab_section_index();
+            writer.reserve_shstrtab();
+            writer.reserve_section_headers();
+
+            let header = FileHeader {
+                os_abi: 0,
+                abi_version: 0,
+                e_type: elf::ET_EXEC,
+                e_machine: elf::EM_RISCV,
+                e_entry: start as u64,
+                e_flags: 0,
+            };
+            writer.write_file_header(&header).unwrap();
+            for bytes in &contents {
+                writer.write_align(4);
+                writer.write(bytes);
+            }
+            writer.write_null_symbol();
+            for ((_, idx, address), name) in symbols.iter().zip(symbol_names) {
+                writer.write_symbol(&Sym {
+                    name: Some(name),
+                    section: Some(headers[*idx].1),
+                    st_info: (elf::STB_GLOBAL << 4) | elf::STT_NOTYPE,
+                    st_other: elf::STV_DEFAULT,
+                    st_shndx: 0,
+                    st_value: *address as u64,
+                    st_size: 0,
+                });
+            }
+            writer.write_strtab();
+            writer.write_shstrtab();
+            writer.write_null_section_header();
+            let placed = sections.iter().zip(&contents).zip(&headers);
+            for ((section, bytes), (name, _, offset)) in placed {
+                let sh_flags = [
+                    (true, elf::SHF_ALLOC),
+                    (section.flags.write, elf::SHF_WRITE),
+                    (section.flags.exec, elf::SHF_EXECINSTR),
+                ]
+                .into_iter()
+                .filter(|(set, _)| *set)
+                .fold(0, |sh_flags, (_, flag)| sh_flags | u64::from(flag));
+                writer.write_section_header(&SectionHeader {
+                    name: Some(*name),
+                    sh_type: elf::SHT_PROGBITS,
+                    sh_flags,
+                    sh_addr: (start + section.address) as u64,
+                    sh_offset: *offset as u64,
+                    sh_size: bytes.len() as u64,
+                    sh_link: 0,
+                    sh_info: 0,
+                    sh_addralign: 4,
+                    sh_entsize: 0,
+                });
+            }
+            writer.write_symtab_section_header(1);
+            writer.write_strtab_section_header();
+            writer.write_shstrtab_section_header();
+            data
+        }
+
+        #[test]
+        fn machine_htif_elf() {
+            use crate::emu::cpu::CSR_MEPC;
+            use crate::emu::machine::MachineState;
+            use crate::utils::assemble_code;
+            let code = "
+                        .text
+                _start: la t0, trap
+                        .word 0x34129073 // csrw mepc, t0
+                        .word 0x30200073 // mret
+                        addi a0, zero, 1
+                trap:   la t1, tohost
+                        addi t2, zero, 7 // exit, test 3 failed
+                        sw t2, 0(t1)
+                        sw zero, 4(t1)
+                loop:   beq zero, zero, loop
+                        .data
+                tohost: .word 0, 0
+                fromhost: .word 0, 0
+            ";
+            let tools = assemble_code(code).unwrap();
+            let trap = 0x8000_0000 + tools.symbols["trap"].relative_address;
+            let data = executable_at(&tools, 0x8000_0000);
+            let reader = ElfReader::new(&data, DataEndianness::Le).unwrap();
+            let mut m: SimpleMachine = utils::load_elf(&reader);
+            // only the pages of the program (and the stack) are there
+            assert_eq!(m.memory_start(), 0x8000_0000);
+            assert!(m.bytes_count() < 2 * utils::STACK_SIZE);
+            assert!(m.assert_reg(2, (0x8000_0000 + m.bytes_count()) as u32));
+            assert_eq!(m.tohost(), reader.symbol_address("tohost"));
+            assert!(m.assert_pc(0x8000_0000));
+
+            m.set_recording(true);
+            let mut state = m.decode().unwrap();
+            while state == MachineState::Ok {
+                state = m.decode().unwrap();
+            }
+            assert_eq!(state, MachineState::Exit(3));
+            assert!(m.assert_reg(10, 0));
+            assert_eq!(m.read_csr(CSR_MEPC), trap as u32);
+
+            // CSR writes are undone when stepping back
+            while m.step_back().is_some() {}
+            assert!(m.assert_pc(0x8000_0000));
+            assert_eq!(m.read_csr(CSR_MEPC), 0);
+        }
+
+        #[test]
+        fn machine_signature_dump() {
+            use crate::utils::write_signature;
+            let words = vec![0x0000006f, 0xdeadbeef, 0x00000001, 0x00000000];
+            let m = SimpleMachine::from_words(&words, DataEndianness::Be);
+            let mut out = Vec::new();
+            write_signature(&m, 4..16, &mut out).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), "deadbeef\n00000001\n00000000\n");
+        }
+
+        #[test]
+        fn machine_signature_elf() {
+            use crate::emu::machine::MachineState;
+            use crate::utils::{assemble_code, signature_range, write_signature};
+            let code = "
+                        .text
+                _start: la t0, begin_signature
+                        lui t1, 0xdeadc
+                        addi t1, t1, -0x111
+                        sw t1, 0(t0)
+                        addi t1, zero, 1
+                        sw t1, 4(t0)
+                        la t0, tohost
+                        addi t1, zero, 1 // exit, passed
+                        sw t1, 0(t0)
+                        sw zero, 4(t0)
+                loop:   beq zero, zero, loop
+                        .data
+                tohost: .word 0, 0
+                begin_signature: .word 0, 0, 0xffffffff
+                end_signature:
+            ";
+            let tools = assemble_code(code).unwrap();
+            let data = executable_at(&tools, 0x8000_0000);
+            let path = std::env::temp_dir().join(format!("rustv-{}.elf", std::process::id()));
+            std::fs::write(&path, &data).unwrap();
+            let range = signature_range(path.to_str().unwrap());
+            std::fs::remove_file(&path).unwrap();
+
+            // the signature lies at the addresses the program was linked at
+            let range = range.unwrap();
+            let data_start = 0x8000_0000 + tools.sections[".data"].address;
+            let begin = data_start + tools.symbols["begin_signature"].relative_address;
+            assert_eq!(range, begin..begin + 12);
+
+            let reader = ElfReader::new(&data, DataEndianness::Le).unwrap();
+            let mut m: SimpleMachine = utils::load_elf(&reader);
+            let mut state = m.decode().unwrap();
+            while state == MachineState::Ok {
+                state = m.decode().unwrap();
+            }
+            assert_eq!(state, MachineState::Exit(0));
+            let mut out = Vec::new();
+            write_signature(&m, range, &mut out).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), "deadbeef\n00000001\nffffffff\n");
+        }
+
+        #[test]
+        fn assembler_diagnostics() {
+            use crate::diagnostics::render;
+            use crate::utils::assemble_code;
+
+            let code = "
+                _start:
+                    addi a0, zero, 0x
+                    beq a0, zero, nowhere
+                    li a0, 1 # comment
+                    addd a1, a2, a3
+                loop: addi a1, a2
+                    j loop
+            ";
+            let errors = assemble_code(code).unwrap_err();
+            let mut found: Vec<(usize, String)> = errors
+                .iter()
+                .map(|d| (d.span.row() + 1, d.message.clone()))
+                .collect();
+            found.sort();
+            let expected = [
+                (3, "invalid number '0x'"),
+                (4, "unknown symbol 'nowhere'"),
+                (5, "unexpected character '#'"),
+                (6, "unknown instruction 'addd'"),
+                (7, "'addi' expects 3 arguments (rd, rs1, imm), got 2"),
+                (8, "unknown instruction 'j'"),
+            ]
+            .map(|(row, msg)| (row, msg.to_string()));
+            assert_eq!(found, expected);
+
+            let report = render(&errors, "test.s", code);
+            assert!(report.contains("error: unknown symbol 'nowhere'\n --> test.s:4:21\n"));
+            assert!(report.contains("4 |                     beq a0, zero, nowhere\n  |                     ^^^^^^^^^^^^^^^^^^^^^\n"));
+            assert!(report.ends_with("could not assemble 'test.s' due to 6 previous errors\n"));
+
+            // errors of every stage are found at once, each of them reported once per statement,
+            // and the labels of wrong statements are still there
+            let code = "
+                    .macro twice r
+                    addi \\r, \\r, 1
+                    addi \\r, \\r, 1
+                    .endm
+                    twice a0
+                    beq zero, zero, 1f
+                    .if missing
+                    .endif
+                    addi a0, a0, $
+                kept: addi a0, a0, 0x100000000
+                    la a1, nowhere
+                    beq a0, zero, kept
+            ";
+            let errors = assemble_code(code).unwrap_err();
+            let mut found: Vec<(usize, String)> = errors
+                .iter()
+                .map(|d| (d.span.row() + 1, d.message.clone()))
+                .collect();
+            found.sort();
+            let expected = [
+                (7, "undefined local label '1f'"),
+                (8, "'missing' is not defined"),
+                (10, "unexpected character '$'"),
+                (11, "value 4294967296 doesn't fit in 32 bits"),
+                (12, "unknown symbol 'nowhere'"),
+            ]
+            .map(|(row, msg)| (row, msg.to_string()));
+            assert_eq!(found, expected);
+        }
+
+        #[test]
+        fn assembler_argument_diagnostics() {
+            use crate::utils::assemble_code;
+
+            let code = "
+                    addi a0, zero, 3 4
+                    li a0, 5 5
+                    li a0, 5, 5
+                    nop a0
+                    li a1, 1 << 64
+                    li a1, 1 >> -1
+                    li a2, 'ab'
+                    li a2, '
+                    li a2, 'a
+                    .byte 1 2
+            ";
+            let errors = assemble_code(code).unwrap_err();
+            let mut found: Vec<(usize, String)> = errors
+                .iter()
+                .map(|d| (d.span.row() + 1, d.message.clone()))
+                .collect();
+            found.sort();
+            let expected = [
+                (2, "expected ',' between arguments"),
+                (3, "expected ',' between arguments"),
+                (4, "invalid arguments for 'li': expected 'rd, imm'"),
+                (5, "invalid arguments for 'nop': expected no arguments"),
+                (6, "invalid shift count 64"),
+                (7, "invalid shift count -1"),
+                (8, "invalid char literal 'ab'"),
+                (9, "invalid char literal '"),
+                (10, "invalid char literal 'a"),
+                (11, "expected ',' between arguments"),
+            ]
+            .map(|(row, msg)| (row, msg.to_string()));
+            assert_eq!(found, expected);
+
+            // a sign after a value still makes a sum
+            let code = "
+                    .data
+                values: .word 5 -1, 5 +1, values -values
+            ";
+            let tools = assemble_code(code).unwrap();
+            assert_eq!(tools.section_words(SectionName::Data), vec![4, 6, 0]);
+        }
+
+        #[test]
+        fn hostio_file_io_protocol() {
+            use crate::emu::hostio::{
+                MemoryPacket, Sandbox, open_flags_to_gdb, parse_memory_packet, parse_reply,
+                request_packet,
+            };
+            // O_WRONLY | O_CREAT | O_TRUNC
+            assert_eq!(open_flags_to_gdb(0o1101), 0x601);
+            assert_eq!(
+                request_packet(56, [0, 0x100, 0o1101, 0o644], 6).unwrap(),
+                "Fopen,100/6,601,1a4"
+            );
+            assert_eq!(
+                request_packet(64, [1, 0x200, 4, 0], 0).unwrap(),
+                "Fwrite,1,200,4"
+            );
+            assert!(request_packet(93, [0; 4], 0).is_none());
+
+            assert_eq!(parse_reply(b"F4"), Some(4));
+            assert_eq!(parse_reply(b"F-1,2"), Some(-2i32 as u32));
+            assert_eq!(parse_reply(b"F-1,5b"), Some(-36i32 as u32));
+            assert_eq!(parse_reply(b"m0,4"), None);
+
+            let Some(MemoryPacket::Write { addr, data }) = parse_memory_packet(b"X10,2:}]a") else {
+                panic!("failed parsing X packet");
+            };
+            assert_eq!((addr, data), (0x10, vec![b'}', b'a']));
+            let Some(MemoryPacket::Read { addr, len }) = parse_memory_packet(b"m20,8") else {
+                panic!("failed parsing m packet");
+            };
+            assert_eq!((addr, len), (0x20, 8));
+
+            let dir = std::env::temp_dir().join(format!("rustv-sandbox-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("dir")).unwrap();
+            std::os::unix::fs::symlink("/", dir.join("escape")).unwrap();
+            std::os::unix::fs::symlink("/nonexistent", dir.join("dangling")).unwrap();
+            std::os::unix::fs::symlink("dir", dir.join("inside")).unwrap();
+            let root = dir.canonicalize().unwrap();
+            let sandbox = Sandbox::new(dir.clone());
+            assert_eq!(sandbox.resolve(b"/dir/file"), Some(root.join("dir/file")));
+            assert_eq!(sandbox.resolve(b"inside/file"), Some(root.join("dir/file")));
+            assert_eq!(sandbox.resolve(b"dir/../../file"), None);
+            assert_eq!(sandbox.resolve(b"escape/etc/passwd"), None);
+            assert_eq!(sandbox.resolve(b"escape"), None);
+            assert_eq!(sandbox.resolve(b"dangling"), None);
+            assert_eq!(sandbox.resolve(b"missing/file"), None);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn machine_snapshot_and_icount() {
+            let code = "
+                li t1, 7
+                sw t1, 32(x0)
+                addi t1, t1, 1
+            ";
+            let mut words = encode_to_words(code);
+            words.resize(16, 0);
+            let mut m = SimpleMachine::from_words(&words, DataEndianness::Be);
+            m.set_recording(true);
+
+            m.decode().unwrap();
+            let snapshot = m.snapshot();
+            m.decode().unwrap();
+            m.decode().unwrap();
+            assert_eq!(m.instruction_count(), 3);
+            assert!(m.assert_reg(6, 8));
+            assert!(m.assert_memory_words(32, 1, &[7]));
+
+            m.step_back();
+            assert_eq!(m.instruction_count(), 2);
+
+            m.restore(&snapshot);
+            assert_eq!(m.instruction_count(), 1);
+            assert!(m.assert_reg(6, 7));
+            assert!(m.assert_pc(4));
+            assert!(m.assert_memory_words(32, 1, &[0]));
+            assert_eq!(m.history_len(), 0);
+
+            m.reset();
+            assert_eq!(m.instruction_count(), 0);
+            assert!(m.assert_reg(6, 0));
+            assert!(m.assert_pc(0));
+            assert_eq!(m.read_memory_word(0), words[0]);
+        }
+
         // Test ISA
         // TODO: test more complex cases (negative offsets, sections in different orders than the
         // usual, jumps to non-existing labels, ...)
@@ -818,7 +1879,7 @@ mod tests {
         fn isa_rvi32_sb() {
             let code = "
                 .section .data
-                var1: .byte 0x4 0xa
+                var1: .byte 0x4, 0xa
                 // var2: .byte 0xa
 
                 .section .text
@@ -831,9 +1892,9 @@ mod tests {
                 .sections
                 .get(".data")
                 .expect("missing start address for data section");
-            let start_addr = data_section.address + 1;
-            let expected = vec![100u8];
-            assert!(m.assert_memory_bytes(start_addr, expected.len(), &expected, 1));
+            let start_addr = data_section.address;
+            assert_eq!(m.read_memory_byte(start_addr), 0x4);
+            assert_eq!(m.read_memory_byte(start_addr + 1), 100);
         }
 
         // OBS: LW syntax is rd, off, rs
@@ -864,7 +1925,7 @@ mod tests {
                 var1: .byte -0x1
                 .section .text
                     la t1, var1
-                    lb t2, 3(t1)
+                    lb t2, 0(t1)
             ";
             let (m, _) = isa_rvi32_mach(code);
             let reg = Register::T2.id() as usize;
@@ -1249,6 +2310,783 @@ mod tests {
             assert_eq!(regs, vec![3 * f, 5 * f, 10 * f]);
         }
 
+        #[test]
+        fn program_expressions() {
+            use crate::utils::assemble_code;
+
+            let code = "
+                        .section .text
+                _start:
+                        addi a0, zero, (1 + 2) * 3
+                        li a1, 'A' + 1
+                        li a2, 1 << 4 | 3
+                        li a3, -(~0x0f & 0xff) / 2
+                        beq zero, zero, . + 8
+                        li a0, 0
+                        la t0, table
+                        lw a4, 4*1(t0)
+                        lw a5, 2*4(t0)
+                        li a6, end - table
+                        li s2, (end - table) * 0x1000 - 1
+                        li s3, 0x12345801
+                        li a7, 93
+                        ecall
+
+                        .section .data
+                table: .word end - table, 'x', 0x10 % 3
+                end:
+            ";
+            let (m, t) = isa_rvi32_mach_until_exit(code);
+            assert!(m.assert_reg(Register::A0.id().into(), 9));
+            assert!(m.assert_reg(Register::A1.id().into(), 66));
+            assert!(m.assert_reg(Register::A2.id().into(), 19));
+            assert!(m.assert_reg(Register::A3.id().into(), -120i32 as u32));
+            assert!(m.assert_reg(Register::A4.id().into(), 'x' as u32));
+            assert!(m.assert_reg(Register::A5.id().into(), 1));
+            assert!(m.assert_reg(Register::A6.id().into(), 12));
+            assert!(m.assert_reg(Register::S2.id().into(), 0xbfff));
+            assert!(m.assert_reg(Register::S3.id().into(), 0x12345801));
+            let varaddr = t.sections.get(".data").unwrap().address;
+            assert_eq!(m.read_memory_words(varaddr, 1), vec![12]);
+
+            let code = "
+                _start: li a0, end + _start
+                        li a1, missing - _start
+                end:
+            ";
+            let errors = assemble_code(code).unwrap_err();
+            let found: Vec<(usize, &str)> =
+                errors.iter().map(|d| (d.span.row(), d.message.as_str())).collect();
+            assert_eq!(
+                found,
+                [
+                    (
+                        1,
+                        "'li' needs a constant, such as the difference of two symbols in the same \
+                         section (addresses are loaded with 'la')",
+                    ),
+                    (2, "unknown symbol 'missing'"),
+                ]
+            );
+        }
+
+        #[test]
+        fn program_constants() {
+            let code = "
+                .equ SYS_EXIT, 93
+                .set STATUS, 3
+                WORDS = 2 * 2
+                        .section .text
+                _start:
+                        li a0, STATUS + FORWARD
+                        addi a1, zero, WORDS
+                        la t0, table
+                        lw a2, WORDS(t0)
+                        addi a3, zero, LEN
+                        li a7, SYS_EXIT
+                        ecall
+                .equ FORWARD, 1
+                .set STATUS, 0
+                        .section .data
+                table: .word WORDS, SYS_EXIT, LEN
+                end:
+                .equ LEN, end - table
+                .equ SECOND, table + 4
+            ";
+            let (m, t) = isa_rvi32_mach_until_exit(code);
+            assert!(m.assert_reg(Register::A0.id().into(), 4));
+            assert!(m.assert_reg(Register::A1.id().into(), 4));
+            assert!(m.assert_reg(Register::A2.id().into(), 93));
+            assert!(m.assert_reg(Register::A3.id().into(), 12));
+            let varaddr = t.sections.get(".data").unwrap().address;
+            assert_eq!(m.read_memory_words(varaddr, 3), vec![4, 93, 12]);
+
+            let symbol = |name: &str| {
+                let symbol = t.symbols.get(name).unwrap();
+                (symbol.section.clone(), symbol.relative_address)
+            };
+            assert_eq!(symbol("SYS_EXIT"), (SectionName::Absolute, 93));
+            assert_eq!(symbol("STATUS"), (SectionName::Absolute, 0));
+            assert_eq!(symbol("LEN"), (SectionName::Absolute, 12));
+            assert_eq!(symbol("SECOND"), (SectionName::Data, 4));
+        }
+
+        #[test]
+        fn program_macros() {
+            use crate::utils::assemble_code;
+
+            let code = r"
+                .macro push reg, off=0
+                        sw \reg, \off(t0)
+                .endm
+                .macro set reg:req, value, rest:vararg
+                        li \reg, \value
+                        .exitm
+                        li \reg, 99
+                .endm
+                        .section .text
+                _start:
+                        la t0, buffer
+                        li a0, 7
+                        push a0, 4
+                first:  push reg=a0
+                        set a1, 5, ignored, too
+                        .rept 3
+                        addi a2, a2, 1
+                        .endr
+                        .irp r, a3, a4
+                        addi \r, zero, 2
+                        .endr
+                        .irpc n, 123
+                        addi a5, a5, \n
+                        .endr
+                .macro skip
+                        beq zero, zero, label\@
+                        li a6, 1
+                label\@:
+                .endm
+                        skip
+                        lw a7, 4(t0)
+                        add a0, a0, a7
+                        li a7, 93
+                        ecall
+                        .section .data
+                buffer: .word 0, 0
+            ";
+            let (m, t) = isa_rvi32_mach_until_exit(code);
+            assert!(m.assert_reg(Register::A0.id().into(), 14));
+            assert!(m.assert_reg(Register::A1.id().into(), 5));
+            assert!(m.assert_reg(Register::A2.id().into(), 3));
+            assert!(m.assert_reg(Register::A3.id().into(), 2));
+            assert!(m.assert_reg(Register::A4.id().into(), 2));
+            assert!(m.assert_reg(Register::A5.id().into(), 6));
+            assert!(m.assert_reg(Register::A6.id().into(), 0));
+            assert!(t.symbols.contains_key("first"));
+            assert!(t.symbols.contains_key("label3"));
+
+            // errors in expanded statements point to the invocation
+            let code = r"
+                .macro bad
+                        addi a0, a0
+                .endm
+                _start:
+                        bad
+                        .rept 2
+                        addd a0, a0, a0
+                        .endr
+                        bad 1
+                        .rept 100000000
+                        nop
+                        .endr
+                .endm
+            ";
+            let errors = assemble_code(code).unwrap_err();
+            let mut found: Vec<(usize, String)> = errors
+                .iter()
+                .map(|d| (d.span.row() + 1, d.message.clone()))
+                .collect();
+            found.sort();
+            let expected = [
+                (6, "'addi' expects 3 arguments (rd, rs1, imm), got 2"),
+                (8, "unknown instruction 'addd'"),
+                (8, "unknown instruction 'addd'"),
+                (10, "too many arguments for macro 'bad'"),
+                (11, "'.rept' would expand to more than 1000000 statements"),
+                (14, "'.endm' without '.macro'"),
+            ]
+            .map(|(row, msg)| (row, msg.to_string()));
+            assert_eq!(found, expected);
+        }
+
+        #[test]
+        fn program_conditionals() {
+            use crate::utils::{assemble_code, assemble_code_with_defines};
+
+            let code = r"
+                .equ WITH_M, 1
+                .macro count n
+                    .if \n
+                        addi a5, a5, 1
+                    .endif
+                .endm
+                        .section .text
+                _start:
+                .if WITH_M == 1 && DEBUG
+                        li a0, 1
+                .else
+                        li a0, 2
+                .endif
+                .ifdef WITH_M
+                        li a1, 3
+                .endif
+                .ifndef _start
+                        this isn't even # valid $ syntax
+                .else
+                    .if 0
+                        .macro unfinished
+                    .else
+                        li a2, 4
+                    .endif
+                .endif
+                .if LEVEL > 2
+                        li a3, 5
+                .endif
+                        li a4, LEVEL
+                        count 1
+                        count 0
+                        count LEVEL
+                        li a7, 93
+                        ecall
+            ";
+            let defines = [(String::from("DEBUG"), 1), (String::from("LEVEL"), 3)];
+            let tools = assemble_code_with_defines(code, &defines).unwrap();
+            let mut m = new_machine_from_tools(&tools);
+            while let Ok(emu::machine::MachineState::Ok) = m.decode() {}
+            assert!(m.assert_reg(Register::A0.id().into(), 1));
+            assert!(m.assert_reg(Register::A1.id().into(), 3));
+            assert!(m.assert_reg(Register::A2.id().into(), 4));
+            assert!(m.assert_reg(Register::A3.id().into(), 5));
+            assert!(m.assert_reg(Register::A4.id().into(), 3));
+            assert!(m.assert_reg(Register::A5.id().into(), 2));
+            assert_eq!(tools.symbols.get("LEVEL").unwrap().section, SectionName::Absolute);
+
+            let errors = assemble_code(code).unwrap_err();
+            let mut found: Vec<(usize, String)> = errors
+                .iter()
+                .map(|d| (d.span.row() + 1, d.message.clone()))
+                .collect();
+            found.sort();
+            let expected = [
+                (10, "'DEBUG' is not defined"),
+                (27, "'LEVEL' is not defined"),
+                (30, "invalid arguments for 'li': expected 'rd, imm'"),
+                (33, "'LEVEL' is not defined"),
+            ]
+            .map(|(row, msg)| (row, msg.to_string()));
+            assert_eq!(found, expected);
+        }
+
+        #[test]
+        fn program_includes() {
+            use crate::diagnostics::render_sources;
+            use crate::obj::dwarfreader::read_line_table;
+            use crate::sources::SourceMap;
+            use crate::utils::{AssemblyOptions, assemble_sources, save_elf_with_debug};
+
+            let dir = std::env::temp_dir().join(format!("rustv-include-{}", std::process::id()));
+            let write = |name: &str, code: &str| {
+                let path = dir.join(name);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, code).unwrap();
+                path.to_string_lossy().to_string()
+            };
+            let main = write(
+                "main.s",
+                ".include \"defs.s\"\n\
+                 .section .text\n\
+                 _start:\n\
+                 li a0, ANSWER\n\
+                 set_a1\n\
+                 li a7, 93\n\
+                 ecall\n",
+            );
+            write(
+                "lib/defs.s",
+                ".equ ANSWER, 42\n\
+                 .macro set_a1\n\
+                 addi a1, zero, 7\n\
+                 .endm\n\
+                 .include \"more.s\"\n",
+            );
+            write("lib/more.s", "\n.equ MORE, 1\n");
+            let second = write("second.s", ".section .text\nhelper: addi a2, zero, 3\nret\n");
+
+            // files are looked for next to the including one and then in the include dirs
+            let mut sources = SourceMap::default();
+            sources.load(&main).unwrap();
+            sources.load(&second).unwrap();
+            let errors = assemble_sources(&mut sources, &AssemblyOptions::default()).unwrap_err();
+            let report = render_sources(&errors, &sources);
+            assert!(report.contains("error: can't find 'defs.s'\n"));
+
+            let options = AssemblyOptions {
+                include_dirs: vec![dir.join("lib").to_string_lossy().to_string()],
+                ..Default::default()
+            };
+            let tools = assemble_sources(&mut sources, &options).unwrap();
+            let names: Vec<&str> = sources.files().iter().map(|s| s.name.as_str()).collect();
+            assert_eq!(names.len(), 4);
+            assert!(names[2].ends_with("defs.s") && names[3].ends_with("more.s"));
+            assert!(tools.symbols.contains_key("MORE") && tools.symbols.contains_key("helper"));
+
+            let mut m = new_machine_from_tools(&tools);
+            while let Ok(emu::machine::MachineState::Ok) = m.decode() {}
+            assert!(m.assert_reg(Register::A0.id().into(), 42));
+            assert!(m.assert_reg(Register::A1.id().into(), 7));
+
+            // line info of the instructions points to the files they come from
+            let objectfile = dir.join("main.o").to_string_lossy().to_string();
+            save_elf_with_debug(tools, &sources, &objectfile).unwrap();
+            let lines = read_line_table(&std::fs::read(&objectfile).unwrap()).unwrap();
+            let lines: Vec<(String, usize)> = lines.into_iter().map(|l| (l.file, l.line)).collect();
+            assert!(lines.contains(&(main.clone(), 4)));
+            assert!(lines.contains(&(second.clone(), 2)));
+
+            // errors in included files point to them, and include cycles are reported
+            write("lib/more.s", "\n.include \"defs.s\"\n");
+            let mut sources = SourceMap::default();
+            sources.load(&main).unwrap();
+            let errors = assemble_sources(&mut sources, &options).unwrap_err();
+            let report = render_sources(&errors, &sources);
+            let more = sources.files()[2].name.clone();
+            assert!(more.ends_with("more.s"));
+            let expected = format!("error: 'defs.s' is included recursively\n --> {}:2:1\n", more);
+            assert!(report.contains(&expected));
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn program_local_labels() {
+            use crate::obj::elfreader::ElfReader;
+            use crate::preprocessor::LOCAL_LABEL_MARK;
+            use crate::utils::{assemble_code, save_elf};
+
+            let code = r"
+                .macro countdown reg, n
+                        li \reg, \n
+                1:      addi \reg, \reg, -1
+                        bne \reg, zero, 1b
+                .endm
+                        .section .text
+                _start:
+                        li a0, 0
+                        li t0, 3
+                1:      addi a0, a0, 2
+                        addi t0, t0, -1
+                        bne t0, zero, 1b
+                        beq zero, zero, 1f
+                        li a0, 99
+                1:      countdown a1, 4
+                        countdown a2, 5
+                        beq zero, zero, .Lskip
+                        li a3, 99
+                .Lskip: la t1, .Lvalue
+                        lw a3, 0(t1)
+                        li a7, 93
+                        ecall
+                        .section .data
+                .Lvalue: .word 7
+            ";
+            let tools = assemble_code(code).unwrap();
+            let mut m = new_machine_from_tools(&tools);
+            while let Ok(emu::machine::MachineState::Ok) = m.decode() {}
+            assert!(m.assert_reg(Register::A0.id().into(), 6));
+            assert!(m.assert_reg(Register::A1.id().into(), 0));
+            assert!(m.assert_reg(Register::A2.id().into(), 0));
+            assert!(m.assert_reg(Register::A3.id().into(), 7));
+            // the third '1:' comes from the first expansion of 'countdown'
+            assert!(tools.symbols.contains_key(&format!(".L1{}3", LOCAL_LABEL_MARK)));
+
+            // local symbols are left out of the symbol table
+            let filename = "test_local_labels.o";
+            save_elf(tools, filename).unwrap();
+            let data = std::fs::read(filename);
+            assert!(std::fs::remove_file(filename).is_ok());
+            let data = data.unwrap();
+            let reader = ElfReader::new(&data, DataEndianness::Le).unwrap();
+            let names: Vec<String> = reader.symbol_addresses().into_iter().map(|s| s.0).collect();
+            assert!(names.contains(&String::from("_start")));
+            assert!(!names.iter().any(|name| name == ".Lskip" || name == ".Lvalue"));
+            assert!(!names.iter().any(|name| name.contains(LOCAL_LABEL_MARK)));
+
+            let code = "
+                1:  beq zero, zero, 2f
+                    bne a0, zero, 1b
+                    bne a0, zero, 3b
+            ";
+            let errors = assemble_code(code).unwrap_err();
+            let found: Vec<(usize, &str)> =
+                errors.iter().map(|d| (d.span.row(), d.message.as_str())).collect();
+            assert_eq!(
+                found,
+                [(1, "undefined local label '2f'"), (3, "undefined local label '3b'")]
+            );
+        }
+
+        #[test]
+        fn program_data_directives() {
+            use crate::obj::elfreader::ElfReader;
+            use crate::utils::{assemble_code, save_elf};
+            use object::{Object, ObjectSection};
+
+            let code = r#"
+                        .section .text
+                _start:
+                        la t0, values
+                        .p2align 3
+                        lw a0, 76(t0)
+                        lw a1, 80(t0)
+                        addi a2, t0, 4
+                        li a7, 93
+                        ecall
+                        .section .data
+                values: .byte 1, 2, 3, 4
+                        .half 0x0605, 0x0807
+                        .2byte 0x0a09
+                        .balign 4, 0xff
+                        .4byte 0x11223344
+                        .long -1
+                        .p2align 3
+                        .dword -2
+                        .8byte 1
+                        .zero 2
+                        .space 2, 7
+                        .fill 2, 2, 0x0102
+                        .float 1.5
+                        .p2align 3
+                        .double -2.0
+                        .asciz "a\tb\n\x41\101"
+                        .string "c"
+                        .ascii "de"
+                        .align 3, 0, 2
+                        .balign 4
+                        .word values + 4, end - values
+                end:
+            "#;
+            let tools = assemble_code(code).unwrap();
+            let address = tools.sections[".data"].address as u32 + 4;
+            let mut m = new_machine_from_tools(&tools);
+            while let Ok(emu::machine::MachineState::Ok) = m.decode() {}
+            assert!(m.assert_reg(Register::A0.id().into(), address));
+            assert!(m.assert_reg(Register::A1.id().into(), 84));
+            assert!(m.assert_reg(Register::A2.id().into(), address));
+
+            let filename = "test_data_directives.o";
+            save_elf(tools, filename).unwrap();
+            let data = std::fs::read(filename);
+            assert!(std::fs::remove_file(filename).is_ok());
+            let data = data.unwrap();
+            let reader = ElfReader::new(&data, DataEndianness::Le).unwrap();
+            let section = reader.section(".data").unwrap();
+            assert_eq!(section.align, 8);
+            #[rustfmt::skip]
+            let expected: [u8; 76] = [
+                1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 0xff, 0xff,
+                0x44, 0x33, 0x22, 0x11, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0,
+                0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 1, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 7, 7, 2, 1, 2, 1, 0, 0, 0xc0, 0x3f, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0xc0,
+                b'a', b'\t', b'b', b'\n', b'A', b'A', 0, b'c', 0, b'd', b'e', 0,
+            ];
+            assert_eq!(section.data[..76], expected);
+            assert_eq!(section.data[80..84], [84, 0, 0, 0]);
+
+            // addresses held by data are relocated
+            let elf = object::File::parse(&*data).unwrap();
+            let relocations: Vec<_> = elf
+                .section_by_name(".data")
+                .unwrap()
+                .relocations()
+                .map(|(offset, rel)| (offset, rel.flags(), rel.addend()))
+                .collect();
+            let r_type = object::elf::R_RISCV_32;
+            let flags = object::RelocationFlags::Elf { r_type };
+            assert_eq!(relocations, [(76, flags, 4)]);
+
+            let code = r#"
+                    .section .data
+                    .balign 3
+                    .align x
+                    .ascii 1
+                    .byte values
+                    .skip 100000000000
+                    .zero 0x1000001
+                    .fill 0x1000000, 8, 1
+                values:
+            "#;
+            let errors = assemble_code(code).unwrap_err();
+            let found: Vec<(usize, &str)> =
+                errors.iter().map(|d| (d.span.row(), d.message.as_str())).collect();
+            assert_eq!(
+                found,
+                [
+                    (2, "'.balign' expects a power of 2 (up to 65536)"),
+                    (3, "expected a constant expression"),
+                    (4, "'.ascii' expects strings"),
+                    (6, "'.skip' can't reserve more than 16777216 bytes"),
+                    (7, "'.zero' can't reserve more than 16777216 bytes"),
+                    (8, "'.fill' can't reserve more than 16777216 bytes"),
+                    (
+                        5,
+                        "data can only hold constants, differences of symbols and (32 bit) addresses",
+                    ),
+                ]
+            );
+
+            // numbers are checked against the width of the data holding them
+            let code = r#"
+                    .section .data
+                    .dword 0x1122334455667788, 0xffffffff
+                    .quad -0x7fffffffffffffff - 1
+                    .byte 255, -128
+            "#;
+            let tools = assemble_code(code).unwrap();
+            let words = tools.section_words(SectionName::Data);
+            let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+            #[rustfmt::skip]
+            let expected: [u8; 26] = [
+                0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11,
+                0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0x80,
+                0xff, 0x80,
+            ];
+            assert_eq!(bytes[..26], expected);
+
+            let code = "
+                    .byte 256
+                    .half -32769
+                    .word 0x100000000
+                    li a0, 0x100000000
+                    .dword 0x10000000000000000
+            ";
+            let errors = assemble_code(code).unwrap_err();
+            let found: Vec<(usize, &str)> =
+                errors.iter().map(|d| (d.span.row(), d.message.as_str())).collect();
+            assert_eq!(
+                found,
+                [
+                    (5, "number '0x10000000000000000' doesn't fit in 64 bits"),
+                    (4, "value 4294967296 doesn't fit in 32 bits"),
+                    (1, "value 256 doesn't fit in 8 bits ('.byte')"),
+                    (2, "value -32769 doesn't fit in 16 bits ('.half')"),
+                    (3, "value 4294967296 doesn't fit in 32 bits ('.word')"),
+                ]
+            );
+        }
+
+        #[test]
+        fn program_sections() {
+            use crate::utils::{assemble_code, save_elf};
+            use object::{Object, ObjectSection, ObjectSymbol, SectionKind};
+
+            let code = r#"
+                        .text
+                _start:
+                        la t0, answer
+                        lw a0, 0(t0)
+                        la t1, counter
+                        lw a1, 0(t1)
+                        sw a0, 0(t1)
+                        lw a2, 0(t1)
+                        .pushsection .rodata
+                answer: .word 42
+                        .popsection
+                        la t2, table
+                        lw a3, 4(t2)
+                        li a7, 93
+                        ecall
+                        .section .mydata, "aw", @progbits
+                        .balign 16
+                table:  .word 1, 2
+                        .bss
+                counter: .zero 4
+                        .section .scratch, "aw", %nobits
+                        .space 8
+            "#;
+            let tools = assemble_code(code).unwrap();
+            assert_eq!(tools.sections[".mydata"].address % 16, 0);
+            let mut m = new_machine_from_tools(&tools);
+            while let Ok(emu::machine::MachineState::Ok) = m.decode() {}
+            assert!(m.assert_reg(Register::A0.id().into(), 42));
+            assert!(m.assert_reg(Register::A1.id().into(), 0));
+            assert!(m.assert_reg(Register::A2.id().into(), 42));
+            assert!(m.assert_reg(Register::A3.id().into(), 2));
+
+            let filename = "test_sections.o";
+            save_elf(tools, filename).unwrap();
+            let data = std::fs::read(filename);
+            assert!(std::fs::remove_file(filename).is_ok());
+            let data = data.unwrap();
+            let elf = object::File::parse(&*data).unwrap();
+            let section = |name| elf.section_by_name(name).unwrap();
+            assert_eq!(section(".text").kind(), SectionKind::Text);
+            assert_eq!(section(".rodata").kind(), SectionKind::ReadOnlyData);
+            assert_eq!(section(".mydata").kind(), SectionKind::Data);
+            assert_eq!(section(".mydata").align(), 16);
+            for name in [".bss", ".scratch"] {
+                assert_eq!(section(name).kind(), SectionKind::UninitializedData);
+                assert!(section(name).data().unwrap().is_empty());
+            }
+            assert_eq!(section(".scratch").size(), 8);
+            let answer = elf.symbols().find(|s| s.name() == Ok("answer")).unwrap();
+            assert_eq!(answer.section_index(), Some(section(".rodata").index()));
+
+            // names made of several parts, as compilers give them
+            let code = r#"
+                        .section .text.startup, "ax", @progbits
+                _start:
+                        la t0, greeting
+                        la t1, ctors
+                        lw a1, 0(t1)
+                        li a7, 93
+                        ecall
+                        .section .rodata.str1.1, "aMS", @progbits, 1
+                greeting: .string "hi"
+                        .section .init_array, "aw", @init_array
+                ctors:  .word _start
+                        .section .note.tool, "", @note
+                        .word 0
+            "#;
+            let tools = assemble_code(code).unwrap();
+            let start = tools.sections[".text.startup"].address;
+            let mut m = new_machine_from_tools(&tools);
+            m.jump(start);
+            while let Ok(emu::machine::MachineState::Ok) = m.decode() {}
+            assert!(m.assert_reg(Register::A1.id().into(), start as u32));
+            let filename = "test_dotted_sections.o";
+            save_elf(tools, filename).unwrap();
+            let data = std::fs::read(filename);
+            assert!(std::fs::remove_file(filename).is_ok());
+            let data = data.unwrap();
+            let elf = object::File::parse(&*data).unwrap();
+            let section = |name| elf.section_by_name(name).unwrap();
+            assert_eq!(section(".text.startup").kind(), SectionKind::Text);
+            assert_eq!(section(".rodata.str1.1").kind(), SectionKind::ReadOnlyData);
+            assert_eq!(section(".rodata.str1.1").data().unwrap(), b"hi\0");
+            let init_array = SectionKind::Elf(object::elf::SHT_INIT_ARRAY);
+            assert_eq!(section(".init_array").kind(), init_array);
+            assert_eq!(section(".note.tool").kind(), SectionKind::Note);
+            let start = elf.symbols().find(|s| s.name() == Ok("_start")).unwrap();
+            assert_eq!(start.section_index(), Some(section(".text.startup").index()));
+
+            let code = r#"
+                    .popsection
+                    .section .x, "q"
+                    .section .y, "a", @comdat
+                    .section
+                    .section .tdata, "awT", @progbits
+            "#;
+            let errors = assemble_code(code).unwrap_err();
+            let found: Vec<(usize, &str)> =
+                errors.iter().map(|d| (d.span.row(), d.message.as_str())).collect();
+            assert_eq!(
+                found,
+                [
+                    (1, "'.popsection' without a matching '.pushsection'"),
+                    (2, "unknown section flag 'q'"),
+                    (
+                        3,
+                        "unknown section type '@comdat' (expected @progbits, @nobits, \
+                         @init_array, @fini_array, @preinit_array or @note)",
+                    ),
+                    (4, "expected a section name, its flags and its type"),
+                    (5, "thread-local sections ('T') aren't supported"),
+                ]
+            );
+
+            let code = "
+                    .bss
+                    .zero 4
+                    .word 1
+                    addi a0, a0, 1
+            ";
+            let errors = assemble_code(code).unwrap_err();
+            let found: Vec<(usize, &str)> =
+                errors.iter().map(|d| (d.span.row(), d.message.as_str())).collect();
+            assert_eq!(
+                found,
+                [(3, "'.bss' can only hold zeros"), (4, "'.bss' can only hold zeros")]
+            );
+        }
+
+        #[test]
+        fn program_symbol_directives() {
+            use crate::utils::{assemble_code, save_elf};
+            use object::{Object, ObjectSection, ObjectSymbol, RelocationTarget};
+            use object::{SymbolFlags, SymbolKind};
+
+            let code = r#"
+                        .text
+                        .globl square, extern_fn
+                        .type square, @function
+                square:
+                        mul a0, a0, a0
+                        ret
+                        .size square, . - square
+                        .weak fallback
+                        .hidden fallback
+                fallback:
+                        la t0, extern_fn
+                        ret
+                        .data
+                        .global counter
+                        .type counter, %object
+                        .protected counter
+                        .size counter, 4
+                counter: .word 0
+                scratch: .word extern_fn + 8
+            "#;
+            let tools = assemble_code(code).unwrap();
+            let filename = "test_symbol_directives.o";
+            save_elf(tools, filename).unwrap();
+            let data = std::fs::read(filename);
+            assert!(std::fs::remove_file(filename).is_ok());
+            let data = data.unwrap();
+            let elf = object::File::parse(&*data).unwrap();
+            let symbol = |name| elf.symbols().find(|s| s.name() == Ok(name)).unwrap();
+            let st_other = |name| match symbol(name).flags() {
+                SymbolFlags::Elf { st_other, .. } => st_other,
+                _ => unreachable!(),
+            };
+
+            assert!(symbol("square").is_global());
+            assert_eq!(symbol("square").kind(), SymbolKind::Text);
+            assert_eq!(symbol("square").size(), 8);
+            assert!(symbol("fallback").is_weak());
+            assert_eq!(st_other("fallback"), object::elf::STV_HIDDEN);
+            assert!(symbol("counter").is_global());
+            assert_eq!(symbol("counter").kind(), SymbolKind::Data);
+            assert_eq!(symbol("counter").size(), 4);
+            assert_eq!(st_other("counter"), object::elf::STV_PROTECTED);
+            assert!(symbol("scratch").is_local());
+            assert!(symbol("extern_fn").is_undefined());
+            assert!(symbol("extern_fn").is_global());
+            let extern_fn = symbol("extern_fn").index();
+            let relocations = elf
+                .sections()
+                .flat_map(|section| section.relocations().collect::<Vec<_>>())
+                .filter(|(_, r)| r.target() == RelocationTarget::Symbol(extern_fn))
+                .map(|(_, r)| r.addend())
+                .collect::<Vec<_>>();
+            assert_eq!(relocations, [0, 8]);
+
+            let code = r#"
+                    .globl ext
+                    .local ghost
+                    .type ext2, @thing
+                    .size missing, 4
+            here:   jal ra, ext
+                    .size here, ext
+            "#;
+            let errors = assemble_code(code).unwrap_err();
+            let found: Vec<(usize, &str)> =
+                errors.iter().map(|d| (d.span.row(), d.message.as_str())).collect();
+            assert_eq!(
+                found,
+                [
+                    (2, "unknown symbol 'ghost'"),
+                    (3, "unknown symbol type '@thing' (expected @function, @object or @notype)"),
+                    (4, "unknown symbol 'missing'"),
+                    (6, "the size of 'here' must be a constant"),
+                    (
+                        5,
+                        "'ext' is defined by another object, so only 'la' and data can refer to it",
+                    ),
+                ]
+            );
+        }
+
         // Test elf R/W
         #[test]
         fn elf_write() {
@@ -1260,7 +3098,7 @@ mod tests {
             let filename = "test_elf_write.o";
 
             let mut writer = ElfWriter::new();
-            writer.set_start_address(0);
+            writer.set_start_address(&SectionName::Text, 0);
             writer
                 .set_section_data(SectionName::Text, bytes, 4)
                 .expect("error setting text data");
@@ -1281,7 +3119,7 @@ mod tests {
 
             // Writing temporary ELF file
             let mut writer = ElfWriter::new();
-            writer.set_start_address(0);
+            writer.set_start_address(&SectionName::Text, 0);
             writer
                 .set_section_data(SectionName::Text, bytes, 4)
                 .expect("error setting text data");
@@ -1317,7 +3155,7 @@ mod tests {
 
             // Saving the binary code in the ELF format
             let mut writer = ElfWriter::new();
-            writer.set_start_address(0);
+            writer.set_start_address(&SectionName::Text, 0);
             writer
                 .set_section_data(SectionName::Text, bytes_written.clone(), 4)
                 .expect("error setting text data");
@@ -1345,5 +3183,65 @@ mod tests {
                 assert!(read_io_res.is_ok());
             }
         }
+
+        #[test]
+        fn elf_load() {
+            let filename = "test_elf_load.o";
+            let code = "
+                li a7, 93
+                mv a0, sp
+                ecall
+            ";
+            let bytes_written = utils::words_to_bytes_le(&encode_to_words(code));
+
+            let mut writer = ElfWriter::new();
+            writer.set_start_address(&SectionName::Text, 0);
+            writer
+                .set_section_data(SectionName::Text, bytes_written, 4)
+                .expect("error setting text data");
+            assert!(writer.save(filename).is_ok());
+            let read_io_res = std::fs::read(filename);
+            assert!(std::fs::remove_file(filename).is_ok());
+
+            let data = read_io_res.unwrap();
+            let reader = ElfReader::new(&data, DataEndianness::Le).unwrap();
+            let sections = reader.loadable_sections();
+            assert_eq!(sections.len(), 1);
+            assert_eq!(sections[0].name, ".text");
+
+            let mut m: SimpleMachine = utils::load_elf(&reader);
+            let memsize = 16 + utils::STACK_SIZE;
+            assert_eq!(m.bytes_count(), memsize);
+            assert!(m.assert_reg(2, memsize as u32));
+            assert!(m.assert_pc(0));
+
+            let mut state = m.decode().unwrap();
+            while state == emu::machine::MachineState::Ok {
+                state = m.decode().unwrap();
+            }
+            assert_eq!(state, emu::machine::MachineState::Exit(memsize as i32));
+        }
+
+        #[test]
+        fn elf_guest_args() {
+            let mut m = SimpleMachine::from_bytes_size(256, DataEndianness::Le);
+            let mut registers = m.read_registers();
+            registers.pop();
+            registers[2] = 256;
+            m.write_registers(registers, 0);
+
+            let args = vec![String::from("prog"), String::from("hello")];
+            utils::push_guest_args(&mut m, &args);
+
+            let sp = m.read_registers()[2] as usize;
+            assert_eq!(sp % 16, 0);
+            assert_eq!(m.read_memory_word(sp), 2);
+            let argv0 = m.read_memory_word(sp + 4) as usize;
+            let argv1 = m.read_memory_word(sp + 8) as usize;
+            assert_eq!(m.read_memory_word(sp + 12), 0);
+            let strings = m.read_memory_bytes(argv0, 16, 4);
+            assert_eq!(&strings[..11], b"prog\0hello\0");
+            assert_eq!(argv1, argv0 + 5);
+        }
     }
 }
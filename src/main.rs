@@ -1,26 +1,37 @@
 use rustv::utils::emulate_from_elf;
 
 pub mod assembler;
+pub mod diagnostics;
 pub mod lexer;
 pub mod parser;
+pub mod preprocessor;
+pub mod sources;
 pub mod streamreader;
 pub mod syntax;
 pub mod tokenizer;
 pub mod utils;
 pub mod emu {
+    pub mod arch;
     pub mod cpu;
     pub mod debugger;
+    pub mod disasm;
+    pub mod hostio;
     pub mod machine;
     pub mod memory;
+    pub mod repl;
+    pub mod trace;
+    pub mod transport;
 }
 pub mod lang {
     pub mod directive;
+    pub mod expr;
     pub mod ext;
     pub mod highassembly;
     pub mod lowassembly;
     pub mod pseudo;
 }
 pub mod obj {
+    pub mod dwarfreader;
     pub mod dwarfwriter;
     pub mod elfreader;
     pub mod elfwriter;
@@ -32,7 +43,7 @@ fn main() {
     let arglen = args.len();
 
     let show_usage = arglen > 1 && matches!(args[1], "--help" | "-h") || arglen == 1;
-    let start_stub = arglen > 1 && matches!(args[1], "--debugger" | "-d");
+    let start_stub = arglen > 1 && matches!(args[1], "--debugger" | "-d" | "--debugger-stdio");
     let assemble_code = arglen > 2 && matches!(args[1], "--assemble" | "-a");
     let build_code = arglen > 2 && matches!(args[1], "--build" | "-b");
     let write_elf = arglen > 2 && matches!(args[1], "--elf" | "-e");
@@ -42,35 +53,54 @@ fn main() {
     let run_from_elf = arglen > 2 && matches!(args[1], "--run-elf");
     let run_from_tools = arglen > 2 && matches!(args[1], "--run-tools");
     let run_from_raw = arglen > 2 && matches!(args[1], "--run-raw");
+    let start_repl = arglen > 2 && matches!(args[1], "--repl" | "--tui");
+    let diff_trace = arglen > 3 && matches!(args[1], "--diff-trace");
 
     if show_usage {
         usage();
         return;
     }
 
-    if assemble_code {
-        use crate::utils::encode_to_elf;
+    let Ok(commit_log) = commit_log_from_args(&args) else {
+        usage();
+        return;
+    };
 
-        let srcfile = args[2];
-        let objectfile = "main.o";
+    let Ok(options) = assembly_options_from_args(&args) else {
+        usage();
+        return;
+    };
+    let srcfiles = sources_from_args(&args);
 
-        let code = std::fs::read_to_string(srcfile).unwrap();
+    if assemble_code {
+        use crate::utils::save_elf;
+
+        // every source to an object of its own (a.s to a.o), or all of them to a single one
+        if args.contains(&"--separate") {
+            for srcfile in &srcfiles {
+                let objectfile = std::path::Path::new(srcfile).with_extension("o");
+                let (tools, _) = assemble_or_exit(&[srcfile], &options);
+                save_elf(tools, &objectfile.to_string_lossy()).unwrap();
+            }
+            return;
+        }
 
-        encode_to_elf(&code, objectfile).unwrap();
+        let objectfile = match args.iter().position(|arg| *arg == "-o") {
+            Some(idx) => args.get(idx + 1).copied().unwrap_or("main.o"),
+            None => "main.o",
+        };
+
+        let (tools, _) = assemble_or_exit(&srcfiles, &options);
+        save_elf(tools, objectfile).unwrap();
 
         return;
     }
 
     if build_code {
-        use crate::utils::build_code_repr;
         // use crate::utils::words_to_bytes_be;
         // use crate::utils::print_bytes_hex;
 
-        let srcfile = args[2];
-
-        let code = std::fs::read_to_string(srcfile).unwrap();
-
-        let tools = build_code_repr(&code);
+        let (tools, _) = assemble_or_exit(&srcfiles, &options);
 
         // let data = tools.data_section_words();
         // let data = words_to_bytes_be(&data);
@@ -81,16 +111,71 @@ fn main() {
 
     if start_stub {
         let memsize = 1024 * 1024;
-        let port = args
+        // an executable can be given before the port
+        let elffile = args
             .get(2)
+            .filter(|arg| !arg.starts_with("--") && arg.parse::<u16>().is_err());
+        let portidx = if elffile.is_some() { 3 } else { 2 };
+        let port = args
+            .get(portidx)
+            .filter(|p| !p.starts_with("--"))
             .map_or(Some(9999u16), |p| p.parse().map_or(None, |v| Some(v)))
             .unwrap();
 
+        use crate::emu::machine::FloatExtension;
+        let float = match args.iter().position(|arg| *arg == "--fpu") {
+            Some(idx) => match args.get(idx + 1) {
+                Some(&"f") => FloatExtension::F,
+                Some(&"d") => FloatExtension::D,
+                _ => {
+                    usage();
+                    return;
+                }
+            },
+            None => FloatExtension::None,
+        };
+
         // use env_logger::Env;
         // env_logger::Builder::from_env(Env::default().default_filter_or("trace")).init();
 
-        use crate::utils::wait_for_new_debugger_at_port;
-        let riscv32_dbg = wait_for_new_debugger_at_port(memsize, port);
+        use crate::emu::transport::Transport;
+        let transport = match args.iter().position(|arg| *arg == "--unix") {
+            _ if args[1] == "--debugger-stdio" => Transport::Stdio,
+            Some(idx) => match args.get(idx + 1) {
+                Some(path) => Transport::Unix(std::path::PathBuf::from(path)),
+                None => {
+                    usage();
+                    return;
+                }
+            },
+            None => Transport::Tcp(port),
+        };
+
+        use crate::utils::{wait_for_new_debugger, wait_for_new_debugger_with_elf};
+        let mut riscv32_dbg = match elffile {
+            Some(elffile) => wait_for_new_debugger_with_elf(elffile, transport, float),
+            None => wait_for_new_debugger(memsize, transport, float),
+        };
+        riscv32_dbg.set_persistent(args.contains(&"--persistent"));
+        let option_value = |name: &str, default: usize| match args.iter().position(|arg| *arg == name) {
+            Some(idx) => args.get(idx + 1).and_then(|v| v.parse::<usize>().ok()),
+            None => Some(default),
+        };
+        let (Some(harts), Some(quantum)) = (option_value("--harts", 1), option_value("--quantum", 1)) else {
+            usage();
+            return;
+        };
+        riscv32_dbg.set_harts(harts, quantum);
+        if let Some(log) = commit_log {
+            riscv32_dbg.set_commit_log(log);
+        }
+        if let Some(idx) = args.iter().position(|arg| *arg == "--sandbox") {
+            let Some(root) = args.get(idx + 1) else {
+                usage();
+                return;
+            };
+            riscv32_dbg.set_sandbox(std::path::PathBuf::from(root));
+        }
 
         riscv32_dbg.custom_gdb_event_loop_thread();
         // riscv32_dbg.default_gdb_event_loop_thread();
@@ -99,16 +184,13 @@ fn main() {
     }
 
     if write_elf {
-        use crate::utils::encode_to_elf;
+        use crate::utils::save_elf;
 
         let linker = "riscv32-unknown-linux-gnu-ld";
         let execfile = "main";
         let objectfile = "main.o";
-        let srcfile = args[2];
-
-        let f = std::fs::read_to_string(srcfile).unwrap();
-
-        encode_to_elf(&f, objectfile).unwrap();
+        let (tools, _) = assemble_or_exit(&srcfiles, &options);
+        save_elf(tools, objectfile).unwrap();
 
         let output = std::process::Command::new(linker)
             .arg(objectfile)
@@ -165,16 +247,13 @@ fn main() {
     }
 
     if write_elf_dbg {
-        use crate::utils::encode_to_elf_with_debug;
+        use crate::utils::save_elf_with_debug;
 
         let linker = "riscv32-unknown-linux-gnu-ld";
         let execfile = "main";
         let objectfile = "main.o";
-        let srcfile = args[2];
-
-        let f = std::fs::read_to_string(srcfile).unwrap();
-
-        encode_to_elf_with_debug(&f, srcfile, objectfile).unwrap();
+        let (tools, sources) = assemble_or_exit(&srcfiles, &options);
+        save_elf_with_debug(tools, &sources, objectfile).unwrap();
 
         let output = std::process::Command::new(linker)
             .arg(objectfile)
@@ -196,28 +275,62 @@ fn main() {
         // Read ELF and execute the Machine (text + data)
         let inputfile = args[2];
 
-        let machine = utils::emulate_from_elf(inputfile);
+        use crate::emu::machine::{Machine as _, MachineState};
+        let mut machine = utils::new_machine_from_elf(inputfile);
+        machine.set_commit_log(commit_log);
+
+        // self-checking tests (which have a 'tohost' symbol) stop once they report their result
+        let result = loop {
+            match machine.decode() {
+                Ok(MachineState::Exit(code)) if machine.tohost().is_some() => break Some(code),
+                Ok(_) => {}
+                Err(_) => break None,
+            }
+        };
 
         print_registers(&machine);
 
+        if let Some(idx) = args.iter().position(|arg| *arg == "--signature") {
+            use crate::utils::{signature_range, write_signature};
+
+            let Some(output) = args.get(idx + 1) else {
+                usage();
+                return;
+            };
+            let Some(range) = signature_range(inputfile) else {
+                eprintln!("Error: begin_signature/end_signature not found in {}", inputfile);
+                std::process::exit(1);
+            };
+            let mut file = std::fs::File::create(output).expect("Failed creating signature file");
+            write_signature(&machine, range, &mut file).expect("Failed writing signature file");
+        }
+
+        if machine.tohost().is_some() {
+            match result {
+                Some(0) => println!("PASS"),
+                Some(test) => {
+                    println!("FAIL (test {})", test);
+                    std::process::exit(1);
+                }
+                None => {
+                    println!("FAIL (no result was reported)");
+                    std::process::exit(1);
+                }
+            }
+        }
+
         return;
     }
 
     if run_from_tools {
         // Read code and instantiate Machine from parser tools
         use crate::emu::machine::Machine as _;
-        use crate::utils::build_code_repr;
         use crate::utils::new_machine_from_tools;
 
-        let inputfile = args[2];
-
-        let data = std::fs::read(inputfile).expect("Failed reading elf file");
-
-        let code = String::from_utf8(data).expect("Failed converting bytes to string");
-
-        let tools = build_code_repr(&code);
+        let (tools, _) = assemble_or_exit(&srcfiles, &options);
 
         let mut m = new_machine_from_tools(&tools);
+        m.set_commit_log(commit_log);
 
         while let Ok(_) = m.decode() {}
 
@@ -226,6 +339,64 @@ fn main() {
         return;
     }
 
+    if diff_trace {
+        // Compare the commit log of a program against a golden one (or write it, with --update)
+        use crate::emu::trace::first_divergence;
+        use crate::utils::trace_program;
+
+        let max_steps = 1_000_000;
+        let (program, golden) = (args[2], args[3]);
+        let context = match args.iter().position(|arg| *arg == "--context") {
+            Some(idx) => match args.get(idx + 1).and_then(|n| n.parse().ok()) {
+                Some(n) => n,
+                None => {
+                    usage();
+                    return;
+                }
+            },
+            None => 5,
+        };
+
+        let trace = trace_program(program, max_steps);
+
+        if args.contains(&"--update") {
+            std::fs::write(golden, trace).expect("Failed writing golden trace");
+            eprintln!("Golden trace written to {}", golden);
+            return;
+        }
+
+        let expected = std::fs::read_to_string(golden).expect("Failed reading golden trace");
+        match first_divergence(&expected, &trace, context) {
+            Some(divergence) => {
+                eprintln!("{}: {}", golden, divergence);
+                std::process::exit(1);
+            }
+            None => eprintln!("{}: traces match", golden),
+        }
+
+        return;
+    }
+
+    if start_repl {
+        // Debug the program from the terminal (assembly files are assembled in memory)
+        use crate::utils::{new_repl_from_elf, new_repl_from_tools};
+
+        let inputfile = args[2];
+
+        let mut repl = if inputfile.ends_with(".s") {
+            let (tools, sources) = assemble_or_exit(&[inputfile], &options);
+            new_repl_from_tools(&tools, &sources)
+        } else {
+            new_repl_from_elf(inputfile)
+        };
+        repl.set_context(args[1] == "--tui");
+
+        repl.run(std::io::stdin().lock(), &mut std::io::stdout())
+            .expect("Failed running the debugger");
+
+        return;
+    }
+
     if run_from_raw {
         // Read code and instantiate Machine from raw binary
         //
@@ -245,6 +416,7 @@ fn main() {
         let words = encode_to_words(&code);
 
         let mut m = SimpleMachine::from_words(&words, DataEndianness::Be);
+        m.set_commit_log(commit_log);
 
         while let Ok(_) = m.decode() {}
 
@@ -254,18 +426,130 @@ fn main() {
     }
 }
 
+/// Assembles 'code' (read from 'srcfile'), exiting with the diagnostics when it has errors
+fn assemble_or_exit(
+    srcfiles: &[&str],
+    options: &crate::utils::AssemblyOptions,
+) -> (crate::assembler::AssemblerTools, crate::sources::SourceMap) {
+    let mut sources = crate::sources::SourceMap::default();
+    for srcfile in srcfiles {
+        if let Err(e) = sources.load(srcfile) {
+            eprintln!("error: can't read '{}': {}", srcfile, e);
+            std::process::exit(1);
+        }
+    }
+    match crate::utils::assemble_sources(&mut sources, options) {
+        Ok(tools) => (tools, sources),
+        Err(errors) => {
+            eprint!("{}", crate::diagnostics::render_sources(&errors, &sources));
+            std::process::exit(1);
+        }
+    }
+}
+
 fn usage() {
-    println!("Usage");
-    println!("  cargo run -- [ --build    | -b ] file.s");
-    println!("  cargo run -- [ --debugger | -d ]");
-    println!("  cargo run -- [ --decode-bin    ] 0x00001117");
-    println!("  cargo run -- [ --decode-text   ] \"addi a2,a1,3\"");
-    println!("  cargo run -- [ --elf      | -e ] file.s");
-    println!("  cargo run -- [ --elf-dbg       ] file.s");
-    println!("  cargo run -- [ --run-elf       ] executable");
-    println!("  cargo run -- [ --run-tools     ] file.s");
-    println!("  cargo run -- [ --run-raw       ] file.s");
-    println!("  cargo run -- [ --help     | -h ]");
+    eprintln!("Usage");
+    eprintln!("  cargo run -- [ --build    | -b ] file.s");
+    eprintln!("  cargo run -- [ --debugger | -d ] [file.elf] [port]");
+    eprintln!("                [--fpu f|d] [--persistent] [--sandbox dir]");
+    eprintln!("                [--harts n] [--quantum n]");
+    eprintln!("                [--unix path]");
+    eprintln!("  cargo run -- [ --debugger-stdio ] [file.elf] [--fpu f|d] [--harts n] ...");
+    eprintln!("  cargo run -- [ --decode-bin    ] 0x00001117");
+    eprintln!("  cargo run -- [ --decode-text   ] \"addi a2,a1,3\"");
+    eprintln!("  cargo run -- [ --elf      | -e ] file.s");
+    eprintln!("  cargo run -- [ --elf-dbg       ] file.s");
+    eprintln!("  cargo run -- [ --run-elf       ] executable [--signature file]");
+    eprintln!("  cargo run -- [ --run-tools     ] file.s");
+    eprintln!("  cargo run -- [ --run-raw       ] file.s");
+    eprintln!("  cargo run -- [ --repl | --tui  ] file.s|executable");
+    eprintln!("  cargo run -- [ --diff-trace    ] file.s|executable golden.log");
+    eprintln!("                [--context n] [--update]");
+    eprintln!("  cargo run -- [ --help     | -h ]");
+    eprintln!("Tracing (--debugger and --run-* modes)");
+    eprintln!("  --trace file [--trace-pc start:end] [--trace-icount start:end]");
+    eprintln!("Assembling (--assemble, --build, --elf, --elf-dbg, --run-tools and --repl modes)");
+    eprintln!("  file.s ... [-D NAME[=value]] ... [-I dir] ...");
+    eprintln!("  --assemble: [-o file.o] (all sources to one object) or --separate (a.s to a.o)");
+}
+
+/// Values of an option given as '-X value' or '-Xvalue' (i.e: '-I dir'), as many times as it's
+/// given (None for an option without value)
+fn option_values<'a>(args: &[&'a str], option: &str) -> Option<Vec<&'a str>> {
+    let mut values = Vec::new();
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.strip_prefix(option) {
+            Some("") => values.push(*it.next()?),
+            Some(value) => values.push(value),
+            None => {}
+        }
+    }
+    Some(values)
+}
+
+/// Source files given to the modes which assemble (options and their values aside)
+fn sources_from_args<'a>(args: &[&'a str]) -> Vec<&'a str> {
+    let mut sources = Vec::new();
+    let mut it = args.iter().skip(2);
+    while let Some(arg) = it.next() {
+        match *arg {
+            "-D" | "-I" | "-o" | "--trace" | "--trace-pc" | "--trace-icount" => {
+                it.next();
+            }
+            arg if arg.starts_with('-') => {}
+            arg => sources.push(arg),
+        }
+    }
+    sources
+}
+
+/// Symbols predefined through '-D NAME=value' (or '-D NAME', which is 1) and directories of
+/// included files ('-I dir')
+fn assembly_options_from_args(args: &[&str]) -> Result<crate::utils::AssemblyOptions, ()> {
+    let mut defines = Vec::new();
+    for define in option_values(args, "-D").ok_or(())? {
+        let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+        let value = match value.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None => value.parse(),
+        };
+        if name.is_empty() {
+            return Err(());
+        }
+        defines.push((name.to_string(), value.map_err(|_| ())?));
+    }
+    let include_dirs = option_values(args, "-I").ok_or(())?;
+    Ok(crate::utils::AssemblyOptions {
+        defines,
+        include_dirs: include_dirs.into_iter().map(String::from).collect(),
+    })
+}
+
+/// Commit log requested through '--trace' (an error means an option was given a bad value)
+fn commit_log_from_args(args: &[&str]) -> Result<Option<crate::emu::trace::CommitLog>, ()> {
+    use crate::emu::trace::{CommitLog, TraceFilter, parse_range};
+
+    let value = |name: &str| args.iter().position(|arg| *arg == name).map(|idx| args.get(idx + 1));
+    let Some(path) = value("--trace") else {
+        return Ok(None);
+    };
+    let path = path.ok_or(())?;
+    let mut filter = TraceFilter::default();
+    if let Some(range) = value("--trace-pc") {
+        let range = range.and_then(|r| parse_range(r)).ok_or(())?;
+        filter.pc = Some(range.start as usize..range.end.min(usize::MAX as u64) as usize);
+    }
+    if let Some(range) = value("--trace-icount") {
+        filter.icount = Some(range.and_then(|r| parse_range(r)).ok_or(())?);
+    }
+    match CommitLog::to_file(path, filter) {
+        Ok(log) => Ok(Some(log)),
+        Err(e) => {
+            eprintln!("Failed creating trace file '{}': {}", path, e);
+            Err(())
+        }
+    }
 }
 
 fn print_registers<T: crate::emu::machine::Machine>(m: &T) -> () {
@@ -0,0 +1,76 @@
+use std::borrow::Cow;
+
+use object::{Object, ObjectKind, ObjectSection, ObjectSymbol, RelocationTarget};
+
+/// Source position of the instruction found at 'address' ('line' starts at 1)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub address: usize,
+    pub file: String,
+    pub line: usize,
+}
+
+/// Reads the line table ('.debug_line') of an ELF file, sorted by address
+///
+/// Files without debugging information have an empty line table
+pub fn read_line_table(data: &[u8]) -> gimli::Result<Vec<SourceLine>> {
+    let Ok(elf) = object::File::parse(data) else {
+        return Ok(Vec::new());
+    };
+    let load_section = |id: gimli::SectionId| -> gimli::Result<Cow<[u8]>> {
+        let Some(section) = elf.section_by_name(id.name()) else {
+            return Ok(Cow::Borrowed(&[]));
+        };
+        let data = section.uncompressed_data().unwrap_or(Cow::Borrowed(&[]));
+        if elf.kind() == ObjectKind::Relocatable {
+            return Ok(Cow::Owned(relocate(&elf, &section, data.into_owned())));
+        }
+        Ok(data)
+    };
+    let dwarf_sections = gimli::DwarfSections::load(load_section)?;
+    let dwarf =
+        dwarf_sections.borrow(|section| gimli::EndianSlice::new(section, gimli::LittleEndian));
+
+    let mut lines = Vec::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let Some(program) = unit.line_program.clone() else {
+            continue;
+        };
+        let mut rows = program.rows();
+        while let Some((header, row)) = rows.next_row()? {
+            if row.end_sequence() {
+                continue;
+            }
+            let (Some(file), Some(line)) = (row.file(header), row.line()) else {
+                continue;
+            };
+            let file = dwarf.attr_string(&unit, file.path_name())?;
+            lines.push(SourceLine {
+                address: row.address() as usize,
+                file: file.to_string_lossy().to_string(),
+                line: line.get() as usize,
+            });
+        }
+    }
+    lines.sort_by_key(|line| line.address);
+    Ok(lines)
+}
+
+/// Applies the (absolute) relocations of a debugging section found in an object file, which
+/// aren't resolved until it gets linked (e.g. offsets of strings in '.debug_line_str')
+fn relocate(elf: &object::File, section: &object::Section, mut data: Vec<u8>) -> Vec<u8> {
+    for (offset, relocation) in section.relocations() {
+        let base = match relocation.target() {
+            RelocationTarget::Symbol(idx) => elf.symbol_by_index(idx).map_or(0, |s| s.address()),
+            _ => 0,
+        };
+        let value = (base as i64 + relocation.addend()) as u32;
+        let offset = offset as usize;
+        if relocation.size() == 32 && offset + 4 <= data.len() {
+            data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+    data
+}
@@ -6,7 +6,7 @@ use object::write::SymbolId;
 
 use crate::{
     assembler::AssemblerTools, lang::highassembly::SectionName, obj::elfwriter::ElfWriter,
-    streamreader::Position,
+    sources::SourceMap,
 };
 
 /// Record information needed to write a section.
@@ -228,8 +228,14 @@ fn add_line(
 pub fn add_debug_information<'a>(
     writer: &mut ElfWriter<'a>,
     tools: AssemblerTools,
-    file_name: &[u8],
+    sources: &SourceMap,
 ) -> () {
+    // the compilation unit is named after the first file
+    let file_name = sources
+        .get(0)
+        .map_or("", |source| source.name.as_str())
+        .as_bytes();
+
     let encoding = gimli::Encoding {
         format: gimli::Format::Dwarf32,
         version: 5,
@@ -256,9 +262,17 @@ pub fn add_debug_information<'a>(
 
     let dir_id = line_program.default_directory();
 
-    let file_lines = gimli::write::LineString::new(file_name, encoding, line_strings);
-
-    let file_lines_id = line_program.add_file(file_lines, dir_id, None);
+    // every file the code comes from (included ones too), in the order of 'sources'
+    let file_ids: Vec<gimli::write::FileId> = sources
+        .files()
+        .iter()
+        .map(|source| {
+            let name =
+                gimli::write::LineString::new(source.name.as_bytes(), encoding, line_strings);
+            line_program.add_file(name, dir_id, None)
+        })
+        .collect();
+    let file_lines_id = file_ids[0];
 
     let main_name = *b"_start";
 
@@ -291,10 +305,16 @@ pub fn add_debug_information<'a>(
         .collect();
     let textsection = sections.get(0).unwrap();
     let insts = textsection.instructions.iter().rev().skip(1).rev();
-    for (idx, inst) in insts.enumerate() {
+    let mut offset = 0;
+    for inst in insts {
         let row = (inst.file_pos.row() + 1) as u64;
         let col = inst.file_pos.col() as u64;
-        add_line(&mut line_program, file_lines_id, row, col, (idx as u64) * 4);
+        let file_id = file_ids
+            .get(inst.file_pos.file())
+            .copied()
+            .unwrap_or(file_lines_id);
+        add_line(&mut line_program, file_id, row, col, offset);
+        offset += inst.data.len() as u64;
     }
 
     line_program.end_sequence(main_size as u64);
@@ -2,10 +2,12 @@ use std::collections::HashMap;
 
 use object::read;
 use object::read::elf::ElfFile32;
-use object::{self, Endianness, Object, ObjectSection, ObjectSymbol};
+use object::{
+    self, Endianness, Object, ObjectSection, ObjectSymbol, SectionFlags, SectionKind, elf,
+};
 
 use crate::assembler::{self, AssemblerTools};
-use crate::lang::highassembly::{self};
+use crate::lang::highassembly::{self, SpecialSection};
 use crate::lang::lowassembly::{self, DataEndianness};
 use crate::streamreader::Position;
 use crate::utils::swap_chunk_endianness;
@@ -38,6 +40,11 @@ pub struct ElfSection {
     pub(crate) address: u32,
     pub(crate) align: usize,
     pub(crate) data: Vec<u8>,
+    /// Size in memory, which differs from the size of 'data' for NOBITS sections (e.g. '.bss')
+    pub(crate) size: usize,
+    /// Whether the section occupies memory during the program execution (SHF_ALLOC), is writable
+    /// (SHF_WRITE), holds code (SHF_EXECINSTR) or takes no space in the file (SHT_NOBITS)
+    pub(crate) flags: highassembly::SectionFlags,
 }
 
 pub struct ElfSymbol {
@@ -45,7 +52,7 @@ pub struct ElfSymbol {
     pub(crate) address: u32,
     pub(crate) section: String,
     pub(crate) length: u64,
-    pub(crate) scope: String,
+    pub(crate) attributes: highassembly::SymbolAttributes,
 }
 
 pub struct ElfRelocation {
@@ -93,6 +100,31 @@ impl<'a> ElfReader<'a> {
         self.pc
     }
 
+    /// Sections which have to be loaded in memory to run the program, sorted by address
+    pub fn loadable_sections(&self) -> Vec<&ElfSection> {
+        let mut sections: Vec<&ElfSection> = self
+            .section_table
+            .values()
+            .filter(|section| section.flags.alloc && section.size > 0)
+            .collect();
+        sections.sort_by_key(|section| section.address);
+        sections
+    }
+
+    pub fn symbol_address(&self, name: &str) -> Option<usize> {
+        self.symbol_table
+            .get(name)
+            .map(|symbol| symbol.address as usize)
+    }
+
+    /// Name and address of every symbol defined in the file
+    pub fn symbol_addresses(&self) -> Vec<(String, usize)> {
+        self.symbol_table
+            .values()
+            .map(|symbol| (symbol.name.clone(), symbol.address as usize))
+            .collect()
+    }
+
     pub fn tools(&self) -> AssemblerTools {
         let sections: HashMap<String, assembler::Section> = self
             .section_table
@@ -102,6 +134,8 @@ impl<'a> ElfReader<'a> {
                 let section = assembler::Section {
                     address: pair.1.address as usize,
                     name,
+                    alignment: pair.1.align,
+                    flags: pair.1.flags,
                 };
                 (pair.1.name.to_string(), section)
             })
@@ -116,7 +150,7 @@ impl<'a> ElfReader<'a> {
                     section,
                     relative_address: pair.1.address as usize,
                     length: pair.1.length as usize,
-                    scope: pair.1.scope.clone(),
+                    attributes: pair.1.attributes,
                 };
                 (pair.0.to_string(), s)
             })
@@ -132,6 +166,8 @@ impl<'a> ElfReader<'a> {
                     id: idx,
                     address: pair.1.offset as usize,
                     addend: pair.1.addend,
+                    kind: assembler::RelocationKind::PcrelPair,
+                    section: highassembly::SectionName::Text,
                 };
                 let relname = pair.0.clone();
                 relocations.entry(relname).or_insert(Vec::new()).push(rel);
@@ -152,13 +188,12 @@ impl<'a> ElfReader<'a> {
                 let name = highassembly::SectionName::from_default_name(name);
                 let addr = section.address() as usize;
                 let data = section.data().unwrap();
-                let data = DataEndianness::build_words_from_bytes(&data, DataEndianness::Le);
                 let alignment = section.align() as usize;
                 let instructions = data
-                    .into_iter()
+                    .chunks(4)
                     .enumerate()
                     .map(|(idx, word)| lowassembly::EncodedData {
-                        data: vec![word],
+                        data: word.to_vec(),
                         alignment,
                         file_pos: Position::new(idx, idx, 0),
                     })
@@ -198,16 +233,35 @@ fn build_section_table<'a>(
                 data.to_vec()
             } else {
                 if align > 1 {
-                    swap_chunk_endianness(data, align)
+                    swap_chunk_endianness(data, 4)
                 } else {
                     data.to_vec()
                 }
             };
+            let sh_flags = match section.flags() {
+                SectionFlags::Elf { sh_flags } => sh_flags,
+                _ => 0,
+            };
+            let flags = highassembly::SectionFlags {
+                alloc: sh_flags & u64::from(elf::SHF_ALLOC) != 0,
+                write: sh_flags & u64::from(elf::SHF_WRITE) != 0,
+                exec: sh_flags & u64::from(elf::SHF_EXECINSTR) != 0,
+                nobits: section.kind().is_bss(),
+                special: match section.kind() {
+                    SectionKind::Elf(elf::SHT_INIT_ARRAY) => Some(SpecialSection::InitArray),
+                    SectionKind::Elf(elf::SHT_FINI_ARRAY) => Some(SpecialSection::FiniArray),
+                    SectionKind::Elf(elf::SHT_PREINIT_ARRAY) => Some(SpecialSection::PreinitArray),
+                    SectionKind::Note => Some(SpecialSection::Note),
+                    _ => None,
+                },
+            };
             let s = ElfSection {
                 name: section.name().unwrap().to_string(),
                 address: section.address() as u32,
                 align,
                 data,
+                size: section.size() as usize,
+                flags,
             };
             section_table.insert(section.name().unwrap().to_string(), s);
         }
@@ -222,13 +276,7 @@ fn build_symbol_table<'a>(elf: &ElfFile32<'a>) -> HashMap<String, ElfSymbol> {
             // kind, scope, flags, endian
             let name = String::from_utf8(name.to_vec()).unwrap();
             let address = symbol.address();
-            let scope = match symbol.scope() {
-                read::SymbolScope::Compilation => String::from("Compilation"),
-                read::SymbolScope::Linkage => String::from("File"),
-                read::SymbolScope::Dynamic => String::from("Dynamic"),
-                // read::SymbolScope::Unknown => panic!(),
-                read::SymbolScope::Unknown => String::from("Unknown"),
-            };
+            let attributes = symbol_attributes(&symbol);
             let length = symbol.size() as u64;
             let section_symb = symbol.section();
             if let Some(section_idx) = section_symb.index() {
@@ -238,7 +286,7 @@ fn build_symbol_table<'a>(elf: &ElfFile32<'a>) -> HashMap<String, ElfSymbol> {
                     address: address as u32,
                     section: section.name().unwrap().to_string(),
                     length,
-                    scope,
+                    attributes,
                 };
                 symbol_table.insert(s.name.clone(), s);
             }
@@ -247,6 +295,34 @@ fn build_symbol_table<'a>(elf: &ElfFile32<'a>) -> HashMap<String, ElfSymbol> {
     symbol_table
 }
 
+fn symbol_attributes<'a>(symbol: &impl ObjectSymbol<'a>) -> highassembly::SymbolAttributes {
+    let binding = if symbol.is_weak() {
+        highassembly::SymbolBinding::Weak
+    } else if symbol.is_global() {
+        highassembly::SymbolBinding::Global
+    } else {
+        highassembly::SymbolBinding::Local
+    };
+    let visibility = match symbol.flags() {
+        object::SymbolFlags::Elf { st_other, .. } => match st_other & 0x3 {
+            elf::STV_HIDDEN => highassembly::SymbolVisibility::Hidden,
+            elf::STV_PROTECTED => highassembly::SymbolVisibility::Protected,
+            _ => highassembly::SymbolVisibility::Default,
+        },
+        _ => highassembly::SymbolVisibility::Default,
+    };
+    let kind = match symbol.kind() {
+        object::SymbolKind::Text => highassembly::SymbolKind::Function,
+        object::SymbolKind::Data => highassembly::SymbolKind::Object,
+        _ => highassembly::SymbolKind::NoType,
+    };
+    highassembly::SymbolAttributes {
+        binding,
+        visibility,
+        kind,
+    }
+}
+
 fn build_relocation_table<'a>(elf: &ElfFile32<'a>) -> HashMap<String, ElfRelocation> {
     let mut relocation_table = HashMap::new();
     let text_section = elf.section_by_name(".text").unwrap();
@@ -1,4 +1,4 @@
-use object::elf::{R_RISCV_PCREL_HI20, R_RISCV_PCREL_LO12_I};
+use object::elf::{self, R_RISCV_32, R_RISCV_PCREL_HI20, R_RISCV_PCREL_LO12_I};
 use object::{
     Architecture,
     BinaryFormat,
@@ -19,7 +19,10 @@ use object::write::{
 
 use std::collections::hash_map::HashMap;
 
-use crate::lang::highassembly::SectionName;
+use crate::lang::highassembly::{
+    SectionFlags, SectionName, SpecialSection, SymbolAttributes, SymbolBinding, SymbolKind,
+    SymbolVisibility,
+};
 
 // Result
 
@@ -57,7 +60,11 @@ pub struct ElfWriter<'a> {
     pub(crate) text: SectionId,
     data: SectionId,
     bss: SectionId,
+    /// Sections other than '.text', '.data' and '.bss' (see 'add_section')
+    custom_sections: HashMap<String, (SectionId, SectionFlags)>,
     symbol_ids: HashMap<String, SymbolId>,
+    /// Local symbols ('.L*'), which are left out of the symbol table
+    local_symbols: HashMap<String, write::Symbol>,
 }
 
 impl<'a> ElfWriter<'a> {
@@ -73,7 +80,61 @@ impl<'a> ElfWriter<'a> {
             text,
             data,
             bss,
+            custom_sections: HashMap::new(),
             symbol_ids,
+            local_symbols: HashMap::new(),
+        }
+    }
+
+    /// Adds a section whose kind and ELF flags follow 'flags' ('.text', '.data' and '.bss' are
+    /// always there)
+    pub fn add_section(&mut self, section_name: &SectionName, flags: SectionFlags) {
+        let SectionName::Custom(name) = section_name else {
+            return;
+        };
+        if self.custom_sections.contains_key(name) {
+            return;
+        }
+        let kind = if let Some(special) = flags.special {
+            SectionKind::Elf(match special {
+                SpecialSection::InitArray => elf::SHT_INIT_ARRAY,
+                SpecialSection::FiniArray => elf::SHT_FINI_ARRAY,
+                SpecialSection::PreinitArray => elf::SHT_PREINIT_ARRAY,
+                SpecialSection::Note => elf::SHT_NOTE,
+            })
+        } else if flags.nobits {
+            SectionKind::UninitializedData
+        } else if flags.exec {
+            SectionKind::Text
+        } else if flags.write {
+            SectionKind::Data
+        } else if flags.alloc {
+            SectionKind::ReadOnlyData
+        } else {
+            SectionKind::Other
+        };
+        let secid = self
+            .obj
+            .add_section(Vec::new(), name.bytes().collect(), kind);
+        let sh_flags = [
+            (flags.alloc, elf::SHF_ALLOC),
+            (flags.write, elf::SHF_WRITE),
+            (flags.exec, elf::SHF_EXECINSTR),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |sh_flags, (_, flag)| sh_flags | u64::from(flag));
+        self.obj.section_mut(secid).flags = object::SectionFlags::Elf { sh_flags };
+        self.custom_sections.insert(name.clone(), (secid, flags));
+    }
+
+    fn section_id(&self, section_name: &SectionName) -> Option<SectionId> {
+        match section_name {
+            SectionName::Text => Some(self.text),
+            SectionName::Data => Some(self.data),
+            SectionName::Bss => Some(self.bss),
+            SectionName::Custom(name) => self.custom_sections.get(name).map(|section| section.0),
+            _ => None,
         }
     }
 
@@ -83,32 +144,39 @@ impl<'a> ElfWriter<'a> {
         data: Vec<u8>,
         align: u64,
     ) -> Result<()> {
-        let secid = match section_name {
-            SectionName::Text => self.text,
-            SectionName::Data => self.data,
-            SectionName::Bss => self.bss,
-            _ => {
-                return Err(ElfWriterError::WrongSection(String::from(
-                    "Can't data to custom sections yet",
-                )));
-            }
+        let Some(secid) = self.section_id(&section_name) else {
+            return Err(ElfWriterError::WrongSection(format!(
+                "Section '{}' wasn't added",
+                section_name.default_name()
+            )));
         };
-        self.obj.section_mut(secid).set_data(data, align);
+        // sections like '.bss' only hold zeros, so just their size is kept
+        if self.obj.section(secid).is_bss() {
+            self.obj.append_section_bss(secid, data.len() as u64, align);
+        } else {
+            self.obj.section_mut(secid).set_data(data, align);
+        }
         Ok(())
     }
 
-    pub fn set_start_address(&mut self, rel_addr_to_text_sec: u64) {
-        self.obj.add_symbol(write::Symbol {
+    /// Adds the entry point, '_start', at 'rel_addr_to_sec_start' within 'section_name' (which
+    /// has to be added before)
+    pub fn set_start_address(&mut self, section_name: &SectionName, rel_addr_to_sec_start: u64) {
+        let secid = self
+            .section_id(section_name)
+            .expect("Can't add the entry point to this type of section");
+        let symbol_id = self.obj.add_symbol(write::Symbol {
             name: b"_start".to_vec(),
-            value: rel_addr_to_text_sec,
+            value: rel_addr_to_sec_start,
             // size: code_len as u64,
             size: 0,
             kind: write::SymbolKind::Text,
             scope: write::SymbolScope::Dynamic,
-            section: write::SymbolSection::Section(self.text),
+            section: write::SymbolSection::Section(secid),
             weak: false,
             flags: write::SymbolFlags::None,
         });
+        self.symbol_ids.insert(String::from("_start"), symbol_id);
     }
 
     pub fn add_symbol(
@@ -117,50 +185,160 @@ impl<'a> ElfWriter<'a> {
         rel_addr_to_sec_start: u64,
         name: &str,
         len: u64,
+        attributes: SymbolAttributes,
     ) {
-        let (kind, section) = match section_name {
-            SectionName::Text => (write::SymbolKind::Label, self.text),
-            SectionName::Data => (write::SymbolKind::Data, self.data),
-            _ => panic!("Can't add symbol to this type of section"),
+        let (kind, section) = match &section_name {
+            // constants (SHN_ABS)
+            SectionName::Absolute => (write::SymbolKind::Label, write::SymbolSection::Absolute),
+            // defined by other objects (SHN_UNDEF)
+            SectionName::Undefined => (write::SymbolKind::Label, write::SymbolSection::Undefined),
+            name => {
+                let secid = self
+                    .section_id(name)
+                    .expect("Can't add symbol to this type of section");
+                let code = match name {
+                    SectionName::Custom(name) => self.custom_sections[name].1.exec,
+                    _ => *name == SectionName::Text,
+                };
+                let kind = if code {
+                    write::SymbolKind::Label
+                } else {
+                    write::SymbolKind::Data
+                };
+                (kind, write::SymbolSection::Section(secid))
+            }
         };
 
         //the symbol is going to span from
         //<start of section + rel_addr>
         //to
         //<start of section + rel_addr + len>
-        //OBS: linkage scope works like static scope in C and this is what we currently support
-        let symbol_id = self.obj.add_symbol(write::Symbol {
+        let kind = match attributes.kind {
+            SymbolKind::Function => write::SymbolKind::Text,
+            SymbolKind::Object => write::SymbolKind::Data,
+            SymbolKind::NoType => kind,
+        };
+        let st_type = match kind {
+            write::SymbolKind::Text => elf::STT_FUNC,
+            write::SymbolKind::Data => elf::STT_OBJECT,
+            _ => elf::STT_NOTYPE,
+        };
+        let st_bind = match attributes.binding {
+            SymbolBinding::Local => elf::STB_LOCAL,
+            SymbolBinding::Global => elf::STB_GLOBAL,
+            SymbolBinding::Weak => elf::STB_WEAK,
+        };
+        let st_other = match attributes.visibility {
+            SymbolVisibility::Default => elf::STV_DEFAULT,
+            SymbolVisibility::Hidden => elf::STV_HIDDEN,
+            SymbolVisibility::Protected => elf::STV_PROTECTED,
+        };
+        // the scope only decides where the symbol goes in the table (local ones go first)
+        let scope = match (attributes.binding, attributes.visibility) {
+            (SymbolBinding::Local, _) => write::SymbolScope::Compilation,
+            (_, SymbolVisibility::Default) => write::SymbolScope::Dynamic,
+            _ => write::SymbolScope::Linkage,
+        };
+        let symbol = write::Symbol {
             name: name.bytes().collect(),
             value: rel_addr_to_sec_start,
             size: len, // ?
             kind,
-            scope: write::SymbolScope::Linkage,
-            section: write::SymbolSection::Section(section),
-            weak: false,
-            flags: write::SymbolFlags::None,
-        });
+            scope,
+            section,
+            weak: attributes.binding == SymbolBinding::Weak,
+            flags: write::SymbolFlags::Elf {
+                st_info: (st_bind << 4) | st_type,
+                st_other,
+            },
+        };
 
+        // as in GNU as, local symbols (i.e: '.Lloop' or numeric labels) aren't written
+        if name.starts_with(".L") {
+            self.local_symbols.insert(name.to_string(), symbol);
+            return;
+        }
+        let symbol_id = self.obj.add_symbol(symbol);
         self.symbol_ids.insert(name.to_string(), symbol_id);
     }
 
+    /// Symbol (and addend) a relocation to 'name' refers to: local symbols are replaced by the
+    /// symbol of their section (or written, when they don't belong to one), as are section names
+    fn relocation_target(&mut self, name: &str, addend: i64) -> (SymbolId, i64) {
+        if !self.symbol_ids.contains_key(name)
+            && !self.local_symbols.contains_key(name)
+            && let Some(section) = self.section_id(&SectionName::from_default_name(name))
+        {
+            return (self.obj.section_symbol(section), addend);
+        }
+        if let Some(symbol) = self.local_symbols.get(name) {
+            if let write::SymbolSection::Section(section) = symbol.section {
+                let value = symbol.value as i64;
+                return (self.obj.section_symbol(section), addend + value);
+            }
+            let symbol = self.local_symbols.remove(name).unwrap();
+            let symbol_id = self.obj.add_symbol(write::Symbol {
+                scope: write::SymbolScope::Compilation,
+                ..symbol
+            });
+            self.symbol_ids.insert(name.to_string(), symbol_id);
+        }
+        let symbol_id = *self
+            .symbol_ids
+            .get(name)
+            .expect("Symbol id not found when creating relocation");
+        (symbol_id, addend)
+    }
+
     pub fn handle_symbol_relocation(
         &mut self,
+        section_name: &SectionName,
         symbol_name: &str,
         text_section_off: u64,
         symbol_addend: i32,
         idx: usize,
     ) -> Result<()> {
+        let Some(section) = self.section_id(section_name) else {
+            return Err(ElfWriterError::WrongSection(String::from(
+                "Can't relocate code in this type of section",
+            )));
+        };
+        let target = self.relocation_target(symbol_name, symbol_addend as i64);
         create_ext_symbol_relocatable_reference(
             &mut self.obj,
-            self.text,
+            section,
             text_section_off,
-            &self.symbol_ids,
+            target,
             symbol_name,
             symbol_addend,
             idx,
         )
     }
 
+    /// Address of 'symbol_name' (plus 'addend') held by data at 'section_off' in 'section_name'
+    pub fn handle_data_relocation(
+        &mut self,
+        section_name: &SectionName,
+        section_off: u64,
+        symbol_name: &str,
+        addend: i32,
+    ) -> Result<()> {
+        let Some(section) = self.section_id(section_name) else {
+            return Err(ElfWriterError::WrongSection(String::from(
+                "Can't relocate data in this type of section",
+            )));
+        };
+        let (symbol, addend) = self.relocation_target(symbol_name, addend as i64);
+        let relocation = Relocation {
+            offset: section_off,
+            symbol,
+            addend,
+            flags: write::RelocationFlags::Elf { r_type: R_RISCV_32 },
+        };
+        self.obj.add_relocation(section, relocation)?;
+        Ok(())
+    }
+
     pub fn save(&self, filename: &str) -> Result<()> {
         let elf_bytes = self.obj.write()?;
         std::fs::write(filename, elf_bytes)?;
@@ -172,14 +350,11 @@ fn create_ext_symbol_relocatable_reference<'a>(
     obj: &mut write::Object<'a>,
     text_section_id: SectionId,
     text_section_off: u64,
-    symbol_ids: &HashMap<String, SymbolId>,
+    (symbol_id, hi_addend): (SymbolId, i64),
     symbol_name: &str,
     symbol_addend: i32,
     tmpidx: usize,
 ) -> Result<()> {
-    let symbol_id = symbol_ids
-        .get(symbol_name)
-        .expect("Symbol id not found when creating relocation");
     let hi_off = text_section_off;
     let lo_off = text_section_off + 4;
 
@@ -203,8 +378,8 @@ fn create_ext_symbol_relocatable_reference<'a>(
 
     let symbol_hi_relocation = Relocation {
         offset: hi_off,
-        symbol: *symbol_id,
-        addend: symbol_addend as i64,
+        symbol: symbol_id,
+        addend: hi_addend,
         flags: write::RelocationFlags::Elf {
             r_type: R_RISCV_PCREL_HI20,
         },
@@ -2,14 +2,26 @@ pub trait Parser {
     type Token;
     type Output;
 
-    fn parse(&self, token: Vec<Self::Token>) -> Self::Output ;
+    /// Statements holding errors are left out, so that the rest can still be checked
+    fn parse(&self, token: Vec<Self::Token>, diagnostics: &mut Vec<Diagnostic>) -> Self::Output ;
 }
 
 /* The following code was written to ease the process of implementing the 'Parser' trait. */
 
+use std::collections::HashMap;
+
+use crate::lang::expr::{
+    self,
+    Item,
+};
+
 use crate::lang::highassembly::{
     ArgValue,
+    SectionChange,
+    SectionFlags,
     SectionName,
+    SectionSpec,
+    SymbolDirective,
     KeyValue,
     GenericLine,
     GenericBlock
@@ -20,6 +32,8 @@ use crate::tokenizer::{
     GenericToken,
 };
 
+use crate::diagnostics::Diagnostic;
+
 //2.1 Converting tokens to their generic representative
 
 fn generalize_tokens<T: ToGenericToken>(tokens: Vec<T>) -> Vec<GenericToken> {
@@ -32,23 +46,32 @@ fn generalize_tokens<T: ToGenericToken>(tokens: Vec<T>) -> Vec<GenericToken> {
 //2.2 Grouping tokens in lines
 
 // TODO: introducing an EOL token to the GenericToken enum would allow this aswell
-fn group_tokens(tokens: Vec<GenericToken>) -> Vec<GenericLine> {
+fn group_tokens(tokens: Vec<GenericToken>, diagnostics: &mut Vec<Diagnostic>) -> Vec<GenericLine> {
     let mut token_groups = Vec::new();
-    let mut args = Vec::new();
+    let mut items = Vec::new();
     for token in tokens.into_iter().rev() {
         match token {
             GenericToken::KeyToken(k, p) =>  {
+                let items: Vec<Item> = items.drain(..).rev().collect();
+                let args = match expr::parse_args(&items) {
+                    Ok(args) => args,
+                    Err(message) => {
+                        diagnostics.push(Diagnostic::error(p, message));
+                        continue;
+                    }
+                };
                 let group = GenericLine {
                     id: 0,
                     file_pos: p,
                     keyword: k,
-                    args: args.drain(..).rev().collect()
+                    args,
                 };
                 token_groups.push(group);
             },
-            GenericToken::ArgToken(a) => {
-                args.push(a);
-            },
+            GenericToken::ArgToken(a) => items.push(Item::Arg(a)),
+            GenericToken::OpToken(op) => items.push(Item::Op(op)),
+            GenericToken::SignedNumber(n) => items.push(Item::SignedNumber(n)),
+            GenericToken::Separator => items.push(Item::Separator),
         }
     }
     let lines: Vec<_> = token_groups.into_iter().rev().collect();
@@ -64,15 +87,95 @@ fn group_tokens(tokens: Vec<GenericToken>) -> Vec<GenericLine> {
         .collect()
 }
 
-// 2.3 Expanding pseudo instructions into groups of real instructions
+// 2.3 Replacing symbolic constants ('.equ', '.set' and '=') with their values
+//   A constant takes the value of its latest assignment, or of its last one when it's used before
+//   being assigned. Assignments which depend on labels are left for the assembler
+
+fn assign_constants(lines: Vec<GenericLine>, diagnostics: &mut Vec<Diagnostic>) -> Vec<GenericLine> {
+    let assigned_value = |line: &GenericLine, constants: &dyn Fn(&str) -> Option<i64>| {
+        match &line.args[..] {
+            [arg @ (ArgValue::Number(_) | ArgValue::Use(..) | ArgValue::Expr(_))] => {
+                expr::substitute(arg, constants)
+            },
+            _ => Err(String::from("expected a single value")),
+        }
+    };
+
+    let mut last = HashMap::new();
+    for line in &lines {
+        if let KeyValue::Assignment(name) = &line.keyword
+            && let Ok(ArgValue::Number(n)) = assigned_value(line, &|name| last.get(name).copied())
+        {
+            last.insert(name.clone(), n);
+        }
+    }
+
+    let mut constants: HashMap<String, i64> = HashMap::new();
+    let mut new_lines = Vec::new();
+    for line in lines {
+        let lookup = |name: &str| constants.get(name).or(last.get(name)).copied();
+        let args = match &line.keyword {
+            KeyValue::Assignment(name) if name.is_empty() => {
+                Err(String::from("expected the name of the constant"))
+            },
+            KeyValue::Assignment(_) => assigned_value(&line, &lookup).map(|arg| vec![arg]),
+            _ => line.args.iter().map(|arg| expr::substitute(arg, &lookup)).collect(),
+        };
+        let args = match args {
+            Ok(args) => args,
+            Err(message) => {
+                diagnostics.push(Diagnostic::error(line.file_pos, message));
+                continue;
+            }
+        };
+        if let KeyValue::Assignment(name) = &line.keyword
+            && let [ArgValue::Number(n)] = args[..]
+        {
+            constants.insert(name.clone(), n);
+        }
+        new_lines.push(GenericLine { args, ..line });
+    }
+    new_lines
+}
+
+// Numbers take up to 64 bits in data (i.e: '.dword') and constants, whose directives check their
+// own widths, and 32 bits everywhere else
 
-fn expand_pseudos(lines: Vec<GenericLine>) -> Vec<GenericLine> {
+fn check_number_widths(lines: Vec<GenericLine>, diagnostics: &mut Vec<Diagnostic>) -> Vec<GenericLine> {
+    lines
+        .into_iter()
+        .filter(|line| {
+            if let KeyValue::AssemblyDirective(_) | KeyValue::Assignment(_) = line.keyword {
+                return true;
+            }
+            let error = line.args.iter().find_map(|arg| match arg {
+                ArgValue::Number(n) => expr::to_i32(*n).err(),
+                _ => None,
+            });
+            if let Some(message) = error {
+                diagnostics.push(Diagnostic::error(line.file_pos, message));
+                return false;
+            }
+            true
+        })
+        .collect()
+}
+
+// 2.4 Expanding pseudo instructions into groups of real instructions
+
+fn expand_pseudos(lines: Vec<GenericLine>, diagnostics: &mut Vec<Diagnostic>) -> Vec<GenericLine> {
     let mut expanded_lines = Vec::new();
     for line in lines {
         match &line.keyword {
             KeyValue::Pseudo(pseudo) => {
-                let extra_lines: Vec<GenericLine> = pseudo
-                    .translate(line.args)
+                let opcode_lines = match pseudo.translate(line.args) {
+                    Ok(opcode_lines) => opcode_lines,
+                    Err(message) => {
+                        diagnostics.push(Diagnostic::error(line.file_pos, message));
+                        continue;
+                    }
+                };
+                let extra_lines: Vec<GenericLine> = opcode_lines
                     .into_iter()
                     .map(|opcode_line| {
                         GenericLine {
@@ -94,14 +197,51 @@ fn expand_pseudos(lines: Vec<GenericLine>) -> Vec<GenericLine> {
     expanded_lines
 }
 
-// 2.4 Expanding directives into bytes
+// 2.5 Expanding directives into bytes
 
-fn expand_assembly_directives(lines: Vec<GenericLine>) -> Vec<GenericLine> {
+fn expand_assembly_directives(lines: Vec<GenericLine>, diagnostics: &mut Vec<Diagnostic>) -> Vec<GenericLine> {
     let mut new_lines = Vec::new();
     for line in lines {
         match &line.keyword {
             KeyValue::AssemblyDirective(d) => {
-                let new_args: Vec<ArgValue> = d.translate(&line.args)
+                let constant = line.args.iter().all(|arg| !matches!(arg, ArgValue::Use(..) | ArgValue::Expr(_)));
+                // alignment directives are translated by the assembler, once it knows where they
+                // are
+                match d.boundary(&line.args) {
+                    Ok(Some(_)) if constant => {
+                        new_lines.push(line);
+                        continue;
+                    },
+                    Ok(Some(_)) => {
+                        diagnostics.push(Diagnostic::error(line.file_pos, "expected a constant expression"));
+                        continue;
+                    },
+                    Err(message) if constant => {
+                        diagnostics.push(Diagnostic::error(line.file_pos, message));
+                        continue;
+                    },
+                    _ => {},
+                }
+                if !constant {
+                    // Expressions on symbols are translated by the assembler, once symbols get
+                    // their addresses, so they must take a fixed size (as in '.word end - start')
+                    let placeholders: Vec<ArgValue> = line.args.iter().map(|_| ArgValue::Number(0)).collect();
+                    let size = d.translate(&placeholders).map(|bytes| bytes.len());
+                    if size != Ok(line.args.len() * d.datatype().size_bytes()) {
+                        diagnostics.push(Diagnostic::error(line.file_pos, "expected a constant expression"));
+                    } else {
+                        new_lines.push(line);
+                    }
+                    continue;
+                }
+                let bytes = match d.translate(&line.args) {
+                    Ok(bytes) => bytes,
+                    Err(message) => {
+                        diagnostics.push(Diagnostic::error(line.file_pos, message));
+                        continue;
+                    }
+                };
+                let new_args: Vec<ArgValue> = bytes
                     .into_iter()
                     .map(|a| ArgValue::Byte(a))
                     .collect();
@@ -120,78 +260,129 @@ fn expand_assembly_directives(lines: Vec<GenericLine>) -> Vec<GenericLine> {
     new_lines
 }
 
-// 2.5 Grouping instructions into sections
+// 2.6 Grouping instructions into sections
+//   Lines before any section go to '.text'. Sections keep the flags they're first given (or the
+//   ones their names imply)
 
-fn group_lines(lines: Vec<GenericLine>) -> Vec<GenericBlock> {
+fn group_lines(lines: Vec<GenericLine>, diagnostics: &mut Vec<Diagnostic>) -> Vec<GenericBlock> {
     let mut blocks = vec![];
     let mut block_lines = vec![];
     let mut metadata = GenericBlock{
         name: SectionName::Metadata,
+        flags: SectionFlags::of(&SectionName::Metadata),
         lines: Vec::new(),
     };
-    for line in lines.into_iter().rev() {
-        match line.keyword {
-            KeyValue::Section(s) => {
-                blocks.push(GenericBlock{
-                    name: s,
-                    lines: block_lines.drain(..).rev().collect(),
-                });
+    let mut flags: HashMap<String, SectionFlags> = HashMap::new();
+    let mut current = SectionName::Text;
+    // sections to go back to with '.popsection'
+    let mut stack = vec![];
+    for line in lines {
+        let spec = match line.keyword {
+            KeyValue::Section(SectionChange::Switch(spec)) => spec,
+            KeyValue::Section(SectionChange::Push(spec)) => {
+                stack.push(current.clone());
+                spec
             },
-            KeyValue::LinkerDirective(_) => metadata.lines.push(line),
-            _ => block_lines.push(line),
+            KeyValue::Section(SectionChange::Pop) => {
+                let Some(name) = stack.pop() else {
+                    let message = "'.popsection' without a matching '.pushsection'";
+                    diagnostics.push(Diagnostic::error(line.file_pos, message));
+                    continue;
+                };
+                SectionSpec{name, flags: None, kind: None}
+            },
+            // '.size' stays where it is written, since its size is often given from '.'
+            KeyValue::LinkerDirective(ref directive) if *directive != SymbolDirective::Size => {
+                metadata.lines.push(line);
+                continue;
+            },
+            _ => {
+                block_lines.push(line);
+                continue;
+            },
+        };
+        if spec.name == SectionName::Custom(String::new()) || !line.args.is_empty() {
+            let message = "expected a section name, its flags and its type";
+            diagnostics.push(Diagnostic::error(line.file_pos, message));
+            continue;
         }
+        match SectionFlags::parse(&spec.name, spec.flags.as_deref(), spec.kind.as_deref()) {
+            Ok(section_flags) => {
+                flags.entry(spec.name.default_name()).or_insert(section_flags);
+            },
+            Err(message) => {
+                diagnostics.push(Diagnostic::error(line.file_pos, message));
+                continue;
+            },
+        }
+        blocks.push((current, std::mem::take(&mut block_lines)));
+        current = spec.name;
     }
-    //If there are instructions left without a session, wrap them in a text section 
-    if block_lines.len() > 0 {
-        blocks.push(GenericBlock{
-            name: SectionName::Text,
-            lines: block_lines.drain(..).rev().collect(),
-        });
+    blocks.push((current, block_lines));
+
+    let mut grouped = vec![metadata];
+    for (name, lines) in blocks {
+        let flags = flags.get(&name.default_name()).copied().unwrap_or(SectionFlags::of(&name));
+        grouped.push(GenericBlock{name, flags, lines});
     }
-    blocks.push(metadata);
-    blocks.into_iter().rev().collect()
+    grouped
 }
 
-// 2.6 Merging same groups
+// 2.7 Merging same groups
+//   '.text', '.data' and '.bss' are always there, while other sections follow '.data' in the
+//   order they first show up in
 
 fn merge_blocks(blocks: Vec<GenericBlock>) -> Vec<GenericBlock> {
-    let mut metadata = GenericBlock{name: SectionName::Metadata, lines: Vec::new()};
-    let mut text = GenericBlock{name: SectionName::Text, lines: vec![]};
-    let mut data = GenericBlock{name: SectionName::Data, lines: vec![]};
-    let mut bss  = GenericBlock{name: SectionName::Bss, lines: vec![]};
-    let mut v = vec![];
+    let new_block = |name: SectionName| GenericBlock{
+        flags: SectionFlags::of(&name),
+        name,
+        lines: vec![],
+    };
+    let mut merged = vec![
+        new_block(SectionName::Metadata),
+        new_block(SectionName::Text),
+        new_block(SectionName::Data),
+    ];
+    let mut bss = new_block(SectionName::Bss);
     for block in blocks {
-        match block.name {
-            SectionName::Text => text.lines.extend(block.lines),
-            SectionName::Data => data.lines.extend(block.lines),
-            SectionName::Bss  => bss.lines.extend(block.lines),
-            SectionName::Metadata  => metadata.lines.extend(block.lines),
-            SectionName::Custom(_) => panic!("Custom sections are not yet implemented :/"),
+        if block.name == SectionName::Absolute {
+            unreachable!("'*ABS*' isn't a section lines can be put in");
         }
+        let merged_block = if block.name == SectionName::Bss {
+            &mut bss
+        } else if let Some(merged_block) = merged.iter_mut().find(|b| b.name == block.name) {
+            merged_block
+        } else {
+            merged.push(new_block(block.name.clone()));
+            merged.last_mut().unwrap()
+        };
+        merged_block.flags = block.flags;
+        merged_block.lines.extend(block.lines);
     }
-    v.push(metadata);
-    v.push(text);
-    v.push(data);
-    v.push(bss);
-    v
+    merged.push(bss);
+    merged
 }
 
-pub fn tokens_to_lines<T: ToGenericToken>(tokens: Vec<T>) -> Vec<GenericLine> {
+pub fn tokens_to_lines<T: ToGenericToken>(tokens: Vec<T>, diagnostics: &mut Vec<Diagnostic>) -> Vec<GenericLine> {
     let tokens = generalize_tokens(tokens);
-    let groups = group_tokens(tokens);
-    let lines  = expand_pseudos(groups);
-    let lines  = expand_assembly_directives(lines);
+    let groups = group_tokens(tokens, diagnostics);
+    let lines  = assign_constants(groups, diagnostics);
+    let lines  = check_number_widths(lines, diagnostics);
+    let lines  = expand_pseudos(lines, diagnostics);
+    let lines  = expand_assembly_directives(lines, diagnostics);
     lines
 }
 
-fn lines_to_blocks(lines: Vec<GenericLine>) -> Vec<GenericBlock> {
-    let blocks = group_lines(lines);
+fn lines_to_blocks(lines: Vec<GenericLine>, diagnostics: &mut Vec<Diagnostic>) -> Vec<GenericBlock> {
+    let blocks = group_lines(lines, diagnostics);
     let blocks = merge_blocks(blocks);
     blocks
 }
 
-pub fn parse<T: ToGenericToken>(tokens: Vec<T>) -> Vec<GenericBlock> {
-    let lines  = tokens_to_lines(tokens);
-    let blocks = lines_to_blocks(lines);
-    blocks
+pub fn parse<T: ToGenericToken>(
+    tokens: Vec<T>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Vec<GenericBlock> {
+    let lines = tokens_to_lines(tokens, diagnostics);
+    lines_to_blocks(lines, diagnostics)
 }
@@ -0,0 +1,786 @@
+pub trait Preprocessor {
+    /// Statements holding errors are left out (keeping their labels), so that the rest can still
+    /// be checked
+    fn expand(
+        &mut self,
+        lexemes: Vec<(String, Position)>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Vec<(String, Position)>;
+}
+
+/* The following code was written to ease the implementation of the 'Preprocessor' trait. */
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::diagnostics::Diagnostic;
+use crate::lang::expr::{self, Item, Value};
+use crate::streamreader::Position;
+
+type Lexeme = (String, Position);
+
+/// Lexeme ending a statement, placed between statements which share a row (as the ones expanded
+/// from the same macro invocation)
+pub const END_OF_STATEMENT: &str = "\n";
+
+/// Nesting of macro invocations past which a macro is taken as endlessly recursive
+const MAX_DEPTH: usize = 100;
+
+/// Statements repetitions ('.rept', '.irp' and '.irpc') can expand to in total, past which
+/// they're taken as a mistake (as '.rept 100000000')
+const MAX_REPEATED: usize = 1_000_000;
+
+#[derive(Debug, Clone)]
+struct Param {
+    name: String,
+    default: Vec<Lexeme>,
+    required: bool,
+    /// Takes all the remaining arguments
+    vararg: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<Param>,
+    body: Vec<Vec<Lexeme>>,
+}
+
+/// '.if' (or '.ifdef', '.ifndef') being assembled, up to its '.endif'
+struct Condition {
+    /// Whether the lines of the current branch get assembled
+    active: bool,
+    /// Whether a branch was already taken (or none can be, within an inactive region)
+    taken: bool,
+    seen_else: bool,
+    pos: Position,
+}
+
+/// Line yet to be expanded
+struct Line {
+    lexemes: Vec<Lexeme>,
+    /// Macro invocation the line was expanded from, which '.exitm' leaves
+    invocation: Option<usize>,
+    depth: usize,
+    /// Files which included the one the line is in (outermost first)
+    includers: Vec<usize>,
+}
+
+impl Line {
+    /// Index of the first lexeme after the labels, which tells what the line is
+    fn keyword_idx(&self) -> usize {
+        self.lexemes
+            .iter()
+            .take_while(|lexeme| lexeme.0.ends_with(':'))
+            .count()
+    }
+
+    fn keyword(&self) -> Option<&str> {
+        self.lexemes
+            .get(self.keyword_idx())
+            .map(|lexeme| lexeme.0.as_str())
+    }
+}
+
+/// Gives the lexemes of the file included from a position
+pub type Include<'a> = dyn FnMut(&str, Position) -> Result<Vec<Lexeme>, String> + 'a;
+
+/// Expands macros ('.macro'/'.endm', '.exitm', '.purgem'), repetitions ('.rept', '.irp' and
+/// '.irpc', closed by '.endr') and included files ('.include'), and drops the regions left out
+/// by conditionals ('.if', '.ifdef', '.ifndef', '.else' and '.endif'), as in GNU as
+///
+/// Statements expanded from a macro take the position of its invocation, while the ones
+/// repeated keep their own. 'defines' are constants set before the code (as '-D NAME=value'),
+/// 'to_item' turns the lexemes of the conditions of '.if' into pieces of an expression and
+/// 'include' gives the lexemes of the file included from a position (as written in '.include')
+pub fn preprocess(
+    lexemes: Vec<Lexeme>,
+    defines: &[(String, i64)],
+    to_item: &dyn Fn(&Lexeme) -> Result<Item, String>,
+    include: &mut Include<'_>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Vec<Lexeme> {
+    let origin = Position::new(0, 0, 0);
+    let defines = defines.iter().map(|(name, value)| {
+        let lexemes = [name.to_string(), String::from("="), value.to_string()];
+        lexemes.map(|lexeme| (lexeme, origin)).to_vec()
+    });
+    let mut expander = MacroExpander {
+        macros: HashMap::new(),
+        invocations: 0,
+        repeated: 0,
+        pending: defines
+            .chain(split_lines(lexemes))
+            .map(|lexemes| Line {
+                lexemes,
+                invocation: None,
+                depth: 0,
+                includers: Vec::new(),
+            })
+            .collect(),
+        output: Vec::new(),
+        symbols: HashMap::new(),
+        conditions: Vec::new(),
+        to_item,
+        include,
+        diagnostics: Vec::new(),
+    };
+    expander.run();
+    for condition in std::mem::take(&mut expander.conditions) {
+        let message = String::from("'.if' without '.endif'");
+        expander
+            .diagnostics
+            .push(Diagnostic::error(condition.pos, message));
+    }
+    diagnostics.append(&mut expander.diagnostics);
+
+    let mut lexemes: Vec<Lexeme> = Vec::new();
+    for line in expander.output {
+        if let (Some(last), Some(first)) = (lexemes.last(), line.first())
+            && last.1.line() == first.1.line()
+        {
+            lexemes.push((String::from(END_OF_STATEMENT), last.1));
+        }
+        lexemes.extend(line);
+    }
+    lexemes
+}
+
+/// Lines are made of the lexemes of a row, up to an 'END_OF_STATEMENT'
+fn split_lines(lexemes: Vec<Lexeme>) -> Vec<Vec<Lexeme>> {
+    let mut lines: Vec<Vec<Lexeme>> = Vec::new();
+    let mut row = None;
+    for lexeme in lexemes {
+        if lexeme.0 == END_OF_STATEMENT {
+            row = None;
+            continue;
+        }
+        if row != Some(lexeme.1.line()) {
+            row = Some(lexeme.1.line());
+            lines.push(Vec::new());
+        }
+        if let Some(line) = lines.last_mut() {
+            line.push(lexeme);
+        }
+    }
+    lines
+}
+
+/// Separates the number of a numeric local label from the count of its definitions in the name
+/// it's given ('.L1^B2' for the second '1:'), as in GNU as
+pub const LOCAL_LABEL_MARK: char = '\u{2}';
+
+/// Names numeric local labels ('1:') after their definitions, which references to them take
+/// from the next ('1f') or the previous ('1b') definition of their number
+pub fn resolve_local_labels(
+    mut lexemes: Vec<Lexeme>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Vec<Lexeme> {
+    // lexemes defining each number, in order
+    let mut definitions: HashMap<String, Vec<usize>> = HashMap::new();
+    for (idx, lexeme) in lexemes.iter().enumerate() {
+        if let Some((number, ':')) = local_label(&lexeme.0) {
+            definitions.entry(number).or_default().push(idx);
+        }
+    }
+
+    let mut undefined = Vec::new();
+    for (idx, lexeme) in lexemes.iter_mut().enumerate() {
+        let Some((number, suffix)) = local_label(&lexeme.0) else {
+            continue;
+        };
+        let defined = definitions.get(&number).map_or(&[][..], Vec::as_slice);
+        let before = defined.partition_point(|&definition| definition < idx);
+        let instance = match suffix {
+            ':' => Some(before),
+            'b' => before.checked_sub(1),
+            _ => (before < defined.len()).then_some(before),
+        };
+        let Some(instance) = instance else {
+            let message = format!("undefined local label '{}'", lexeme.0);
+            diagnostics.push(Diagnostic::error(lexeme.1, message).with_len(lexeme.0.len()));
+            undefined.push(idx);
+            continue;
+        };
+        let name = format!(".L{}{}{}", number, LOCAL_LABEL_MARK, instance + 1);
+        lexeme.0 = if suffix == ':' { name + ":" } else { name };
+    }
+    drop_statements(lexemes, &undefined)
+}
+
+/// Leaves out the statements holding the lexemes at 'wrong', keeping their labels
+fn drop_statements(lexemes: Vec<Lexeme>, wrong: &[usize]) -> Vec<Lexeme> {
+    if wrong.is_empty() {
+        return lexemes;
+    }
+    // statements are numbered as they come (see 'split_lines')
+    let mut statements = Vec::with_capacity(lexemes.len());
+    for (idx, lexeme) in lexemes.iter().enumerate() {
+        let previous = idx.checked_sub(1).map(|previous| &lexemes[previous]);
+        let starts = previous.is_none_or(|previous| {
+            previous.0 == END_OF_STATEMENT || previous.1.line() != lexeme.1.line()
+        });
+        let last = statements.last().copied().unwrap_or(0);
+        statements.push(if starts { last + 1 } else { last });
+    }
+    let wrong: Vec<usize> = wrong.iter().map(|idx| statements[*idx]).collect();
+    let mut labels = true;
+    let mut current = 0;
+    lexemes
+        .into_iter()
+        .zip(statements)
+        .filter(|(lexeme, statement)| {
+            if *statement != current {
+                current = *statement;
+                labels = true;
+            }
+            labels &= lexeme.0.ends_with(':');
+            labels || lexeme.0 == END_OF_STATEMENT || !wrong.contains(statement)
+        })
+        .map(|(lexeme, _)| lexeme)
+        .collect()
+}
+
+/// Number and suffix of a numeric local label ('1:') or of a reference to one ('1b', '1f')
+fn local_label(lexeme: &str) -> Option<(String, char)> {
+    let suffix = lexeme
+        .chars()
+        .last()
+        .filter(|ch| matches!(ch, ':' | 'b' | 'f'))?;
+    let number = &lexeme[..lexeme.len() - 1];
+    let is_number = !number.is_empty() && number.chars().all(|ch| ch.is_ascii_digit());
+    is_number.then(|| (number.to_string(), suffix))
+}
+
+struct MacroExpander<'a> {
+    macros: HashMap<String, Macro>,
+    /// Macro invocations so far, which is the value of '\@'
+    invocations: usize,
+    /// Statements expanded from repetitions so far
+    repeated: usize,
+    pending: VecDeque<Line>,
+    output: Vec<Vec<Lexeme>>,
+    /// Symbols defined so far (labels and constants), with their values when known
+    symbols: HashMap<String, Option<i64>>,
+    conditions: Vec<Condition>,
+    to_item: &'a dyn Fn(&Lexeme) -> Result<Item, String>,
+    include: &'a mut Include<'a>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl MacroExpander<'_> {
+    fn run(&mut self) {
+        while let Some(mut line) = self.pending.pop_front() {
+            let idx = line.keyword_idx();
+            let Some((keyword, pos)) = line.lexemes.get(idx).cloned() else {
+                if self.is_active() {
+                    self.emit(line.lexemes);
+                }
+                continue;
+            };
+            let is_conditional = matches!(
+                keyword.as_str(),
+                ".if" | ".ifdef" | ".ifndef" | ".else" | ".endif"
+            );
+            // nothing but the conditionals (to find where the region ends) is looked at
+            if !is_conditional && !self.is_active() {
+                continue;
+            }
+            let is_macro = self.macros.contains_key(&keyword);
+            let is_block = matches!(
+                keyword.as_str(),
+                ".macro"
+                    | ".rept"
+                    | ".irp"
+                    | ".irpc"
+                    | ".exitm"
+                    | ".purgem"
+                    | ".endm"
+                    | ".endr"
+                    | ".include"
+            );
+            if !is_macro && !is_block && !is_conditional {
+                self.emit(line.lexemes);
+                continue;
+            }
+
+            // labels stay where the line was
+            let args = line.lexemes.split_off(idx + 1);
+            line.lexemes.truncate(idx);
+            if idx > 0 && self.is_active() {
+                self.emit(std::mem::take(&mut line.lexemes));
+            }
+
+            let result = match keyword.as_str() {
+                ".if" | ".ifdef" | ".ifndef" => self.open_condition(&keyword, &args, pos),
+                ".else" => match self.conditions.last_mut() {
+                    Some(condition) if !condition.seen_else => {
+                        condition.active = !condition.taken;
+                        condition.taken = true;
+                        condition.seen_else = true;
+                        Ok(())
+                    }
+                    Some(_) => Err(String::from("'.else' after '.else'")),
+                    None => Err(String::from("'.else' without '.if'")),
+                },
+                ".endif" => match self.conditions.pop() {
+                    Some(_) => Ok(()),
+                    None => Err(String::from("'.endif' without '.if'")),
+                },
+                ".macro" => self.define(&args),
+                ".rept" | ".irp" | ".irpc" => self.repeat(&keyword, &args, &line),
+                ".exitm" => match line.invocation {
+                    Some(invocation) => {
+                        while self
+                            .pending
+                            .front()
+                            .is_some_and(|next| next.invocation == Some(invocation))
+                        {
+                            self.pending.pop_front();
+                        }
+                        Ok(())
+                    }
+                    None => Err(String::from("'.exitm' outside of a macro")),
+                },
+                ".purgem" => match &args[..] {
+                    [(name, _)] if self.macros.remove(name).is_some() => Ok(()),
+                    [(name, _)] => Err(format!("unknown macro '{}'", name)),
+                    _ => Err(String::from("'.purgem' expects the name of a macro")),
+                },
+                ".endm" => Err(String::from("'.endm' without '.macro'")),
+                ".endr" => Err(String::from("'.endr' without '.rept', '.irp' or '.irpc'")),
+                ".include" => self.include(&args, &line, pos),
+                _ => self.invoke(&keyword, &args, &line, pos),
+            };
+            if let Err(message) = result {
+                self.diagnostics.push(Diagnostic::error(pos, message));
+            }
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.conditions
+            .last()
+            .is_none_or(|condition| condition.active)
+    }
+
+    /// Outputs a line, keeping track of the symbols it defines
+    fn emit(&mut self, lexemes: Vec<Lexeme>) {
+        let idx = lexemes
+            .iter()
+            .take_while(|lexeme| lexeme.0.ends_with(':'))
+            .count();
+        for (label, _) in &lexemes[..idx] {
+            let name = label.trim_end_matches(':').to_string();
+            self.symbols.insert(name, None);
+        }
+        let assignment = match &lexemes[idx..] {
+            [(keyword, _), (name, _), (comma, _), value @ ..]
+                if matches!(keyword.as_str(), ".equ" | ".set") && comma == "," =>
+            {
+                Some((name, value))
+            }
+            [(name, _), (assign, _), value @ ..] if assign == "=" => Some((name, value)),
+            _ => None,
+        };
+        if let Some((name, value)) = assignment {
+            // constants which can't be evaluated yet are reported by the parser (if wrong)
+            let value = self.evaluate(value).ok();
+            self.symbols.insert(name.to_string(), value);
+        }
+        self.output.push(lexemes);
+    }
+
+    /// Value of a constant expression, i.e: the condition of '.if'
+    fn evaluate(&self, lexemes: &[Lexeme]) -> Result<i64, String> {
+        let items = lexemes
+            .iter()
+            .map(self.to_item)
+            .collect::<Result<Vec<Item>, String>>()?;
+        let expr = expr::parse_expr(&items)?;
+        let undefined = expr
+            .names()
+            .into_iter()
+            .find(|name| *name != "." && !self.symbols.contains_key(*name));
+        if let Some(name) = undefined {
+            return Err(format!("'{}' is not defined", name));
+        }
+        let lookup = |name: &str| {
+            self.symbols
+                .get(name)
+                .copied()
+                .flatten()
+                .map(Value::constant)
+        };
+        let value = expr.evaluate(&lookup)?;
+        if !value.is_constant() {
+            return Err(String::from("expected a constant expression"));
+        }
+        Ok(value.constant)
+    }
+
+    /// '.if expr', '.ifdef symbol' and '.ifndef symbol'
+    fn open_condition(
+        &mut self,
+        keyword: &str,
+        args: &[Lexeme],
+        pos: Position,
+    ) -> Result<(), String> {
+        // within an inactive region, the condition doesn't even get evaluated
+        let enclosing = self.is_active();
+        let result = match (keyword, args) {
+            _ if !enclosing => Ok(false),
+            (".if", _) => self.evaluate(args).map(|value| value != 0),
+            (_, [(name, _)]) => Ok(self.symbols.contains_key(name) == (keyword == ".ifdef")),
+            _ => Err(format!("'{}' expects the name of a symbol", keyword)),
+        };
+        let active = *result.as_ref().unwrap_or(&false);
+        self.conditions.push(Condition {
+            active,
+            taken: active || !enclosing,
+            seen_else: false,
+            pos,
+        });
+        result.map(|_| ())
+    }
+
+    /// Takes the lines up to the one closing the block which was just opened (dropping it)
+    fn take_body(&mut self, open: &[&str], close: &str) -> Option<Vec<Line>> {
+        let mut depth = 0;
+        let mut body = Vec::new();
+        while let Some(line) = self.pending.pop_front() {
+            match line.keyword() {
+                Some(keyword) if open.contains(&keyword) => depth += 1,
+                Some(keyword) if keyword == close && depth == 0 => return Some(body),
+                Some(keyword) if keyword == close => depth -= 1,
+                _ => {}
+            }
+            body.push(line);
+        }
+        // without the closing line, the lines are assembled as if the block weren't there
+        self.pending.extend(body);
+        None
+    }
+
+    /// '.macro name [param[=default|:req|:vararg]], ...'
+    fn define(&mut self, args: &[Lexeme]) -> Result<(), String> {
+        let Some(body) = self.take_body(&[".macro"], ".endm") else {
+            return Err(String::from("'.macro' without '.endm'"));
+        };
+        let Some(((name, _), params)) = args.split_first().filter(|(name, _)| is_name(&name.0))
+        else {
+            return Err(String::from("'.macro' expects the name of the macro"));
+        };
+        let params = parse_params(params)?;
+        let body = body.into_iter().map(|line| line.lexemes).collect();
+        self.macros.insert(name.to_string(), Macro { params, body });
+        Ok(())
+    }
+
+    fn invoke(
+        &mut self,
+        name: &str,
+        args: &[Lexeme],
+        line: &Line,
+        pos: Position,
+    ) -> Result<(), String> {
+        if line.depth >= MAX_DEPTH {
+            return Err(format!("macro '{}' is nested too deeply", name));
+        }
+        let Some(invoked) = self.macros.get(name) else {
+            return Err(format!("unknown macro '{}'", name));
+        };
+        let bindings = bind_args(name, &invoked.params, split_args(args))?;
+        let counter = self.invocations;
+        self.invocations += 1;
+        let lines: Vec<Line> = invoked
+            .body
+            .iter()
+            .map(|body_line| Line {
+                lexemes: body_line
+                    .iter()
+                    .flat_map(|lexeme| substitute(&lexeme.0, pos, &bindings, Some(counter)))
+                    .collect(),
+                invocation: Some(counter),
+                depth: line.depth + 1,
+                includers: line.includers.clone(),
+            })
+            .collect();
+        for expanded in lines.into_iter().rev() {
+            self.pending.push_front(expanded);
+        }
+        Ok(())
+    }
+
+    /// '.include "file"'
+    fn include(&mut self, args: &[Lexeme], line: &Line, pos: Position) -> Result<(), String> {
+        let path = match args {
+            [(path, _)] => path
+                .strip_prefix('"')
+                .and_then(|path| path.strip_suffix('"')),
+            _ => None,
+        };
+        let Some(path) = path else {
+            return Err(String::from("'.include' expects the name of a file"));
+        };
+        let lexemes = (self.include)(path, pos)?;
+        let mut includers = line.includers.clone();
+        includers.push(pos.file());
+        if let Some((_, first)) = lexemes.first()
+            && includers.contains(&first.file())
+        {
+            return Err(format!("'{}' is included recursively", path));
+        }
+        let lines: Vec<Line> = split_lines(lexemes)
+            .into_iter()
+            .map(|lexemes| Line {
+                lexemes,
+                invocation: line.invocation,
+                depth: line.depth,
+                includers: includers.clone(),
+            })
+            .collect();
+        for included in lines.into_iter().rev() {
+            self.pending.push_front(included);
+        }
+        Ok(())
+    }
+
+    /// '.rept count', '.irp symbol, values...' and '.irpc symbol, chars'
+    fn repeat(&mut self, keyword: &str, args: &[Lexeme], line: &Line) -> Result<(), String> {
+        let Some(body) = self.take_body(&[".rept", ".irp", ".irpc"], ".endr") else {
+            return Err(format!("'{}' without '.endr'", keyword));
+        };
+        let iterations: Vec<HashMap<String, Vec<Lexeme>>> = match keyword {
+            ".rept" => match args {
+                [(count, _)] if let Some(count) = parse_count(count) => {
+                    self.count_repeated(keyword, count, body.len())?;
+                    vec![HashMap::new(); count]
+                }
+                _ => return Err(String::from("'.rept' expects a non-negative count")),
+            },
+            _ => {
+                let mut args = split_args(args).into_iter();
+                let symbol = match args.next().as_deref() {
+                    Some([(symbol, _)]) if is_name(symbol) => symbol.to_string(),
+                    _ => return Err(format!("'{}' expects a symbol and its values", keyword)),
+                };
+                let values: Vec<Vec<Lexeme>> = if keyword == ".irp" {
+                    args.collect()
+                } else {
+                    args.flatten()
+                        .flat_map(|(text, pos)| {
+                            text.chars()
+                                .map(|ch| vec![(ch.to_string(), pos)])
+                                .collect::<Vec<_>>()
+                        })
+                        .collect()
+                };
+                self.count_repeated(keyword, values.len().max(1), body.len())?;
+                if values.is_empty() {
+                    // the body is still expanded once, with no value
+                    vec![HashMap::from([(symbol, Vec::new())])]
+                } else {
+                    values
+                        .into_iter()
+                        .map(|value| HashMap::from([(symbol.clone(), value)]))
+                        .collect()
+                }
+            }
+        };
+        let mut lines = Vec::new();
+        for bindings in &iterations {
+            for body_line in &body {
+                lines.push(Line {
+                    lexemes: body_line
+                        .lexemes
+                        .iter()
+                        .flat_map(|lexeme| substitute(&lexeme.0, lexeme.1, bindings, None))
+                        .collect(),
+                    invocation: line.invocation,
+                    depth: line.depth,
+                    includers: body_line.includers.clone(),
+                });
+            }
+        }
+        for repeated in lines.into_iter().rev() {
+            self.pending.push_front(repeated);
+        }
+        Ok(())
+    }
+
+    /// Adds the statements of a repetition to the ones expanded so far, unless there'd be too many
+    fn count_repeated(&mut self, keyword: &str, count: usize, body_len: usize) -> Result<(), String> {
+        let repeated = self.repeated.saturating_add(count.saturating_mul(body_len));
+        if repeated > MAX_REPEATED {
+            return Err(format!(
+                "'{}' would expand to more than {} statements",
+                keyword, MAX_REPEATED
+            ));
+        }
+        self.repeated = repeated;
+        Ok(())
+    }
+}
+
+fn is_name(s: &str) -> bool {
+    s.starts_with(|ch: char| ch.is_ascii_alphabetic() || ch == '_')
+        && s.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
+
+fn parse_count(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn parse_params(lexemes: &[Lexeme]) -> Result<Vec<Param>, String> {
+    let mut params: Vec<Param> = Vec::new();
+    let mut it = lexemes.iter().peekable();
+    while let Some((token, _)) = it.next() {
+        if token == "," {
+            continue;
+        }
+        let (name, qualifier) = token.split_once(':').unwrap_or((token, ""));
+        if !is_name(name) {
+            return Err(format!("invalid macro parameter '{}'", token));
+        }
+        if !matches!(qualifier, "" | "req" | "vararg") {
+            return Err(format!(
+                "unknown qualifier ':{}' of parameter '{}'",
+                qualifier, name
+            ));
+        }
+        if params.iter().any(|param| param.name == name) {
+            return Err(format!("duplicate macro parameter '{}'", name));
+        }
+        let mut default = Vec::new();
+        if it.next_if(|(token, _)| token == "=").is_some() {
+            while let Some(lexeme) = it.next_if(|(token, _)| token != ",") {
+                default.push(lexeme.clone());
+            }
+        }
+        params.push(Param {
+            name: name.to_string(),
+            default,
+            required: qualifier == "req",
+            vararg: qualifier == "vararg",
+        });
+    }
+    Ok(params)
+}
+
+/// Arguments are separated by commas (the ones in parentheses aside)
+fn split_args(lexemes: &[Lexeme]) -> Vec<Vec<Lexeme>> {
+    if lexemes.is_empty() {
+        return Vec::new();
+    }
+    let mut args = vec![Vec::new()];
+    let mut depth = 0;
+    for lexeme in lexemes {
+        match lexeme.0.as_str() {
+            "," if depth == 0 => {
+                args.push(Vec::new());
+                continue;
+            }
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            _ => {}
+        }
+        if let Some(arg) = args.last_mut() {
+            arg.push(lexeme.clone());
+        }
+    }
+    args
+}
+
+/// Values of the parameters of a macro, given positionally or as 'param=value' (and left empty
+/// to take their default)
+fn bind_args(
+    name: &str,
+    params: &[Param],
+    args: Vec<Vec<Lexeme>>,
+) -> Result<HashMap<String, Vec<Lexeme>>, String> {
+    let mut bindings = HashMap::new();
+    let mut positional = params.iter();
+    for (idx, arg) in args.iter().enumerate() {
+        if let [(key, _), (eq, _), value @ ..] = &arg[..]
+            && eq == "="
+            && let Some(param) = params.iter().find(|param| param.name == *key)
+        {
+            bindings.insert(param.name.clone(), value.to_vec());
+            continue;
+        }
+        let Some(param) = positional.next() else {
+            return Err(format!("too many arguments for macro '{}'", name));
+        };
+        if param.vararg {
+            // (positions are the ones of the invocation, once substituted)
+            let comma = (String::from(","), Position::new(0, 0, 0));
+            bindings.insert(param.name.clone(), args[idx..].join(&comma));
+            break;
+        }
+        if !arg.is_empty() {
+            bindings.insert(param.name.clone(), arg.clone());
+        }
+    }
+    for param in params {
+        if bindings.contains_key(&param.name) {
+            continue;
+        }
+        if param.required {
+            return Err(format!(
+                "missing value for parameter '{}' of macro '{}'",
+                param.name, name
+            ));
+        }
+        bindings.insert(param.name.clone(), param.default.clone());
+    }
+    Ok(bindings)
+}
+
+/// Replaces '\param' with its value, '\@' with the number of the invocation and drops '\()'
+/// (which separates a parameter from the text following it)
+fn substitute(
+    text: &str,
+    pos: Position,
+    bindings: &HashMap<String, Vec<Lexeme>>,
+    counter: Option<usize>,
+) -> Vec<Lexeme> {
+    // the value of a parameter can be made of many lexemes, i.e: '4(sp)'
+    if let Some(value) = text.strip_prefix('\\').and_then(|name| bindings.get(name)) {
+        return value.iter().map(|(text, _)| (text.clone(), pos)).collect();
+    }
+    if text.starts_with('"') || !text.contains('\\') {
+        return vec![(text.to_string(), pos)];
+    }
+
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(idx) = rest.find('\\') {
+        result.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        if let Some(after) = rest.strip_prefix("()") {
+            rest = after;
+            continue;
+        }
+        if let Some(after) = rest.strip_prefix('@')
+            && let Some(counter) = counter
+        {
+            result.push_str(&counter.to_string());
+            rest = after;
+            continue;
+        }
+        let len = rest
+            .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '_'))
+            .unwrap_or(rest.len());
+        match bindings.get(&rest[..len]) {
+            Some(value) => {
+                value.iter().for_each(|(text, _)| result.push_str(text));
+                rest = &rest[len..];
+            }
+            None => result.push('\\'),
+        }
+    }
+    result.push_str(rest);
+    vec![(result, pos)]
+}
@@ -0,0 +1,69 @@
+//! Source files an assembly is made of: the ones given to the assembler and the ones they
+//! include, which positions refer to by their index ('Position::file')
+
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    /// Name used in diagnostics and debugging information
+    pub name: String,
+    pub code: String,
+    /// Canonical path of the file (None for code which isn't read from a file)
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    /// Adds code which isn't read from a file, giving back its index
+    pub fn add(&mut self, name: &str, code: &str) -> usize {
+        self.files.push(SourceFile {
+            name: name.to_string(),
+            code: code.to_string(),
+            path: None,
+        });
+        self.files.len() - 1
+    }
+
+    /// Reads a file, giving back its index (files are read only once)
+    pub fn load(&mut self, name: &str) -> std::io::Result<usize> {
+        let path = Path::new(name).canonicalize()?;
+        if let Some(idx) = self
+            .files
+            .iter()
+            .position(|file| file.path.as_ref() == Some(&path))
+        {
+            return Ok(idx);
+        }
+        let code = std::fs::read_to_string(&path)?;
+        self.files.push(SourceFile {
+            name: name.to_string(),
+            code,
+            path: Some(path),
+        });
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn get(&self, file: usize) -> Option<&SourceFile> {
+        self.files.get(file)
+    }
+
+    pub fn get_mut(&mut self, file: usize) -> Option<&mut SourceFile> {
+        self.files.get_mut(file)
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
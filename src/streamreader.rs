@@ -3,11 +3,31 @@ pub struct Position {
     pub(self) seq: usize,
     pub(self) row: usize,
     pub(self) col: usize,
+    /// Source file (its index in the 'SourceMap' of the assembly)
+    pub(self) file: usize,
 }
 
 impl Position {
     pub fn new(seq: usize, row: usize, col: usize) -> Self {
-        Position { seq, row, col }
+        Position {
+            seq,
+            row,
+            col,
+            file: 0,
+        }
+    }
+
+    pub fn with_file(self, file: usize) -> Self {
+        Position { file, ..self }
+    }
+
+    pub fn file(&self) -> usize {
+        self.file
+    }
+
+    /// Row of the position, told apart from the ones of other files
+    pub fn line(&self) -> (usize, usize) {
+        (self.file, self.row)
     }
 
     pub fn seq(&self) -> usize {
@@ -1,16 +1,17 @@
 pub mod gas {
     use crate::lang::{
-        directive::Directive, directive::DirectiveInstruction, ext::Extension, ext::M, ext::RV32I,
-        highassembly::ArgValue, highassembly::GenericBlock, highassembly::KeyValue,
-        highassembly::Register, highassembly::SectionName, pseudo::Pseudo,
-        pseudo::PseudoInstruction,
+        directive::Directive, directive::DirectiveInstruction, expr::Item, expr::Operator,
+        ext::Extension, ext::M, ext::RV32I, highassembly::ArgValue, highassembly::GenericBlock,
+        highassembly::KeyValue, highassembly::Register, highassembly::SectionChange,
+        highassembly::SectionName, highassembly::SectionSpec, highassembly::SymbolDirective,
+        pseudo::Pseudo, pseudo::PseudoInstruction,
     };
 
     use crate::streamreader::{
         CharStreamReader, Position, PositionedStringStreamReader, StreamReader, StringStreamReader,
     };
 
-    use crate::lexer::CommonClassifier;
+    use crate::lexer::{CommonClassifier, Lexer as _, read_number};
 
     use crate::tokenizer::{
         GenericToken, ToDirective, ToExtension, ToGenericToken, ToPseudo, ToRegister,
@@ -19,15 +20,23 @@ pub mod gas {
 
     use crate::parser::{self};
 
+    use crate::preprocessor;
+
+    use crate::sources::SourceMap;
+
+    use std::path::Path;
+
     use crate::assembler::{self, AssemblerTools};
 
+    use crate::diagnostics::Diagnostic;
+
     /* Lexer */
 
     pub struct Lexer;
 
     impl CommonClassifier for Lexer {
         fn is_ambiguous(&self, ch: char) -> bool {
-            ch == '+' || ch == '-'
+            matches!(ch, '+' | '-' | '<' | '>' | '\'' | '=' | '!' | '&' | '|')
         }
         fn handle_ambiguous(&self, it: &mut CharStreamReader) -> Option<String> {
             let Some(first_ch) = it.current_token() else {
@@ -36,6 +45,49 @@ pub mod gas {
 
             let mut s = String::from(first_ch);
 
+            // shifts and comparisons ('<<', '>>', '<=' and '>=')
+            if matches!(first_ch, '<' | '>') {
+                if let Some(ch) = it.advance_and_read()
+                    && (ch == first_ch || ch == '=')
+                {
+                    s.push(ch);
+                    it.advance();
+                }
+                return Some(s);
+            }
+
+            // '==', '!=', '&&' and '||' (or a single char)
+            if matches!(first_ch, '=' | '!' | '&' | '|') {
+                let second_ch = if first_ch == '!' { '=' } else { first_ch };
+                if it.advance_and_read() == Some(second_ch) {
+                    s.push(second_ch);
+                    it.advance();
+                }
+                return Some(s);
+            }
+
+            // char literals ('a', '\n') go up to their closing quote, within the line (the
+            // tokenizer reports the malformed ones)
+            if first_ch == '\'' {
+                while let Some(ch) = it.advance_and_read()
+                    && ch != '\n'
+                {
+                    s.push(ch);
+                    if ch == '\'' {
+                        it.advance();
+                        break;
+                    }
+                    if ch == '\\'
+                        && let Some(escaped) = it.next_token()
+                        && escaped != '\n'
+                    {
+                        s.push(escaped);
+                        it.advance();
+                    }
+                }
+                return Some(s);
+            }
+
             let Some(second_ch) = it.advance_and_read() else {
                 return Some(s);
             };
@@ -51,13 +103,59 @@ pub mod gas {
         }
 
         fn is_unit(&self, ch: char) -> bool {
-            matches!(ch, ',' | '(' | ')')
+            matches!(ch, ',' | '(' | ')' | '*' | '%' | '^' | '~')
+        }
+
+        // numeric local labels ('1:') and references to them ('1b', '1f') are kept whole, as
+        // well as floating point numbers ('1.5', '2.5e-3')
+        fn handle_number(&self, it: &mut CharStreamReader) -> Option<String> {
+            let mut number = read_number(it)?;
+            if number.starts_with("0x") {
+                return Some(number);
+            }
+            let is_digit = |ch: Option<char>| ch.is_some_and(|ch| ch.is_ascii_digit());
+            let mut float = false;
+            if it.current_token() == Some('.') && is_digit(it.next_token()) {
+                float = true;
+                number.push('.');
+                while is_digit(it.advance_and_read()) {
+                    number.extend(it.current_token());
+                }
+            }
+            if let Some(e @ ('e' | 'E')) = it.current_token()
+                && (is_digit(it.next_token()) || matches!(it.next_token(), Some('+' | '-')))
+            {
+                float = true;
+                number.push(e);
+                number.extend(it.advance_and_read());
+                while is_digit(it.advance_and_read()) {
+                    number.extend(it.current_token());
+                }
+            }
+            if float {
+                return Some(number);
+            }
+            match it.current_token() {
+                Some(':') => number.push(':'),
+                Some(suffix @ ('b' | 'f'))
+                    if !it.next_token().is_some_and(|ch| self.is_identifier(ch)) =>
+                {
+                    number.push(suffix)
+                }
+                _ => return Some(number),
+            }
+            it.advance();
+            Some(number)
         }
 
         fn is_comment(&self, ch: char) -> bool {
             ch == '/'
         }
         fn handle_comment(&self, it: &mut CharStreamReader) -> Option<String> {
+            // a single '/' is the division operator
+            if it.next_token() != Some('/') {
+                return it.read_and_advance().map(|ch| ch.to_string());
+            }
             while let Some(ch) = it.read_and_advance() {
                 if ch == '\n' {
                     break;
@@ -67,7 +165,8 @@ pub mod gas {
         }
 
         fn is_identifier(&self, ch: char) -> bool {
-            ch.is_ascii_alphanumeric() || ch == '.' || ch == ':' || ch == '_'
+            // '' and '@' for the parameters of macros ('\param', '\@')
+            ch.is_ascii_alphanumeric() || matches!(ch, '.' | ':' | '_' | '\\' | '@')
         }
         fn handle_identifier(&self, it: &mut CharStreamReader) -> Option<String> {
             let mut name = String::new();
@@ -76,12 +175,103 @@ pub mod gas {
                     break;
                 }
                 name.push(ch);
+                // '\()' separates a macro parameter from the text following it
+                if ch == '\\' && it.next_token() == Some('(') {
+                    name.push('(');
+                    it.advance();
+                    if it.next_token() == Some(')') {
+                        name.push(')');
+                        it.advance();
+                    }
+                }
                 let _ = it.advance_and_read();
             }
             Some(name)
         }
     }
 
+    /* Preprocessor */
+
+    /// Symbols predefined with constant values (as '-D NAME=value') are set in 'defines'
+    ///
+    /// Included files are looked for next to the file including them and then in 'include_dirs'
+    /// (as '-I dir'), and get added to 'sources'
+    pub struct Preprocessor<'a> {
+        pub defines: Vec<(String, i64)>,
+        pub include_dirs: Vec<String>,
+        pub sources: &'a mut SourceMap,
+    }
+
+    impl preprocessor::Preprocessor for Preprocessor<'_> {
+        fn expand(
+            &mut self,
+            lexemes: Vec<(String, Position)>,
+            diagnostics: &mut Vec<Diagnostic>,
+        ) -> Vec<(String, Position)> {
+            let (sources, include_dirs) = (&mut *self.sources, &self.include_dirs);
+            let mut include = |path: &str, pos: Position| {
+                let file = find_include(sources, include_dirs, path, pos)?;
+                let code = sources.get(file).map_or("", |source| source.code.as_str());
+                let lexemes = Lexer
+                    .get_tokens(code)
+                    .map_err(|_| format!("can't read '{}'", path))?;
+                Ok(lexemes
+                    .into_iter()
+                    .map(|(lexeme, pos)| (lexeme, pos.with_file(file)))
+                    .collect())
+            };
+            let lexemes = preprocessor::preprocess(
+                lexemes,
+                &self.defines,
+                &to_item,
+                &mut include,
+                diagnostics,
+            );
+            preprocessor::resolve_local_labels(lexemes, diagnostics)
+        }
+    }
+
+    /// Reads the file included (as 'path') from 'pos', giving back its index in 'sources'
+    fn find_include(
+        sources: &mut SourceMap,
+        include_dirs: &[String],
+        path: &str,
+        pos: Position,
+    ) -> Result<usize, String> {
+        let including = sources.get(pos.file()).map(|source| source.name.clone());
+        let here = including
+            .as_deref()
+            .and_then(|name| Path::new(name).parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let candidates = std::iter::once(here.join(path))
+            .chain(include_dirs.iter().map(|dir| Path::new(dir).join(path)));
+        for candidate in candidates {
+            if candidate.is_file() {
+                let name = candidate.to_string_lossy();
+                return sources
+                    .load(&name)
+                    .map_err(|e| format!("can't read '{}': {}", name, e));
+            }
+        }
+        Err(format!("can't find '{}'", path))
+    }
+
+    /// Classifies a single lexeme the way the tokenizer does, as a piece of an expression
+    fn to_item(lexeme: &(String, Position)) -> Result<Item, String> {
+        let end = (String::from("\n"), lexeme.1);
+        let mut it = PositionedStringStreamReader::new(vec![lexeme.clone()].into_iter(), end);
+        let token = TokenClassifier::handle_token(&Tokenizer, &mut it)
+            .map_err(|diagnostic| diagnostic.message)?;
+        match token.and_then(|token| token.to_generic_token()) {
+            Some(GenericToken::ArgToken(arg)) => Ok(Item::Arg(arg)),
+            Some(GenericToken::OpToken(op)) => Ok(Item::Op(op)),
+            Some(GenericToken::SignedNumber(n)) => Ok(Item::SignedNumber(n)),
+            Some(GenericToken::Separator) => Ok(Item::Separator),
+            _ => Err(format!("unexpected '{}' in expression", lexeme.0)),
+        }
+    }
+
     /* Tokenizer */
     pub struct Tokenizer;
 
@@ -90,15 +280,37 @@ pub mod gas {
         Op(Box<dyn Extension>, Position),
         Pseudo(Box<dyn Pseudo>, Position),
         AssemblyDirective(Box<dyn Directive>, Position),
-        LinkerDirective(String, Position),
+        LinkerDirective(SymbolDirective, Position),
         Reg(Register),
         Name(String, i32),
-        Str(String),
+        Str(Vec<u8>),
         Label(String, Position),
-        Number(i32),
-        Section(String, Position),
+        Assign(String, Position),
+        Number(i64),
+        /// Written with its sign ('-1', '+3')
+        SignedNumber(i64),
+        Float(f64),
+        Section(SectionChange, Position),
         Plus,
         Minus,
+        Star,
+        Slash,
+        Percent,
+        Shl,
+        Shr,
+        Ampersand,
+        Pipe,
+        Caret,
+        Tilde,
+        EqEq,
+        BangEq,
+        Lt,
+        Le,
+        Gt,
+        Ge,
+        AndAnd,
+        OrOr,
+        Bang,
         Lpar,
         Rpar,
         Comma,
@@ -162,9 +374,18 @@ pub mod gas {
         fn to_directive(&self, token: &str) -> Option<Box<dyn Directive>> {
             match token {
                 ".byte" => Some(Box::new(DirectiveInstruction::Byte)),
-                ".word" => Some(Box::new(DirectiveInstruction::Word)),
+                ".half" | ".short" | ".2byte" => Some(Box::new(DirectiveInstruction::Half)),
+                ".word" | ".long" | ".4byte" => Some(Box::new(DirectiveInstruction::Word)),
+                ".dword" | ".8byte" | ".quad" => Some(Box::new(DirectiveInstruction::Dword)),
+                ".float" => Some(Box::new(DirectiveInstruction::Float)),
+                ".double" => Some(Box::new(DirectiveInstruction::Double)),
                 ".ascii" => Some(Box::new(DirectiveInstruction::Ascii)),
-                ".skip" => Some(Box::new(DirectiveInstruction::Skip)),
+                ".asciz" | ".string" => Some(Box::new(DirectiveInstruction::Asciz)),
+                ".zero" => Some(Box::new(DirectiveInstruction::Zero)),
+                ".skip" | ".space" => Some(Box::new(DirectiveInstruction::Skip)),
+                ".fill" => Some(Box::new(DirectiveInstruction::Fill)),
+                ".align" | ".p2align" => Some(Box::new(DirectiveInstruction::Align)),
+                ".balign" => Some(Box::new(DirectiveInstruction::Balign)),
                 _ => None,
             }
         }
@@ -233,7 +454,11 @@ pub mod gas {
         }
 
         fn is_symbol(&self, token: &str) -> bool {
-            matches!(token, "," | "(" | ")" | "+" | "-")
+            [
+                ",", "(", ")", "+", "-", "*", "/", "%", "<<", ">>", "&", "|", "^", "~", "==", "!=",
+                "<", "<=", ">", ">=", "&&", "||", "!",
+            ]
+            .contains(&token)
         }
 
         fn is_opcode(&self, token: &str) -> bool {
@@ -244,13 +469,25 @@ pub mod gas {
             let mut chs = token.chars();
             let f: char = chs.nth(0).unwrap_or(' ');
             let first_ch_check = f.is_ascii_alphabetic() || matches!(f, '_' | '.');
-            let remaining_string_check =
-                chs.all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '_'));
+            // dots may separate parts of a name, as in '.text.startup' or '.rodata.str1.1'
+            let remaining_string_check = chs.all(|ch| {
+                ch.is_ascii_alphanumeric()
+                    || matches!(ch, '_' | '.' | preprocessor::LOCAL_LABEL_MARK)
+            });
             first_ch_check && remaining_string_check
         }
 
         fn is_section(&self, token: &str) -> bool {
-            token == ".section"
+            matches!(
+                token,
+                ".section"
+                    | ".pushsection"
+                    | ".popsection"
+                    | ".text"
+                    | ".data"
+                    | ".rodata"
+                    | ".bss"
+            )
         }
 
         fn is_directive(&self, token: &str) -> bool {
@@ -262,48 +499,71 @@ pub mod gas {
         }
 
         fn is_custom(&self, token: &str) -> bool {
-            ToPseudo::is_pseudo(self, token) || token == ".globl"
+            ToPseudo::is_pseudo(self, token)
+                || matches!(token, ".equ" | ".set")
+                || to_symbol_directive(token).is_some()
         }
 
-        fn handle_number(&self, it: &mut PositionedStringStreamReader) -> Option<Self::Token> {
-            let Some(token) = it.current_token_ref() else {
-                return None;
+        fn handle_number(
+            &self,
+            it: &mut PositionedStringStreamReader,
+        ) -> Result<Option<Self::Token>, Diagnostic> {
+            let Some((token, pos)) = it.current_token_ref() else {
+                return Ok(None);
             };
 
-            let token = &token.0;
+            if token.starts_with('\'') {
+                return match char_literal(token) {
+                    Some(ch) => Ok(Some(Token::Number(ch as i64))),
+                    None => {
+                        let message = format!("invalid char literal {}", token);
+                        Err(Diagnostic::error(*pos, message).with_len(token.chars().count()))
+                    }
+                };
+            }
+
+            let len = token.chars().count();
+            if !token.contains('x') && token.contains(['.', 'e', 'E']) {
+                return match token.parse::<f64>() {
+                    Ok(x) => Ok(Some(Token::Float(x))),
+                    Err(_) => {
+                        let message = format!("invalid number '{}'", token);
+                        Err(Diagnostic::error(*pos, message).with_len(len))
+                    }
+                };
+            }
 
-            let decimal = if token.contains('x') {
+            let number = if token.contains('x') {
                 let hex = token.replace("0x", "");
-                i32::from_str_radix(&hex, 16)
+                i128::from_str_radix(&hex, 16)
             } else {
-                token.parse::<i32>()
+                token.parse::<i128>()
             };
 
-            let Ok(decimal) = decimal else {
-                panic!("Error converting number to decimal");
+            let Ok(number) = number else {
+                let message = format!("invalid number '{}'", token);
+                return Err(Diagnostic::error(*pos, message).with_len(len));
             };
-
-            // Check for syntax: <offset> '(' <Identifier> ')'
-            let Some(_) = it.advance_if(|next_token| &next_token.0 == "(") else {
-                return Some(Token::Number(decimal));
-            };
-            let Some(identifier) = it.advance_if(|next_token| {
-                !TokenClassifier::is_register(self, &next_token.0)
-                    && self.is_identifier(&next_token.0)
-            }) else {
-                return Some(Token::Number(decimal));
-            };
-            let Some(_) = it.advance_if(|next_token| &next_token.0 == ")") else {
-                return Some(Token::Number(decimal));
-            };
-            Some(Token::Name(identifier.0, decimal))
+            // unsigned 64 bit values are accepted too (i.e: 0xffffffffffffffff), as in gas, while
+            // the parser checks the width of the places they're used in
+            if !(i64::MIN as i128..=u64::MAX as i128).contains(&number) {
+                let message = format!("number '{}' doesn't fit in 64 bits", token);
+                return Err(Diagnostic::error(*pos, message).with_len(len));
+            }
+            if token.starts_with(['+', '-']) {
+                Ok(Some(Token::SignedNumber(number as i64)))
+            } else {
+                Ok(Some(Token::Number(number as i64)))
+            }
         }
 
         fn handle_string(&self, it: &mut PositionedStringStreamReader) -> Option<Self::Token> {
             let Some(token) = it.current_token_ref() else {
                 return None;
             };
-            Some(Token::Str(token.0.trim_matches('"').to_string()))
+            let literal = token.0.strip_prefix('"').unwrap_or(&token.0);
+            let literal = literal.strip_suffix('"').unwrap_or(literal);
+            Some(Token::Str(unescape(literal)))
         }
 
         fn handle_symbol(&self, it: &mut PositionedStringStreamReader) -> Option<Self::Token> {
@@ -316,6 +576,24 @@ pub mod gas {
                 ")" => Some(Token::Rpar),
                 "+" => Some(Token::Plus),
                 "-" => Some(Token::Minus),
+                "*" => Some(Token::Star),
+                "/" => Some(Token::Slash),
+                "%" => Some(Token::Percent),
+                "<<" => Some(Token::Shl),
+                ">>" => Some(Token::Shr),
+                "&" => Some(Token::Ampersand),
+                "|" => Some(Token::Pipe),
+                "^" => Some(Token::Caret),
+                "~" => Some(Token::Tilde),
+                "==" => Some(Token::EqEq),
+                "!=" => Some(Token::BangEq),
+                "<" => Some(Token::Lt),
+                "<=" => Some(Token::Le),
+                ">" => Some(Token::Gt),
+                ">=" => Some(Token::Ge),
+                "&&" => Some(Token::AndAnd),
+                "||" => Some(Token::OrOr),
+                "!" => Some(Token::Bang),
                 _ => None,
             }
         }
@@ -331,17 +609,71 @@ pub mod gas {
         }
 
         fn handle_identifier(&self, it: &mut PositionedStringStreamReader) -> Option<Self::Token> {
-            let Some(token) = it.current_token_ref() else {
-                return None;
-            };
-            Some(Token::Name(token.0.to_string(), 0))
+            let (name, pos) = it.current_token()?;
+            // 'name = value'
+            if it.advance_if(|next| next.0 == "=").is_some() {
+                return Some(Token::Assign(name, pos));
+            }
+            Some(Token::Name(name, 0))
         }
 
+        // '.section name, "flags", @type', whose flags and type are optional (the name is left
+        // empty when missing)
         fn handle_section(&self, it: &mut PositionedStringStreamReader) -> Option<Self::Token> {
-            let Some(token) = it.advance_and_read() else {
-                return None;
+            let (directive, pos) = it.current_token()?;
+            let spec = |name: &str| SectionSpec {
+                name: SectionName::from_default_name(name),
+                flags: None,
+                kind: None,
+            };
+            match directive.as_str() {
+                ".popsection" => return Some(Token::Section(SectionChange::Pop, pos)),
+                ".section" | ".pushsection" => {}
+                shortcut => {
+                    return Some(Token::Section(SectionChange::Switch(spec(shortcut)), pos));
+                }
+            }
+            let same_line = |next: &(String, Position)| next.1.line() == pos.line();
+            let comma = |next: &(String, Position)| same_line(next) && next.0 == ",";
+            let mut spec = it
+                .advance_if(|next| same_line(next) && self.is_identifier(&next.0))
+                .map_or(spec(""), |name| spec(&name.0));
+            if it.advance_if(comma).is_some() {
+                spec.flags = it
+                    .advance_if(|next| same_line(next) && next.0.starts_with('"'))
+                    .map(|flags| flags.0.trim_matches('"').to_string());
+            }
+            if spec.flags.is_some() && it.advance_if(comma).is_some() {
+                // '@type' is a single lexeme, while '%type' is two
+                let percent = it
+                    .advance_if(|next| same_line(next) && next.0 == "%")
+                    .is_some();
+                let is_kind = |kind: &str| {
+                    (percent || kind.starts_with('@'))
+                        && kind
+                            .trim_start_matches('@')
+                            .chars()
+                            .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+                };
+                spec.kind = it
+                    .advance_if(|next| same_line(next) && is_kind(&next.0))
+                    .map(|kind| {
+                        if percent {
+                            format!("%{}", kind.0)
+                        } else {
+                            kind.0
+                        }
+                    });
+                // the size of the entries of merged sections ('"aMS", @progbits, 1')
+                if spec.kind.is_some() && it.advance_if(comma).is_some() {
+                    it.advance_if(|next| same_line(next) && next.0.parse::<u32>().is_ok());
+                }
+            }
+            let change = match directive.as_str() {
+                ".pushsection" => SectionChange::Push(spec),
+                _ => SectionChange::Switch(spec),
             };
-            Some(Token::Section(token.0[1..].to_string(), token.1))
+            Some(Token::Section(change, pos))
         }
 
         fn handle_directive(&self, it: &mut PositionedStringStreamReader) -> Option<Self::Token> {
@@ -372,8 +704,42 @@ pub mod gas {
             };
             if let Some(p) = ToPseudo::to_pseudo(self, &token.0) {
                 Some(Token::Pseudo(p, token.1))
-            } else if &token.0 == ".globl" {
-                Some(Token::LinkerDirective(token.0.to_string(), token.1))
+            } else if &token.0 == ".type" {
+                // '.type name, @kind' (the name and the kind are left empty when missing)
+                let pos = token.1;
+                let same_line = |next: &(String, Position)| next.1.line() == pos.line();
+                let symbol = it
+                    .advance_if(|next| same_line(next) && self.is_identifier(&next.0))
+                    .map_or(String::new(), |name| name.0);
+                let mut kind = String::new();
+                if it
+                    .advance_if(|next| same_line(next) && next.0 == ",")
+                    .is_some()
+                {
+                    // '@kind' is a single lexeme, while '%kind' is two
+                    if it
+                        .advance_if(|next| same_line(next) && next.0 == "%")
+                        .is_some()
+                    {
+                        kind.push('%');
+                    }
+                    if let Some(next) = it.advance_if(same_line) {
+                        kind.push_str(&next.0);
+                    }
+                }
+                Some(Token::LinkerDirective(
+                    SymbolDirective::Type { symbol, kind },
+                    pos,
+                ))
+            } else if let Some(directive) = to_symbol_directive(&token.0) {
+                Some(Token::LinkerDirective(directive, token.1))
+            } else if matches!(token.0.as_str(), ".equ" | ".set") {
+                // '.equ name, value' (the name is left empty when missing)
+                let pos = token.1;
+                let name = it
+                    .advance_if(|next| next.1.line() == pos.line() && self.is_identifier(&next.0))
+                    .map_or(String::new(), |name| name.0);
+                Some(Token::Assign(name, pos))
             } else {
                 None
             }
@@ -393,11 +759,29 @@ pub mod gas {
     impl ToGenericToken for Token {
         fn to_generic_token(self) -> Option<GenericToken> {
             match self {
-                Token::Plus => None,
-                Token::Minus => None,
-                Token::Lpar => None,
-                Token::Rpar => None,
-                Token::Comma => None,
+                Token::Plus => Some(GenericToken::OpToken(Operator::Plus)),
+                Token::Minus => Some(GenericToken::OpToken(Operator::Minus)),
+                Token::Star => Some(GenericToken::OpToken(Operator::Mul)),
+                Token::Slash => Some(GenericToken::OpToken(Operator::Div)),
+                Token::Percent => Some(GenericToken::OpToken(Operator::Rem)),
+                Token::Shl => Some(GenericToken::OpToken(Operator::Shl)),
+                Token::Shr => Some(GenericToken::OpToken(Operator::Shr)),
+                Token::Ampersand => Some(GenericToken::OpToken(Operator::And)),
+                Token::Pipe => Some(GenericToken::OpToken(Operator::Or)),
+                Token::Caret => Some(GenericToken::OpToken(Operator::Xor)),
+                Token::Tilde => Some(GenericToken::OpToken(Operator::Not)),
+                Token::EqEq => Some(GenericToken::OpToken(Operator::Eq)),
+                Token::BangEq => Some(GenericToken::OpToken(Operator::Ne)),
+                Token::Lt => Some(GenericToken::OpToken(Operator::Lt)),
+                Token::Le => Some(GenericToken::OpToken(Operator::Le)),
+                Token::Gt => Some(GenericToken::OpToken(Operator::Gt)),
+                Token::Ge => Some(GenericToken::OpToken(Operator::Ge)),
+                Token::AndAnd => Some(GenericToken::OpToken(Operator::LogicalAnd)),
+                Token::OrOr => Some(GenericToken::OpToken(Operator::LogicalOr)),
+                Token::Bang => Some(GenericToken::OpToken(Operator::LogicalNot)),
+                Token::Lpar => Some(GenericToken::OpToken(Operator::Lpar)),
+                Token::Rpar => Some(GenericToken::OpToken(Operator::Rpar)),
+                Token::Comma => Some(GenericToken::Separator),
                 Token::Op(extension, pos) => {
                     Some(GenericToken::KeyToken(KeyValue::Op(extension), pos))
                 }
@@ -407,6 +791,9 @@ pub mod gas {
                 Token::Label(label, pos) => {
                     Some(GenericToken::KeyToken(KeyValue::Label(label), pos))
                 }
+                Token::Assign(name, pos) => {
+                    Some(GenericToken::KeyToken(KeyValue::Assignment(name), pos))
+                }
                 Token::AssemblyDirective(directive, pos) => Some(GenericToken::KeyToken(
                     KeyValue::AssemblyDirective(directive),
                     pos,
@@ -415,32 +802,102 @@ pub mod gas {
                 Token::Name(name, off) => Some(GenericToken::ArgToken(ArgValue::Use(name, off))),
                 Token::Str(literal) => Some(GenericToken::ArgToken(ArgValue::Literal(literal))),
                 Token::Number(n) => Some(GenericToken::ArgToken(ArgValue::Number(n))),
-                Token::Section(sec, pos) => match sec.as_str() {
-                    "text" => Some(GenericToken::KeyToken(
-                        KeyValue::Section(SectionName::Text),
-                        pos,
-                    )),
-                    "data" => Some(GenericToken::KeyToken(
-                        KeyValue::Section(SectionName::Data),
-                        pos,
-                    )),
-                    "bss" => Some(GenericToken::KeyToken(
-                        KeyValue::Section(SectionName::Bss),
-                        pos,
-                    )),
-                    other => Some(GenericToken::KeyToken(
-                        KeyValue::Section(SectionName::Custom(other.to_string())),
-                        pos,
-                    )),
-                },
-                Token::LinkerDirective(s, pos) => match s.as_str() {
-                    ".globl" => Some(GenericToken::KeyToken(KeyValue::LinkerDirective(s), pos)),
-                    _ => None,
-                },
+                Token::SignedNumber(n) => Some(GenericToken::SignedNumber(n)),
+                Token::Float(x) => Some(GenericToken::ArgToken(ArgValue::Float(x))),
+                Token::Section(change, pos) => {
+                    Some(GenericToken::KeyToken(KeyValue::Section(change), pos))
+                }
+                Token::LinkerDirective(directive, pos) => Some(GenericToken::KeyToken(
+                    KeyValue::LinkerDirective(directive),
+                    pos,
+                )),
             }
         }
     }
 
+    /// Directives giving attributes to symbols ('.type' is told apart since it takes a kind)
+    fn to_symbol_directive(token: &str) -> Option<SymbolDirective> {
+        match token {
+            ".globl" | ".global" => Some(SymbolDirective::Global),
+            ".local" => Some(SymbolDirective::Local),
+            ".weak" => Some(SymbolDirective::Weak),
+            ".hidden" => Some(SymbolDirective::Hidden),
+            ".protected" => Some(SymbolDirective::Protected),
+            ".size" => Some(SymbolDirective::Size),
+            ".type" => Some(SymbolDirective::Type {
+                symbol: String::new(),
+                kind: String::new(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Bytes of the contents of a string literal, whose escape sequences ('\n', '\t', '\\', '\"',
+    /// octal ones as '\101' and hexadecimal ones as '\x41') are replaced
+    fn unescape(literal: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut chars = literal.chars().peekable();
+        while let Some(ch) = chars.next() {
+            if ch != '\\' {
+                let mut buffer = [0; 4];
+                bytes.extend(ch.encode_utf8(&mut buffer).as_bytes());
+                continue;
+            }
+            let byte = match chars.next() {
+                Some('n') => b'\n',
+                Some('t') => b'\t',
+                Some('r') => b'\r',
+                Some('b') => 0x08,
+                Some('f') => 0x0c,
+                Some('v') => 0x0b,
+                Some('x' | 'X') => {
+                    let mut value: u32 = 0;
+                    while let Some(digit) = chars.peek().and_then(|ch| ch.to_digit(16)) {
+                        value = (value << 4) | digit;
+                        chars.next();
+                    }
+                    value as u8
+                }
+                Some(digit @ '0'..='7') => {
+                    let mut value = digit.to_digit(8).unwrap();
+                    for _ in 0..2 {
+                        match chars.peek().and_then(|ch| ch.to_digit(8)) {
+                            Some(digit) => value = (value << 3) | digit,
+                            None => break,
+                        }
+                        chars.next();
+                    }
+                    value as u8
+                }
+                // '\\', '\"' and the unknown ones stand for the char escaped
+                Some(escaped) => {
+                    let mut buffer = [0; 4];
+                    bytes.extend(escaped.encode_utf8(&mut buffer).as_bytes());
+                    continue;
+                }
+                None => b'\\',
+            };
+            bytes.push(byte);
+        }
+        bytes
+    }
+
+    /// Value of a char literal ('a' or '\n'), a single char or escape sequence between quotes
+    fn char_literal(token: &str) -> Option<char> {
+        let literal = token.strip_prefix('\'')?.strip_suffix('\'')?;
+        let mut chars = literal.chars();
+        let ch = match (chars.next()?, chars.next()) {
+            ('\\', Some('n')) => '\n',
+            ('\\', Some('t')) => '\t',
+            ('\\', Some('r')) => '\r',
+            ('\\', Some('0')) => '\0',
+            ('\\', Some(escaped @ ('\\' | '\'' | '"'))) => escaped,
+            (ch, None) if !matches!(ch, '\\' | '\'') => ch,
+            _ => return None,
+        };
+        chars.next().is_none().then_some(ch)
+    }
+
     /* Parser */
 
     pub struct Parser;
@@ -449,8 +906,12 @@ pub mod gas {
         type Token = Token;
         type Output = Vec<GenericBlock>;
 
-        fn parse(&self, tokens: Vec<Self::Token>) -> Self::Output {
-            parser::parse(tokens)
+        fn parse(
+            &self,
+            tokens: Vec<Self::Token>,
+            diagnostics: &mut Vec<Diagnostic>,
+        ) -> Self::Output {
+            parser::parse(tokens, diagnostics)
         }
     }
 
@@ -460,8 +921,12 @@ pub mod gas {
     impl assembler::Assembler for Assembler {
         type Input = Vec<GenericBlock>;
 
-        fn assemble(&self, instruction: Self::Input) -> AssemblerTools {
-            assembler::assemble(instruction)
+        fn assemble(
+            &self,
+            instruction: Self::Input,
+            diagnostics: &mut Vec<Diagnostic>,
+        ) -> AssemblerTools {
+            assembler::assemble(instruction, diagnostics)
         }
     }
 
@@ -2,6 +2,9 @@ use crate::lang::highassembly::{
     ArgValue,
     KeyValue,
 };
+use crate::lang::expr::Operator;
+use crate::preprocessor::END_OF_STATEMENT;
+use crate::diagnostics::Diagnostic;
 use crate::streamreader::{
     StreamReader,
     StringStreamReader,
@@ -13,6 +16,11 @@ use crate::streamreader::{
 pub enum GenericToken {
     KeyToken(KeyValue, Position),
     ArgToken(ArgValue),
+    OpToken(Operator),
+    /// A number written with its sign, which can also be added to what precedes it
+    SignedNumber(i64),
+    /// Between arguments (',')
+    Separator,
 }
 
 pub trait ToGenericToken {
@@ -21,7 +29,13 @@ pub trait ToGenericToken {
 
 pub trait Tokenizer {
     type Token: ToGenericToken;
-    fn parse(&self, tokens: Vec<(String, Position)>) -> Vec<<Self as Tokenizer>::Token> ;
+    /// Statements holding errors are left out (keeping their labels), so that the rest can still
+    /// be checked
+    fn parse(
+        &self,
+        tokens: Vec<(String, Position)>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Vec<<Self as Tokenizer>::Token> ;
 }
 
 
@@ -59,22 +73,18 @@ pub trait TokenClassifier {
     fn is_directive(&self, _: &str) -> bool ;
     fn is_custom(&self, token: &str) -> bool ;
     fn is_label(&self, token: &str) -> bool ;
+    // Anything starting like a number is one, and 'handle_number' reports the malformed ones
     fn is_number(&self, token: &str) -> bool {
-        let is_decimal = token.parse::<i32>().is_ok();
-        let is_hex = if token.contains('x') {
-            let without_pref = token.replace("0x", "");
-            i32::from_str_radix(&without_pref, 16).is_ok()
-        }
-        else {
-            false
-        };
-        is_decimal || is_hex
+        let is_char = token.starts_with('\'');
+        is_char || token
+            .trim_start_matches(['+', '-'])
+            .starts_with(|ch: char| ch.is_ascii_digit())
     }
     fn is_string(&self, token: &str) -> bool {
         token.starts_with('"') && token.ends_with('"')
     }
 
-    fn handle_number(&self, it: &mut PositionedStringStreamReader) -> Option<Self::Token> ;
+    fn handle_number(&self, it: &mut PositionedStringStreamReader) -> Result<Option<Self::Token>, Diagnostic> ;
     fn handle_string(&self, it: &mut PositionedStringStreamReader) -> Option<Self::Token> ;
     fn handle_symbol(&self, it: &mut PositionedStringStreamReader) -> Option<Self::Token> ;
     fn handle_register(&self, it: &mut PositionedStringStreamReader) -> Option<Self::Token> ;
@@ -129,22 +139,28 @@ pub trait TokenClassifier {
         TokenClass::Ignore
     }
 
-    fn handle_token(&self, it: &mut PositionedStringStreamReader) -> Option<Self::Token> {
+    fn handle_token(&self, it: &mut PositionedStringStreamReader) -> Result<Option<Self::Token>, Diagnostic> {
         let token = it.current_token().expect("Lexer failed when retrieving token");
         let class = self.classify(token.0.as_str());
         // println!("Processing {} as {:?}", token, class);
         match class {
-            TokenClass::Label      => self.handle_label(it),
+            TokenClass::Label      => Ok(self.handle_label(it)),
             TokenClass::Number     => self.handle_number(it),
-            TokenClass::Symbol     => self.handle_symbol(it),
-            TokenClass::Section    => self.handle_section(it),
-            TokenClass::Directive  => self.handle_directive(it),
-            TokenClass::Custom     => self.handle_custom(it),
-            TokenClass::Opcode     => self.handle_opcode(it),
-            TokenClass::String     => self.handle_string(it),
-            TokenClass::Identifier => self.handle_identifier(it),
-            TokenClass::Register   => self.handle_register(it),
-            TokenClass::Ignore     => None
+            TokenClass::Symbol     => Ok(self.handle_symbol(it)),
+            TokenClass::Section    => Ok(self.handle_section(it)),
+            TokenClass::Directive  => Ok(self.handle_directive(it)),
+            TokenClass::Custom     => Ok(self.handle_custom(it)),
+            TokenClass::Opcode     => Ok(self.handle_opcode(it)),
+            TokenClass::String     => Ok(self.handle_string(it)),
+            TokenClass::Identifier => Ok(self.handle_identifier(it)),
+            TokenClass::Register   => Ok(self.handle_register(it)),
+            TokenClass::Ignore     => {
+                let what = if token.0.chars().count() == 1 { "character " } else { "" };
+                Err(
+                    Diagnostic::error(token.1, format!("unexpected {}'{}'", what, token.0))
+                        .with_len(token.0.chars().count())
+                )
+            }
         }
     }
 }
@@ -155,17 +171,71 @@ The 'Lexer' Trait is implemented for any entity which implements the 'tokenizer:
 impl<T: ToGenericToken, C: TokenClassifier<Token = T>> Tokenizer for C {
     type Token = T;
 
-    fn parse(&self, tokens: Vec<(String, Position)>) -> Vec<<Self as Tokenizer>::Token>  {
+    fn parse(
+        &self,
+        tokens: Vec<(String, Position)>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Vec<<Self as Tokenizer>::Token> {
         let mut it = PositionedStringStreamReader::new(tokens.into_iter(), (String::from("\n"), Position::new(0, 0, 0)));
 
         let mut tokens = Vec::new();
-
-        while let Some(_) = it.current_token() {
-            if let Some(lex) = self.handle_token(&mut it) {
-                tokens.push(lex);
+        let mut row = None;
+        // whether the current token begins a statement (only labels may come before it)
+        let mut statement_start = true;
+        // tokens of the current statement (labels aside) start at 'statement_idx', and are
+        // dropped once it ends if any of them is wrong
+        let mut statement_idx = 0;
+        let mut failed = false;
+
+        while let Some((token, pos)) = it.current_token() {
+            // statements expanded from macros share the row of their invocation
+            if row != Some(pos.line()) || token == END_OF_STATEMENT {
+                row = Some(pos.line());
+                statement_start = true;
+                if std::mem::take(&mut failed) {
+                    tokens.truncate(statement_idx);
+                }
+            }
+            if token == END_OF_STATEMENT {
+                it.advance();
+                continue;
+            }
+            if statement_start {
+                statement_idx = tokens.len();
+            }
+            let class = self.classify(&token);
+
+            // a statement begins with an unknown name: its arguments would otherwise be
+            // taken as the previous statement's, so the whole line is skipped ('name = value'
+            // assignments aside)
+            let assignment = it.next_token_ref().is_some_and(|next| next.0 == "=");
+            if statement_start && matches!(class, TokenClass::Identifier) && !assignment {
+                let kind = if token.starts_with('.') { "directive" } else { "instruction" };
+                diagnostics.push(
+                    Diagnostic::error(pos, format!("unknown {} '{}'", kind, token))
+                        .with_len(token.chars().count())
+                );
+                while it.next_token_ref().is_some_and(|next| next.1.line() == pos.line() && next.0 != END_OF_STATEMENT) {
+                    it.advance();
+                }
+                it.advance();
+                continue;
+            }
+            statement_start = matches!(class, TokenClass::Label);
+
+            match self.handle_token(&mut it) {
+                Ok(Some(lex)) => tokens.push(lex),
+                Ok(None) => {}
+                Err(diagnostic) => {
+                    diagnostics.push(diagnostic);
+                    failed = true;
+                }
             }
             it.advance();
         }
+        if failed {
+            tokens.truncate(statement_idx);
+        }
 
         tokens
     }
@@ -1,37 +1,119 @@
-use crate::assembler::{Assembler, AssemblerTools};
+use crate::assembler::{Assembler, AssemblerTools, RelocationKind};
+use crate::diagnostics;
 use crate::emu::debugger::SimpleGdbStub;
-use crate::emu::machine::{Machine, SimpleMachine};
-use crate::lang::highassembly::SectionName;
+use crate::emu::machine::{FloatExtension, Machine, MachineState, SimpleMachine};
+use crate::emu::repl::Repl;
+use crate::emu::trace::{CommitLog, SharedBuffer, TraceFilter};
+use crate::emu::transport::Transport;
+use crate::lang::highassembly::{Register, SectionName};
 use crate::lang::lowassembly::{DataEndianness, EncodedData};
 use crate::lexer::Lexer;
+use crate::obj::dwarfreader::{SourceLine, read_line_table};
 use crate::obj::dwarfwriter::add_debug_information;
 use crate::obj::elfreader;
 use crate::obj::elfwriter;
 use crate::parser::Parser;
+use crate::preprocessor::Preprocessor;
+use crate::sources::SourceMap;
 use crate::syntax;
 use crate::tokenizer::Tokenizer;
 
-pub fn build_code_repr(code: &str) -> AssemblerTools {
+/// Assembles 'code', reporting the errors of all its statements
+pub fn assemble_code(code: &str) -> diagnostics::Result<AssemblerTools> {
+    assemble_code_with_defines(code, &[])
+}
+
+/// Same as 'assemble_code', with symbols predefined as constants (i.e: '-D NAME=value')
+pub fn assemble_code_with_defines(
+    code: &str,
+    defines: &[(String, i64)],
+) -> diagnostics::Result<AssemblerTools> {
+    let mut sources = SourceMap::default();
+    sources.add("<source>", code);
+    let options = AssemblyOptions {
+        defines: defines.to_vec(),
+        ..Default::default()
+    };
+    assemble_sources(&mut sources, &options)
+}
+
+/// Options of an assembly, as given in the command line
+#[derive(Debug, Clone, Default)]
+pub struct AssemblyOptions {
+    /// Symbols predefined as constants ('-D NAME=value')
+    pub defines: Vec<(String, i64)>,
+    /// Directories where included files are looked for ('-I dir')
+    pub include_dirs: Vec<String>,
+}
+
+/// Assembles the files of 'sources' into a single object, reporting the errors of all their
+/// statements (the files they include get added to 'sources')
+///
+/// Every stage leaves out the statements it finds errors in (keeping their labels), so the ones
+/// after it still get to check the rest of the code
+pub fn assemble_sources(
+    sources: &mut SourceMap,
+    options: &AssemblyOptions,
+) -> diagnostics::Result<AssemblerTools> {
     let mut lexer = syntax::gas::Lexer;
     let tokenizer = syntax::gas::Tokenizer;
     let parser = syntax::gas::Parser;
     let assembler = syntax::gas::Assembler;
 
-    let lexemes = lexer.get_tokens(code);
+    let mut diagnostics = Vec::new();
+    let mut lexemes = Vec::new();
+    for file in 0..sources.len() {
+        let code = sources.get(file).map_or("", |source| source.code.as_str());
+        match lexer.get_tokens(code) {
+            Ok(file_lexemes) => lexemes.extend(
+                file_lexemes
+                    .into_iter()
+                    .map(|(lexeme, pos)| (lexeme, pos.with_file(file))),
+            ),
+            Err(found) => diagnostics.extend(found),
+        }
+    }
+
+    let mut preprocessor = syntax::gas::Preprocessor {
+        defines: options.defines.clone(),
+        include_dirs: options.include_dirs.clone(),
+        sources,
+    };
+    let lexemes = preprocessor.expand(lexemes, &mut diagnostics);
     // println!("{:?}", tokens);
     // dbg!(&tokens);
 
-    let tokens = tokenizer.parse(lexemes);
+    let tokens = tokenizer.parse(lexemes, &mut diagnostics);
     // println!("{:?}", lexemes);
     // dbg!(&lexemes);
 
-    let blocks = parser.parse(tokens);
+    let blocks = parser.parse(tokens, &mut diagnostics);
     // dbg!(&blocks);
 
-    let tools = assembler.assemble(blocks);
+    let tools = assembler.assemble(blocks, &mut diagnostics);
     // dbg!(&tools);
 
-    tools
+    if diagnostics.is_empty() {
+        Ok(tools)
+    } else {
+        Err(diagnostics)
+    }
+}
+
+/// Same as 'assemble_code', for code known to be valid (panics with the diagnostics otherwise)
+pub fn build_code_repr(code: &str) -> AssemblerTools {
+    assemble_code(code)
+        .unwrap_or_else(|errors| panic!("{}", diagnostics::render(&errors, "<source>", code)))
+}
+
+/// Same as 'build_code_repr', for a file (the sources it's made of are given back too)
+pub fn build_file_repr(filename: &str) -> (AssemblerTools, SourceMap) {
+    let mut sources = SourceMap::default();
+    sources.load(filename).expect("Failed reading source file");
+    match assemble_sources(&mut sources, &AssemblyOptions::default()) {
+        Ok(tools) => (tools, sources),
+        Err(errors) => panic!("{}", diagnostics::render_sources(&errors, &sources)),
+    }
 }
 
 pub fn encode_to_words(code: &str) -> Vec<u32> {
@@ -48,30 +130,35 @@ fn write_from_tools<'a>(mut output: AssemblerTools) -> (elfwriter::ElfWriter<'a>
     let relocation_table = &output.relocations;
     let blocks = &output.blocks;
 
-    if symbol_table.contains_key("_start") {
-        let symb = symbol_table.get("_start").expect("No _start found");
-        writer.set_start_address(symb.relative_address.try_into().unwrap());
-        symbol_table.remove("_start").unwrap();
-    } else {
-        writer.set_start_address(0);
+    let mut sections: Vec<_> = output.sections.values().collect();
+    sections.sort_by_key(|section| section.address);
+    for section in sections {
+        writer.add_section(&section.name, section.flags);
+    }
+
+    // the entry point is always global, so only the object defining it exports it
+    if let Some(symb) = symbol_table.remove("_start") {
+        writer.set_start_address(&symb.section, symb.relative_address.try_into().unwrap());
     }
 
     for (name, symb) in symbol_table {
         let symbol_section = symb.section.clone();
         let symbol_addr = symb.relative_address.try_into().unwrap();
         let length = symb.length;
-        writer.add_symbol(symbol_section, symbol_addr, &name, length as u64);
+        writer.add_symbol(
+            symbol_section,
+            symbol_addr,
+            name,
+            length as u64,
+            symb.attributes,
+        );
     }
 
     for block in blocks {
         if block.instructions.len() > 0 {
             let name = &block.name;
             let data = encoded_data_to_bytes_le(&block.instructions);
-            let alignment = match name {
-                SectionName::Text => 4,
-                SectionName::Data => 1,
-                _ => panic!(""),
-            };
+            let alignment = output.sections[&name.default_name()].alignment as u64;
             writer
                 .set_section_data(name.clone(), data, alignment)
                 .expect("");
@@ -83,9 +170,14 @@ fn write_from_tools<'a>(mut output: AssemblerTools) -> (elfwriter::ElfWriter<'a>
             let offset = relocation.address.try_into().unwrap();
             let addend = relocation.addend;
             let relidx = relocation.id;
-            writer
-                .handle_symbol_relocation(symbname, offset, addend, relidx)
-                .unwrap();
+            match relocation.kind {
+                RelocationKind::PcrelPair => writer
+                    .handle_symbol_relocation(&relocation.section, symbname, offset, addend, relidx)
+                    .unwrap(),
+                RelocationKind::Abs32 => writer
+                    .handle_data_relocation(&relocation.section, offset, symbname, addend)
+                    .unwrap(),
+            }
         }
     }
 
@@ -93,9 +185,7 @@ fn write_from_tools<'a>(mut output: AssemblerTools) -> (elfwriter::ElfWriter<'a>
 }
 
 pub fn encode_to_elf(code: &str, output_file: &str) -> elfwriter::Result<()> {
-    let output = build_code_repr(code);
-    let (writer, _) = write_from_tools(output);
-    writer.save(output_file)
+    save_elf(build_code_repr(code), output_file)
 }
 
 pub fn encode_to_elf_with_debug(
@@ -103,36 +193,51 @@ pub fn encode_to_elf_with_debug(
     input_file: &str,
     output_file: &str,
 ) -> elfwriter::Result<()> {
-    let output = build_code_repr(code);
-    let (mut writer, tools) = write_from_tools(output);
-    add_debug_information(&mut writer, tools, input_file.as_bytes());
+    let mut sources = SourceMap::default();
+    sources.add(input_file, code);
+    save_elf_with_debug(build_code_repr(code), &sources, output_file)
+}
+
+pub fn save_elf(tools: AssemblerTools, output_file: &str) -> elfwriter::Result<()> {
+    let (writer, _) = write_from_tools(tools);
+    writer.save(output_file)
+}
+
+pub fn save_elf_with_debug(
+    tools: AssemblerTools,
+    sources: &SourceMap,
+    output_file: &str,
+) -> elfwriter::Result<()> {
+    let (mut writer, tools) = write_from_tools(tools);
+    add_debug_information(&mut writer, tools, sources);
     writer.save(output_file)
 }
 
 pub fn new_machine_from_tools(tools: &AssemblerTools) -> SimpleMachine {
-    let text_start = tools.text_section_start();
-    let textdata = tools.text_section_bytes_be();
-
-    let data_start = tools.data_section_start();
-    let datadata = tools.data_section_bytes_be();
+    // every section occupying memory while the program runs is placed at its address
+    let sections: Vec<_> = tools
+        .sections
+        .values()
+        .filter(|section| section.flags.alloc)
+        .map(|section| {
+            let words = tools.section_words(section.name.clone());
+            (section.address, words_to_bytes_be(&words))
+        })
+        .collect();
 
-    let minsize = textdata.len() + datadata.len();
-    let max_start = if text_start > data_start {
-        text_start
-    } else {
-        data_start
-    };
-    let memsize = if max_start > minsize {
-        max_start + minsize
-    } else {
-        max_start + (minsize - max_start)
-    } + 4usize;
+    let memsize = sections
+        .iter()
+        .map(|(address, bytes)| address + bytes.len())
+        .max()
+        .unwrap_or(0)
+        + 4usize;
 
     let pc = 0;
 
     let mut m = SimpleMachine::from_bytes_size(memsize, DataEndianness::Be);
-    m.write_memory_bytes(text_start, &textdata);
-    m.write_memory_bytes(data_start, &datadata);
+    for (address, bytes) in sections {
+        m.write_memory_bytes(address, &bytes);
+    }
     m.jump(pc);
     m
 }
@@ -143,49 +248,103 @@ pub fn new_machine_from_elf(filename: &str) -> SimpleMachine {
     let reader = elfreader::ElfReader::new(&data, DataEndianness::Le)
         .expect("Failed instantiating elf file reader");
 
-    let textsec = reader.section(".text").unwrap();
-    let datasec = reader.section(".data");
-
-    let textdata = &textsec.data;
-    let text_start = textsec.address as usize;
-
-    let has_data_section = datasec.is_some();
-
-    let (memsize, data_start, datadata) = {
-        if !has_data_section {
-            let memsize = text_start + textdata.len();
-            (memsize, None, None)
-        } else {
-            let datadata = &datasec.unwrap().data;
-            let data_start = datasec.unwrap().address as usize;
-            let minsize = textdata.len() + datadata.len();
-            let max_start = if text_start > data_start {
-                text_start
-            } else {
-                data_start
-            };
-            let memsize = if max_start > minsize {
-                max_start + minsize
-            } else {
-                max_start + (minsize - max_start)
-            };
-            (memsize, Some(data_start), Some(datadata))
-        }
-    };
+    load_elf(&reader)
+}
 
-    let pc = reader.pc();
+/// Size of the stack placed right after the last section of an ELF file loaded in a machine
+pub const STACK_SIZE: usize = 64 * 1024;
+
+/// Builds a machine out of an ELF file: every allocated section is loaded at its address, the pc
+/// is set to the entry point and 'sp' points to the top of the stack
+///
+/// Memory starts at the page of the first section, so programs linked at high addresses (i.e:
+/// 0x80000000, as the RISC-V tests) only take the memory they use
+pub fn load_elf<T: Machine>(reader: &elfreader::ElfReader) -> T {
+    let sections = reader.loadable_sections();
+
+    let start = sections
+        .first()
+        .map_or(0, |section| section.address as usize & !0xfff);
+    let sections_end = sections
+        .iter()
+        .map(|section| section.address as usize + section.size)
+        .max()
+        .unwrap_or(0);
+    let memsize = sections_end.next_multiple_of(16) - start + STACK_SIZE;
+
+    let mut m = T::from_bytes_size(memsize, DataEndianness::Le);
+    m.set_memory_start(start);
+
+    for section in sections {
+        // memory is written a word at a time, so trailing bytes must be padded
+        let mut data = section.data.clone();
+        data.resize(data.len().next_multiple_of(4), 0);
+        m.write_memory_bytes(section.address as usize, &data);
+    }
 
-    let mut m = SimpleMachine::from_bytes_size(memsize, DataEndianness::Le);
+    let mut registers = m.read_registers();
+    registers.pop(); // pc
+    registers[Register::SP.id() as usize] = (start + memsize) as u32;
+    m.write_registers(registers, reader.pc());
 
-    m.write_memory_bytes(text_start, textdata);
-    if has_data_section {
-        m.write_memory_bytes(data_start.unwrap(), datadata.unwrap());
+    // self-checking tests (riscv-tests, ...) report their result through 'tohost'
+    if let Some(tohost) = reader.symbol_address("tohost") {
+        m.set_htif(tohost, reader.symbol_address("fromhost"));
     }
-    m.jump(pc);
 
     m
 }
 
+/// Memory region between the 'begin_signature' and 'end_signature' symbols of an executable,
+/// where the RISC-V architectural tests leave their results
+pub fn signature_range(filename: &str) -> Option<std::ops::Range<usize>> {
+    let data = std::fs::read(filename).ok()?;
+    let reader = elfreader::ElfReader::new(&data, DataEndianness::Le).ok()?;
+    Some(reader.symbol_address("begin_signature")?..reader.symbol_address("end_signature")?)
+}
+
+/// Writes the words of a memory region as hex, one per line (i.e: the signature file expected by
+/// the RISC-V architectural tests)
+pub fn write_signature<T: Machine, W: std::io::Write>(
+    m: &T,
+    range: std::ops::Range<usize>,
+    out: &mut W,
+) -> std::io::Result<()> {
+    for addr in range.step_by(4) {
+        writeln!(out, "{:08x}", m.read_memory_word(addr))?;
+    }
+    Ok(())
+}
+
+/// Places the program arguments at the top of the stack, the way Linux does: 'sp' points to
+/// 'argc', which is followed by the 'argv' pointers and by empty 'envp' and 'auxv' vectors
+pub fn push_guest_args<T: Machine>(m: &mut T, args: &[String]) {
+    let mut registers = m.read_registers();
+    let pc = registers.pop().unwrap_or(0) as usize;
+    let sp_idx = Register::SP.id() as usize;
+    let top = registers[sp_idx] as usize;
+
+    let mut strings: Vec<u8> = Vec::new();
+    let mut offsets = Vec::new();
+    for arg in args {
+        offsets.push(strings.len());
+        strings.extend_from_slice(arg.as_bytes());
+        strings.push(0);
+    }
+    strings.resize(strings.len().next_multiple_of(16), 0);
+    let strings_start = top - strings.len();
+
+    let mut words: Vec<u32> = vec![args.len() as u32];
+    words.extend(offsets.iter().map(|off| (strings_start + off) as u32));
+    words.extend([0, 0, 0, 0]); // end of argv, envp and auxv (AT_NULL)
+    let sp = (strings_start - words.len() * 4) & !0xf;
+
+    m.write_memory_bytes(strings_start, &strings);
+    m.write_memory_bytes(sp, &words_to_bytes_le(&words));
+    registers[sp_idx] = sp as u32;
+    m.write_registers(registers, pc);
+}
+
 pub fn new_machine_from_bytes(text_bytes: &Vec<u8>) -> SimpleMachine {
     // print_bytes_hex(text_bytes);
     SimpleMachine::from_bytes(text_bytes, DataEndianness::Be)
@@ -208,14 +367,120 @@ pub fn emulate_from_elf(inputfile: &str) -> SimpleMachine {
     machine
 }
 
-pub fn wait_for_new_debugger_at_port<'a>(
+/// Runs a program (an assembly file or an executable) until it exits, stops with an error or
+/// reaches 'max_steps', giving back its commit log
+pub fn trace_program(filename: &str, max_steps: usize) -> String {
+    let mut m = if filename.ends_with(".s") {
+        new_machine_from_tools(&build_file_repr(filename).0)
+    } else {
+        new_machine_from_elf(filename)
+    };
+    let buffer = SharedBuffer::default();
+    m.set_commit_log(Some(CommitLog::new(
+        Box::new(buffer.clone()),
+        TraceFilter::default(),
+    )));
+    for _ in 0..max_steps {
+        match m.decode() {
+            Ok(MachineState::Ok) => {}
+            _ => break,
+        }
+    }
+    m.set_commit_log(None);
+    buffer.contents()
+}
+
+pub fn wait_for_new_debugger<'a>(
     memsize: usize,
-    port: u16,
+    transport: Transport,
+    float: FloatExtension,
+) -> SimpleGdbStub<'a, SimpleMachine> {
+    SimpleGdbStub::<SimpleMachine>::new(memsize, transport, float)
+        .expect("Failed when instantiating riscv32 debugger")
+}
+
+pub fn wait_for_new_debugger_with_elf<'a>(
+    filename: &str,
+    transport: Transport,
+    float: FloatExtension,
 ) -> SimpleGdbStub<'a, SimpleMachine> {
-    SimpleGdbStub::<SimpleMachine>::new(memsize, port)
+    SimpleGdbStub::<SimpleMachine>::from_elf(filename, transport, float)
         .expect("Failed when instantiating riscv32 debugger")
 }
 
+/// Builds the terminal debugger for an ELF file, whose source lines are taken from its
+/// debugging information (if there's any)
+pub fn new_repl_from_elf(filename: &str) -> Repl<SimpleMachine> {
+    let data = std::fs::read(filename).expect("Failed reading elf file");
+
+    let reader = elfreader::ElfReader::new(&data, DataEndianness::Le)
+        .expect("Failed instantiating elf file reader");
+
+    let mut m: SimpleMachine = load_elf(&reader);
+    push_guest_args(&mut m, &[filename.to_string()]);
+
+    let lines = read_line_table(&data).unwrap_or_default();
+    let mut files: Vec<String> = lines.iter().map(|line| line.file.clone()).collect();
+    files.dedup();
+
+    let mut repl = Repl::new(m, reader.symbol_addresses(), lines);
+    for file in files {
+        if let Ok(code) = std::fs::read_to_string(&file) {
+            repl.add_source(&file, &code);
+        }
+    }
+    repl
+}
+
+/// Builds the terminal debugger for assembled code, which runs in memory (execution starts at
+/// '_start', if there's one), showing the lines of 'sources'
+pub fn new_repl_from_tools(tools: &AssemblerTools, sources: &SourceMap) -> Repl<SimpleMachine> {
+    let mut m = new_machine_from_tools(tools);
+
+    let symbols: Vec<(String, usize)> = tools
+        .symbols
+        .iter()
+        // constants and the symbols of other objects aren't addresses
+        .filter(|(_, symbol)| {
+            !matches!(
+                symbol.section,
+                SectionName::Absolute | SectionName::Undefined
+            )
+        })
+        .map(|(name, symbol)| {
+            let start = tools
+                .sections
+                .values()
+                .find(|section| section.name == symbol.section)
+                .map_or(0, |section| section.address);
+            (name.clone(), start + symbol.relative_address)
+        })
+        .collect();
+    if let Some((_, start)) = symbols.iter().find(|(name, _)| name == "_start") {
+        m.set_pc(*start);
+    }
+
+    let mut lines = Vec::new();
+    for block in tools.blocks.iter().filter(|b| b.name == SectionName::Text) {
+        let mut address = block.addr;
+        for inst in &block.instructions {
+            let file = sources.get(inst.file_pos.file()).map_or("", |s| &s.name);
+            lines.push(SourceLine {
+                address,
+                file: file.to_string(),
+                line: inst.file_pos.row() + 1,
+            });
+            address += inst.data.len();
+        }
+    }
+
+    let mut repl = Repl::new(m, symbols, lines);
+    for source in sources.files() {
+        repl.add_source(&source.name, &source.code);
+    }
+    repl
+}
+
 // Data conversion
 
 /// Retrieves a mask to be used with the '&' to filter the first <n> bits of a word
@@ -333,17 +598,9 @@ pub fn words_to_bytes_le(words: &Vec<u32>) -> Vec<u8> {
         .collect()
 }
 
-pub fn encoded_data_to_bytes_le(data: &Vec<EncodedData>) -> Vec<u8> {
-    data.into_iter()
-        .map(|words_data| {
-            let mut raw_bytes = Vec::new();
-            for word in &words_data.data {
-                let word_bytes = u32::to_le_bytes(*word);
-                raw_bytes.extend(&word_bytes);
-            }
-            raw_bytes
-        })
-        .flatten()
+pub fn encoded_data_to_bytes_le(data: &[EncodedData]) -> Vec<u8> {
+    data.iter()
+        .flat_map(|line| line.data.iter().copied())
         .collect()
 }
 